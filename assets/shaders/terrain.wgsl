@@ -2,49 +2,79 @@
 #import bevy_pbr::mesh_types
 #import bevy_terrain::config
 #import bevy_terrain::tile
+#import bevy_terrain::bindings
 
-struct TerrainConfig {
-    lod_count: u32,
-    height: f32,
-    chunk_size: u32,
-    terrain_size: u32,
-
-    height_scale: f32,
-    density_scale: f32,
-    albedo_scale: f32,
-    _empty: u32,
-    height_offset: f32,
-    density_offset: f32,
-    albedo_offset: f32,
-    _empty: u32,
-}
-
-// terrain view bindings
-@group(1) @binding(0)
-var<uniform> view_config: TerrainViewConfig;
-@group(1) @binding(1)
-var quadtree: texture_2d_array<u32>;
-@group(1) @binding(2)
-var<storage> tiles: TileList;
-
-// terrain bindings
-@group(2) @binding(0)
-var<uniform> config: TerrainConfig;
-@group(2) @binding(1)
-var filter_sampler: sampler;
-@group(2) @binding(2)
-var height_atlas: texture_2d_array<f32>;
+// further attachments, whose indices are selected by the shader defs of the terrain pipeline
+#ifdef ALBEDO_ATTACHMENT_1
 @group(2) @binding(3)
-var density_atlas: texture_2d_array<f32>;
-#ifdef ALBEDO
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 1u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_2
 @group(2) @binding(4)
 var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 2u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_3
+@group(2) @binding(5)
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 3u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_4
+@group(2) @binding(6)
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 4u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_5
+@group(2) @binding(7)
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 5u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_6
+@group(2) @binding(8)
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 6u;
+#endif
+#ifdef ALBEDO_ATTACHMENT_7
+@group(2) @binding(9)
+var albedo_atlas: texture_2d_array<f32>;
+let albedo_attachment: u32 = 7u;
+#endif
+#ifdef NORMAL_ATTACHMENT_1
+@group(2) @binding(3)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 1u;
+#endif
+#ifdef NORMAL_ATTACHMENT_2
+@group(2) @binding(4)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 2u;
+#endif
+#ifdef NORMAL_ATTACHMENT_3
+@group(2) @binding(5)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 3u;
+#endif
+#ifdef NORMAL_ATTACHMENT_4
+@group(2) @binding(6)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 4u;
+#endif
+#ifdef NORMAL_ATTACHMENT_5
+@group(2) @binding(7)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 5u;
+#endif
+#ifdef NORMAL_ATTACHMENT_6
+@group(2) @binding(8)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 6u;
+#endif
+#ifdef NORMAL_ATTACHMENT_7
+@group(2) @binding(9)
+var normal_atlas: texture_2d_array<f32>;
+let normal_attachment: u32 = 7u;
 #endif
-
-
-// mesh bindings
-@group(3) @binding(0)
-var<uniform> mesh: Mesh;
 
 #import bevy_pbr::pbr_types
 #import bevy_pbr::utils
@@ -57,11 +87,6 @@ var<uniform> mesh: Mesh;
 #import bevy_terrain::terrain
 #import bevy_terrain::debug
 
-fn height_vertex(atlas_index: i32, atlas_coords: vec2<f32>) -> f32 {
-    let height_coords = atlas_coords * config.height_scale + config.height_offset;
-    return config.height * textureSampleLevel(height_atlas, filter_sampler, height_coords, atlas_index, 0.0).x;
-}
-
 fn color_fragment(
     in: FragmentInput,
     lod: u32,
@@ -70,10 +95,15 @@ fn color_fragment(
 ) -> vec4<f32> {
     var color = vec4<f32>(0.0);
 
-    let height_coords = atlas_coords * config.height_scale + config.height_offset;
-    let albedo_coords = atlas_coords * config.albedo_scale + config.albedo_offset;
+    let height_coords = attachment_coords(0u, atlas_coords);
 
+#ifdef NORMAL_ATTACHMENT
+    let normal_coords = attachment_coords(normal_attachment, atlas_coords);
+    let world_normal = sample_normal(normal_coords, atlas_index);
+#endif
+#ifndef NORMAL_ATTACHMENT
     let world_normal = calculate_normal(height_coords, atlas_index, lod);
+#endif
 
     #ifndef BRIGHT
         color = mix(color, vec4<f32>(1.0), 0.5);
@@ -84,8 +114,11 @@ fn color_fragment(
     #endif
 
     #ifdef ALBEDO
+    #ifdef ALBEDO_ATTACHMENT
+        let albedo_coords = attachment_coords(albedo_attachment, atlas_coords);
         color = mix(color, textureSample(albedo_atlas, filter_sampler, albedo_coords, atlas_index), 0.5);
     #endif
+    #endif
 
     #ifdef SHOW_UV
         color = mix(color, vec4<f32>(atlas_coords.x, atlas_coords.y, 0.0, 1.0), 0.5);
@@ -118,42 +151,7 @@ fn color_fragment(
 
 @vertex
 fn vertex(vertex: VertexInput) -> VertexOutput {
-    var tile_lod = 0u;
-    for (; tile_lod < 4u; tile_lod = tile_lod + 1u) {
-        if (vertex.index < tiles.counts[tile_lod].y) {
-            break;
-        }
-    }
-
-    let tile_size = calc_tile_count(tile_lod);
-    let vertices_per_row = (tile_size + 2u) << 1u;
-    let vertices_per_tile = vertices_per_row * tile_size;
-
-    let tile_index  = (vertex.index - tiles.counts[tile_lod].x) / vertices_per_tile + tile_lod * 100000u;
-    let vertex_index = (vertex.index - tiles.counts[tile_lod].x) % vertices_per_tile;
-
-    let tile = tiles.data[tile_index];
-    let local_position = calculate_position(vertex_index, tile, vertices_per_row, tile_size);
-
-    let world_position = vec3<f32>(local_position.x, view_config.height_under_viewer, local_position.y);
-    let blend = calculate_blend(world_position, view_config.vertex_blend);
-
-    let lookup = atlas_lookup(blend.log_distance, local_position);
-    var height = height_vertex(lookup.atlas_index, lookup.atlas_coords);
-
-    if (blend.ratio < 1.0) {
-        let lookup2 = atlas_lookup(blend.log_distance + 1.0, local_position);
-        var height2 = height_vertex(lookup2.atlas_index, lookup2.atlas_coords);
-        height = mix(height2, height, blend.ratio);
-    }
-
-    var output = vertex_output(local_position, height);
-
-#ifdef SHOW_TILES
-    output.color = show_tiles(tile, local_position, tile_lod);
-#endif
-
-    return output;
+    return terrain_vertex(vertex);
 }
 
 @fragment
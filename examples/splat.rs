@@ -0,0 +1,189 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            AddressMode, Extent3d, FilterMode, SamplerDescriptor, TextureDimension, TextureFormat,
+        },
+        texture::ImageSampler,
+    },
+};
+use bevy_terrain::{prelude::*, preprocess::prelude::*};
+
+const TERRAIN_SIZE: u32 = 1024;
+const LOD_COUNT: u32 = 5;
+const CHUNK_SIZE: u32 = 128;
+const HEIGHT: f32 = 200.0;
+const NODE_ATLAS_SIZE: u32 = 300;
+/// The attachments loaded in addition to the height.
+const ATTACHMENTS: [&str; 1] = ["splat"];
+/// The size of the generated material textures in texels.
+const LAYER_SIZE: u32 = 64;
+/// The colors of the sand, grass, rock and snow layers.
+const LAYER_COLORS: [[u8; 3]; 4] = [
+    [194, 178, 128],
+    [86, 125, 70],
+    [110, 105, 100],
+    [240, 240, 245],
+];
+
+fn main() {
+    preprocess();
+
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .insert_resource(
+            TerrainPipelineConfig::from_manifest(
+                &TerrainManifest::load("assets/terrain/"),
+                &ATTACHMENTS,
+            )
+            .unwrap(),
+        )
+        .add_plugin(TerrainPlugin)
+        .add_startup_system(setup)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<SplatMaterial>>,
+    mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+) {
+    let (config, from_disk_loader) =
+        TerrainConfig::load("terrain/", NODE_ATLAS_SIZE, &ATTACHMENTS).unwrap();
+
+    // Generate simple material layers, whose albedo alpha stores the height of the surface.
+    let albedo_layers = layer_texture(TextureFormat::Rgba8UnormSrgb, |layer, x, y| {
+        let variation = ((x * 7 + y * 13) % 16) as u8;
+        let [r, g, b] = LAYER_COLORS[layer as usize].map(|c| c.saturating_sub(variation));
+
+        vec![r, g, b, 16 * variation]
+    });
+    let normal_layers = layer_texture(TextureFormat::Rgba8Unorm, |_, _, _| {
+        vec![128, 128, 255, 255]
+    });
+    let roughness_layers = layer_texture(TextureFormat::R8Unorm, |layer, _, _| {
+        vec![[230, 200, 160, 80][layer as usize]]
+    });
+
+    let material = SplatMaterial::new(
+        &config,
+        "splat",
+        SplatLayout::Weights,
+        LAYER_COLORS.len() as u32,
+        images.add(albedo_layers),
+        images.add(normal_layers),
+        images.add(roughness_layers),
+    );
+
+    // Create the terrain, which is shaded with the splat material.
+    let terrain = commands
+        .spawn_bundle(TerrainBundle::with_material(
+            config.clone(),
+            materials.add(material),
+        ))
+        .insert(from_disk_loader)
+        .id();
+
+    let view_config = TerrainViewConfig::new(&config, 10, 5.0, 3.0, 10.0, 0.2, 0.2, 0.2);
+
+    let view = commands
+        .spawn_bundle(Camera3dBundle {
+            transform: Transform::from_xyz(-200.0, 500.0, -200.0)
+                .looking_at(Vec3::new(500.0, 0.0, 500.0), Vec3::Y),
+            ..default()
+        })
+        .insert(TerrainView)
+        .id();
+
+    let quadtree = Quadtree::from_configs(&config, &view_config);
+    view_configs.insert((terrain, view), view_config);
+    quadtrees.insert((terrain, view), quadtree);
+
+    commands.spawn_bundle(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            illuminance: 10000.0,
+            ..default()
+        },
+        transform: Transform::from_rotation(Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4)),
+        ..default()
+    });
+}
+
+/// Creates a repeating array texture with one layer per material, whose texels are
+/// returned by `texel` for each layer and position.
+fn layer_texture(format: TextureFormat, texel: impl Fn(u32, u32, u32) -> Vec<u8>) -> Image {
+    let layer_count = LAYER_COLORS.len() as u32;
+
+    let mut data = Vec::new();
+    for layer in 0..layer_count {
+        for y in 0..LAYER_SIZE {
+            for x in 0..LAYER_SIZE {
+                data.extend(texel(layer, x, y));
+            }
+        }
+    }
+
+    let size = Extent3d {
+        width: LAYER_SIZE,
+        height: LAYER_SIZE * layer_count,
+        depth_or_array_layers: 1,
+    };
+
+    let mut image = Image::new(size, TextureDimension::D2, data, format);
+    image.reinterpret_stacked_2d_as_array(layer_count);
+    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        ..default()
+    });
+
+    image
+}
+
+fn preprocess() {
+    let mut manifest = TerrainManifest::new(
+        "assets/terrain/",
+        TERRAIN_SIZE,
+        CHUNK_SIZE,
+        LOD_COUNT,
+        HEIGHT,
+    );
+
+    manifest.preprocess_tiles(
+        "assets/terrain/source/height",
+        "height",
+        ImageFormat::LUMA16,
+        false,
+        CHUNK_SIZE,
+        2,
+        &DownSampleFilter::Average,
+        None,
+    );
+
+    // Cover the terrain with grass, replaced by sand in the lowlands, rock on steep slopes
+    // and snow on the peaks.
+    let rules = [
+        SplatRule::new(1),
+        SplatRule {
+            height: Some(SplatRange::new(0.0, 20.0, 10.0)),
+            weight: 2.0,
+            ..SplatRule::new(0)
+        },
+        SplatRule {
+            slope: Some(SplatRange::new(0.4, 1.0, 0.1)),
+            weight: 3.0,
+            ..SplatRule::new(2)
+        },
+        SplatRule {
+            height: Some(SplatRange::new(150.0, HEIGHT, 20.0)),
+            weight: 4.0,
+            ..SplatRule::new(3)
+        },
+    ];
+
+    manifest.preprocess_splat("splat", "height", 1, &rules, SplatLayout::Weights);
+}
@@ -1,4 +1,4 @@
-use bevy::{prelude::*, render::render_resource::*};
+use bevy::prelude::*;
 use bevy_terrain::{prelude::*, preprocess::prelude::*};
 
 const TERRAIN_SIZE: u32 = 1024;
@@ -6,59 +6,41 @@ const LOD_COUNT: u32 = 5;
 const CHUNK_SIZE: u32 = 128;
 const HEIGHT: f32 = 200.0;
 const NODE_ATLAS_SIZE: u32 = 300;
+/// The attachments loaded from the preprocessed terrain, starting with the height.
+const ATTACHMENTS: [&str; 4] = ["height", "density", "albedo", "normal"];
 
 fn main() {
-    // Should only be run once. Comment out after the first run.
+    // Re-running the preprocessing only rebuilds the nodes affected by changed sources.
     preprocess();
 
     App::new()
         .add_plugins(DefaultPlugins)
-        .insert_resource(TerrainPipelineConfig {
-            attachment_count: 3, // has to match the attachments of the terrain
-            ..default()
-        })
+        .insert_resource(
+            TerrainPipelineConfig::from_manifest(
+                &TerrainManifest::load("assets/terrain/"),
+                &ATTACHMENTS,
+            )
+            .unwrap(),
+        )
         .add_plugin(TerrainPlugin)
         .add_startup_system(setup)
+        .add_system(follow_camera)
         .run();
 }
 
+/// Marks the sun, which follows the camera.
+#[derive(Component)]
+struct Sun;
+
 fn setup(
     mut commands: Commands,
     mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
     mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
 ) {
-    // Configure all the important properties of the terrain, as well as its attachments.
-    let mut config = TerrainConfig::new(
-        TERRAIN_SIZE,
-        CHUNK_SIZE,
-        LOD_COUNT,
-        HEIGHT,
-        NODE_ATLAS_SIZE,
-        "terrain/".to_string(),
-    );
-    let mut from_disk_loader = AttachmentFromDiskLoader::default();
-
-    config.add_attachment_from_disk(
-        &mut from_disk_loader,
-        "height",
-        TextureFormat::R16Unorm,
-        CHUNK_SIZE,
-        2,
-    );
-    config.add_attachment_from_disk(
-        &mut from_disk_loader,
-        "density",
-        TextureFormat::R16Unorm,
-        CHUNK_SIZE,
-        0,
-    );
-    config.add_attachment_from_disk(
-        &mut from_disk_loader,
-        "albedo",
-        TextureFormat::Rgba8UnormSrgb,
-        2 * CHUNK_SIZE,
-        1,
-    );
+    // Load the properties of the terrain, as well as its attachments, from the manifest
+    // written during preprocessing.
+    let (config, from_disk_loader) =
+        TerrainConfig::load("terrain/", NODE_ATLAS_SIZE, &ATTACHMENTS).unwrap();
 
     // Create the terrain.
     let terrain = commands
@@ -82,57 +64,101 @@ fn setup(
     // Store the quadtree and the view config for the terrain and view.
     // This will hopefully be way nicer once the ECS can handle relations.
     let quadtree = Quadtree::from_configs(&config, &view_config);
-    view_configs.insert((terrain, view), view_config);
+    view_configs.insert((terrain, view), view_config.clone());
     quadtrees.insert((terrain, view), quadtree);
 
     // Create a sunlight for the physical based lighting.
-    commands.spawn_bundle(DirectionalLightBundle {
-        directional_light: DirectionalLight {
-            illuminance: 10000.0,
+    // The sun is a terrain view as well, so that the terrain casts shadows.
+    // Its translation follows the camera, because it selects the level of detail of the shadows.
+    let sun = commands
+        .spawn_bundle(DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: 10000.0,
+                shadows_enabled: true,
+                shadow_projection: OrthographicProjection {
+                    left: -(TERRAIN_SIZE as f32),
+                    right: TERRAIN_SIZE as f32,
+                    bottom: -(TERRAIN_SIZE as f32),
+                    top: TERRAIN_SIZE as f32,
+                    near: -(TERRAIN_SIZE as f32),
+                    far: TERRAIN_SIZE as f32,
+                    ..default()
+                },
+                ..default()
+            },
+            transform: Transform {
+                translation: Vec3::new(-200.0, 500.0, -200.0),
+                rotation: Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4),
+                ..default()
+            },
             ..default()
-        },
-        transform: Transform {
-            translation: Vec3::new(0.0, 1.0, 0.0),
-            rotation: Quat::from_rotation_x(-std::f32::consts::FRAC_PI_4),
-            ..default()
-        },
-        ..default()
-    });
+        })
+        .insert(TerrainView)
+        .insert(Sun)
+        .id();
+
+    // The sun tessellates the terrain with cheaper settings than the camera.
+    let shadow_config = view_config.shadow_config();
+    let quadtree = Quadtree::from_configs(&config, &shadow_config);
+    view_configs.insert((terrain, sun), shadow_config);
+    quadtrees.insert((terrain, sun), quadtree);
+}
+
+/// Moves the sun with the camera, so that the shadows near the camera are detailed.
+fn follow_camera(
+    camera_query: Query<&Transform, (With<Camera>, Without<Sun>)>,
+    mut sun_query: Query<&mut Transform, With<Sun>>,
+) {
+    let camera = camera_query.single();
+
+    for mut sun in sun_query.iter_mut() {
+        sun.translation = camera.translation;
+    }
 }
 
 fn preprocess() {
-    preprocess_tiles(
-        "assets/terrain/source/height",
-        "assets/terrain/data/height",
-        0,
-        LOD_COUNT,
-        (0, 0),
+    let mut manifest = TerrainManifest::new(
+        "assets/terrain/",
         TERRAIN_SIZE,
         CHUNK_SIZE,
-        2,
-        ImageFormat::LUMA16,
+        LOD_COUNT,
+        HEIGHT,
     );
 
-    preprocess_density(
-        "assets/terrain/data/height",
-        "assets/terrain/data/density",
-        LOD_COUNT,
-        (0, 0),
-        (9, 9),
+    manifest.preprocess_tiles(
+        "assets/terrain/source/height",
+        "height",
+        ImageFormat::LUMA16,
+        false,
         CHUNK_SIZE,
         2,
-        HEIGHT,
+        &DownSampleFilter::Average,
+        None,
     );
 
-    preprocess_tiles(
+    manifest.preprocess_density(
+        "density",
+        "height",
+        &DensityMetric::Slope,
+        &DownSampleFilter::Max,
+    );
+
+    manifest.preprocess_tiles(
         "assets/terrain/source/albedo.png",
-        "assets/terrain/data/albedo",
-        0,
-        LOD_COUNT,
-        (0, 0),
-        2 * TERRAIN_SIZE,
+        "albedo",
+        ImageFormat::RGB,
+        true,
         2 * CHUNK_SIZE,
         1,
-        ImageFormat::RGB,
+        &DownSampleFilter::GammaAverage,
+        None,
+    );
+
+    manifest.preprocess_normal(
+        "normal",
+        "height",
+        1,
+        ImageFormat::RG16,
+        NormalEncoding::Octahedral,
     );
 }
@@ -8,8 +8,8 @@ use bevy::{
 };
 
 pub struct AttachmentFromDisk {
-    path: String,
-    format: TextureFormat,
+    pub(crate) path: String,
+    pub(crate) format: TextureFormat,
 }
 
 /// This component is used to load attachments from disk memory into the corresponding
@@ -1,22 +1,33 @@
 use crate::data_structures::node_atlas::NodeAtlas;
+use crate::render::terrain_material::{DefaultTerrainMaterial, TerrainMaterial};
 use crate::{terrain::Terrain, terrain::TerrainConfig};
 use bevy::prelude::*;
 
 #[derive(Bundle)]
-pub struct TerrainBundle {
+pub struct TerrainBundle<M: TerrainMaterial = DefaultTerrainMaterial> {
     terrain: Terrain,
     node_atlas: NodeAtlas,
     config: TerrainConfig,
+    material: Handle<M>,
     transform: Transform,
     global_transform: GlobalTransform,
 }
 
 impl TerrainBundle {
+    /// Creates a terrain, which is shaded with the [`DefaultTerrainMaterial`].
     pub fn new(config: TerrainConfig) -> Self {
+        Self::with_material(config, default())
+    }
+}
+
+impl<M: TerrainMaterial> TerrainBundle<M> {
+    /// Creates a terrain, which is shaded with the material.
+    pub fn with_material(config: TerrainConfig, material: Handle<M>) -> Self {
         Self {
             terrain: Terrain,
             node_atlas: NodeAtlas::from_config(&config),
             config,
+            material,
             transform: default(),
             global_transform: default(),
         }
@@ -3,6 +3,7 @@ use crate::{
         node_atlas::{LoadingNode, NodeAtlas},
         AtlasAttachment, AtlasIndex,
     },
+    editing::AttachmentUpdate,
     terrain::{Terrain, TerrainComponents},
 };
 use bevy::{
@@ -15,7 +16,7 @@ use bevy::{
         Extract, MainWorld,
     },
 };
-use std::mem;
+use std::{mem, num::NonZeroU32};
 
 impl AtlasAttachment {
     /// Creates the attachment from its config.
@@ -58,12 +59,19 @@ impl AtlasAttachment {
 /// alongside the data to update it.
 ///
 /// All attachments of newly loaded nodes are copied into their according atlas attachment.
+/// Edited regions of the attachments are written into the atlas directly.
 #[derive(Component)]
 pub struct GpuNodeAtlas {
     /// Stores the atlas attachments of the terrain.
     pub(crate) attachments: Vec<Handle<Image>>,
+    /// Stores the height bounds of the nodes by their atlas index.
+    pub(crate) height_bounds_buffer: Buffer,
     /// Stores the nodes, that have finished loading this frame.
     pub(crate) loaded_nodes: Vec<LoadingNode>,
+    /// Stores the regions of the attachments, that have been edited this frame.
+    pub(crate) updated_attachments: Vec<AttachmentUpdate>,
+    /// Stores the height bounds, if they have changed this frame.
+    pub(crate) height_bounds: Option<Vec<Vec2>>,
 }
 
 impl GpuNodeAtlas {
@@ -79,15 +87,69 @@ impl GpuNodeAtlas {
             .map(|attachment| attachment.create(device, images, node_atlas.size))
             .collect();
 
+        let height_bounds_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: "height_bounds_buffer".into(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            contents: bytemuck::cast_slice(&node_atlas.height_bounds),
+        });
+
         Self {
             attachments,
+            height_bounds_buffer,
             loaded_nodes: Vec::new(),
+            updated_attachments: Vec::new(),
+            height_bounds: None,
         }
     }
 
     /// Updates the atlas attachments, by copying over the data of the nodes that have
-    /// finished loading this frame.
-    fn update(&mut self, command_encoder: &mut CommandEncoder, images: &RenderAssets<Image>) {
+    /// finished loading this frame and writing the regions edited this frame.
+    /// Changed height bounds are written as well.
+    fn update(
+        &mut self,
+        command_encoder: &mut CommandEncoder,
+        queue: &RenderQueue,
+        images: &RenderAssets<Image>,
+    ) {
+        if let Some(height_bounds) = self.height_bounds.take() {
+            queue.write_buffer(
+                &self.height_bounds_buffer,
+                0,
+                bytemuck::cast_slice(&height_bounds),
+            );
+        }
+
+        for update in self.updated_attachments.drain(..) {
+            let atlas_attachment = match images.get(&self.attachments[update.attachment_index]) {
+                Some(atlas_attachment) => atlas_attachment,
+                None => continue,
+            };
+
+            queue.write_texture(
+                ImageCopyTexture {
+                    texture: &atlas_attachment.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: update.origin.x,
+                        y: update.origin.y,
+                        z: update.atlas_index as u32,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                &update.data,
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(update.bytes_per_row),
+                    rows_per_image: None,
+                },
+                Extent3d {
+                    width: update.size.x,
+                    height: update.size.y,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
         for node in self.loaded_nodes.drain(..) {
             for (node_handle, atlas_handle) in
                 self.attachments
@@ -149,8 +211,8 @@ pub(crate) fn initialize_gpu_node_atlas(
     }
 }
 
-/// Extracts the nodes that have finished loading from all [`NodeAtlas`]es into the
-/// corresponding [`GpuNodeAtlas`]es.
+/// Extracts the nodes that have finished loading, the edited regions and the height bounds
+/// from all [`NodeAtlas`]es into the corresponding [`GpuNodeAtlas`]es.
 pub(crate) fn extract_node_atlas(
     mut main_world: ResMut<MainWorld>,
     mut gpu_node_atlases: ResMut<TerrainComponents<GpuNodeAtlas>>,
@@ -163,6 +225,14 @@ pub(crate) fn extract_node_atlas(
             &mut node_atlas.loaded_nodes,
             &mut gpu_node_atlas.loaded_nodes,
         );
+        mem::swap(
+            &mut node_atlas.updated_attachments,
+            &mut gpu_node_atlas.updated_attachments,
+        );
+
+        if mem::take(&mut node_atlas.height_bounds_changed) {
+            gpu_node_atlas.height_bounds = Some(node_atlas.height_bounds.clone());
+        }
     }
 }
 
@@ -179,7 +249,7 @@ pub(crate) fn queue_node_atlas_updates(
 
     for terrain in terrain_query.iter() {
         let gpu_node_atlas = gpu_node_atlases.get_mut(&terrain).unwrap();
-        gpu_node_atlas.update(&mut command_encoder, &images);
+        gpu_node_atlas.update(&mut command_encoder, &queue, &images);
     }
 
     queue.submit(vec![command_encoder.finish()]);
@@ -74,7 +74,7 @@ pub struct AtlasAttachment {
     /// The handle of the attachment array texture.
     pub(crate) handle: Handle<Image>,
     /// The name of the attachment.
-    pub(crate) name: &'static str,
+    pub(crate) name: String,
     /// The none overlapping texture size in pixels.
     pub(crate) texture_size: u32,
     /// The overlapping border size around the texture, used to prevent sampling artifacts.
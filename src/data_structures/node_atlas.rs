@@ -1,7 +1,9 @@
 use crate::{
     data_structures::{
-        quadtree::Quadtree, AtlasAttachment, AtlasIndex, AttachmentIndex, NodeId, INVALID_NODE_ID,
+        calc_node_id, quadtree::Quadtree, AtlasAttachment, AtlasIndex, AttachmentIndex,
+        NodeCoordinate, NodeId, INVALID_NODE_ID,
     },
+    editing::{AttachmentUpdate, TexelLayout},
     terrain::{Terrain, TerrainConfig},
     TerrainView, TerrainViewComponents,
 };
@@ -45,7 +47,11 @@ impl LoadingNode {
 pub struct NodeData {
     // Todo: replace with array or vec of options
     /// Stores all of the cpu accessible attachments of the node.
-    pub(crate) _attachments: HashMap<AttachmentIndex, Handle<Image>>,
+    pub(crate) attachments: HashMap<AttachmentIndex, Handle<Image>>,
+    /// Stores the data of the attachments edited at runtime.
+    /// Edited attachments are copied out of their images, because modifying an image asset
+    /// would upload the whole image to the GPU again.
+    pub(crate) edited_attachments: HashMap<AttachmentIndex, Vec<u8>>,
 }
 
 /// The current state of a node of a [`NodeAtlas`].
@@ -91,14 +97,35 @@ struct UnusedNode {
 pub struct NodeAtlas {
     /// Nodes that are requested to be loaded this frame.
     pub load_events: Vec<NodeId>,
+    /// Nodes that have finished loading this frame.
+    pub(crate) finished_nodes: Vec<NodeId>,
+    /// Nodes whose atlas index has been reused for another node this frame, alongside that
+    /// atlas index. Their data stays available, until the new node has finished loading.
+    pub(crate) evicted_nodes: Vec<(NodeId, AtlasIndex)>,
     /// Stores the cpu accessible data of all loaded nodes.
     pub(crate) data: Vec<NodeData>, // Todo: build api for accessing data on the cpu
     /// Stores the atlas attachments of the terrain.
     pub(crate) attachments: Vec<AtlasAttachment>,
+    /// The index of the attachment storing the height.
+    pub(crate) height_attachment: AttachmentIndex,
     /// Stores the nodes, that have finished loading this frame.
     /// This data will be send to the
     /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas) each frame.
     pub(crate) loaded_nodes: Vec<LoadingNode>,
+    /// Stores the regions of the attachments, that have been edited this frame.
+    /// This data will be send to the
+    /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas) each frame.
+    pub(crate) updated_attachments: Vec<AttachmentUpdate>,
+    /// Stores the normalized minimum and maximum height of the node at each atlas index,
+    /// used to cull the tiles of the terrain.
+    /// The bounds include the bounds of the loaded descendants of the node, because the
+    /// tiles are culled with the bounds of the coarsest node covering them, while their
+    /// vertices may be displaced by the heights of finer nodes. Down sampling averages the
+    /// heights, thus the bounds of coarse nodes alone are too narrow.
+    pub(crate) height_bounds: Vec<Vec2>,
+    /// Indicates whether the height bounds have changed this frame and have to be send to the
+    /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas).
+    pub(crate) height_bounds_changed: bool,
     /// Stores the currently loading nodes.
     pub(crate) loading_nodes: HashMap<NodeId, LoadingNode>,
     /// The size of the node atlas, which determines how many nodes it can store.
@@ -110,6 +137,66 @@ pub struct NodeAtlas {
 }
 
 impl NodeAtlas {
+    /// Returns the cpu accessible data of the attachment of the node at the atlas index.
+    /// Edited attachments are read from their edited copy.
+    pub(crate) fn attachment_data<'a>(
+        &'a self,
+        images: &'a Assets<Image>,
+        atlas_index: AtlasIndex,
+        attachment_index: AttachmentIndex,
+    ) -> Option<&'a [u8]> {
+        let data = &self.data[atlas_index as usize];
+
+        match data.edited_attachments.get(&attachment_index) {
+            Some(edited) => Some(edited),
+            None => images
+                .get(data.attachments.get(&attachment_index)?)
+                .map(|image| &image.data[..]),
+        }
+    }
+
+    /// Returns the atlas index of the node, if it has finished loading.
+    fn loaded_atlas_index(&self, node_id: NodeId) -> Option<AtlasIndex> {
+        self.nodes
+            .get(&node_id)
+            .filter(|node| node.state == LoadingState::Loaded)
+            .map(|node| node.atlas_index)
+    }
+
+    /// Sets the height bounds of the node to its own `bounds` combined with the bounds of its
+    /// loaded children and widens the bounds of all of its loaded ancestors accordingly.
+    fn set_height_bounds(&mut self, node_id: NodeId, atlas_index: AtlasIndex, bounds: Vec2) {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let union = |a: Vec2, b: Vec2| Vec2::new(a.x.min(b.x), a.y.max(b.y));
+
+        let mut bounds = bounds;
+
+        if lod > 0 {
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let child_id = calc_node_id(lod - 1, 2 * x + dx, 2 * y + dy);
+
+                if let Some(child_index) = self.loaded_atlas_index(child_id) {
+                    bounds = union(bounds, self.height_bounds[child_index as usize]);
+                }
+            }
+        }
+
+        self.height_bounds[atlas_index as usize] = bounds;
+
+        // the lod is stored in four bits of the node id
+        for parent_lod in lod + 1..16 {
+            let shift = parent_lod - lod;
+            let parent_id = calc_node_id(parent_lod, x >> shift, y >> shift);
+
+            if let Some(parent_index) = self.loaded_atlas_index(parent_id) {
+                let parent_bounds = &mut self.height_bounds[parent_index as usize];
+                *parent_bounds = union(*parent_bounds, bounds);
+            }
+        }
+
+        self.height_bounds_changed = true;
+    }
+
     /// Creates a new quadtree from parameters.
     ///
     /// * `size` - The size of the node atlas, which determines how many nodes it can store.
@@ -124,11 +211,17 @@ impl NodeAtlas {
 
         Self {
             load_events: default(),
+            finished_nodes: default(),
+            evicted_nodes: default(),
             loaded_nodes: default(),
+            updated_attachments: default(),
+            height_bounds: vec![Vec2::new(0.0, 1.0); size as usize],
+            height_bounds_changed: false,
             loading_nodes: default(),
             nodes: default(),
             data: vec![default(); size as usize],
             attachments,
+            height_attachment: 0,
             size,
             unused_nodes,
         }
@@ -136,7 +229,10 @@ impl NodeAtlas {
 
     /// Creates a new quadtree from a terrain config.
     pub fn from_config(config: &TerrainConfig) -> Self {
-        Self::new(config.node_atlas_size as u16, config.attachments.clone())
+        Self {
+            height_attachment: config.height_attachment,
+            ..Self::new(config.node_atlas_size as u16, config.attachments.clone())
+        }
     }
 
     /// Adjusts the node atlas according to the requested and released nodes of the [`Quadtree`]
@@ -148,6 +244,7 @@ impl NodeAtlas {
             nodes,
             loading_nodes,
             load_events,
+            evicted_nodes,
             ..
         } = self;
 
@@ -182,7 +279,10 @@ impl NodeAtlas {
                 // remove least recently used node and reuse its atlas index
                 let unused_node = unused_nodes.pop_front().expect("Atlas out of indices");
 
-                nodes.remove(&unused_node.node_id);
+                if nodes.remove(&unused_node.node_id).is_some() {
+                    evicted_nodes.push((unused_node.node_id, unused_node.atlas_index));
+                }
+
                 nodes.insert(
                     node_id,
                     AtlasNode {
@@ -212,6 +312,8 @@ impl NodeAtlas {
         let NodeAtlas {
             ref mut data,
             ref mut load_events,
+            ref mut finished_nodes,
+            ref mut evicted_nodes,
             ref mut nodes,
             ref mut loading_nodes,
             ref mut loaded_nodes,
@@ -219,6 +321,8 @@ impl NodeAtlas {
         } = self;
 
         load_events.clear();
+        finished_nodes.clear();
+        evicted_nodes.clear();
 
         // update all nodes that have finished loading
         for (node_id, loading_node) in loading_nodes.drain_filter(|_, node| node.finished_loading())
@@ -228,9 +332,11 @@ impl NodeAtlas {
 
                 // Todo: only keep attachments required by the CPU around
                 data[node.atlas_index as usize] = NodeData {
-                    _attachments: loading_node.attachments.clone(),
+                    attachments: loading_node.attachments.clone(),
+                    edited_attachments: default(),
                 };
 
+                finished_nodes.push(node_id);
                 loaded_nodes.push(loading_node);
             } else {
                 dbg!("Dropped node after loading.");
@@ -256,3 +362,58 @@ pub(crate) fn update_node_atlas(
         }
     }
 }
+
+/// Updates the height bounds of all nodes, that have finished loading or whose height
+/// attachment has been edited this frame.
+pub(crate) fn update_height_bounds(
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<&mut NodeAtlas, With<Terrain>>,
+) {
+    for mut node_atlas in terrain_query.iter_mut() {
+        let node_atlas = &mut *node_atlas;
+        let height_attachment = node_atlas.height_attachment;
+
+        let mut node_ids: Vec<NodeId> = node_atlas.finished_nodes.clone();
+
+        // edits only know the atlas index of the node
+        for update in &node_atlas.updated_attachments {
+            if update.attachment_index == height_attachment {
+                node_ids.extend(
+                    node_atlas
+                        .nodes
+                        .iter()
+                        .filter(|(_, node)| node.atlas_index == update.atlas_index)
+                        .map(|(&node_id, _)| node_id),
+                );
+            }
+        }
+
+        // update the finer nodes first, so that their bounds are included by their ancestors
+        node_ids.sort_unstable_by_key(|&node_id| (NodeCoordinate::from(node_id).lod, node_id));
+        node_ids.dedup();
+
+        let layout = match node_atlas.attachments.get(height_attachment) {
+            Some(attachment) => TexelLayout::new(attachment.format),
+            None => continue,
+        };
+
+        for node_id in node_ids {
+            let atlas_index = match node_atlas.loaded_atlas_index(node_id) {
+                Some(atlas_index) => atlas_index,
+                None => continue,
+            };
+            let data = match node_atlas.attachment_data(&images, atlas_index, height_attachment) {
+                Some(data) => data,
+                None => continue,
+            };
+
+            let bounds = (0..data.len() / layout.texel_size())
+                .map(|index| layout.read(data, index)[0])
+                .fold(Vec2::new(1.0, 0.0), |bounds, height| {
+                    Vec2::new(bounds.x.min(height), bounds.y.max(height))
+                });
+
+            node_atlas.set_height_bounds(node_id, atlas_index, bounds);
+        }
+    }
+}
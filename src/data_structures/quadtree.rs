@@ -331,16 +331,18 @@ fn height_under_viewer(
         return 0.0;
     }
 
-    let node = node_atlas.data[node.atlas_index as usize]
-        ._attachments
-        .get(&0)
+    let height_attachment = node_atlas.height_attachment;
+    let size = images
+        .get(&node_atlas.data[node.atlas_index as usize].attachments[&height_attachment])
+        .unwrap()
+        .size();
+    let data = node_atlas
+        .attachment_data(images, node.atlas_index, height_attachment)
         .unwrap();
 
-    let image = images.get(node).unwrap();
-
-    let position = (image.size() * atlas_coords).as_uvec2();
-    let index = 2 * (position.x + position.y * image.size().x as u32) as usize;
-    let height = ((image.data[index + 1] as u16) << 8) + image.data[index] as u16;
+    let position = (size * atlas_coords).as_uvec2();
+    let index = 2 * (position.x + position.y * size.x as u32) as usize;
+    let height = ((data[index + 1] as u16) << 8) + data[index] as u16;
     let height = height as f32 / u16::MAX as f32 * quadtree.height;
 
     return height;
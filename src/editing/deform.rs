@@ -0,0 +1,489 @@
+//! Deforms the height of the terrain during gameplay, e.g. for craters, tracks or digging.
+//!
+//! # Explanation
+//! A [`TerrainDeformation`] describes an analytic shape, which either carves the height down
+//! to its lower surface or fills it up to its upper one.
+//! Because the shapes are analytic, they are evaluated directly at the texels of every lod,
+//! instead of rebuilding the coarser lods from the edited nodes of lod 0.
+//!
+//! All deformations are stored in an ordered log by the [`TerrainDeformer`].
+//! Nodes that finish loading later on replay all logged deformations, that overlap them.
+//! Carving and filling only clamp the height of each texel, so replaying them onto a node,
+//! that already contains them, changes nothing.
+//! Thus every client, which receives the same log (e.g. a late joining one), ends up with
+//! the same terrain, regardless of which nodes it had loaded at which time.
+//! The shapes are evaluated with basic arithmetic only (the rotation of boxes uses a polynomial
+//! approximation of sine and cosine), so that the heights are bit identical across platforms.
+//!
+//! Deformations are not recorded in the undo history of the
+//! [`TerrainEditor`](super::TerrainEditor). Instead, the undo steps overlapping them are
+//! discarded, because undoing those would revert the deformations as well.
+//! Deformations are not saved to the node files either.
+
+use crate::{
+    data_structures::{node_atlas::NodeAtlas, AttachmentIndex, NodeCoordinate, NodeId},
+    editing::{attachment_index, edit_attachment, NodeRegion, TerrainEditor, TexelRect},
+    terrain::TerrainConfig,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+/// Computes the sine and cosine of the angle with basic arithmetic only, so that the result is
+/// bit identical on all platforms, unlike [`f32::sin_cos`], which depends on the math library.
+/// Multiples of a quarter turn are exact.
+fn deterministic_sin_cos(angle: f32) -> (f32, f32) {
+    let quadrant = (angle / FRAC_PI_2).round();
+    let x = angle - quadrant * FRAC_PI_2;
+    let x2 = x * x;
+
+    // taylor series, which are accurate to single precision for |x| <= π / 4
+    let sin = x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0))));
+    let cos = 1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)));
+
+    match (quadrant as i64).rem_euclid(4) {
+        0 => (sin, cos),
+        1 => (cos, -sin),
+        2 => (-sin, -cos),
+        _ => (-cos, sin),
+    }
+}
+
+/// An analytic shape of a [`TerrainDeformation`], in the local space of the terrain.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeformationShape {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    /// The cross section of the capsule is evaluated at the point of its axis, which is
+    /// horizontally closest, so it is exact for level capsules (e.g. tracks).
+    Capsule {
+        start: Vec3,
+        end: Vec3,
+        radius: f32,
+    },
+    /// A box rotated by `rotation` radians around the vertical axis.
+    /// The rotation is approximated deterministically.
+    Box {
+        center: Vec3,
+        half_size: Vec3,
+        rotation: f32,
+    },
+}
+
+impl DeformationShape {
+    /// Returns the lower and upper height of the shape at the horizontal position,
+    /// if the vertical line through it intersects the shape.
+    fn span(&self, position: Vec2) -> Option<(f32, f32)> {
+        let sphere_span = |center: Vec3, radius: f32| {
+            let distance_squared = position.distance_squared(center.xz());
+
+            (distance_squared <= radius * radius).then(|| {
+                let extent = (radius * radius - distance_squared).sqrt();
+                (center.y - extent, center.y + extent)
+            })
+        };
+
+        match *self {
+            Self::Sphere { center, radius } => sphere_span(center, radius),
+            Self::Capsule { start, end, radius } => {
+                let axis = end.xz() - start.xz();
+                let length_squared = axis.length_squared();
+
+                let t = if length_squared == 0.0 {
+                    0.0
+                } else {
+                    ((position - start.xz()).dot(axis) / length_squared).clamp(0.0, 1.0)
+                };
+
+                sphere_span(start.lerp(end, t), radius)
+            }
+            Self::Box {
+                center,
+                half_size,
+                rotation,
+            } => {
+                let (sin, cos) = deterministic_sin_cos(rotation);
+                let offset = position - center.xz();
+                let local = Vec2::new(
+                    cos * offset.x + sin * offset.y,
+                    cos * offset.y - sin * offset.x,
+                );
+
+                (local.x.abs() <= half_size.x && local.y.abs() <= half_size.z)
+                    .then(|| (center.y - half_size.y, center.y + half_size.y))
+            }
+        }
+    }
+
+    /// Returns the horizontal bounding rectangle of the shape.
+    fn bounds(&self) -> (Vec2, Vec2) {
+        match *self {
+            Self::Sphere { center, radius } => (center.xz() - radius, center.xz() + radius),
+            Self::Capsule { start, end, radius } => (
+                start.xz().min(end.xz()) - radius,
+                start.xz().max(end.xz()) + radius,
+            ),
+            Self::Box {
+                center,
+                half_size,
+                rotation,
+            } => {
+                let (sin, cos) = deterministic_sin_cos(rotation);
+                let extent = Vec2::new(
+                    cos.abs() * half_size.x + sin.abs() * half_size.z,
+                    sin.abs() * half_size.x + cos.abs() * half_size.z,
+                );
+
+                (center.xz() - extent, center.xz() + extent)
+            }
+        }
+    }
+}
+
+/// Determines how a [`TerrainDeformation`] changes the height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeformationMode {
+    /// Lowers the height to the lower surface of the shape, e.g. for craters and digging.
+    Carve,
+    /// Raises the height to the upper surface of the shape, e.g. for piles.
+    Fill,
+}
+
+/// An event, which deforms the height of all terrains with a [`TerrainDeformer`].
+///
+/// Deformations are compact and serializable, so that they can be replicated over the network.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TerrainDeformation {
+    pub shape: DeformationShape,
+    pub mode: DeformationMode,
+}
+
+impl TerrainDeformation {
+    /// Applies the deformation to the height in world units at the horizontal position.
+    #[inline]
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        match (self.shape.span(position), self.mode) {
+            (Some((lower, _)), DeformationMode::Carve) => height.min(lower),
+            (Some((_, upper)), DeformationMode::Fill) => height.max(upper),
+            (None, _) => height,
+        }
+    }
+}
+
+/// Applies [`TerrainDeformation`]s to the height of a terrain and keeps their ordered log.
+#[derive(Component)]
+pub struct TerrainDeformer {
+    /// The index of the height attachment.
+    height_attachment: AttachmentIndex,
+    /// All deformations in the order they have been applied.
+    log: Vec<TerrainDeformation>,
+    /// The count of deformations of the log, that have been applied to the loaded nodes.
+    applied: usize,
+}
+
+impl TerrainDeformer {
+    /// Creates a deformer for the terrain, whose height is stored in the attachment `height_name`.
+    pub fn new(config: &TerrainConfig, height_name: &str) -> Self {
+        Self {
+            height_attachment: attachment_index(config, height_name),
+            log: default(),
+            applied: 0,
+        }
+    }
+
+    /// Returns the ordered log of all deformations, e.g. to send it to a late joining client.
+    pub fn log(&self) -> &[TerrainDeformation] {
+        &self.log
+    }
+
+    /// Appends the deformations to the log, e.g. the log received from a server.
+    /// They are applied to the loaded nodes at the end of the frame.
+    pub fn replay(&mut self, deformations: impl IntoIterator<Item = TerrainDeformation>) {
+        self.log.extend(deformations);
+    }
+}
+
+/// Converts the horizontal bounds in world units into the texel rectangle of the lod.
+fn texel_rect(bounds: (Vec2, Vec2), texels_per_unit: f32, lod: u32) -> TexelRect {
+    let scale = texels_per_unit / (1 << lod) as f32;
+
+    TexelRect {
+        min: (bounds.0 * scale).floor().as_ivec2(),
+        max: (bounds.1 * scale).ceil().as_ivec2() + 1,
+    }
+}
+
+/// Applies the deformations to all texels of the height attachment of the lod inside the rect.
+/// Returns the edited regions.
+fn deform(
+    deformations: &[TerrainDeformation],
+    attachment_index: AttachmentIndex,
+    lod: u32,
+    rect: TexelRect,
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    config: &TerrainConfig,
+) -> Vec<NodeRegion> {
+    let attachment = &node_atlas.attachments[attachment_index];
+    let units_per_texel =
+        config.chunk_size as f32 / attachment.texture_size as f32 * (1 << lod) as f32;
+
+    edit_attachment(
+        node_atlas,
+        images,
+        attachment_index,
+        lod,
+        rect,
+        |_, position, texel| {
+            let position = (position.as_vec2() + 0.5) * units_per_texel;
+
+            let height = deformations
+                .iter()
+                .fold(texel[0] * config.height, |height, deformation| {
+                    deformation.apply(position, height)
+                });
+
+            [height / config.height, 0.0, 0.0, 0.0]
+        },
+    )
+    .into_iter()
+    .map(|edit| edit.region)
+    .collect()
+}
+
+/// Replays all logged deformations, that overlap the node, onto it.
+/// Returns the edited regions.
+fn replay_node(
+    deformations: &[TerrainDeformation],
+    attachment_index: AttachmentIndex,
+    node_id: NodeId,
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    config: &TerrainConfig,
+) -> Vec<NodeRegion> {
+    let attachment = &node_atlas.attachments[attachment_index];
+    let (texture_size, border_size) = (
+        attachment.texture_size as i32,
+        attachment.border_size as i32,
+    );
+    let texels_per_unit = attachment.texture_size as f32 / config.chunk_size as f32;
+    let NodeCoordinate { lod, x, y } = node_id.into();
+
+    let node_rect = TexelRect {
+        min: IVec2::new(x as i32, y as i32) * texture_size - border_size,
+        max: IVec2::new(x as i32 + 1, y as i32 + 1) * texture_size + border_size,
+    };
+
+    let overlapping: Vec<_> = deformations
+        .iter()
+        .filter(|deformation| {
+            let rect = texel_rect(deformation.shape.bounds(), texels_per_unit, lod);
+
+            rect.min.cmplt(node_rect.max).all() && rect.max.cmpgt(node_rect.min).all()
+        })
+        .copied()
+        .collect();
+
+    if overlapping.is_empty() {
+        return Vec::new();
+    }
+
+    deform(
+        &overlapping,
+        attachment_index,
+        lod,
+        node_rect,
+        node_atlas,
+        images,
+        config,
+    )
+}
+
+/// Logs the deformation events and applies all new deformations to the loaded nodes of every lod.
+/// Nodes, that finished loading this frame, replay the previously applied deformations.
+/// The undo steps of the [`TerrainEditor`] overlapping the deformed regions are discarded.
+pub(crate) fn apply_terrain_deformations(
+    mut deformation_events: EventReader<TerrainDeformation>,
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(
+        &mut TerrainDeformer,
+        &mut NodeAtlas,
+        &TerrainConfig,
+        Option<&mut TerrainEditor>,
+    )>,
+) {
+    let deformations: Vec<_> = deformation_events.iter().copied().collect();
+
+    for (mut deformer, mut node_atlas, config, editor) in terrain_query.iter_mut() {
+        let deformer = &mut *deformer;
+        let attachment_index = deformer.height_attachment;
+        let mut regions = Vec::new();
+
+        for node_id in node_atlas.finished_nodes.clone() {
+            regions.extend(replay_node(
+                &deformer.log[..deformer.applied],
+                attachment_index,
+                node_id,
+                &mut node_atlas,
+                &images,
+                config,
+            ));
+        }
+
+        deformer.log.extend(&deformations);
+
+        let attachment = &node_atlas.attachments[attachment_index];
+        let texels_per_unit = attachment.texture_size as f32 / config.chunk_size as f32;
+
+        for deformation in &deformer.log[deformer.applied..] {
+            for lod in 0..config.lod_count {
+                let rect = texel_rect(deformation.shape.bounds(), texels_per_unit, lod);
+
+                regions.extend(deform(
+                    &[*deformation],
+                    attachment_index,
+                    lod,
+                    rect,
+                    &mut node_atlas,
+                    &images,
+                    config,
+                ));
+            }
+        }
+
+        deformer.applied = deformer.log.len();
+
+        if let Some(mut editor) = editor {
+            if !regions.is_empty() {
+                editor.history.invalidate(&regions);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{PI, TAU};
+
+    /// A mix of overlapping deformations, including rotated boxes.
+    fn log() -> Vec<TerrainDeformation> {
+        vec![
+            TerrainDeformation {
+                shape: DeformationShape::Sphere {
+                    center: Vec3::new(8.0, 4.0, 8.0),
+                    radius: 6.0,
+                },
+                mode: DeformationMode::Carve,
+            },
+            TerrainDeformation {
+                shape: DeformationShape::Box {
+                    center: Vec3::new(10.0, 2.0, 6.0),
+                    half_size: Vec3::new(5.0, 1.5, 2.0),
+                    rotation: 0.7,
+                },
+                mode: DeformationMode::Fill,
+            },
+            TerrainDeformation {
+                shape: DeformationShape::Capsule {
+                    start: Vec3::new(0.0, 3.0, 12.0),
+                    end: Vec3::new(16.0, 5.0, 2.0),
+                    radius: 2.5,
+                },
+                mode: DeformationMode::Carve,
+            },
+            TerrainDeformation {
+                shape: DeformationShape::Box {
+                    center: Vec3::new(4.0, 6.0, 4.0),
+                    half_size: Vec3::new(3.0, 1.0, 1.0),
+                    rotation: -2.3,
+                },
+                mode: DeformationMode::Fill,
+            },
+        ]
+    }
+
+    /// Applies the deformations to a 16x16 height grid.
+    fn apply(deformations: &[TerrainDeformation], heights: &[f32]) -> Vec<f32> {
+        heights
+            .iter()
+            .enumerate()
+            .map(|(index, &height)| {
+                let position = Vec2::new((index % 16) as f32, (index / 16) as f32) + 0.5;
+
+                deformations.iter().fold(height, |height, deformation| {
+                    deformation.apply(position, height)
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sin_cos_approximates_the_math_library() {
+        for step in -400..=400 {
+            let angle = step as f32 * 0.01;
+            let (sin, cos) = deterministic_sin_cos(angle);
+
+            assert!((sin - angle.sin()).abs() < 1e-6, "sin({angle})");
+            assert!((cos - angle.cos()).abs() < 1e-6, "cos({angle})");
+        }
+    }
+
+    #[test]
+    fn quarter_turns_are_exact() {
+        assert_eq!(deterministic_sin_cos(0.0), (0.0, 1.0));
+        assert_eq!(deterministic_sin_cos(PI / 2.0), (1.0, -0.0));
+        assert_eq!(deterministic_sin_cos(PI), (-0.0, -1.0));
+        assert_eq!(deterministic_sin_cos(-PI / 2.0), (-1.0, 0.0));
+        assert_eq!(deterministic_sin_cos(TAU), (0.0, 1.0));
+    }
+
+    #[test]
+    fn rotated_boxes_are_bounded() {
+        let shape = DeformationShape::Box {
+            center: Vec3::new(3.0, 0.0, -2.0),
+            half_size: Vec3::new(4.0, 1.0, 1.5),
+            rotation: 2.1,
+        };
+        let (min, max) = shape.bounds();
+
+        for y in -40..40 {
+            for x in -40..40 {
+                let position = Vec2::new(x as f32, y as f32) * 0.25;
+
+                if shape.span(position).is_some() {
+                    assert!(position.cmpge(min).all() && position.cmple(max).all());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replaying_the_log_is_idempotent() {
+        let heights: Vec<f32> = (0..256).map(|index| (index % 7) as f32).collect();
+        let deformed = apply(&log(), &heights);
+
+        assert_eq!(apply(&log(), &deformed), deformed);
+    }
+
+    #[test]
+    fn late_replay_matches_live_deformation() {
+        let heights: Vec<f32> = (0..256).map(|index| (index % 5) as f32 + 1.0).collect();
+        let log = log();
+
+        // a node, which was loaded the whole time, is deformed by one event at a time
+        let live = log.iter().fold(heights.clone(), |heights, deformation| {
+            apply(&[*deformation], &heights)
+        });
+
+        // a node, which was evicted and loaded again, replays the log onto its saved data,
+        // which may already contain some of the deformations
+        for applied in 0..=log.len() {
+            let saved = apply(&log[..applied], &heights);
+
+            assert_eq!(apply(&log, &saved), live);
+        }
+    }
+}
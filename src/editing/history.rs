@@ -0,0 +1,756 @@
+//! Records the edits of a [`TerrainEditor`] to undo and redo them.
+//!
+//! Each edit is stored per node and attachment, limited to the edited region.
+//! Instead of whole snapshots only the XOR of the previous and the new texels is kept,
+//! which is zero outside of the changed texels and thus compresses well with a simple
+//! run length encoding of its zeros. The same delta restores both directions.
+//!
+//! This only holds, as long as the region has not been changed outside of the history.
+//! Thus the hashes of the previous and the new texels are stored alongside each delta and
+//! undo steps, whose regions do not match the expected texels anymore, are discarded instead
+//! of being applied.
+
+use crate::{
+    data_structures::{node_atlas::NodeAtlas, AttachmentIndex, NodeId},
+    editing::{read_region, write_region, EditCommand, NodeRegion, RegionEdit, TerrainEditor},
+};
+use bevy::{prelude::*, utils::HashMap};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    mem,
+};
+
+/// The default memory limit of the history in bytes.
+const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            output.push(byte);
+            return;
+        }
+
+        output.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], index: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*index];
+        *index += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+
+        shift += 7;
+    }
+}
+
+/// Compresses the data into pairs of zero runs and literal runs.
+/// Literal runs end at the next run of at least four zeros.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut index = 0;
+
+    while index < data.len() {
+        let zeros = data[index..].iter().take_while(|&&byte| byte == 0).count();
+        index += zeros;
+
+        let start = index;
+
+        while index < data.len() && !data[index..].starts_with(&[0; 4]) {
+            index += 1;
+        }
+
+        write_varint(&mut output, zeros);
+        write_varint(&mut output, index - start);
+        output.extend_from_slice(&data[start..index]);
+    }
+
+    output
+}
+
+fn decompress(data: &[u8], length: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(length);
+    let mut index = 0;
+
+    while index < data.len() {
+        let zeros = read_varint(data, &mut index);
+        output.resize(output.len() + zeros, 0);
+
+        let literals = read_varint(data, &mut index);
+        output.extend_from_slice(&data[index..index + literals]);
+        index += literals;
+    }
+
+    output
+}
+
+/// Stores the texels of the edited regions, which the history reads and restores.
+pub(crate) trait RegionStorage {
+    /// Reads the tightly packed texels of the region, if it is available.
+    fn read(&self, region: &NodeRegion) -> Option<Vec<u8>>;
+
+    /// Writes the tightly packed texels into the region.
+    /// Returns false, if the region is not available.
+    fn write(&mut self, region: &NodeRegion, data: Vec<u8>) -> bool;
+}
+
+/// The regions of the loaded nodes of a node atlas.
+pub(crate) struct LoadedRegions<'a> {
+    pub(crate) node_atlas: &'a mut NodeAtlas,
+    pub(crate) images: &'a Assets<Image>,
+}
+
+impl RegionStorage for LoadedRegions<'_> {
+    fn read(&self, region: &NodeRegion) -> Option<Vec<u8>> {
+        read_region(self.node_atlas, self.images, region)
+    }
+
+    fn write(&mut self, region: &NodeRegion, data: Vec<u8>) -> bool {
+        write_region(self.node_atlas, self.images, region, data)
+    }
+}
+
+fn hash_texels(texels: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    texels.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The compressed XOR of the previous and the new texels of a region.
+struct RegionDelta {
+    region: NodeRegion,
+    texel_size: usize,
+    /// The uncompressed size of the delta in bytes.
+    length: usize,
+    data: Vec<u8>,
+    /// The hashes of the previous and the new texels of the region.
+    hashes: (u64, u64),
+}
+
+impl RegionDelta {
+    fn new(edit: RegionEdit) -> Self {
+        let delta: Vec<u8> = edit
+            .before
+            .iter()
+            .zip(&edit.after)
+            .map(|(before, after)| before ^ after)
+            .collect();
+
+        Self {
+            region: edit.region,
+            texel_size: delta.len() / (edit.region.size.x * edit.region.size.y) as usize,
+            length: delta.len(),
+            data: compress(&delta),
+            hashes: (hash_texels(&edit.before), hash_texels(&edit.after)),
+        }
+    }
+
+    /// Updates the hashes from the current texels of the region, which are the new ones.
+    /// Returns false, if the region is not available.
+    fn rehash(&mut self, storage: &impl RegionStorage) -> bool {
+        let mut texels = match storage.read(&self.region) {
+            Some(texels) => texels,
+            None => return false,
+        };
+
+        let after = hash_texels(&texels);
+        self.toggle(&mut texels);
+        self.hashes = (hash_texels(&texels), after);
+
+        true
+    }
+
+    /// Combines the consecutive deltas of the same node attachment.
+    fn merge(self, other: Self) -> Self {
+        let region = self.region.union(other.region);
+        let texel_size = self.texel_size;
+        let mut delta = vec![0; (region.size.x * region.size.y) as usize * texel_size];
+
+        for part in [self, other] {
+            let offset = part.region.origin - region.origin;
+            let bytes_per_row = part.region.size.x as usize * texel_size;
+
+            for (row, bytes) in decompress(&part.data, part.length)
+                .chunks(bytes_per_row)
+                .enumerate()
+            {
+                let start = ((offset.y as usize + row) * region.size.x as usize
+                    + offset.x as usize)
+                    * texel_size;
+
+                for (value, byte) in delta[start..start + bytes_per_row].iter_mut().zip(bytes) {
+                    *value ^= byte;
+                }
+            }
+        }
+
+        // the hashes of the combined region are updated, once it has been recorded
+        Self {
+            region,
+            texel_size,
+            length: delta.len(),
+            data: compress(&delta),
+            hashes: (0, 0),
+        }
+    }
+
+    /// Toggles the texels between their previous and their new values.
+    fn toggle(&self, texels: &mut [u8]) {
+        for (texel, byte) in texels.iter_mut().zip(decompress(&self.data, self.length)) {
+            *texel ^= byte;
+        }
+    }
+}
+
+/// The outcome of applying a [`HistoryEntry`].
+#[derive(Debug, PartialEq)]
+pub(crate) enum Restored {
+    /// The regions, that have been restored. Regions of unavailable nodes are skipped.
+    Regions(Vec<NodeRegion>),
+    /// The entry has not been applied, because one of its regions has been changed outside
+    /// of the history.
+    Conflict,
+}
+
+/// All deltas of a single undo step.
+#[derive(Default)]
+struct HistoryEntry {
+    deltas: HashMap<(AttachmentIndex, NodeId), RegionDelta>,
+}
+
+impl HistoryEntry {
+    /// Inserts the delta of an edit, which has just been applied to the storage.
+    fn insert(&mut self, delta: RegionDelta, storage: &impl RegionStorage) {
+        let key = (delta.region.attachment_index, delta.region.node_id);
+
+        let delta = match self.deltas.remove(&key) {
+            Some(previous) => {
+                let mut delta = previous.merge(delta);
+
+                if !delta.rehash(storage) {
+                    return;
+                }
+
+                delta
+            }
+            None => delta,
+        };
+
+        self.deltas.insert(key, delta);
+    }
+
+    /// The memory used by the compressed deltas in bytes.
+    fn memory(&self) -> usize {
+        self.deltas
+            .values()
+            .map(|delta| delta.data.len() + mem::size_of::<RegionDelta>())
+            .sum()
+    }
+
+    /// Applies all deltas, if all available regions match the texels expected by the history.
+    /// The new texels are expected when undoing and the previous ones when redoing.
+    fn apply(&self, storage: &mut impl RegionStorage, undo: bool) -> Restored {
+        let mut restored = Vec::new();
+
+        for delta in self.deltas.values() {
+            let texels = match storage.read(&delta.region) {
+                Some(texels) => texels,
+                None => continue,
+            };
+
+            let expected = if undo { delta.hashes.1 } else { delta.hashes.0 };
+
+            if hash_texels(&texels) != expected {
+                return Restored::Conflict;
+            }
+
+            restored.push((delta, texels));
+        }
+
+        Restored::Regions(
+            restored
+                .into_iter()
+                .filter_map(|(delta, mut texels)| {
+                    delta.toggle(&mut texels);
+                    storage.write(&delta.region, texels).then_some(delta.region)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The undo and redo history of a [`TerrainEditor`].
+pub(crate) struct EditHistory {
+    undo_entries: VecDeque<HistoryEntry>,
+    redo_entries: Vec<HistoryEntry>,
+    /// Collects all edits of the current stroke into a single entry.
+    stroke: Option<HistoryEntry>,
+    /// The memory limit of all entries (including the current stroke) in bytes.
+    memory_limit: usize,
+}
+
+impl Default for EditHistory {
+    fn default() -> Self {
+        Self {
+            undo_entries: default(),
+            redo_entries: default(),
+            stroke: None,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+        }
+    }
+}
+
+impl EditHistory {
+    pub(crate) fn begin_stroke(&mut self) {
+        self.end_stroke();
+        self.stroke = Some(default());
+    }
+
+    pub(crate) fn end_stroke(&mut self) {
+        if let Some(entry) = self.stroke.take() {
+            self.push(entry);
+        }
+    }
+
+    /// Records the edits, which have just been applied to the storage, into the current stroke,
+    /// or as a separate entry, if there is no stroke.
+    pub(crate) fn record(&mut self, storage: &impl RegionStorage, edits: Vec<RegionEdit>) {
+        if edits.is_empty() {
+            return;
+        }
+
+        // new edits invalidate the undone entries
+        self.redo_entries.clear();
+
+        match &mut self.stroke {
+            Some(stroke) => {
+                for edit in edits {
+                    stroke.insert(RegionDelta::new(edit), storage);
+                }
+
+                self.limit_memory();
+            }
+            None => {
+                let mut entry = HistoryEntry::default();
+
+                for edit in edits {
+                    entry.insert(RegionDelta::new(edit), storage);
+                }
+
+                self.push(entry);
+            }
+        }
+    }
+
+    fn push(&mut self, entry: HistoryEntry) {
+        if entry.deltas.is_empty() {
+            return;
+        }
+
+        self.redo_entries.clear();
+        self.undo_entries.push_back(entry);
+        self.limit_memory();
+    }
+
+    /// The memory used by all entries, including the current stroke, in bytes.
+    fn memory(&self) -> usize {
+        self.undo_entries
+            .iter()
+            .chain(&self.redo_entries)
+            .chain(&self.stroke)
+            .map(HistoryEntry::memory)
+            .sum()
+    }
+
+    /// Drops the oldest undo entries and then the last entries to be redone, until the history
+    /// fits into its memory limit. The current stroke is never dropped.
+    fn limit_memory(&mut self) {
+        let mut memory = self.memory();
+
+        while memory > self.memory_limit {
+            let entry = match self.undo_entries.pop_front() {
+                Some(entry) => entry,
+                None if !self.redo_entries.is_empty() => self.redo_entries.remove(0),
+                None => break,
+            };
+
+            memory -= entry.memory();
+        }
+    }
+
+    /// Reverts the last entry and returns the restored regions.
+    ///
+    /// Entries, whose regions have been changed outside of the history, are discarded
+    /// together with all older entries, because those depend on them.
+    pub(crate) fn undo(&mut self, storage: &mut impl RegionStorage) -> Restored {
+        self.end_stroke();
+
+        let entry = match self.undo_entries.pop_back() {
+            Some(entry) => entry,
+            None => return Restored::Regions(Vec::new()),
+        };
+
+        let restored = entry.apply(storage, true);
+
+        match restored {
+            Restored::Regions(_) => self.redo_entries.push(entry),
+            Restored::Conflict => self.undo_entries.clear(),
+        }
+
+        restored
+    }
+
+    /// Reapplies the last undone entry and returns the restored regions.
+    ///
+    /// Entries, whose regions have been changed outside of the history, are discarded
+    /// together with all entries undone before them.
+    pub(crate) fn redo(&mut self, storage: &mut impl RegionStorage) -> Restored {
+        let entry = match self.redo_entries.pop() {
+            Some(entry) => entry,
+            None => return Restored::Regions(Vec::new()),
+        };
+
+        let restored = entry.apply(storage, false);
+
+        match restored {
+            Restored::Regions(_) => self.undo_entries.push_back(entry),
+            Restored::Conflict => self.redo_entries.clear(),
+        }
+
+        restored
+    }
+
+    /// Discards all entries overlapping the regions, which have been changed outside of the
+    /// history, because undoing them would revert these changes as well.
+    /// The overlapping deltas of the current stroke are discarded too.
+    pub(crate) fn invalidate(&mut self, regions: &[NodeRegion]) {
+        let overlaps =
+            |delta: &RegionDelta| regions.iter().any(|region| region.overlaps(&delta.region));
+
+        self.undo_entries
+            .retain(|entry| !entry.deltas.values().any(overlaps));
+        self.redo_entries
+            .retain(|entry| !entry.deltas.values().any(overlaps));
+
+        if let Some(stroke) = &mut self.stroke {
+            stroke.deltas.retain(|_, delta| !overlaps(delta));
+        }
+    }
+
+    /// Removes all deltas of the nodes, whose data is no longer available.
+    pub(crate) fn forget_nodes(&mut self, node_ids: &[NodeId]) {
+        for entry in self
+            .undo_entries
+            .iter_mut()
+            .chain(&mut self.redo_entries)
+            .chain(&mut self.stroke)
+        {
+            entry
+                .deltas
+                .retain(|&(_, node_id), _| !node_ids.contains(&node_id));
+        }
+
+        self.undo_entries.retain(|entry| !entry.deltas.is_empty());
+        self.redo_entries.retain(|entry| !entry.deltas.is_empty());
+    }
+}
+
+impl TerrainEditor {
+    /// Starts a stroke, which combines all following edits into a single undo step,
+    /// until the stroke ends.
+    /// Without a stroke, every edit is a separate undo step.
+    pub fn begin_stroke(&mut self) {
+        self.commands.push(EditCommand::BeginStroke);
+    }
+
+    /// Ends the current stroke.
+    pub fn end_stroke(&mut self) {
+        self.commands.push(EditCommand::EndStroke);
+    }
+
+    /// Queues to revert the last undo step.
+    /// Undo steps of nodes, that have been evicted from the node atlas, are discarded.
+    /// If the edited texels have been changed outside of the history in the meantime,
+    /// the undo step and all older ones are discarded, instead of corrupting the terrain.
+    pub fn undo(&mut self) {
+        self.commands.push(EditCommand::Undo);
+    }
+
+    /// Queues to reapply the last reverted undo step.
+    pub fn redo(&mut self) {
+        self.commands.push(EditCommand::Redo);
+    }
+
+    /// Sets the memory limit of the history in bytes (64 MiB by default), including the
+    /// current stroke and the undone steps.
+    /// The oldest undo steps are dropped first, once the limit is exceeded.
+    pub fn set_history_limit(&mut self, memory_limit: usize) {
+        self.history.memory_limit = memory_limit;
+        self.history.limit_memory();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The size of the single node attachment of the [`TestStorage`].
+    const SIZE: u32 = 8;
+
+    /// A single node attachment with texels of one byte.
+    struct TestStorage {
+        texels: Vec<u8>,
+    }
+
+    impl TestStorage {
+        fn new() -> Self {
+            Self {
+                texels: (0..SIZE * SIZE).map(|index| index as u8).collect(),
+            }
+        }
+
+        fn rows(region: &NodeRegion) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+            (0..region.size.y).map(|row| {
+                let start = ((region.origin.y + row) * SIZE + region.origin.x) as usize;
+                start..start + region.size.x as usize
+            })
+        }
+
+        /// Sets all texels of the region to the value and returns the edit.
+        fn edit(&mut self, region: NodeRegion, value: u8) -> RegionEdit {
+            let before = self.read(&region).unwrap();
+            let after = vec![value; before.len()];
+            self.write(&region, after.clone());
+
+            RegionEdit {
+                region,
+                before,
+                after,
+            }
+        }
+    }
+
+    impl RegionStorage for TestStorage {
+        fn read(&self, region: &NodeRegion) -> Option<Vec<u8>> {
+            Some(
+                Self::rows(region)
+                    .flat_map(|range| self.texels[range].to_vec())
+                    .collect(),
+            )
+        }
+
+        fn write(&mut self, region: &NodeRegion, data: Vec<u8>) -> bool {
+            for (range, row) in Self::rows(region).zip(data.chunks(region.size.x as usize)) {
+                self.texels[range].copy_from_slice(row);
+            }
+
+            true
+        }
+    }
+
+    fn region(x: u32, y: u32, width: u32, height: u32) -> NodeRegion {
+        NodeRegion {
+            attachment_index: 0,
+            node_id: 0,
+            origin: UVec2::new(x, y),
+            size: UVec2::new(width, height),
+        }
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let mut sparse = vec![0; 1000];
+        sparse[10] = 3;
+        sparse[11] = 0;
+        sparse[12] = 7;
+        sparse[500..520].fill(255);
+
+        let inputs = [
+            Vec::new(),
+            vec![0; 300],
+            vec![1; 300],
+            vec![1, 0, 0, 2, 0, 0, 0, 0, 3, 0],
+            (0..=255).collect(),
+            sparse.clone(),
+        ];
+
+        for input in inputs {
+            assert_eq!(decompress(&compress(&input), input.len()), input);
+        }
+
+        assert!(compress(&sparse).len() < 40);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let values = [0, 1, 127, 128, 300, 1 << 20, usize::MAX >> 1];
+        let mut data = Vec::new();
+
+        for value in values {
+            write_varint(&mut data, value);
+        }
+
+        let mut index = 0;
+
+        for value in values {
+            assert_eq!(read_varint(&data, &mut index), value);
+        }
+
+        assert_eq!(index, data.len());
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_texels() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+        let original = storage.texels.clone();
+
+        let edit = storage.edit(region(1, 1, 4, 3), 200);
+        history.record(&storage, vec![edit]);
+        let first = storage.texels.clone();
+
+        let edit = storage.edit(region(3, 2, 5, 5), 100);
+        history.record(&storage, vec![edit]);
+        let second = storage.texels.clone();
+
+        assert!(matches!(history.undo(&mut storage), Restored::Regions(_)));
+        assert_eq!(storage.texels, first);
+        assert!(matches!(history.undo(&mut storage), Restored::Regions(_)));
+        assert_eq!(storage.texels, original);
+        assert_eq!(history.undo(&mut storage), Restored::Regions(Vec::new()));
+
+        history.redo(&mut storage);
+        assert_eq!(storage.texels, first);
+        history.redo(&mut storage);
+        assert_eq!(storage.texels, second);
+    }
+
+    #[test]
+    fn strokes_are_undone_at_once() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+        let original = storage.texels.clone();
+
+        history.begin_stroke();
+
+        for (x, value) in [(0, 10), (2, 20), (3, 30)] {
+            let edit = storage.edit(region(x, x, 3, 3), value);
+            history.record(&storage, vec![edit]);
+        }
+
+        history.end_stroke();
+        let stroke = storage.texels.clone();
+
+        history.undo(&mut storage);
+        assert_eq!(storage.texels, original);
+
+        history.redo(&mut storage);
+        assert_eq!(storage.texels, stroke);
+    }
+
+    #[test]
+    fn new_edits_discard_the_undone_steps() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+
+        let edit = storage.edit(region(0, 0, 2, 2), 1);
+        history.record(&storage, vec![edit]);
+        history.undo(&mut storage);
+
+        let edit = storage.edit(region(4, 4, 2, 2), 2);
+        history.record(&storage, vec![edit]);
+        let texels = storage.texels.clone();
+
+        assert_eq!(history.redo(&mut storage), Restored::Regions(Vec::new()));
+        assert_eq!(storage.texels, texels);
+    }
+
+    #[test]
+    fn external_changes_are_not_overwritten() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+
+        let edit = storage.edit(region(0, 0, 4, 4), 50);
+        history.record(&storage, vec![edit]);
+
+        // a write bypassing the history
+        storage.edit(region(2, 2, 4, 4), 80);
+        let texels = storage.texels.clone();
+
+        assert_eq!(history.undo(&mut storage), Restored::Conflict);
+        assert_eq!(storage.texels, texels);
+        assert_eq!(history.undo(&mut storage), Restored::Regions(Vec::new()));
+    }
+
+    #[test]
+    fn invalidated_regions_discard_the_overlapping_steps() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+
+        let edit = storage.edit(region(0, 0, 2, 2), 10);
+        history.record(&storage, vec![edit]);
+
+        let edit = storage.edit(region(4, 4, 2, 2), 20);
+        history.record(&storage, vec![edit]);
+
+        // a write bypassing the history, which is reported to it
+        storage.edit(region(5, 5, 3, 3), 30);
+        history.invalidate(&[region(5, 5, 3, 3)]);
+
+        let texels = storage.texels.clone();
+
+        // only the first step remains
+        assert_eq!(
+            history.undo(&mut storage),
+            Restored::Regions(vec![region(0, 0, 2, 2)])
+        );
+        assert_eq!(history.undo(&mut storage), Restored::Regions(Vec::new()));
+        assert_eq!(storage.texels[..2], TestStorage::new().texels[..2]);
+        assert_eq!(storage.texels[16..], texels[16..]);
+    }
+
+    #[test]
+    fn memory_limit_includes_undone_steps_and_the_stroke() {
+        let mut storage = TestStorage::new();
+        let mut history = EditHistory::default();
+
+        for value in 1..=4 {
+            let edit = storage.edit(region(0, 0, SIZE, SIZE), value);
+            history.record(&storage, vec![edit]);
+        }
+
+        for _ in 0..4 {
+            history.undo(&mut storage);
+        }
+
+        let entry_memory = history.redo_entries[0].memory();
+        history.memory_limit = 2 * entry_memory;
+        history.limit_memory();
+
+        assert_eq!(history.redo_entries.len(), 2);
+        assert!(history.memory() <= history.memory_limit);
+
+        // the last undone steps remain
+        history.redo(&mut storage);
+        history.redo(&mut storage);
+        assert_eq!(history.redo(&mut storage), Restored::Regions(Vec::new()));
+
+        history.begin_stroke();
+        let edit = storage.edit(region(0, 0, SIZE, SIZE), 200);
+        history.record(&storage, vec![edit]);
+
+        assert!(history.stroke.is_some());
+        assert!(history.memory() <= history.memory_limit);
+    }
+}
@@ -0,0 +1,731 @@
+//! This module contains the runtime editing of the terrain attachments, e.g. for level editors.
+//!
+//! # Explanation
+//! Edits are applied to the CPU copies (stored in the [`NodeData`](crate::data_structures::node_atlas::NodeData))
+//! of all affected loaded nodes of lod 0, including the borders they share with their neighbours.
+//! The attachments are copied out of their images on the first edit, so that the images are
+//! never modified and only the changed regions are re-uploaded into the array layers of the
+//! [`GpuNodeAtlas`](crate::data_structures::gpu_node_atlas::GpuNodeAtlas).
+//! The coarser lods are updated lazily, one lod per frame, by combining the texels of the
+//! edited children with the down sample filter of the attachment.
+//!
+//! Sculpting only edits the height attachment. Attachments derived from the height during
+//! preprocessing, like a baked normal attachment, are not regenerated and become stale inside
+//! the sculpted regions. Terrains that are sculpted should calculate their normals from the
+//! height instead, i.e. use a [`TerrainPipelineConfig`](crate::render::TerrainPipelineConfig)
+//! without a normal attachment.
+//!
+//! Only loaded nodes can be edited. The unsaved edits of nodes, that are released by all
+//! quadtrees and evicted from the [`NodeAtlas`], are written into their node files, see
+//! [`persist`] for details. Their history is lost.
+
+use crate::{
+    data_structures::{
+        calc_node_id,
+        node_atlas::{LoadingState, NodeAtlas},
+        AtlasIndex, AttachmentIndex, NodeCoordinate, NodeId,
+    },
+    editing::history::{EditHistory, LoadedRegions, Restored},
+    preprocess::down_sample::DownSampleFilter,
+    terrain::TerrainConfig,
+};
+use bevy::{
+    prelude::*,
+    render::render_resource::TextureFormat,
+    tasks::Task,
+    utils::{HashMap, HashSet},
+};
+use itertools::iproduct;
+use std::{mem, ops::Range};
+
+pub mod deform;
+pub mod history;
+pub mod paint;
+pub mod persist;
+pub mod sculpt;
+
+/// The layout of the texels of an editable attachment.
+#[derive(Clone, Copy)]
+pub(crate) struct TexelLayout {
+    channel_count: usize,
+    /// The size of a single channel in bytes.
+    channel_size: usize,
+}
+
+impl TexelLayout {
+    pub(crate) fn new(format: TextureFormat) -> Self {
+        Self::try_new(format)
+            .unwrap_or_else(|| panic!("Attachments of the format {format:?} can not be edited."))
+    }
+
+    /// Returns the layout of the format, if attachments of it can be edited.
+    pub(crate) fn try_new(format: TextureFormat) -> Option<Self> {
+        let (channel_count, channel_size) = match format {
+            TextureFormat::R8Unorm => (1, 1),
+            TextureFormat::Rg8Unorm => (2, 1),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => (4, 1),
+            TextureFormat::R16Unorm => (1, 2),
+            TextureFormat::Rg16Unorm => (2, 2),
+            TextureFormat::Rgba16Unorm => (4, 2),
+            _ => return None,
+        };
+
+        Some(Self {
+            channel_count,
+            channel_size,
+        })
+    }
+
+    /// The size of a single texel in bytes.
+    #[inline]
+    pub(crate) fn texel_size(self) -> usize {
+        self.channel_count * self.channel_size
+    }
+
+    /// Reads the normalized channels of the texel. Unused channels are zero.
+    pub(crate) fn read(self, data: &[u8], index: usize) -> [f32; 4] {
+        let mut texel = [0.0; 4];
+        let start = index * self.texel_size();
+
+        for (channel, value) in texel.iter_mut().take(self.channel_count).enumerate() {
+            let offset = start + channel * self.channel_size;
+
+            *value = match self.channel_size {
+                1 => data[offset] as f32 / u8::MAX as f32,
+                _ => u16::from_le_bytes([data[offset], data[offset + 1]]) as f32 / u16::MAX as f32,
+            };
+        }
+
+        texel
+    }
+
+    /// Writes the normalized channels of the texel.
+    pub(crate) fn write(self, data: &mut [u8], index: usize, texel: [f32; 4]) {
+        let start = index * self.texel_size();
+
+        for (channel, value) in texel.iter().take(self.channel_count).enumerate() {
+            let offset = start + channel * self.channel_size;
+            let value = value.clamp(0.0, 1.0);
+
+            match self.channel_size {
+                1 => data[offset] = (value * u8::MAX as f32).round() as u8,
+                _ => data[offset..offset + 2]
+                    .copy_from_slice(&((value * u16::MAX as f32).round() as u16).to_le_bytes()),
+            }
+        }
+    }
+}
+
+/// Returns the weight of a brush at the distance from its center, which is one inside of
+/// the `hardness` fraction of the `radius` and falls off smoothly to zero at the `radius`.
+#[inline]
+pub(crate) fn brush_weight(distance: f32, radius: f32, hardness: f32) -> f32 {
+    let t = distance / radius.max(f32::EPSILON);
+
+    if t >= 1.0 {
+        return 0.0;
+    }
+
+    let t = ((1.0 - t) / (1.0 - hardness.clamp(0.0, 1.0)).max(f32::EPSILON)).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// A rectangle of texels of an attachment, measured in texels of its lod.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TexelRect {
+    pub(crate) min: IVec2,
+    /// The exclusive end of the rectangle.
+    pub(crate) max: IVec2,
+}
+
+impl TexelRect {
+    /// Returns the rectangle covering all texels, whose centers lie inside the circle.
+    pub(crate) fn around(center: Vec2, radius: f32) -> Self {
+        Self {
+            min: (center - radius).floor().as_ivec2(),
+            max: (center + radius).ceil().as_ivec2() + 1,
+        }
+    }
+
+    pub(crate) fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Returns the texels of the next coarser lod, which depend on this rectangle.
+    pub(crate) fn parent(self) -> Self {
+        Self {
+            min: IVec2::new(self.min.x.div_euclid(2), self.min.y.div_euclid(2)),
+            max: IVec2::new(
+                (self.max.x + 1).div_euclid(2),
+                (self.max.y + 1).div_euclid(2),
+            ),
+        }
+    }
+}
+
+/// A changed region of a node attachment, that has to be re-uploaded into the
+/// [`GpuNodeAtlas`](crate::data_structures::gpu_node_atlas::GpuNodeAtlas).
+pub(crate) struct AttachmentUpdate {
+    pub(crate) atlas_index: AtlasIndex,
+    pub(crate) attachment_index: AttachmentIndex,
+    /// The origin of the region inside the node, including the border.
+    pub(crate) origin: UVec2,
+    pub(crate) size: UVec2,
+    pub(crate) bytes_per_row: u32,
+    /// The tightly packed texels of the region.
+    pub(crate) data: Vec<u8>,
+}
+
+/// A rectangular region of an attachment of a node, measured in texels including the border.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct NodeRegion {
+    pub(crate) attachment_index: AttachmentIndex,
+    pub(crate) node_id: NodeId,
+    pub(crate) origin: UVec2,
+    pub(crate) size: UVec2,
+}
+
+impl NodeRegion {
+    /// Returns the smallest region covering both regions of the same node attachment.
+    pub(crate) fn union(self, other: Self) -> Self {
+        let origin = self.origin.min(other.origin);
+        let end = (self.origin + self.size).max(other.origin + other.size);
+
+        Self {
+            origin,
+            size: end - origin,
+            ..self
+        }
+    }
+
+    /// Checks whether both regions share texels of the same node attachment.
+    pub(crate) fn overlaps(&self, other: &Self) -> bool {
+        self.attachment_index == other.attachment_index
+            && self.node_id == other.node_id
+            && self.origin.cmplt(other.origin + other.size).all()
+            && other.origin.cmplt(self.origin + self.size).all()
+    }
+
+    /// Returns the rectangle of the region in global texels of the lod of its node.
+    pub(crate) fn rect(&self, node_atlas: &NodeAtlas) -> TexelRect {
+        let attachment = &node_atlas.attachments[self.attachment_index];
+        let NodeCoordinate { x, y, .. } = self.node_id.into();
+
+        let node_origin = IVec2::new(x as i32, y as i32) * attachment.texture_size as i32
+            - attachment.border_size as i32;
+
+        TexelRect {
+            min: node_origin + self.origin.as_ivec2(),
+            max: node_origin + (self.origin + self.size).as_ivec2(),
+        }
+    }
+
+    /// Returns the byte ranges of the rows of the region inside the data of the node attachment.
+    fn rows(&self, node_atlas: &NodeAtlas) -> impl Iterator<Item = Range<usize>> {
+        let attachment = &node_atlas.attachments[self.attachment_index];
+        let texel_size = TexelLayout::new(attachment.format).texel_size();
+        let node_size = (attachment.texture_size + 2 * attachment.border_size) as usize;
+        let (origin, size) = (self.origin, self.size);
+
+        (0..size.y as usize).map(move |row| {
+            let start = ((origin.y as usize + row) * node_size + origin.x as usize) * texel_size;
+            start..start + size.x as usize * texel_size
+        })
+    }
+}
+
+/// The previous and the new texels of an edited region.
+pub(crate) struct RegionEdit {
+    pub(crate) region: NodeRegion,
+    pub(crate) before: Vec<u8>,
+    pub(crate) after: Vec<u8>,
+}
+
+/// Returns the atlas index of the node, if it is loaded.
+fn loaded_node(node_atlas: &NodeAtlas, node_id: NodeId) -> Option<AtlasIndex> {
+    let node = node_atlas.nodes.get(&node_id)?;
+
+    (node.state == LoadingState::Loaded).then_some(node.atlas_index)
+}
+
+/// Reads the tightly packed texels of the region, if its node is loaded.
+pub(crate) fn read_region(
+    node_atlas: &NodeAtlas,
+    images: &Assets<Image>,
+    region: &NodeRegion,
+) -> Option<Vec<u8>> {
+    let atlas_index = loaded_node(node_atlas, region.node_id)?;
+    let attachment = node_atlas.attachment_data(images, atlas_index, region.attachment_index)?;
+
+    let mut data = Vec::new();
+
+    for range in region.rows(node_atlas) {
+        data.extend_from_slice(&attachment[range]);
+    }
+
+    Some(data)
+}
+
+/// Writes the tightly packed texels into the region, if its node is loaded,
+/// and queues the region to be re-uploaded.
+///
+/// The texels are written into the edited copy of the attachment, which is created from its
+/// image on the first edit. The image itself is left untouched, so that only the region is
+/// uploaded instead of the whole image.
+pub(crate) fn write_region(
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    region: &NodeRegion,
+    data: Vec<u8>,
+) -> bool {
+    let atlas_index = match loaded_node(node_atlas, region.node_id) {
+        Some(atlas_index) => atlas_index,
+        None => return false,
+    };
+
+    let rows: Vec<_> = region.rows(node_atlas).collect();
+    let node_data = &mut node_atlas.data[atlas_index as usize];
+
+    if !node_data
+        .edited_attachments
+        .contains_key(&region.attachment_index)
+    {
+        let image = match node_data
+            .attachments
+            .get(&region.attachment_index)
+            .and_then(|handle| images.get(handle))
+        {
+            Some(image) => image,
+            None => return false,
+        };
+
+        node_data
+            .edited_attachments
+            .insert(region.attachment_index, image.data.clone());
+    }
+
+    let attachment = node_data
+        .edited_attachments
+        .get_mut(&region.attachment_index)
+        .unwrap();
+
+    let bytes_per_row = data.len() / region.size.y as usize;
+
+    for (range, row) in rows.into_iter().zip(data.chunks(bytes_per_row)) {
+        attachment[range].copy_from_slice(row);
+    }
+
+    node_atlas.updated_attachments.push(AttachmentUpdate {
+        atlas_index,
+        attachment_index: region.attachment_index,
+        origin: region.origin,
+        size: region.size,
+        bytes_per_row: bytes_per_row as u32,
+        data,
+    });
+
+    true
+}
+
+/// Provides read access to the texels of an attachment of all loaded nodes,
+/// addressed by their global texel position per lod.
+pub(crate) struct AttachmentView<'a> {
+    node_atlas: &'a NodeAtlas,
+    images: &'a Assets<Image>,
+    attachment_index: AttachmentIndex,
+    texture_size: i32,
+    border_size: i32,
+    layout: TexelLayout,
+}
+
+impl<'a> AttachmentView<'a> {
+    fn new(
+        node_atlas: &'a NodeAtlas,
+        images: &'a Assets<Image>,
+        attachment_index: AttachmentIndex,
+    ) -> Self {
+        let attachment = &node_atlas.attachments[attachment_index];
+
+        Self {
+            node_atlas,
+            images,
+            attachment_index,
+            texture_size: attachment.texture_size as i32,
+            border_size: attachment.border_size as i32,
+            layout: TexelLayout::new(attachment.format),
+        }
+    }
+
+    /// Returns the attachment data of the node, if it is loaded.
+    fn node(&self, lod: u32, x: i32, y: i32) -> Option<&'a [u8]> {
+        if x < 0 || y < 0 {
+            return None;
+        }
+
+        let atlas_index = loaded_node(self.node_atlas, calc_node_id(lod, x as u32, y as u32))?;
+
+        self.node_atlas
+            .attachment_data(self.images, atlas_index, self.attachment_index)
+    }
+
+    /// Returns the normalized texel at the global position of the lod,
+    /// if the node containing it is loaded.
+    pub(crate) fn get(&self, lod: u32, position: IVec2) -> Option<[f32; 4]> {
+        let x = position.x.div_euclid(self.texture_size);
+        let y = position.y.div_euclid(self.texture_size);
+
+        let data = self.node(lod, x, y)?;
+
+        let size = self.texture_size + 2 * self.border_size;
+        let local = position - IVec2::new(x, y) * self.texture_size + self.border_size;
+
+        Some(self.layout.read(data, (local.y * size + local.x) as usize))
+    }
+}
+
+/// Edits all texels of the attachment inside the rectangle of the lod, for every loaded node
+/// overlapping it (including their borders), and queues the changed regions for upload.
+///
+/// The `edit` function receives the previous value of each texel, all reads through the
+/// view observe the attachment before this edit.
+/// Returns the edited regions of all nodes.
+pub(crate) fn edit_attachment(
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    attachment_index: AttachmentIndex,
+    lod: u32,
+    rect: TexelRect,
+    edit: impl Fn(&AttachmentView, IVec2, [f32; 4]) -> [f32; 4],
+) -> Vec<RegionEdit> {
+    let view = AttachmentView::new(node_atlas, images, attachment_index);
+    let (texture_size, border_size, layout) = (view.texture_size, view.border_size, view.layout);
+    let size = texture_size + 2 * border_size;
+
+    let first = (rect.min - border_size).max(IVec2::ZERO);
+    let last = rect.max - 1 + border_size;
+
+    let edits: Vec<_> = iproduct!(
+        first.x.div_euclid(texture_size)..=last.x.div_euclid(texture_size),
+        first.y.div_euclid(texture_size)..=last.y.div_euclid(texture_size)
+    )
+    .filter_map(|(x, y)| {
+        let data = view.node(lod, x, y)?;
+
+        // the global position of the first texel of the node, including its border
+        let node_origin = IVec2::new(x, y) * texture_size - border_size;
+
+        let origin = (rect.min - node_origin).max(IVec2::ZERO);
+        let end = (rect.max - node_origin).min(IVec2::splat(size));
+
+        if origin.x >= end.x || origin.y >= end.y {
+            return None;
+        }
+
+        let texels: Vec<[f32; 4]> = iproduct!(origin.y..end.y, origin.x..end.x)
+            .map(|(v, u)| {
+                let texel = layout.read(data, (v * size + u) as usize);
+                edit(&view, node_origin + IVec2::new(u, v), texel)
+            })
+            .collect();
+
+        let region = NodeRegion {
+            attachment_index,
+            node_id: calc_node_id(lod, x as u32, y as u32),
+            origin: origin.as_uvec2(),
+            size: (end - origin).as_uvec2(),
+        };
+
+        Some((region, texels))
+    })
+    .collect();
+
+    edits
+        .into_iter()
+        .filter_map(|(region, texels)| {
+            let before = read_region(node_atlas, images, &region)?;
+            let mut after = vec![0; before.len()];
+
+            for (index, texel) in texels.into_iter().enumerate() {
+                layout.write(&mut after, index, texel);
+            }
+
+            write_region(node_atlas, images, &region, after.clone());
+
+            Some(RegionEdit {
+                region,
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+/// A queued edit of a [`TerrainEditor`].
+pub(crate) enum EditCommand {
+    Sculpt(sculpt::Brush),
+    /// Paints into the attachment with the index.
+    Paint(AttachmentIndex, paint::PaintBrush),
+    BeginStroke,
+    EndStroke,
+    Undo,
+    Redo,
+}
+
+/// Edits the attachments of a terrain at runtime.
+///
+/// Edits are queued with methods like [`TerrainEditor::sculpt`] or [`TerrainEditor::paint`]
+/// and applied in order at the end of the frame.
+#[derive(Component)]
+pub struct TerrainEditor {
+    /// The index of the height attachment.
+    pub(crate) height_attachment: AttachmentIndex,
+    /// The queued edits.
+    pub(crate) commands: Vec<EditCommand>,
+    /// The undo and redo history of the edits.
+    pub(crate) history: EditHistory,
+    /// The edited regions of each attachment, whose coarser lods are out of date.
+    stale_regions: HashMap<(AttachmentIndex, u32), TexelRect>,
+    /// The edited nodes of lod 0 of each attachment, that have not been saved yet.
+    /// This includes the evicted nodes, whose ancestors have not been rebuilt yet.
+    pub(crate) edited_nodes: HashMap<AttachmentIndex, HashSet<NodeId>>,
+    /// The filters used to rebuild the lods of each attachment, while editing and when saving.
+    pub(crate) filters: HashMap<AttachmentIndex, DownSampleFilter>,
+    /// Indicates whether the edited nodes should be saved this frame.
+    pub(crate) save_requested: bool,
+    /// The latest save, which writes the node files in the background.
+    pub(crate) save_task: Option<Task<()>>,
+}
+
+/// Returns the index of the attachment with the name, if the terrain has one.
+pub(crate) fn find_attachment(config: &TerrainConfig, name: &str) -> Option<AttachmentIndex> {
+    config
+        .attachments
+        .iter()
+        .position(|attachment| attachment.name == name)
+}
+
+/// Returns the index of the attachment with the name.
+pub(crate) fn attachment_index(config: &TerrainConfig, name: &str) -> AttachmentIndex {
+    find_attachment(config, name).expect("The terrain has no attachment with this name.")
+}
+
+impl TerrainEditor {
+    /// Creates an editor for the terrain, whose height is stored in the attachment `height_name`.
+    pub fn new(config: &TerrainConfig, height_name: &str) -> Self {
+        Self {
+            height_attachment: attachment_index(config, height_name),
+            commands: default(),
+            history: default(),
+            stale_regions: default(),
+            edited_nodes: default(),
+            filters: default(),
+            save_requested: false,
+            save_task: None,
+        }
+    }
+
+    /// Sets the filter used to rebuild the lods of the attachment `name`, while editing and
+    /// when saving. It should match the filter used during preprocessing and defaults to
+    /// [`DownSampleFilter::Average`], or [`DownSampleFilter::Max`] for hole masks.
+    pub fn set_down_sample_filter(
+        &mut self,
+        config: &TerrainConfig,
+        name: &str,
+        filter: DownSampleFilter,
+    ) {
+        self.filters.insert(attachment_index(config, name), filter);
+    }
+
+    /// Queues all edited nodes to be saved back into the node files of the terrain,
+    /// see [`persist`] for details.
+    /// The files are written in the background. Saves, that have not started yet, are cancelled
+    /// if the editor is removed.
+    pub fn save(&mut self) {
+        self.save_requested = true;
+    }
+
+    /// Records the edited node of lod 0 for saving and marks the coarser lods of the
+    /// region as out of date.
+    fn mark_region(&mut self, node_atlas: &NodeAtlas, region: &NodeRegion) {
+        let lod = NodeCoordinate::from(region.node_id).lod;
+
+        if lod == 0 {
+            self.edited_nodes
+                .entry(region.attachment_index)
+                .or_default()
+                .insert(region.node_id);
+        }
+
+        let rect = region.rect(node_atlas).parent();
+
+        self.stale_regions
+            .entry((region.attachment_index, lod + 1))
+            .and_modify(|stale| *stale = stale.union(rect))
+            .or_insert(rect);
+    }
+
+    /// Marks the edited regions and records them in the history.
+    fn record(
+        &mut self,
+        node_atlas: &mut NodeAtlas,
+        images: &Assets<Image>,
+        edits: Vec<RegionEdit>,
+    ) {
+        for edit in &edits {
+            self.mark_region(node_atlas, &edit.region);
+        }
+
+        self.history
+            .record(&LoadedRegions { node_atlas, images }, edits);
+    }
+
+    /// Marks the regions restored by the history.
+    fn mark_restored(&mut self, node_atlas: &NodeAtlas, restored: Restored) {
+        match restored {
+            Restored::Regions(regions) => {
+                for region in regions {
+                    self.mark_region(node_atlas, &region);
+                }
+            }
+            Restored::Conflict => warn!(
+                "The edited texels have been changed outside of the history, \
+                 thus the conflicting undo steps have been discarded."
+            ),
+        }
+    }
+}
+
+/// Applies the queued edits of all terrain editors in order.
+pub(crate) fn apply_terrain_edits(
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(
+        &mut TerrainEditor,
+        &mut NodeAtlas,
+        &TerrainConfig,
+        &GlobalTransform,
+    )>,
+) {
+    for (mut editor, mut node_atlas, config, transform) in terrain_query.iter_mut() {
+        for command in mem::take(&mut editor.commands) {
+            match command {
+                EditCommand::Sculpt(brush) => {
+                    let edits = sculpt::apply_brush(
+                        &brush,
+                        editor.height_attachment,
+                        &mut node_atlas,
+                        &images,
+                        config,
+                        transform,
+                    );
+
+                    editor.record(&mut node_atlas, &images, edits);
+                }
+                EditCommand::Paint(attachment_index, brush) => {
+                    let edits = paint::apply_brush(
+                        &brush,
+                        attachment_index,
+                        &mut node_atlas,
+                        &images,
+                        config,
+                        transform,
+                    );
+
+                    editor.record(&mut node_atlas, &images, edits);
+                }
+                EditCommand::BeginStroke => editor.history.begin_stroke(),
+                EditCommand::EndStroke => editor.history.end_stroke(),
+                EditCommand::Undo => {
+                    let restored = editor.history.undo(&mut LoadedRegions {
+                        node_atlas: &mut node_atlas,
+                        images: &images,
+                    });
+
+                    editor.mark_restored(&node_atlas, restored);
+                }
+                EditCommand::Redo => {
+                    let restored = editor.history.redo(&mut LoadedRegions {
+                        node_atlas: &mut node_atlas,
+                        images: &images,
+                    });
+
+                    editor.mark_restored(&node_atlas, restored);
+                }
+            }
+        }
+    }
+}
+
+/// Updates the out of date regions of the finest stale lod of each edited terrain,
+/// by combining the texels of their children with the down sample filter of the attachment.
+pub(crate) fn update_edited_lods(
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(&mut TerrainEditor, &mut NodeAtlas, &TerrainConfig)>,
+) {
+    for (mut editor, mut node_atlas, config) in terrain_query.iter_mut() {
+        let lod = match editor.stale_regions.keys().map(|&(_, lod)| lod).min() {
+            Some(lod) => lod,
+            None => continue,
+        };
+
+        let regions: Vec<_> = editor
+            .stale_regions
+            .drain_filter(|&(_, stale_lod), _| stale_lod == lod)
+            .collect();
+
+        for ((attachment_index, lod), rect) in regions {
+            if lod >= config.lod_count {
+                continue;
+            }
+
+            let filter = editor
+                .filters
+                .get(&attachment_index)
+                .cloned()
+                .unwrap_or(DownSampleFilter::Average);
+
+            let edits = edit_attachment(
+                &mut node_atlas,
+                &images,
+                attachment_index,
+                lod,
+                rect,
+                |view, position, texel| {
+                    let children: Option<Vec<[f32; 4]>> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .iter()
+                        .map(|&(dx, dy)| view.get(lod - 1, position * 2 + IVec2::new(dx, dy)))
+                        .collect();
+
+                    // keep the previous value, if not all children are loaded
+                    children.map_or(texel, |children| {
+                        filter.reduce(&children.try_into().unwrap())
+                    })
+                },
+            );
+
+            for edit in &edits {
+                editor.mark_region(&node_atlas, &edit.region);
+            }
+        }
+    }
+}
+
+/// Removes the nodes evicted from the node atlas this frame from the history, because their
+/// data is no longer available.
+pub(crate) fn forget_evicted_nodes(mut terrain_query: Query<(&mut TerrainEditor, &NodeAtlas)>) {
+    for (mut editor, node_atlas) in terrain_query.iter_mut() {
+        if node_atlas.evicted_nodes.is_empty() {
+            continue;
+        }
+
+        let node_ids: Vec<NodeId> = node_atlas
+            .evicted_nodes
+            .iter()
+            .map(|&(node_id, _)| node_id)
+            .collect();
+
+        editor.history.forget_nodes(&node_ids);
+    }
+}
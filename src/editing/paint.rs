@@ -0,0 +1,193 @@
+use crate::{
+    data_structures::{node_atlas::NodeAtlas, AttachmentIndex},
+    editing::{
+        brush_weight, edit_attachment, find_attachment, EditCommand, RegionEdit, TerrainEditor,
+        TexelLayout, TexelRect,
+    },
+    preprocess::down_sample::DownSampleFilter,
+    terrain::TerrainConfig,
+};
+use bevy::{math::Vec3Swizzles, prelude::*, render::render_resource::TextureFormat};
+use std::{error::Error, fmt};
+
+/// Determines how a [`PaintBrush`] modifies the texels of an attachment.
+#[derive(Clone, Debug)]
+pub enum PaintTool {
+    /// Blends the color channels towards the `color` by `strength` in [0, 1].
+    /// The alpha channel is left untouched.
+    Tint { color: Color, strength: f32 },
+    /// Moves the weight of the material `channel` towards one by `strength` in [0, 1],
+    /// while the weights of all channels are kept normalized to a sum of one.
+    Weight { channel: usize, strength: f32 },
+    /// Sets the first channel of a hole mask to one, or back to zero if `cut` is false.
+    /// The mask has to be interpreted by the material of the terrain.
+    ///
+    /// Hole masks are binary, so their lods are built with [`DownSampleFilter::Max`] instead of
+    /// averaging, unless another filter has been set for the attachment. Thus a hole in any
+    /// child cuts the coarser texel as well.
+    Hole { cut: bool },
+}
+
+/// The reasons, why a [`PaintBrush`] can not be applied to an attachment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PaintError {
+    /// The terrain has no attachment with this name.
+    UnknownAttachment(String),
+    /// The attachment has a texture format, that can not be edited.
+    UnsupportedFormat(TextureFormat),
+    /// The weight channel exceeds the channels of the attachment.
+    InvalidChannel {
+        channel: usize,
+        channel_count: usize,
+    },
+}
+
+impl fmt::Display for PaintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownAttachment(name) => write!(f, "The terrain has no attachment {name:?}."),
+            Self::UnsupportedFormat(format) => {
+                write!(f, "Attachments of the format {format:?} can not be edited.")
+            }
+            Self::InvalidChannel {
+                channel,
+                channel_count,
+            } => write!(
+                f,
+                "The channel {channel} exceeds the {channel_count} channels of the attachment."
+            ),
+        }
+    }
+}
+
+impl Error for PaintError {}
+
+/// A brush applied to an attachment of the terrain at a world position.
+#[derive(Clone, Debug)]
+pub struct PaintBrush {
+    pub tool: PaintTool,
+    /// The world position of the center of the brush. Only its horizontal components are used.
+    pub position: Vec3,
+    /// The radius of the brush in world units.
+    pub radius: f32,
+    /// The fraction of the radius in [0, 1], that is affected with full strength.
+    /// The rest falls off smoothly.
+    pub hardness: f32,
+}
+
+impl TerrainEditor {
+    /// Queues the brush to be applied to the attachment `name`.
+    ///
+    /// Returns an error and queues nothing, if the terrain has no editable attachment with
+    /// this name, or if the weight channel of the brush exceeds its channels.
+    pub fn paint(
+        &mut self,
+        config: &TerrainConfig,
+        name: &str,
+        brush: PaintBrush,
+    ) -> Result<(), PaintError> {
+        let attachment_index = find_attachment(config, name)
+            .ok_or_else(|| PaintError::UnknownAttachment(name.to_string()))?;
+
+        let format = config.attachments[attachment_index].format;
+        let layout = TexelLayout::try_new(format).ok_or(PaintError::UnsupportedFormat(format))?;
+
+        match brush.tool {
+            PaintTool::Weight { channel, .. } if channel >= layout.channel_count => {
+                return Err(PaintError::InvalidChannel {
+                    channel,
+                    channel_count: layout.channel_count,
+                });
+            }
+            PaintTool::Hole { .. } => {
+                self.filters
+                    .entry(attachment_index)
+                    .or_insert(DownSampleFilter::Max);
+            }
+            _ => {}
+        }
+
+        self.commands
+            .push(EditCommand::Paint(attachment_index, brush));
+
+        Ok(())
+    }
+}
+
+/// Applies the brush to all loaded nodes of lod 0 of the attachment.
+pub(crate) fn apply_brush(
+    brush: &PaintBrush,
+    attachment_index: AttachmentIndex,
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    config: &TerrainConfig,
+    transform: &GlobalTransform,
+) -> Vec<RegionEdit> {
+    let attachment = &node_atlas.attachments[attachment_index];
+    let channel_count = TexelLayout::new(attachment.format).channel_count;
+    // the terrain covers one world unit per texel of a chunk sized attachment
+    let texels_per_unit = attachment.texture_size as f32 / config.chunk_size as f32;
+    let center = transform
+        .compute_matrix()
+        .inverse()
+        .transform_point3(brush.position)
+        .xz()
+        * texels_per_unit;
+    let radius = brush.radius * texels_per_unit;
+    let rect = TexelRect::around(center, radius);
+
+    // srgb attachments store their colors gamma encoded
+    let color = match (&brush.tool, attachment.format) {
+        (PaintTool::Tint { color, .. }, TextureFormat::Rgba8UnormSrgb) => color.as_rgba_f32(),
+        (PaintTool::Tint { color, .. }, _) => color.as_linear_rgba_f32(),
+        _ => [0.0; 4],
+    };
+
+    edit_attachment(
+        node_atlas,
+        images,
+        attachment_index,
+        0,
+        rect,
+        |_, position, mut texel| {
+            let texel_center = position.as_vec2() + 0.5;
+            let weight = brush_weight(texel_center.distance(center), radius, brush.hardness);
+
+            if weight == 0.0 {
+                return texel;
+            }
+
+            match brush.tool {
+                PaintTool::Tint { strength, .. } => {
+                    let color_count = channel_count.min(3);
+
+                    for (value, target) in texel.iter_mut().zip(color).take(color_count) {
+                        *value += (target - *value) * strength * weight;
+                    }
+                }
+                PaintTool::Weight { channel, strength } => {
+                    for (index, value) in texel.iter_mut().take(channel_count).enumerate() {
+                        let target = if index == channel { 1.0 } else { 0.0 };
+                        *value += (target - *value) * strength * weight;
+                    }
+
+                    let sum: f32 = texel[..channel_count].iter().sum();
+
+                    if sum > 0.0 {
+                        for value in &mut texel[..channel_count] {
+                            *value /= sum;
+                        }
+                    }
+                }
+                PaintTool::Hole { cut } => {
+                    // holes are binary, so only the inner part of the brush is applied
+                    if weight >= 0.5 {
+                        texel[0] = if cut { 1.0 } else { 0.0 };
+                    }
+                }
+            }
+
+            texel
+        },
+    )
+}
@@ -0,0 +1,257 @@
+//! Saves the runtime edits back into the node files of the terrain.
+//!
+//! The edited nodes of lod 0 are written into the same `{node_id}.png` layout the
+//! [`AttachmentFromDiskLoader`](crate::attachment_loader::AttachmentFromDiskLoader) reads.
+//! Afterwards only the ancestors of these nodes are rebuilt, with the same down sampling and
+//! stitching used during preprocessing, so that the edits survive between sessions without
+//! preprocessing the whole terrain again.
+//!
+//! The files are written in the background, one save after another.
+//!
+//! Edited nodes, that are evicted from the node atlas before they have been saved, are written
+//! into their node files right away, so that their edits are not lost and show up once they are
+//! loaded again. Their ancestors are rebuilt with the next save.
+//!
+//! Note that preprocessing the attachment again overwrites the saved edits of all nodes,
+//! whose source tiles have changed.
+
+use crate::{
+    data_structures::{node_atlas::NodeAtlas, AtlasIndex, AttachmentIndex, NodeId},
+    editing::{loaded_node, TerrainEditor},
+    preprocess::{down_sample::DownSampleFilter, rebuild_ancestors, ImageFormat},
+    terrain::TerrainConfig,
+    terrain_manifest::TerrainManifest,
+};
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use image::{DynamicImage, GrayAlphaImage, ImageBuffer, Luma, LumaA, RgbaImage};
+use std::{collections::HashSet, mem};
+
+/// Converts the data of a loaded node attachment back into an image of the format.
+fn node_image(data: &[u8], size: u32, format: ImageFormat) -> DynamicImage {
+    let data = data.to_vec();
+
+    let to_u16 = |data: Vec<u8>| -> Vec<u16> {
+        data.chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect()
+    };
+
+    match format {
+        // rgb images are expanded to rgba when they are loaded
+        ImageFormat::RGB => DynamicImage::from(
+            DynamicImage::from(RgbaImage::from_raw(size, size, data).unwrap()).to_rgb8(),
+        ),
+        ImageFormat::RGBA => DynamicImage::from(RgbaImage::from_raw(size, size, data).unwrap()),
+        ImageFormat::LUMA16 => DynamicImage::from(
+            <ImageBuffer<Luma<u16>, _>>::from_raw(size, size, to_u16(data)).unwrap(),
+        ),
+        ImageFormat::RG8 => DynamicImage::from(GrayAlphaImage::from_raw(size, size, data).unwrap()),
+        ImageFormat::RG16 => DynamicImage::from(
+            <ImageBuffer<LumaA<u16>, _>>::from_raw(size, size, to_u16(data)).unwrap(),
+        ),
+    }
+}
+
+/// The node files of an attachment, that are written in the background.
+struct AttachmentSave {
+    directory: String,
+    format: ImageFormat,
+    lod_count: u32,
+    texture_size: u32,
+    border_size: u32,
+    /// The edited nodes of lod 0 and their images.
+    nodes: Vec<(NodeId, DynamicImage)>,
+    /// The nodes of lod 0, whose ancestors are rebuilt with the filter, after all nodes have
+    /// been written.
+    rebuilt_nodes: Option<(HashSet<NodeId>, DownSampleFilter)>,
+}
+
+impl AttachmentSave {
+    fn new(node_atlas: &NodeAtlas, manifest: &TerrainManifest, attachment_index: usize) -> Self {
+        let attachment = &node_atlas.attachments[attachment_index];
+        let format = manifest
+            .attachment(&attachment.name)
+            .expect("The attachment is missing from the terrain manifest.")
+            .format;
+
+        Self {
+            directory: manifest.attachment_directory(&attachment.name),
+            format,
+            lod_count: manifest.lod_count,
+            texture_size: attachment.texture_size,
+            border_size: attachment.border_size,
+            nodes: Vec::new(),
+            rebuilt_nodes: None,
+        }
+    }
+
+    /// Adds the edited node at the atlas index to the written nodes.
+    /// Returns false, if its data is not available.
+    fn add_node(
+        &mut self,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        attachment_index: AttachmentIndex,
+        node_id: NodeId,
+        atlas_index: AtlasIndex,
+    ) -> bool {
+        let size = self.texture_size + 2 * self.border_size;
+
+        match node_atlas.attachment_data(images, atlas_index, attachment_index) {
+            Some(data) => {
+                self.nodes
+                    .push((node_id, node_image(data, size, self.format)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(self) {
+        for (node_id, image) in self.nodes {
+            image
+                .save(format!("{}/{node_id}.png", self.directory))
+                .expect("Could not save file.");
+        }
+
+        if let Some((rebuilt_nodes, filter)) = self.rebuilt_nodes {
+            rebuild_ancestors(
+                &self.directory,
+                rebuilt_nodes,
+                self.lod_count,
+                self.texture_size,
+                self.border_size,
+                self.format,
+                &filter,
+            );
+        }
+    }
+}
+
+impl TerrainEditor {
+    /// Writes the node files in the background, after all previous saves have finished.
+    fn spawn_save(&mut self, saves: Vec<AttachmentSave>) {
+        let previous = self.save_task.take();
+
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            if let Some(previous) = previous {
+                previous.await;
+            }
+
+            for save in saves {
+                save.write();
+            }
+        });
+
+        self.save_task = Some(task);
+    }
+}
+
+/// Saves the edited nodes of all terrain editors, that requested it.
+///
+/// The loaded edited nodes are written and the ancestors of all edited nodes are rebuilt.
+/// Edited nodes, that are no longer loaded, have already been written when they were evicted.
+pub(crate) fn save_terrain_edits(
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(&mut TerrainEditor, &NodeAtlas, &TerrainConfig)>,
+) {
+    for (mut editor, node_atlas, config) in terrain_query.iter_mut() {
+        if !mem::take(&mut editor.save_requested) {
+            continue;
+        }
+
+        let manifest = TerrainManifest::load(&format!("assets/{}", config.path));
+
+        let saves = mem::take(&mut editor.edited_nodes)
+            .into_iter()
+            .map(|(attachment_index, nodes)| {
+                let mut save = AttachmentSave::new(node_atlas, &manifest, attachment_index);
+
+                for &node_id in &nodes {
+                    if let Some(atlas_index) = loaded_node(node_atlas, node_id) {
+                        save.add_node(node_atlas, &images, attachment_index, node_id, atlas_index);
+                    }
+                }
+
+                let filter = editor
+                    .filters
+                    .get(&attachment_index)
+                    .cloned()
+                    .unwrap_or(DownSampleFilter::Average);
+
+                save.rebuilt_nodes = Some((nodes.into_iter().collect(), filter));
+                save
+            })
+            .collect();
+
+        editor.spawn_save(saves);
+    }
+}
+
+/// Writes the unsaved edits of the nodes evicted from the node atlas this frame into their
+/// node files, before their data is replaced by the nodes loaded in their place.
+///
+/// The images of the evicted nodes are updated as well, in case they are requested again,
+/// before their image assets are dropped.
+pub(crate) fn flush_evicted_nodes(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut TerrainEditor, &NodeAtlas, &TerrainConfig)>,
+) {
+    for (mut editor, node_atlas, config) in terrain_query.iter_mut() {
+        let evicted_edits: Vec<_> = node_atlas
+            .evicted_nodes
+            .iter()
+            .flat_map(|&(node_id, atlas_index)| {
+                editor
+                    .edited_nodes
+                    .iter()
+                    .filter(move |(_, nodes)| nodes.contains(&node_id))
+                    .map(move |(&attachment_index, _)| (attachment_index, node_id, atlas_index))
+            })
+            .collect();
+
+        if evicted_edits.is_empty() {
+            continue;
+        }
+
+        let manifest = TerrainManifest::load(&format!("assets/{}", config.path));
+
+        let saves = evicted_edits
+            .into_iter()
+            .filter_map(|(attachment_index, node_id, atlas_index)| {
+                let mut save = AttachmentSave::new(node_atlas, &manifest, attachment_index);
+
+                if !save.add_node(node_atlas, &images, attachment_index, node_id, atlas_index) {
+                    warn!("The unsaved edits of the evicted node {node_id} are lost.");
+                    editor
+                        .edited_nodes
+                        .get_mut(&attachment_index)
+                        .unwrap()
+                        .remove(&node_id);
+                    return None;
+                }
+
+                warn!(
+                    "The evicted node {node_id} has unsaved edits, which are written into its \
+                     node file. Its ancestors are rebuilt with the next save."
+                );
+
+                let node_data = &node_atlas.data[atlas_index as usize];
+
+                if let (Some(data), Some(image)) = (
+                    node_data.edited_attachments.get(&attachment_index),
+                    node_data
+                        .attachments
+                        .get(&attachment_index)
+                        .and_then(|handle| images.get_mut(handle)),
+                ) {
+                    image.data.copy_from_slice(data);
+                }
+
+                Some(save)
+            })
+            .collect();
+
+        editor.spawn_save(saves);
+    }
+}
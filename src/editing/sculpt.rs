@@ -0,0 +1,181 @@
+use crate::{
+    data_structures::{node_atlas::NodeAtlas, AttachmentIndex},
+    editing::{brush_weight, edit_attachment, EditCommand, RegionEdit, TerrainEditor, TexelRect},
+    preprocess::noise::gradient_noise,
+    terrain::TerrainConfig,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use image::DynamicImage;
+use std::sync::Arc;
+
+/// A grayscale image, that can be stamped onto the height with [`SculptTool::Stamp`].
+#[derive(Clone, Debug)]
+pub struct HeightStamp {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl HeightStamp {
+    /// Creates the stamp from the first channel of the image.
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let image = image.to_luma32f();
+
+        Self {
+            width: image.width(),
+            height: image.height(),
+            values: image.into_raw(),
+        }
+    }
+
+    /// Loads the stamp from an image file.
+    pub fn load(file_path: &str) -> Self {
+        Self::from_image(&image::open(file_path).expect("Could not load stamp."))
+    }
+
+    /// Samples the stamp bilinearly at the uv coordinate in the range [0, 1].
+    fn sample(&self, uv: Vec2) -> f32 {
+        let size = Vec2::new(self.width as f32, self.height as f32);
+        let position = (uv * size - 0.5).clamp(Vec2::ZERO, size - 1.0);
+
+        let cell = position.floor();
+        let t = position - cell;
+
+        let get = |dx: u32, dy: u32| {
+            let x = (cell.x as u32 + dx).min(self.width - 1);
+            let y = (cell.y as u32 + dy).min(self.height - 1);
+            self.values[(y * self.width + x) as usize]
+        };
+
+        let top = get(0, 0) + (get(1, 0) - get(0, 0)) * t.x;
+        let bottom = get(0, 1) + (get(1, 1) - get(0, 1)) * t.x;
+
+        top + (bottom - top) * t.y
+    }
+}
+
+/// Determines how a [`Brush`] modifies the height.
+///
+/// All heights and amounts are measured in world units and applied once per brush,
+/// so continuous strokes should scale them by the frame time.
+#[derive(Clone, Debug)]
+pub enum SculptTool {
+    /// Raises the height by `amount`, or lowers it if the amount is negative.
+    Raise { amount: f32 },
+    /// Moves the height towards the average of its neighbours by `strength` in [0, 1].
+    Smooth { strength: f32 },
+    /// Moves the height towards `height` by `strength` in [0, 1].
+    Flatten { height: f32, strength: f32 },
+    /// Adds gradient noise with the `amplitude` and the `frequency` in features per world unit.
+    Noise {
+        amplitude: f32,
+        frequency: f32,
+        seed: u32,
+    },
+    /// Adds the stamp scaled by `amount`, stretched over the diameter of the brush.
+    Stamp {
+        stamp: Arc<HeightStamp>,
+        amount: f32,
+    },
+}
+
+/// A brush applied to the height of the terrain at a world position.
+#[derive(Clone, Debug)]
+pub struct Brush {
+    pub tool: SculptTool,
+    /// The world position of the center of the brush. Only its horizontal components are used.
+    pub position: Vec3,
+    /// The radius of the brush in world units.
+    pub radius: f32,
+    /// The fraction of the radius in [0, 1], that is affected with full strength.
+    /// The rest falls off smoothly.
+    pub hardness: f32,
+}
+
+impl TerrainEditor {
+    /// Queues the brush to be applied to the height of the terrain.
+    ///
+    /// Only the height is edited. A baked normal attachment is not regenerated and thus stale
+    /// inside the sculpted region, see [`editing`](crate::editing) for details.
+    pub fn sculpt(&mut self, brush: Brush) {
+        self.commands.push(EditCommand::Sculpt(brush));
+    }
+}
+
+/// Applies the brush to all loaded nodes of lod 0 of the height attachment.
+pub(crate) fn apply_brush(
+    brush: &Brush,
+    attachment_index: AttachmentIndex,
+    node_atlas: &mut NodeAtlas,
+    images: &Assets<Image>,
+    config: &TerrainConfig,
+    transform: &GlobalTransform,
+) -> Vec<RegionEdit> {
+    let attachment = &node_atlas.attachments[attachment_index];
+    // the terrain covers one world unit per texel of a chunk sized attachment
+    let texels_per_unit = attachment.texture_size as f32 / config.chunk_size as f32;
+    let center = transform
+        .compute_matrix()
+        .inverse()
+        .transform_point3(brush.position)
+        .xz()
+        * texels_per_unit;
+    let radius = brush.radius * texels_per_unit;
+    let rect = TexelRect::around(center, radius);
+
+    edit_attachment(
+        node_atlas,
+        images,
+        attachment_index,
+        0,
+        rect,
+        |view, position, texel| {
+            let texel_center = position.as_vec2() + 0.5;
+            let weight = brush_weight(texel_center.distance(center), radius, brush.hardness);
+
+            if weight == 0.0 {
+                return texel;
+            }
+
+            let height = texel[0] * config.height;
+
+            let target = match &brush.tool {
+                SculptTool::Raise { amount } => height + amount,
+                SculptTool::Smooth { strength } => {
+                    let (sum, count) = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                        .iter()
+                        .filter_map(|&(dx, dy)| view.get(0, position + IVec2::new(dx, dy)))
+                        .fold((0.0, 0), |(sum, count), texel| (sum + texel[0], count + 1));
+
+                    let average = if count == 0 {
+                        height
+                    } else {
+                        sum / count as f32 * config.height
+                    };
+
+                    height + (average - height) * strength
+                }
+                SculptTool::Flatten {
+                    height: target,
+                    strength,
+                } => height + (target - height) * strength,
+                SculptTool::Noise {
+                    amplitude,
+                    frequency,
+                    seed,
+                } => {
+                    let position = texel_center / texels_per_unit;
+                    height + amplitude * gradient_noise(position * *frequency, *seed)
+                }
+                SculptTool::Stamp { stamp, amount } => {
+                    let uv = (texel_center - center) / (2.0 * radius) + 0.5;
+                    height + amount * stamp.sample(uv)
+                }
+            };
+
+            let height = height + (target - height) * weight;
+
+            [height / config.height, 0.0, 0.0, 0.0]
+        },
+    )
+}
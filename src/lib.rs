@@ -41,35 +41,47 @@ use crate::{
         gpu_quadtree::{
             extract_quadtree, initialize_gpu_quadtree, queue_quadtree_update, GpuQuadtree,
         },
-        node_atlas::update_node_atlas,
+        node_atlas::{update_height_bounds, update_node_atlas},
         quadtree::{
             adjust_quadtree, compute_quadtree_request, update_height_under_viewer, Quadtree,
         },
     },
     debug::{change_config, extract_debug, toggle_debug, DebugTerrain},
+    editing::{
+        apply_terrain_edits,
+        deform::{apply_terrain_deformations, TerrainDeformation},
+        forget_evicted_nodes,
+        persist::{flush_evicted_nodes, save_terrain_edits},
+        update_edited_lods,
+    },
     render::{
         compute_pipelines::{TerrainComputeNode, TerrainComputePipelines},
         culling::{queue_terrain_culling_bind_group, CullingBindGroup},
+        depth_pyramid::{
+            prepare_terrain_depth_textures, queue_depth_pyramids, DepthPyramid,
+            TerrainDepthPyramidNode,
+        },
         extract_terrain,
-        render_pipeline::{queue_terrain, TerrainRenderPipeline},
+        render_pipeline::TerrainRenderPipeline,
         shaders::add_shader,
-        terrain_data::{initialize_terrain_data, TerrainData},
+        splat_material::SplatMaterial,
+        terrain_data::{initialize_terrain_data, queue_terrain_mesh_uniform, TerrainData},
+        terrain_material::{DefaultTerrainMaterial, TerrainMaterialPlugin},
         terrain_view_data::{initialize_terrain_view_data, TerrainViewData},
-        DrawTerrain, TerrainPipelineConfig,
+        TerrainPipelineConfig,
     },
     terrain::{Terrain, TerrainComponents, TerrainConfig},
     terrain_view::{
-        extract_terrain_view_config, queue_terrain_view_config, TerrainView, TerrainViewComponents,
-        TerrainViewConfig,
+        extract_terrain_view_config, queue_terrain_view_config, update_view_position, TerrainView,
+        TerrainViewComponents, TerrainViewConfig,
     },
 };
 use bevy::{
-    core_pipeline::core_3d::Opaque3d,
+    core_pipeline::core_3d::prepare_core_3d_depth_textures,
     prelude::*,
     render::{
         extract_component::ExtractComponentPlugin, main_graph::node::CAMERA_DRIVER,
-        render_graph::RenderGraph, render_phase::AddRenderCommand, render_resource::*, RenderApp,
-        RenderStage,
+        render_graph::RenderGraph, render_resource::*, RenderApp, RenderStage,
     },
 };
 
@@ -77,9 +89,11 @@ pub mod attachment_loader;
 pub mod bundles;
 pub mod data_structures;
 pub mod debug;
+pub mod editing;
 pub mod preprocess;
 pub mod render;
 pub mod terrain;
+pub mod terrain_manifest;
 pub mod terrain_view;
 
 #[allow(missing_docs)]
@@ -89,9 +103,23 @@ pub mod prelude {
         attachment_loader::AttachmentFromDiskLoader,
         bundles::TerrainBundle,
         data_structures::quadtree::Quadtree,
+        editing::{
+            deform::{DeformationMode, DeformationShape, TerrainDeformation, TerrainDeformer},
+            paint::{PaintBrush, PaintError, PaintTool},
+            sculpt::{Brush, HeightStamp, SculptTool},
+            TerrainEditor,
+        },
         preprocess::prelude,
-        render::TerrainPipelineConfig,
+        render::{
+            render_pipeline::TerrainPipelineKey,
+            splat_material::{SplatMaterial, SplatSettings},
+            terrain_material::{
+                DefaultTerrainMaterial, TerrainMaterial, TerrainMaterialKey, TerrainMaterialPlugin,
+            },
+            TerrainPipelineConfig,
+        },
         terrain::{Terrain, TerrainConfig},
+        terrain_manifest::{TerrainLoadError, TerrainManifest},
         terrain_view::{TerrainView, TerrainViewComponents, TerrainViewConfig},
         TerrainPlugin,
     };
@@ -108,6 +136,7 @@ impl Plugin for TerrainPlugin {
             .init_resource::<DebugTerrain>()
             .init_resource::<TerrainViewComponents<Quadtree>>()
             .init_resource::<TerrainViewComponents<TerrainViewConfig>>()
+            .add_event::<TerrainDeformation>()
             .add_system(toggle_debug)
             .add_system(change_config)
             .add_system_to_stage(
@@ -118,8 +147,40 @@ impl Plugin for TerrainPlugin {
                 CoreStage::Last,
                 compute_quadtree_request.before(update_node_atlas),
             )
+            .add_system_to_stage(
+                CoreStage::Last,
+                apply_terrain_edits.before(update_node_atlas),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                update_edited_lods
+                    .after(apply_terrain_edits)
+                    .before(update_node_atlas),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                save_terrain_edits
+                    .after(update_edited_lods)
+                    .before(update_node_atlas),
+            )
             .add_system_to_stage(CoreStage::Last, update_node_atlas)
             .add_system_to_stage(CoreStage::Last, adjust_quadtree.after(update_node_atlas))
+            .add_system_to_stage(
+                CoreStage::Last,
+                flush_evicted_nodes.after(update_node_atlas),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                forget_evicted_nodes.after(update_node_atlas),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                apply_terrain_deformations.after(update_node_atlas),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                update_height_bounds.after(apply_terrain_deformations),
+            )
             .add_system_to_stage(
                 CoreStage::Last,
                 start_loading_attachment_from_disk.after(update_node_atlas),
@@ -127,7 +188,8 @@ impl Plugin for TerrainPlugin {
             .add_system_to_stage(
                 CoreStage::Last,
                 update_height_under_viewer.after(adjust_quadtree),
-            );
+            )
+            .add_system_to_stage(CoreStage::Last, update_view_position);
 
         let config = app
             .world
@@ -136,11 +198,9 @@ impl Plugin for TerrainPlugin {
 
         let render_app = app
             .sub_app_mut(RenderApp)
-            .add_render_command::<Opaque3d, DrawTerrain>()
             .insert_resource(config)
             .init_resource::<DebugTerrain>()
             .init_resource::<TerrainRenderPipeline>()
-            .init_resource::<SpecializedRenderPipelines<TerrainRenderPipeline>>()
             .init_resource::<TerrainComputePipelines>()
             .init_resource::<SpecializedComputePipelines<TerrainComputePipelines>>()
             .init_resource::<TerrainComponents<GpuNodeAtlas>>()
@@ -149,6 +209,7 @@ impl Plugin for TerrainPlugin {
             .init_resource::<TerrainViewComponents<TerrainViewData>>()
             .init_resource::<TerrainViewComponents<TerrainViewConfig>>()
             .init_resource::<TerrainViewComponents<CullingBindGroup>>()
+            .init_resource::<TerrainViewComponents<DepthPyramid>>()
             .add_system_to_stage(RenderStage::Extract, extract_terrain)
             .add_system_to_stage(RenderStage::Extract, extract_terrain_view_config)
             .add_system_to_stage(RenderStage::Extract, extract_debug)
@@ -160,7 +221,9 @@ impl Plugin for TerrainPlugin {
             )
             .add_system_to_stage(
                 RenderStage::Extract,
-                initialize_terrain_view_data.after(initialize_gpu_quadtree),
+                initialize_terrain_view_data
+                    .after(initialize_gpu_quadtree)
+                    .after(initialize_terrain_data),
             )
             .add_system_to_stage(
                 RenderStage::Extract,
@@ -170,19 +233,38 @@ impl Plugin for TerrainPlugin {
                 RenderStage::Extract,
                 extract_quadtree.after(initialize_gpu_quadtree),
             )
-            .add_system_to_stage(RenderStage::Queue, queue_terrain)
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_terrain_depth_textures.after(prepare_core_3d_depth_textures),
+            )
+            .add_system_to_stage(RenderStage::Queue, queue_terrain_mesh_uniform)
             .add_system_to_stage(RenderStage::Queue, queue_quadtree_update)
             .add_system_to_stage(RenderStage::Queue, queue_node_atlas_updates)
-            .add_system_to_stage(RenderStage::Queue, queue_terrain_culling_bind_group)
+            .add_system_to_stage(RenderStage::Queue, queue_depth_pyramids)
+            .add_system_to_stage(
+                RenderStage::Queue,
+                queue_terrain_culling_bind_group.after(queue_depth_pyramids),
+            )
             .add_system_to_stage(RenderStage::Queue, queue_terrain_view_config);
 
         let compute_node = TerrainComputeNode::from_world(&mut render_app.world);
+        let depth_pyramid_node = TerrainDepthPyramidNode::from_world(&mut render_app.world);
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
         render_graph.add_node("terrain_compute", compute_node);
+        render_graph.add_node("terrain_depth_pyramid", depth_pyramid_node);
 
         render_graph
             .add_node_edge("terrain_compute", CAMERA_DRIVER)
             .unwrap();
+        render_graph
+            .add_node_edge(CAMERA_DRIVER, "terrain_depth_pyramid")
+            .unwrap();
+
+        app.add_plugin(TerrainMaterialPlugin::<DefaultTerrainMaterial>::default())
+            .add_plugin(TerrainMaterialPlugin::<SplatMaterial>::default());
+        app.world
+            .resource_mut::<Assets<DefaultTerrainMaterial>>()
+            .set_untracked(Handle::<DefaultTerrainMaterial>::default(), default());
     }
 }
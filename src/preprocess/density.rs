@@ -1,115 +1,237 @@
-use crate::{
-    data_structures::calc_node_id,
-    preprocess::{div_ceil, div_floor, down_sample_nodes, load_node, ImageFormat},
-    Vec3,
+use crate::preprocess::{
+    derive::{preprocess_derived, HeightWindow},
+    down_sample::DownSampleFilter,
+    ImageFormat,
 };
-use image::{DynamicImage, ImageBuffer, Luma};
+use bevy::prelude::*;
 use itertools::iproduct;
-use std::fs;
+use std::{fmt, sync::Arc};
 
-fn height_to_density(
-    height_node: &DynamicImage,
-    texture_size: u32,
-    border_size: u32,
-    height: f32,
-) -> DynamicImage {
-    let height_node = height_node.as_luma16().unwrap();
-
-    let density_node = ImageBuffer::from_fn(texture_size, texture_size, |x, y| {
-        let left = height_node
-            .get_pixel(x + border_size - 1, y + border_size)
-            .0[0] as f32
-            / u16::MAX as f32;
-        let up = height_node
-            .get_pixel(x + border_size, y + border_size - 1)
-            .0[0] as f32
-            / u16::MAX as f32;
-        let right = height_node
-            .get_pixel(x + border_size + 1, y + border_size)
-            .0[0] as f32
-            / u16::MAX as f32;
-        let down = height_node
-            .get_pixel(x + border_size, y + border_size + 1)
-            .0[0] as f32
-            / u16::MAX as f32;
-
-        let normal = Vec3::new(right - left, 2.0 / height, down - up).normalize();
-        let slope = 1.0 - normal.dot(Vec3::new(0.0, 1.0, 0.0));
-        let slope = (slope * u16::MAX as f32) as u16;
-
-        Luma([slope])
-    });
-
-    DynamicImage::from(density_node)
+/// A custom density metric, that computes the density of a texel from its surrounding heights.
+pub type CustomDensityMetric = Arc<dyn Fn(&HeightWindow) -> f32 + Send + Sync>;
+
+/// Determines how densely the terrain should be tessellated at each texel.
+///
+/// The resulting density is clamped to the range [0, 1] and mapped to a tile resolution
+/// by the `density_thresholds` of the [`TerrainViewConfig`](crate::terrain_view::TerrainViewConfig).
+#[derive(Clone)]
+pub enum DensityMetric {
+    /// The steepness of the terrain (one minus the up component of the normal).
+    Slope,
+    /// The absolute discrete Laplacian of the height, scaled by `scale`.
+    Curvature { scale: f32 },
+    /// The standard deviation of the heights of the 3x3 neighbourhood, scaled by `scale`.
+    HeightVariance { scale: f32 },
+    /// The error of the height compared to the interpolated surface of the next coarser lod,
+    /// scaled by `scale`.
+    LodError { scale: f32 },
+    /// Computes the density with a custom function.
+    ///
+    /// The key identifies the function in the settings of the content manifest, so it has to
+    /// change (e.g. by including a version), whenever the behaviour of the function changes.
+    /// Otherwise previously preprocessed nodes are reused.
+    Custom(String, CustomDensityMetric),
 }
 
-pub fn density_chunks(
-    height_directory: &str,
-    density_directory: &str,
-    first: (u32, u32),
-    last: (u32, u32),
-    texture_size: u32,
-    border_size: u32,
-    height: f32,
-) {
-    for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
-        let node_id = calc_node_id(0, x, y);
-        let density_file_path = format!("{density_directory}/{node_id}.png");
-        let height_file_path = format!("{height_directory}/{node_id}.png");
-
-        let height_node = load_node(
-            &height_file_path,
-            texture_size,
-            border_size,
-            ImageFormat::LUMA16,
-        );
-
-        let density_node = height_to_density(&height_node, texture_size, border_size, height);
-
-        density_node
-            .save(density_file_path)
-            .expect("Could not save file.");
+impl Default for DensityMetric {
+    fn default() -> Self {
+        Self::Slope
+    }
+}
+
+impl fmt::Debug for DensityMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Slope => write!(f, "Slope"),
+            Self::Curvature { scale } => write!(f, "Curvature({scale})"),
+            Self::HeightVariance { scale } => write!(f, "HeightVariance({scale})"),
+            Self::LodError { scale } => write!(f, "LodError({scale})"),
+            Self::Custom(key, _) => write!(f, "Custom({key:?})"),
+        }
+    }
+}
+
+impl DensityMetric {
+    /// Computes the density of the texel.
+    pub fn density(&self, window: &HeightWindow) -> f32 {
+        let density = match self {
+            Self::Slope => 1.0 - window.normal().dot(Vec3::Y),
+            Self::Curvature { scale } => window.laplacian().abs() * scale,
+            Self::HeightVariance { scale } => {
+                let heights: Vec<f32> = iproduct!(-1..=1, -1..=1)
+                    .map(|(dx, dy)| window.get(dx, dy))
+                    .collect();
+
+                let mean = heights.iter().sum::<f32>() / 9.0;
+                let variance = heights.iter().map(|h| (h - mean).powi(2)).sum::<f32>() / 9.0;
+
+                variance.sqrt() * scale
+            }
+            Self::LodError { scale } => {
+                // the vertices of the coarser lod lie on the even texels
+                let interpolated = match (window.position().x & 1, window.position().y & 1) {
+                    (0, 0) => window.get(0, 0),
+                    (1, 0) => (window.get(-1, 0) + window.get(1, 0)) / 2.0,
+                    (0, 1) => (window.get(0, -1) + window.get(0, 1)) / 2.0,
+                    _ => {
+                        (window.get(-1, -1)
+                            + window.get(1, -1)
+                            + window.get(-1, 1)
+                            + window.get(1, 1))
+                            / 4.0
+                    }
+                };
+
+                (window.get(0, 0) - interpolated).abs() * scale
+            }
+            Self::Custom(_, metric) => metric(window),
+        };
+
+        density.clamp(0.0, 1.0)
     }
 }
 
+/// Derives the density attachment from the height attachment using the density `metric`
+/// and builds all of its lods.
+///
+/// The density is derived for all existing lod 0 height nodes, see
+/// [`preprocess_derived`](super::derive::preprocess_derived).
 pub fn preprocess_density(
     height_directory: &str,
     density_directory: &str,
     lod_count: u32,
-    first: (u32, u32),
-    last: (u32, u32),
     texture_size: u32,
     border_size: u32,
     height: f32,
+    metric: &DensityMetric,
+    filter: &DownSampleFilter,
 ) {
-    let _ = fs::remove_dir_all(density_directory);
-    fs::create_dir_all(density_directory).unwrap();
+    assert!(
+        border_size > 0,
+        "The height attachment requires a border to compute the density."
+    );
 
-    density_chunks(
+    preprocess_derived(
         height_directory,
         density_directory,
-        first,
-        last,
+        lod_count,
         texture_size,
         border_size,
+        0,
         height,
+        ImageFormat::LUMA16,
+        filter,
+        &format!("density {metric:?}"),
+        |window| [metric.density(window), 0.0, 0.0, 0.0],
     );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::calc_node_id;
+    use image::{ImageBuffer, Luma};
+    use std::{fs, process};
+
+    const HEIGHT: f32 = 100.0;
+
+    /// A height node of 2x2 texels with a border of one texel.
+    /// The height increases by 10 units along x and by 20 units along y.
+    fn height_node() -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        ImageBuffer::from_fn(4, 4, |x, y| {
+            let height = (10 * x + 20 * y) as f32 / HEIGHT;
+            Luma([(height * u16::MAX as f32).round() as u16])
+        })
+    }
+
+    /// The density of the custom metric, which is the height divided by the terrain height.
+    fn relative_height() -> DensityMetric {
+        DensityMetric::Custom(
+            "relative height".into(),
+            Arc::new(|window| window.get(0, 0) / HEIGHT),
+        )
+    }
+
+    /// Computes the density of the metric at all texels of the height node.
+    fn densities(metric: &DensityMetric) -> Vec<f32> {
+        let height_node = height_node();
+
+        iproduct!(0..2, 0..2)
+            .map(|(y, x)| {
+                let window =
+                    HeightWindow::new(&height_node, UVec2::new(x, y), UVec2::ZERO, 1, HEIGHT);
+                metric.density(&window)
+            })
+            .collect()
+    }
+
+    fn assert_densities(densities: &[f32], expected: &[f32]) {
+        assert_eq!(densities.len(), expected.len());
+
+        for (density, expected) in densities.iter().zip(expected) {
+            assert!(
+                (density - expected).abs() < 1e-3,
+                "{densities:?} != {expected:?}"
+            );
+        }
+    }
+
+    /// Reads the density node of lod 0 in the density directory.
+    fn density_node(directory: &str) -> Vec<f32> {
+        let node = image::open(format!("{directory}/{}.png", calc_node_id(0, 0, 0))).unwrap();
+
+        node.as_luma16()
+            .unwrap()
+            .pixels()
+            .map(|pixel| pixel.0[0] as f32 / u16::MAX as f32)
+            .collect()
+    }
+
+    #[test]
+    fn custom_metrics_compute_the_density() {
+        assert_densities(&densities(&relative_height()), &[0.3, 0.4, 0.5, 0.6]);
+
+        let steep = DensityMetric::Custom("steep".into(), Arc::new(|_| 2.0));
+        assert_densities(&densities(&steep), &[1.0; 4]);
+    }
+
+    #[test]
+    fn changed_metric_keys_rebuild_the_density() {
+        let directory = std::env::temp_dir().join(format!("density_{}", process::id()));
+        let height_directory = format!("{}/height", directory.display());
+        let density_directory = format!("{}/density", directory.display());
+
+        fs::create_dir_all(&height_directory).unwrap();
+        height_node()
+            .save(format!("{height_directory}/{}.png", calc_node_id(0, 0, 0)))
+            .unwrap();
+
+        let preprocess = |metric: &DensityMetric| {
+            preprocess_density(
+                &height_directory,
+                &density_directory,
+                1,
+                2,
+                1,
+                HEIGHT,
+                metric,
+                &DownSampleFilter::Average,
+            );
+
+            density_node(&density_directory)
+        };
+
+        assert_densities(&preprocess(&relative_height()), &[0.3, 0.4, 0.5, 0.6]);
+
+        // the nodes are reused, as long as the key stays the same
+        let flat = |_: &HeightWindow| 0.0;
+        let unchanged = DensityMetric::Custom("relative height".into(), Arc::new(flat));
+        assert_densities(&preprocess(&unchanged), &[0.3, 0.4, 0.5, 0.6]);
+
+        // a new key marks all nodes dirty
+        let flat = DensityMetric::Custom("flat".into(), Arc::new(flat));
+        assert_densities(&preprocess(&flat), &[0.0; 4]);
 
-    let mut first = first;
-    let mut last = last;
-
-    for lod in 1..lod_count {
-        first = (div_floor(first.0, 2), div_floor(first.1, 2));
-        last = (div_ceil(last.0, 2), div_ceil(last.1, 2));
-
-        down_sample_nodes(
-            density_directory,
-            first,
-            last,
-            lod,
-            texture_size,
-            0,
-            ImageFormat::LUMA16,
-        );
+        fs::remove_dir_all(directory).unwrap();
     }
 }
@@ -0,0 +1,241 @@
+use crate::{
+    data_structures::{NodeCoordinate, NodeId},
+    preprocess::{
+        down_sample::DownSampleFilter,
+        existing_nodes, load_node,
+        manifest::{hash_source, ContentManifest},
+        normal::height_normal,
+        rebuild_ancestors, ImageFormat,
+    },
+};
+use bevy::prelude::*;
+use image::{
+    DynamicImage, GrayAlphaImage, ImageBuffer, Luma, LumaA, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use std::{fs, path::Path};
+
+/// Provides access to the heights surrounding a texel of a height node.
+pub struct HeightWindow<'a> {
+    height_node: &'a ImageBuffer<Luma<u16>, Vec<u16>>,
+    /// The position of the texel inside the node, excluding the border.
+    position: UVec2,
+    /// The position of the first texel of the node inside the terrain.
+    origin: UVec2,
+    border_size: u32,
+    height: f32,
+}
+
+impl<'a> HeightWindow<'a> {
+    pub(crate) fn new(
+        height_node: &'a ImageBuffer<Luma<u16>, Vec<u16>>,
+        position: UVec2,
+        origin: UVec2,
+        border_size: u32,
+        height: f32,
+    ) -> Self {
+        Self {
+            height_node,
+            position,
+            origin,
+            border_size,
+            height,
+        }
+    }
+
+    /// Returns the position of the texel inside the node, excluding the border.
+    pub fn position(&self) -> UVec2 {
+        self.position
+    }
+
+    /// Returns the position of the texel inside the terrain, in texels of lod 0.
+    pub fn global_position(&self) -> UVec2 {
+        self.origin + self.position
+    }
+
+    /// Returns the minimum and maximum corner of the node inside the terrain,
+    /// in texels of lod 0.
+    pub fn node_bounds(&self) -> (Vec2, Vec2) {
+        let size = self.height_node.width() - 2 * self.border_size;
+
+        (self.origin.as_vec2(), (self.origin + size).as_vec2())
+    }
+
+    /// Returns the height in world units at the offset relative to the texel.
+    /// The offset must not exceed the border size of the height attachment.
+    pub fn get(&self, dx: i32, dy: i32) -> f32 {
+        let x = (self.position.x + self.border_size) as i32 + dx;
+        let y = (self.position.y + self.border_size) as i32 + dy;
+
+        self.height_node.get_pixel(x as u32, y as u32).0[0] as f32 / u16::MAX as f32 * self.height
+    }
+
+    /// Returns the discrete Laplacian of the height at the texel.
+    /// It is positive in concave (e.g. valleys) and negative in convex (e.g. ridges) regions.
+    pub fn laplacian(&self) -> f32 {
+        self.get(-1, 0) + self.get(1, 0) + self.get(0, -1) + self.get(0, 1) - 4.0 * self.get(0, 0)
+    }
+
+    /// Returns the normal of the terrain at the texel.
+    pub fn normal(&self) -> Vec3 {
+        height_normal(
+            self.height_node,
+            self.position.x + self.border_size,
+            self.position.y + self.border_size,
+            self.height,
+        )
+    }
+}
+
+/// Creates an image of the format from normalized channel values.
+/// Unused channels are ignored.
+fn encode_image(
+    size: u32,
+    format: ImageFormat,
+    texel: impl Fn(u32, u32) -> [f32; 4],
+) -> DynamicImage {
+    let to_u8 = |value: f32| (value.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8;
+    let to_u16 = |value: f32| (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16;
+
+    match format {
+        ImageFormat::RGB => DynamicImage::from(RgbImage::from_fn(size, size, |x, y| {
+            let [r, g, b, _] = texel(x, y);
+            Rgb([to_u8(r), to_u8(g), to_u8(b)])
+        })),
+        ImageFormat::RGBA => DynamicImage::from(RgbaImage::from_fn(size, size, |x, y| {
+            Rgba(texel(x, y).map(to_u8))
+        })),
+        ImageFormat::LUMA16 => {
+            DynamicImage::from(<ImageBuffer<Luma<u16>, _>>::from_fn(size, size, |x, y| {
+                Luma([to_u16(texel(x, y)[0])])
+            }))
+        }
+        ImageFormat::RG8 => DynamicImage::from(GrayAlphaImage::from_fn(size, size, |x, y| {
+            let [r, g, ..] = texel(x, y);
+            LumaA([to_u8(r), to_u8(g)])
+        })),
+        ImageFormat::RG16 => {
+            DynamicImage::from(<ImageBuffer<LumaA<u16>, _>>::from_fn(size, size, |x, y| {
+                let [r, g, ..] = texel(x, y);
+                LumaA([to_u16(r), to_u16(g)])
+            }))
+        }
+    }
+}
+
+/// Derives the node from the corresponding height node.
+fn derive_node(
+    height_directory: &str,
+    derived_directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    height_border_size: u32,
+    border_size: u32,
+    height: f32,
+    format: ImageFormat,
+    texel: &impl Fn(&HeightWindow) -> [f32; 4],
+) {
+    let height_node = load_node(
+        &format!("{height_directory}/{node_id}.png"),
+        texture_size,
+        height_border_size,
+        ImageFormat::LUMA16,
+    );
+    let height_node = height_node.as_luma16().unwrap();
+
+    let NodeCoordinate { x, y, .. } = node_id.into();
+    let origin = UVec2::new(x, y) * texture_size;
+
+    // the border is filled by stitching afterwards
+    let derived_node = encode_image(texture_size + 2 * border_size, format, |x, y| {
+        let x = x.clamp(border_size, border_size + texture_size - 1) - border_size;
+        let y = y.clamp(border_size, border_size + texture_size - 1) - border_size;
+
+        texel(&HeightWindow::new(
+            height_node,
+            UVec2::new(x, y),
+            origin,
+            height_border_size,
+            height,
+        ))
+    });
+
+    derived_node
+        .save(format!("{derived_directory}/{node_id}.png"))
+        .expect("Could not save file.");
+}
+
+/// Derives an attachment from the height attachment by evaluating `texel` for every texel
+/// and builds all of its lods.
+///
+/// The function returns the normalized channel values of the texel, which are stored in the
+/// `format`. Only the lod 0 height nodes, that exist inside the height directory, are derived.
+/// The `settings` identify the derivation, if they change all nodes are derived again.
+/// Otherwise, like [`preprocess_tiles`](super::preprocess_tiles), only the nodes of changed
+/// height nodes and their ancestors are rebuilt on subsequent runs.
+pub fn preprocess_derived(
+    height_directory: &str,
+    derived_directory: &str,
+    lod_count: u32,
+    texture_size: u32,
+    height_border_size: u32,
+    border_size: u32,
+    height: f32,
+    format: ImageFormat,
+    filter: &DownSampleFilter,
+    settings: &str,
+    texel: impl Fn(&HeightWindow) -> [f32; 4],
+) {
+    let settings = format!(
+        "derived {lod_count} {texture_size} {height_border_size} {border_size} {height} {format:?} {filter:?} {settings}"
+    );
+
+    let previous = ContentManifest::load(derived_directory, &settings).unwrap_or_else(|| {
+        let _ = fs::remove_dir_all(derived_directory);
+        fs::create_dir_all(derived_directory).unwrap();
+        default()
+    });
+
+    let mut manifest = ContentManifest::new(settings);
+
+    for node_id in existing_nodes(height_directory) {
+        if NodeCoordinate::from(node_id).lod == 0 {
+            manifest.insert(
+                format!("{node_id}.png"),
+                hash_source(&format!("{height_directory}/{node_id}.png")),
+                vec![node_id],
+            );
+        }
+    }
+
+    let dirty_nodes = manifest.dirty_nodes(&previous);
+
+    for &node_id in &dirty_nodes {
+        if Path::new(&format!("{height_directory}/{node_id}.png")).exists() {
+            derive_node(
+                height_directory,
+                derived_directory,
+                node_id,
+                texture_size,
+                height_border_size,
+                border_size,
+                height,
+                format,
+                &texel,
+            );
+        } else {
+            let _ = fs::remove_file(format!("{derived_directory}/{node_id}.png"));
+        }
+    }
+
+    rebuild_ancestors(
+        derived_directory,
+        dirty_nodes,
+        lod_count,
+        texture_size,
+        border_size,
+        format,
+        filter,
+    );
+
+    manifest.save(derived_directory);
+}
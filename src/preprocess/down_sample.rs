@@ -0,0 +1,427 @@
+use crate::preprocess::{nodata::NoDataMarker, ImageFormat};
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GenericImage, GenericImageView, ImageBuffer, Pixel,
+};
+use itertools::iproduct;
+use std::{fmt, ops::Deref, sync::Arc};
+
+/// A custom reducer, that combines the four child texels (top left, top right, bottom left,
+/// bottom right) into a single texel.
+///
+/// All channel values are normalized to the range [0, 1]. Unused channels are zero.
+pub type DownSampleReducer = Arc<dyn Fn(&[[f32; 4]; 4]) -> [f32; 4] + Send + Sync>;
+
+/// Configures how the four child texels of a node are combined into a texel of its parent.
+///
+/// Different attachments require different filters, e.g. categorical data like material
+/// indices must never be interpolated, while height bounds should keep their extremes.
+#[derive(Clone)]
+pub enum DownSampleFilter {
+    /// Resizes with one of the filters of the image crate (e.g. `Triangle` or `Lanczos3`).
+    Resize(FilterType),
+    /// Averages the four child texels.
+    Average,
+    /// Selects the minimum of the four child texels per channel.
+    Min,
+    /// Selects the maximum of the four child texels per channel.
+    Max,
+    /// Selects the most common of the four child texels, or the top left one if all differ.
+    Mode,
+    /// Selects the top left child texel.
+    Nearest,
+    /// Averages the color channels in linear space, which is required for sRGB data.
+    GammaAverage,
+    /// Combines the child texels with a custom reducer.
+    ///
+    /// The key identifies the reducer in the settings of the content manifest, so it has to
+    /// change (e.g. by including a version), whenever the behaviour of the reducer changes.
+    /// Otherwise previously preprocessed nodes are reused.
+    Custom(String, DownSampleReducer),
+    /// Combines only the child texels with data using the inner filter,
+    /// missing child nodes have no data either. Texels whose children all have no data,
+    /// have no data themselves. `Resize` filters are applied as is.
+    IgnoreNoData(Box<DownSampleFilter>, NoDataMarker),
+}
+
+impl Default for DownSampleFilter {
+    fn default() -> Self {
+        Self::Resize(FilterType::Triangle)
+    }
+}
+
+impl fmt::Debug for DownSampleFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Resize(filter) => write!(f, "Resize({filter:?})"),
+            Self::Average => write!(f, "Average"),
+            Self::Min => write!(f, "Min"),
+            Self::Max => write!(f, "Max"),
+            Self::Mode => write!(f, "Mode"),
+            Self::Nearest => write!(f, "Nearest"),
+            Self::GammaAverage => write!(f, "GammaAverage"),
+            Self::Custom(key, _) => write!(f, "Custom({key:?})"),
+            Self::IgnoreNoData(filter, marker) => write!(f, "IgnoreNoData({filter:?}, {marker:?})"),
+        }
+    }
+}
+
+#[inline]
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[inline]
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl DownSampleFilter {
+    /// Wraps the filter, so that it ignores child texels without data.
+    pub fn ignore_nodata(self, marker: NoDataMarker) -> Self {
+        match self {
+            Self::IgnoreNoData(filter, _) => Self::IgnoreNoData(filter, marker),
+            filter => Self::IgnoreNoData(Box::new(filter), marker),
+        }
+    }
+
+    /// Returns the marker of texels without data, if they are ignored by the filter.
+    pub(crate) fn nodata_marker(&self) -> Option<NoDataMarker> {
+        match self {
+            Self::IgnoreNoData(_, marker) => Some(*marker),
+            _ => None,
+        }
+    }
+
+    /// Combines the four child texels into a single texel.
+    /// `Resize` filters only apply to whole nodes, single texels are averaged instead.
+    pub(crate) fn reduce(&self, samples: &[[f32; 4]; 4]) -> [f32; 4] {
+        let per_channel = |reduce: fn(f32, f32) -> f32| {
+            samples[1..].iter().fold(samples[0], |mut texel, sample| {
+                for (value, &other) in texel.iter_mut().zip(sample) {
+                    *value = reduce(*value, other);
+                }
+                texel
+            })
+        };
+
+        match self {
+            Self::Resize(_) | Self::Average => per_channel(|a, b| a + b).map(|value| value / 4.0),
+            Self::Min => per_channel(f32::min),
+            Self::Max => per_channel(f32::max),
+            Self::Mode => {
+                let count =
+                    |sample: &[f32; 4]| samples.iter().filter(|&other| other == sample).count();
+
+                samples[1..].iter().fold(samples[0], |mode, sample| {
+                    if count(sample) > count(&mode) {
+                        *sample
+                    } else {
+                        mode
+                    }
+                })
+            }
+            Self::Nearest => samples[0],
+            Self::GammaAverage => {
+                let mut texel = [0.0; 4];
+
+                for (channel, value) in texel.iter_mut().enumerate() {
+                    // only the color channels are sRGB encoded, alpha is stored linearly
+                    if channel < 3 {
+                        let sum: f32 = samples
+                            .iter()
+                            .map(|sample| srgb_to_linear(sample[channel]))
+                            .sum();
+                        *value = linear_to_srgb(sum / 4.0);
+                    } else {
+                        *value = samples.iter().map(|sample| sample[channel]).sum::<f32>() / 4.0;
+                    }
+                }
+
+                texel
+            }
+            Self::Custom(_, reducer) => reducer(samples),
+            Self::IgnoreNoData(filter, _) => filter.reduce(samples),
+        }
+    }
+}
+
+type NodeBuffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+fn down_sample_buffer<P: Pixel + 'static>(
+    node: &mut NodeBuffer<P>,
+    child_node: &NodeBuffer<P>,
+    x: u32,
+    y: u32,
+    texture_size: u32,
+    border_size: u32,
+    filter: &DownSampleFilter,
+    max_value: f32,
+    from_f32: impl Fn(f32) -> P::Subpixel,
+) where
+    P::Subpixel: Into<f32> + 'static,
+{
+    let child_size = texture_size >> 1;
+    let marker = filter.nodata_marker();
+
+    let filter = match filter {
+        DownSampleFilter::IgnoreNoData(filter, _) => filter.as_ref(),
+        filter => filter,
+    };
+
+    if let DownSampleFilter::Resize(filter) = filter {
+        // crop the border away
+        let child_node = child_node.view(border_size, border_size, texture_size, texture_size);
+        // down sample to half quarter the resolution
+        let child_node = imageops::resize(child_node.deref(), child_size, child_size, *filter);
+        node.copy_from(&child_node, x, y).unwrap();
+        return;
+    }
+
+    let channel_count = P::CHANNEL_COUNT as usize;
+
+    for (cx, cy) in iproduct!(0..child_size, 0..child_size) {
+        let mut samples = [[0.0; 4]; 4];
+
+        for (sample, (dx, dy)) in samples.iter_mut().zip([(0, 0), (1, 0), (0, 1), (1, 1)]) {
+            let texel =
+                child_node.get_pixel(border_size + (cx << 1) + dx, border_size + (cy << 1) + dy);
+
+            for (value, &channel) in sample.iter_mut().zip(texel.channels()) {
+                *value = channel.into() / max_value;
+            }
+        }
+
+        let texel = match marker {
+            Some(marker) => {
+                let valid: Vec<[f32; 4]> = samples
+                    .iter()
+                    .filter(|sample| !marker.matches(sample, channel_count, max_value))
+                    .copied()
+                    .collect();
+
+                if valid.is_empty() {
+                    marker.texel()
+                } else {
+                    // replace the texels without data by the mean of the others, which keeps
+                    // the result of all filters within the range of the valid texels
+                    let mean = valid.iter().fold([0.0; 4], |mut mean, sample| {
+                        for (mean, value) in mean.iter_mut().zip(sample) {
+                            *mean += value / valid.len() as f32;
+                        }
+                        mean
+                    });
+
+                    for sample in &mut samples {
+                        if marker.matches(sample, channel_count, max_value) {
+                            *sample = mean;
+                        }
+                    }
+
+                    filter.reduce(&samples)
+                }
+            }
+            None => filter.reduce(&samples),
+        };
+        let channels: Vec<P::Subpixel> = texel[..channel_count]
+            .iter()
+            .map(|&value| from_f32((value.clamp(0.0, 1.0) * max_value).round()))
+            .collect();
+
+        node.put_pixel(x + cx, y + cy, *P::from_slice(&channels));
+    }
+}
+
+/// Down samples the child node to half its resolution and copies it into the
+/// corresponding quarter of the node.
+pub(crate) fn down_sample_overlay(
+    node: &mut DynamicImage,
+    child_node: &DynamicImage,
+    child_x: u32,
+    child_y: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    filter: &DownSampleFilter,
+) {
+    if let Some(marker) = filter.nodata_marker() {
+        marker.validate(format);
+    }
+
+    let child_size = texture_size >> 1;
+
+    let x = child_x * child_size + border_size;
+    let y = child_y * child_size + border_size;
+
+    match format {
+        ImageFormat::RGB => down_sample_buffer(
+            node.as_mut_rgb8().unwrap(),
+            child_node.as_rgb8().unwrap(),
+            x,
+            y,
+            texture_size,
+            border_size,
+            filter,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RGBA => down_sample_buffer(
+            node.as_mut_rgba8().unwrap(),
+            child_node.as_rgba8().unwrap(),
+            x,
+            y,
+            texture_size,
+            border_size,
+            filter,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::LUMA16 => down_sample_buffer(
+            node.as_mut_luma16().unwrap(),
+            child_node.as_luma16().unwrap(),
+            x,
+            y,
+            texture_size,
+            border_size,
+            filter,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+        ImageFormat::RG8 => down_sample_buffer(
+            node.as_mut_luma_alpha8().unwrap(),
+            child_node.as_luma_alpha8().unwrap(),
+            x,
+            y,
+            texture_size,
+            border_size,
+            filter,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RG16 => down_sample_buffer(
+            node.as_mut_luma_alpha16().unwrap(),
+            child_node.as_luma_alpha16().unwrap(),
+            x,
+            y,
+            texture_size,
+            border_size,
+            filter,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    const SAMPLES: [[f32; 4]; 4] = [
+        [0.0, 0.2, 1.0, 0.5],
+        [0.4, 0.2, 0.0, 0.5],
+        [0.8, 0.6, 1.0, 0.5],
+        [0.4, 0.2, 0.0, 0.5],
+    ];
+
+    fn assert_texel(texel: [f32; 4], expected: [f32; 4]) {
+        for (value, expected) in texel.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-6, "{texel:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn per_channel_reducers() {
+        assert_texel(
+            DownSampleFilter::Average.reduce(&SAMPLES),
+            [0.4, 0.3, 0.5, 0.5],
+        );
+        assert_texel(DownSampleFilter::Min.reduce(&SAMPLES), [0.0, 0.2, 0.0, 0.5]);
+        assert_texel(DownSampleFilter::Max.reduce(&SAMPLES), [0.8, 0.6, 1.0, 0.5]);
+        assert_texel(DownSampleFilter::Nearest.reduce(&SAMPLES), SAMPLES[0]);
+    }
+
+    #[test]
+    fn mode_selects_the_most_common_texel() {
+        assert_texel(DownSampleFilter::Mode.reduce(&SAMPLES), SAMPLES[1]);
+
+        let distinct = [[0.1; 4], [0.2; 4], [0.3; 4], [0.4; 4]];
+        assert_texel(DownSampleFilter::Mode.reduce(&distinct), distinct[0]);
+    }
+
+    #[test]
+    fn gamma_average_averages_colors_in_linear_space() {
+        let samples = [[0.0; 4], [1.0; 4], [0.0; 4], [1.0; 4]];
+        let texel = DownSampleFilter::GammaAverage.reduce(&samples);
+        let color = linear_to_srgb(0.5);
+
+        // the color channels are brighter than their plain average, alpha is not
+        assert_texel(texel, [color, color, color, 0.5]);
+        assert!(color > 0.5);
+    }
+
+    #[test]
+    fn srgb_conversion_round_trips() {
+        for value in [0.0, 0.01, 0.2, 0.5, 0.9, 1.0] {
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+    }
+
+    /// A custom reducer, which computes the range of each channel.
+    fn range() -> DownSampleFilter {
+        DownSampleFilter::Custom(
+            "range".into(),
+            Arc::new(|samples| {
+                let mut texel = [0.0; 4];
+
+                for (channel, value) in texel.iter_mut().enumerate() {
+                    let (min, max) = samples.iter().fold((1.0f32, 0.0f32), |(min, max), sample| {
+                        (min.min(sample[channel]), max.max(sample[channel]))
+                    });
+                    *value = max - min;
+                }
+
+                texel
+            }),
+        )
+    }
+
+    #[test]
+    fn custom_reducers_reduce_the_samples() {
+        assert_texel(range().reduce(&SAMPLES), [0.8, 0.4, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn custom_reducers_down_sample_nodes() {
+        // a child node of 4x4 texels, whose value is the sum of its coordinates times 1000
+        let child_node = DynamicImage::from(<ImageBuffer<Luma<u16>, _>>::from_fn(4, 4, |x, y| {
+            Luma([(1000 * (x + y)) as u16])
+        }));
+        let mut node = DynamicImage::from(<ImageBuffer<Luma<u16>, _>>::new(4, 4));
+
+        down_sample_overlay(
+            &mut node,
+            &child_node,
+            1,
+            0,
+            4,
+            0,
+            ImageFormat::LUMA16,
+            &range(),
+        );
+
+        // each 2x2 block spans a range of 2000, the other quarters stay empty
+        for (x, y, texel) in node.as_luma16().unwrap().enumerate_pixels() {
+            let expected = if x >= 2 && y < 2 { 2000 } else { 0 };
+            assert_eq!(texel.0[0], expected, "texel ({x}, {y})");
+        }
+    }
+}
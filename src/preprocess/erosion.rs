@@ -0,0 +1,549 @@
+use crate::{
+    data_structures::{calc_node_id, NodeCoordinate, NodeId},
+    preprocess::{
+        down_sample::DownSampleFilter,
+        existing_nodes,
+        manifest::{hash_source, ContentManifest},
+        noise::hash,
+        rebuild_ancestors, ImageFormat,
+    },
+};
+use bevy::prelude::*;
+use image::{DynamicImage, ImageBuffer, Luma};
+use itertools::iproduct;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Moves material from steep slopes downhill, until every slope is below the talus angle.
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalErosion {
+    pub iterations: u32,
+    /// The largest stable height difference between adjacent texels in world units.
+    pub talus: f32,
+    /// The fraction of the excess material moved per iteration, in the range [0, 1].
+    pub strength: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 16,
+            talus: 1.0,
+            strength: 0.5,
+        }
+    }
+}
+
+/// Simulates water droplets flowing downhill, that erode and deposit sediment.
+#[derive(Clone, Copy, Debug)]
+pub struct HydraulicErosion {
+    /// The average count of droplets spawned per texel.
+    pub droplets_per_texel: f32,
+    /// The maximum count of steps a droplet moves.
+    pub max_lifetime: u32,
+    /// How much a droplet keeps its direction instead of following the gradient, in [0, 1].
+    pub inertia: f32,
+    /// Scales the amount of sediment a droplet can carry.
+    pub capacity: f32,
+    pub min_capacity: f32,
+    /// The fraction of the surplus sediment deposited per step, in [0, 1].
+    pub deposition: f32,
+    /// The fraction of the free capacity eroded per step, in [0, 1].
+    pub erosion: f32,
+    /// The fraction of water evaporating per step, in [0, 1].
+    pub evaporation: f32,
+    pub gravity: f32,
+    pub seed: u32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            droplets_per_texel: 1.0,
+            max_lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Configures the erosion of the height attachment.
+#[derive(Clone, Copy, Debug)]
+pub struct ErosionSettings {
+    /// Applied first, if present.
+    pub hydraulic: Option<HydraulicErosion>,
+    /// Applied second, if present.
+    pub thermal: Option<ThermalErosion>,
+    /// The count of texels of the adjacent nodes simulated around each node.
+    /// Droplets travelling farther than this and thermal iterations exceeding it cause
+    /// small discontinuities at node edges. It must not exceed the texture size.
+    pub overlap: u32,
+}
+
+impl Default for ErosionSettings {
+    fn default() -> Self {
+        Self {
+            hydraulic: Some(default()),
+            thermal: Some(default()),
+            overlap: 32,
+        }
+    }
+}
+
+/// The heights of a node and its overlap with the adjacent nodes in world units.
+struct HeightField {
+    size: u32,
+    heights: Vec<f32>,
+    /// The amount of water that flowed through each texel.
+    flow: Vec<f32>,
+    /// The amount of sediment deposited at each texel in world units.
+    deposition: Vec<f32>,
+}
+
+impl HeightField {
+    #[inline]
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.size + x) as usize
+    }
+
+    /// Returns the bilinearly interpolated height and its gradient at the position.
+    fn sample(&self, position: Vec2) -> (f32, Vec2) {
+        let cell = position.floor();
+        let t = position - cell;
+        let (x, y) = (cell.x as u32, cell.y as u32);
+
+        let top_left = self.heights[self.index(x, y)];
+        let top_right = self.heights[self.index(x + 1, y)];
+        let bottom_left = self.heights[self.index(x, y + 1)];
+        let bottom_right = self.heights[self.index(x + 1, y + 1)];
+
+        let gradient = Vec2::new(
+            (top_right - top_left) * (1.0 - t.y) + (bottom_right - bottom_left) * t.y,
+            (bottom_left - top_left) * (1.0 - t.x) + (bottom_right - top_right) * t.x,
+        );
+
+        let height = top_left * (1.0 - t.x) * (1.0 - t.y)
+            + top_right * t.x * (1.0 - t.y)
+            + bottom_left * (1.0 - t.x) * t.y
+            + bottom_right * t.x * t.y;
+
+        (height, gradient)
+    }
+
+    /// Adds the amount to the four texels surrounding the position, weighted bilinearly.
+    fn add(&mut self, position: Vec2, amount: f32) {
+        let cell = position.floor();
+        let t = position - cell;
+        let (x, y) = (cell.x as u32, cell.y as u32);
+
+        for (dx, dy, weight) in [
+            (0, 0, (1.0 - t.x) * (1.0 - t.y)),
+            (1, 0, t.x * (1.0 - t.y)),
+            (0, 1, (1.0 - t.x) * t.y),
+            (1, 1, t.x * t.y),
+        ] {
+            let index = self.index(x + dx, y + dy);
+            self.heights[index] += amount * weight;
+        }
+    }
+
+    fn contains(&self, position: Vec2) -> bool {
+        position.x >= 0.0
+            && position.y >= 0.0
+            && position.x < (self.size - 1) as f32
+            && position.y < (self.size - 1) as f32
+    }
+}
+
+impl HydraulicErosion {
+    /// Simulates the droplets of all texels of the height field.
+    ///
+    /// The droplets are seeded by their global position, so that overlapping height fields
+    /// of adjacent nodes simulate the same droplets.
+    fn apply(&self, field: &mut HeightField, origin: IVec2) {
+        let droplet_count = self.droplets_per_texel.ceil() as i32;
+
+        for (x, y, droplet) in iproduct!(0..field.size, 0..field.size, 0..droplet_count) {
+            let global = origin + IVec2::new(x as i32, y as i32);
+            let seed = self.seed.wrapping_add(3 * droplet as u32);
+
+            if hash(global.x, global.y, seed) >= self.droplets_per_texel - droplet as f32 {
+                continue;
+            }
+
+            let offset = Vec2::new(
+                hash(global.x, global.y, seed.wrapping_add(1)),
+                hash(global.x, global.y, seed.wrapping_add(2)),
+            );
+
+            self.simulate_droplet(field, Vec2::new(x as f32, y as f32) + offset);
+        }
+    }
+
+    fn simulate_droplet(&self, field: &mut HeightField, mut position: Vec2) {
+        let mut direction = Vec2::ZERO;
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..self.max_lifetime {
+            if !field.contains(position) {
+                break;
+            }
+
+            let (height, gradient) = field.sample(position);
+
+            direction =
+                (direction * self.inertia - gradient * (1.0 - self.inertia)).normalize_or_zero();
+
+            if direction == Vec2::ZERO {
+                break;
+            }
+
+            let next_position = position + direction;
+
+            if !field.contains(next_position) {
+                break;
+            }
+
+            let delta_height = field.sample(next_position).0 - height;
+            let capacity = (-delta_height * speed * water * self.capacity).max(self.min_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // fill up pits, or drop the surplus sediment
+                let deposit = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * self.deposition
+                };
+
+                sediment -= deposit;
+                field.add(position, deposit);
+
+                let index = field.index(position.x as u32, position.y as u32);
+                field.deposition[index] += deposit;
+            } else {
+                // never erode deeper than the height difference, to avoid digging holes
+                let erode = ((capacity - sediment) * self.erosion).min(-delta_height);
+
+                sediment += erode;
+                field.add(position, -erode);
+            }
+
+            let index = field.index(position.x as u32, position.y as u32);
+            field.flow[index] += water;
+
+            speed = (speed * speed - delta_height * self.gravity)
+                .max(0.0)
+                .sqrt();
+            water *= 1.0 - self.evaporation;
+            position = next_position;
+        }
+    }
+}
+
+impl ThermalErosion {
+    fn apply(&self, field: &mut HeightField) {
+        let size = field.size as i32;
+
+        for _ in 0..self.iterations {
+            let mut delta = vec![0.0; field.heights.len()];
+
+            for (x, y) in iproduct!(0..size, 0..size) {
+                let index = field.index(x as u32, y as u32);
+                let height = field.heights[index];
+
+                let neighbours: Vec<(usize, f32)> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .map(|&(dx, dy)| (x + dx, y + dy))
+                    .filter(|&(x, y)| x >= 0 && y >= 0 && x < size && y < size)
+                    .map(|(x, y)| field.index(x as u32, y as u32))
+                    .map(|neighbour| (neighbour, height - field.heights[neighbour] - self.talus))
+                    .filter(|&(_, excess)| excess > 0.0)
+                    .collect();
+
+                let max_excess = neighbours
+                    .iter()
+                    .map(|&(_, excess)| excess)
+                    .fold(0.0, f32::max);
+                let total_excess: f32 = neighbours.iter().map(|&(_, excess)| excess).sum();
+
+                if total_excess <= 0.0 {
+                    continue;
+                }
+
+                // distribute the material proportionally to the excess of each neighbour
+                let moved = self.strength * max_excess / 2.0;
+                delta[index] -= moved;
+
+                for (neighbour, excess) in neighbours {
+                    delta[neighbour] += moved * excess / total_excess;
+                }
+            }
+
+            for (height, delta) in field.heights.iter_mut().zip(delta) {
+                *height += delta;
+            }
+        }
+    }
+}
+
+/// Loads the interior of the node and the overlapping texels of its adjacent nodes.
+/// Missing adjacent nodes are replaced by the clamped interior of the node.
+fn load_height_field(
+    height_directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    overlap: u32,
+    height: f32,
+) -> HeightField {
+    let NodeCoordinate { x, y, .. } = node_id.into();
+
+    let nodes: HashMap<(i32, i32), ImageBuffer<Luma<u16>, Vec<u16>>> = iproduct!(-1..=1, -1..=1)
+        .filter_map(|(dx, dy)| {
+            let (x, y) = (x as i32 + dx, y as i32 + dy);
+
+            if x < 0 || y < 0 {
+                return None;
+            }
+
+            let file_path = format!(
+                "{height_directory}/{}.png",
+                calc_node_id(0, x as u32, y as u32)
+            );
+
+            if !Path::new(&file_path).exists() {
+                return None;
+            }
+
+            let node = image::open(file_path).unwrap().into_luma16();
+            Some(((dx, dy), node))
+        })
+        .collect();
+
+    let size = texture_size + 2 * overlap;
+
+    let heights = iproduct!(0..size, 0..size)
+        .map(|(y, x)| {
+            let local = |v: u32| v as i32 - overlap as i32;
+            let (lx, ly) = (local(x), local(y));
+
+            let direction = |v: i32| v.div_euclid(texture_size as i32);
+            let (dx, dy) = (direction(lx), direction(ly));
+
+            let value = match nodes.get(&(dx, dy)) {
+                Some(node) => node.get_pixel(
+                    (lx - dx * texture_size as i32) as u32 + border_size,
+                    (ly - dy * texture_size as i32) as u32 + border_size,
+                ),
+                None => {
+                    let clamp = |v: i32| v.clamp(0, texture_size as i32 - 1) as u32 + border_size;
+                    nodes[&(0, 0)].get_pixel(clamp(lx), clamp(ly))
+                }
+            };
+
+            value.0[0] as f32 / u16::MAX as f32 * height
+        })
+        .collect();
+
+    let texel_count = (size * size) as usize;
+
+    HeightField {
+        size,
+        heights,
+        flow: vec![0.0; texel_count],
+        deposition: vec![0.0; texel_count],
+    }
+}
+
+/// Erodes the node and writes it and the optional masks to their directories.
+fn erode_node(
+    height_directory: &str,
+    eroded_directory: &str,
+    flow_directory: Option<&str>,
+    deposition_directory: Option<&str>,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    height: f32,
+    settings: &ErosionSettings,
+) {
+    let overlap = settings.overlap;
+
+    let mut field = load_height_field(
+        height_directory,
+        node_id,
+        texture_size,
+        border_size,
+        overlap,
+        height,
+    );
+
+    let NodeCoordinate { x, y, .. } = node_id.into();
+    let origin = IVec2::new(
+        (x * texture_size) as i32 - overlap as i32,
+        (y * texture_size) as i32 - overlap as i32,
+    );
+
+    if let Some(hydraulic) = settings.hydraulic {
+        hydraulic.apply(&mut field, origin);
+    }
+    if let Some(thermal) = settings.thermal {
+        thermal.apply(&mut field);
+    }
+
+    let interior = |values: &[f32], x: u32, y: u32| {
+        let x = x.clamp(border_size, border_size + texture_size - 1) - border_size + overlap;
+        let y = y.clamp(border_size, border_size + texture_size - 1) - border_size + overlap;
+        values[field.index(x, y)]
+    };
+
+    // the border is filled by stitching afterwards
+    let size = texture_size + 2 * border_size;
+    let eroded_node = <ImageBuffer<Luma<u16>, _>>::from_fn(size, size, |x, y| {
+        let value = interior(&field.heights, x, y) / height;
+        Luma([(value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16])
+    });
+
+    DynamicImage::from(eroded_node)
+        .save(format!("{eroded_directory}/{node_id}.png"))
+        .expect("Could not save file.");
+
+    // the masks are stored without a border, like the density
+    let save_mask = |directory: &str, values: &[f32]| {
+        let mask = <ImageBuffer<Luma<u16>, _>>::from_fn(texture_size, texture_size, |x, y| {
+            let value = 1.0 - (-interior(values, x + border_size, y + border_size)).exp();
+            Luma([(value * u16::MAX as f32).round() as u16])
+        });
+
+        DynamicImage::from(mask)
+            .save(format!("{directory}/{node_id}.png"))
+            .expect("Could not save file.");
+    };
+
+    if let Some(flow_directory) = flow_directory {
+        save_mask(flow_directory, &field.flow);
+    }
+    if let Some(deposition_directory) = deposition_directory {
+        save_mask(deposition_directory, &field.deposition);
+    }
+}
+
+/// Erodes the lod 0 height nodes into a new height attachment and builds all of its lods.
+///
+/// Each node is simulated together with `overlap` texels of its adjacent nodes, so only
+/// nine nodes are loaded at once. The amount of water that flowed through and the sediment
+/// deposited at each texel can optionally be stored as additional
+/// [`ImageFormat::LUMA16`] attachments without a border.
+///
+/// All existing lod 0 height nodes are eroded. Like [`preprocess_tiles`](super::preprocess_tiles),
+/// only the nodes affected by changed height nodes are eroded again on subsequent runs.
+pub fn preprocess_erosion(
+    height_directory: &str,
+    eroded_directory: &str,
+    flow_directory: Option<&str>,
+    deposition_directory: Option<&str>,
+    lod_count: u32,
+    texture_size: u32,
+    border_size: u32,
+    height: f32,
+    settings: &ErosionSettings,
+    filter: &DownSampleFilter,
+) {
+    assert!(
+        settings.overlap <= texture_size,
+        "The overlap must not exceed the texture size."
+    );
+
+    let settings_string = format!(
+        "erosion {flow_directory:?} {deposition_directory:?} {lod_count} {texture_size} {border_size} {height} {settings:?} {filter:?}"
+    );
+
+    let directories = [Some(eroded_directory), flow_directory, deposition_directory];
+
+    let previous = ContentManifest::load(eroded_directory, &settings_string).unwrap_or_else(|| {
+        for directory in directories.into_iter().flatten() {
+            let _ = fs::remove_dir_all(directory);
+            fs::create_dir_all(directory).unwrap();
+        }
+        default()
+    });
+
+    let mut manifest = ContentManifest::new(settings_string);
+
+    for node_id in existing_nodes(height_directory) {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+
+        if lod != 0 {
+            continue;
+        }
+
+        // the overlap reaches into the adjacent nodes
+        let affected_nodes = iproduct!(-1..=1, -1..=1)
+            .map(|(dx, dy)| (x as i32 + dx, y as i32 + dy))
+            .filter(|&(x, y)| x >= 0 && y >= 0)
+            .map(|(x, y)| calc_node_id(0, x as u32, y as u32))
+            .filter(|node_id| Path::new(&format!("{height_directory}/{node_id}.png")).exists())
+            .collect();
+
+        manifest.insert(
+            format!("{node_id}.png"),
+            hash_source(&format!("{height_directory}/{node_id}.png")),
+            affected_nodes,
+        );
+    }
+
+    let dirty_nodes = manifest.dirty_nodes(&previous);
+
+    for &node_id in &dirty_nodes {
+        if !Path::new(&format!("{height_directory}/{node_id}.png")).exists() {
+            for directory in directories.into_iter().flatten() {
+                let _ = fs::remove_file(format!("{directory}/{node_id}.png"));
+            }
+            continue;
+        }
+
+        erode_node(
+            height_directory,
+            eroded_directory,
+            flow_directory,
+            deposition_directory,
+            node_id,
+            texture_size,
+            border_size,
+            height,
+            settings,
+        );
+    }
+
+    rebuild_ancestors(
+        eroded_directory,
+        dirty_nodes.clone(),
+        lod_count,
+        texture_size,
+        border_size,
+        ImageFormat::LUMA16,
+        filter,
+    );
+
+    // thin flow channels should not vanish in the coarser lods
+    for directory in [flow_directory, deposition_directory].into_iter().flatten() {
+        rebuild_ancestors(
+            directory,
+            dirty_nodes.clone(),
+            lod_count,
+            texture_size,
+            0,
+            ImageFormat::LUMA16,
+            &DownSampleFilter::Max,
+        );
+    }
+
+    manifest.save(eroded_directory);
+}
@@ -0,0 +1,141 @@
+use crate::{
+    data_structures::{calc_node_id, NodeCoordinate},
+    preprocess::{div_ceil, existing_nodes, new_image, overlay_node, ImageFormat},
+};
+use itertools::iproduct;
+use std::path::Path;
+
+/// Checks whether nodes of the format can be exported into files of the type.
+fn supports_format(file_type: image::ImageFormat, format: ImageFormat) -> bool {
+    match file_type {
+        image::ImageFormat::Png => true,
+        image::ImageFormat::Tiff => !matches!(format, ImageFormat::RG8 | ImageFormat::RG16),
+        _ => false,
+    }
+}
+
+/// Reassembles the nodes of the `lod` into a single image, which is the inverse of
+/// [`preprocess_tiles`](super::preprocess_tiles).
+///
+/// The `region` is the offset and size in texels of the `lod`. If it is `None`, all existing
+/// nodes of the lod are exported. The borders of the nodes are dropped and missing nodes
+/// are left empty. The file type is deduced from the extension of the output path,
+/// either png or tiff (without georeferencing).
+/// Tiff does not support the two channel formats ([`ImageFormat::RG8`] and
+/// [`ImageFormat::RG16`]), which are rejected before anything is exported.
+pub fn export_nodes(
+    directory: &str,
+    output_file_path: &str,
+    lod: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    region: Option<((u32, u32), (u32, u32))>,
+) {
+    let file_type = image::ImageFormat::from_path(output_file_path)
+        .unwrap_or_else(|_| panic!("Unknown file type of {output_file_path}."));
+
+    assert!(
+        supports_format(file_type, format),
+        "The file type {file_type:?} can not store the format {format:?}."
+    );
+
+    let (offset, size) = region.unwrap_or_else(|| {
+        let (first, last) = existing_nodes(directory)
+            .into_iter()
+            .map(NodeCoordinate::from)
+            .filter(|coordinate| coordinate.lod == lod)
+            .fold(
+                ((u32::MAX, u32::MAX), (0, 0)),
+                |(first, last), coordinate| {
+                    (
+                        (first.0.min(coordinate.x), first.1.min(coordinate.y)),
+                        (last.0.max(coordinate.x + 1), last.1.max(coordinate.y + 1)),
+                    )
+                },
+            );
+
+        assert!(
+            first.0 < last.0,
+            "There are no nodes of lod {lod} to export."
+        );
+
+        (
+            (first.0 * texture_size, first.1 * texture_size),
+            (
+                (last.0 - first.0) * texture_size,
+                (last.1 - first.1) * texture_size,
+            ),
+        )
+    });
+
+    let mut output = new_image(size.0, size.1, format);
+
+    let first = (offset.0 / texture_size, offset.1 / texture_size);
+    let last = (
+        div_ceil(offset.0 + size.0, texture_size),
+        div_ceil(offset.1 + size.1, texture_size),
+    );
+
+    for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
+        let node_id = calc_node_id(lod, x, y);
+        let file_path = format!("{directory}/{node_id}.png");
+
+        if !Path::new(&file_path).exists() {
+            continue;
+        }
+
+        let node = image::open(file_path).unwrap().crop_imm(
+            border_size,
+            border_size,
+            texture_size,
+            texture_size,
+        );
+
+        let dx = (x * texture_size) as i64 - offset.0 as i64;
+        let dy = (y * texture_size) as i64 - offset.1 as i64;
+
+        overlay_node(&mut output, &node, dx, dy, format);
+    }
+
+    output.save(output_file_path).expect("Could not save file.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_channel_formats_can_not_be_exported_as_tiff() {
+        let formats = [
+            ImageFormat::RGB,
+            ImageFormat::RGBA,
+            ImageFormat::LUMA16,
+            ImageFormat::RG8,
+            ImageFormat::RG16,
+        ];
+
+        for format in formats {
+            assert!(supports_format(image::ImageFormat::Png, format));
+            assert!(!supports_format(image::ImageFormat::Jpeg, format));
+            assert_eq!(
+                supports_format(image::ImageFormat::Tiff, format),
+                !matches!(format, ImageFormat::RG8 | ImageFormat::RG16)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can not store the format")]
+    fn unsupported_formats_are_rejected_up_front() {
+        export_nodes(
+            "does/not/exist",
+            "export.tiff",
+            0,
+            128,
+            1,
+            ImageFormat::RG16,
+            None,
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use crate::preprocess::nodata::inpaint;
+use bevy::{math::Vec3Swizzles, prelude::*};
+use image::{DynamicImage, ImageBuffer, Luma};
+use itertools::iproduct;
+use std::fs;
+
+/// Determines how multiple heights falling into the same texel are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    Min,
+    Max,
+    Mean,
+}
+
+/// A height raster in world units, where texels without any data are `None`.
+pub struct HeightRaster {
+    pub width: u32,
+    pub height: u32,
+    pub values: Vec<Option<f32>>,
+    /// The amount of values aggregated per texel, used for the mean.
+    counts: Vec<u32>,
+}
+
+impl HeightRaster {
+    pub fn new(width: u32, height: u32) -> Self {
+        let texel_count = (width * height) as usize;
+
+        Self {
+            width,
+            height,
+            values: vec![None; texel_count],
+            counts: vec![0; texel_count],
+        }
+    }
+
+    #[inline]
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    /// Aggregates the value into the texel.
+    fn insert(&mut self, x: u32, y: u32, value: f32, aggregation: Aggregation) {
+        let index = self.index(x, y);
+        let count = &mut self.counts[index];
+        *count += 1;
+
+        self.values[index] = Some(match self.values[index] {
+            None => value,
+            Some(current) => match aggregation {
+                Aggregation::Min => current.min(value),
+                Aggregation::Max => current.max(value),
+                Aggregation::Mean => current + (value - current) / *count as f32,
+            },
+        });
+    }
+
+    /// Returns the smallest and largest height of the raster.
+    pub fn height_range(&self) -> (f32, f32) {
+        self.values
+            .iter()
+            .flatten()
+            .fold((f32::MAX, f32::MIN), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            })
+    }
+
+    /// Fills all texels without data by repeatedly averaging their known neighbours,
+    /// which interpolates the surrounding heights smoothly into the holes.
+    pub fn fill_holes(&mut self, smoothing_iterations: u32) {
+        let mut values: Vec<Option<[f32; 4]>> = self
+            .values
+            .iter()
+            .map(|value| value.map(|value| [value, 0.0, 0.0, 0.0]))
+            .collect();
+
+        inpaint(&mut values, self.width, self.height, smoothing_iterations);
+
+        self.values = values
+            .into_iter()
+            .map(|value| value.map(|value| value[0]))
+            .collect();
+    }
+
+    /// Splits the raster into source tiles, stored as `{name}_{x}_{y}.png` inside the output
+    /// directory, which can be preprocessed with [`preprocess_tiles`](super::preprocess_tiles).
+    ///
+    /// The heights are mapped from the `range` (or the detected height range of the raster if
+    /// it is `None`) to [0, 1]. Partial tiles at the edges are padded with the closest texel
+    /// and texels without data are stored as zero. Returns the range used, whose extent is the
+    /// height of the terrain.
+    pub fn save_tiles(
+        &self,
+        output_directory: &str,
+        name: &str,
+        tile_size: u32,
+        range: Option<(f32, f32)>,
+    ) -> (f32, f32) {
+        let (min, max) = range.unwrap_or_else(|| self.height_range());
+
+        fs::create_dir_all(output_directory).unwrap();
+
+        let tile_count = (
+            (self.width + tile_size - 1) / tile_size,
+            (self.height + tile_size - 1) / tile_size,
+        );
+
+        for (x, y) in iproduct!(0..tile_count.0, 0..tile_count.1) {
+            let tile = <ImageBuffer<Luma<u16>, _>>::from_fn(tile_size, tile_size, |u, v| {
+                let u = (x * tile_size + u).min(self.width - 1);
+                let v = (y * tile_size + v).min(self.height - 1);
+
+                let value = self.values[self.index(u, v)]
+                    .map_or(0.0, |value| (value - min) / (max - min).max(f32::EPSILON));
+
+                Luma([(value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16])
+            });
+
+            DynamicImage::from(tile)
+                .save(format!("{output_directory}/{name}_{x}_{y}.png"))
+                .expect("Could not save file.");
+        }
+
+        (min, max)
+    }
+}
+
+/// Maps positions in world units onto the texels of a raster.
+#[derive(Clone, Copy, Debug)]
+pub struct RasterMapping {
+    /// The world position of the corner of the first texel.
+    pub origin: Vec2,
+    /// The size of a single texel in world units.
+    pub texel_size: f32,
+}
+
+impl RasterMapping {
+    #[inline]
+    fn to_texel(self, position: Vec2) -> Vec2 {
+        (position - self.origin) / self.texel_size
+    }
+}
+
+/// Reads an ASCII point cloud with one `x y z` point per line, separated by whitespace or
+/// commas. Any additional columns and lines that are not points (e.g. headers) are ignored.
+///
+/// Following the GIS convention, x and y are horizontal and z is the height.
+pub fn read_xyz(file_path: &str) -> Vec<Vec3> {
+    let content = fs::read_to_string(file_path).expect("Could not read point cloud.");
+
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut values = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|value| !value.is_empty())
+                .map(|value| value.parse::<f32>());
+
+            match (values.next()?, values.next()?, values.next()?) {
+                (Ok(x), Ok(y), Ok(z)) => Some(Vec3::new(x, y, z)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Reads the vertices and triangles of a Wavefront OBJ mesh.
+/// Polygons are triangulated as fans.
+///
+/// Following the Bevy convention, x and z are horizontal and y is the height.
+pub fn read_obj(file_path: &str) -> (Vec<Vec3>, Vec<[usize; 3]>) {
+    let content = fs::read_to_string(file_path).expect("Could not read mesh.");
+
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+
+        match parts.next() {
+            Some("v") => {
+                let mut coordinate = || parts.next().and_then(|value| value.parse().ok());
+
+                if let (Some(x), Some(y), Some(z)) = (coordinate(), coordinate(), coordinate()) {
+                    vertices.push(Vec3::new(x, y, z));
+                }
+            }
+            Some("f") => {
+                // only the vertex index of each `v/vt/vn` triple is relevant,
+                // negative indices are relative to the end of the vertex list
+                let indices: Vec<usize> = parts
+                    .filter_map(|part| part.split('/').next()?.parse::<i64>().ok())
+                    .map(|index| {
+                        if index < 0 {
+                            (vertices.len() as i64 + index) as usize
+                        } else {
+                            (index - 1) as usize
+                        }
+                    })
+                    .collect();
+
+                for i in 1..indices.len().saturating_sub(1) {
+                    triangles.push([indices[0], indices[i], indices[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (vertices, triangles)
+}
+
+/// Rasterizes the point cloud into a height raster of `width` x `height` texels.
+/// Points outside of the raster are ignored.
+pub fn rasterize_points(
+    points: &[Vec3],
+    mapping: RasterMapping,
+    width: u32,
+    height: u32,
+    aggregation: Aggregation,
+) -> HeightRaster {
+    let mut raster = HeightRaster::new(width, height);
+
+    for point in points {
+        let texel = mapping.to_texel(point.truncate()).floor();
+
+        if texel.x >= 0.0 && texel.y >= 0.0 && texel.x < width as f32 && texel.y < height as f32 {
+            raster.insert(texel.x as u32, texel.y as u32, point.z, aggregation);
+        }
+    }
+
+    raster
+}
+
+/// Rasterizes the triangle mesh into a height raster of `width` x `height` texels.
+///
+/// Each triangle is sampled at the centers of the texels it covers, so overlapping surfaces
+/// (e.g. bridges or overhangs) are combined by the aggregation.
+pub fn rasterize_mesh(
+    vertices: &[Vec3],
+    triangles: &[[usize; 3]],
+    mapping: RasterMapping,
+    width: u32,
+    height: u32,
+    aggregation: Aggregation,
+) -> HeightRaster {
+    let mut raster = HeightRaster::new(width, height);
+
+    for triangle in triangles {
+        let [a, b, c] = triangle.map(|index| {
+            let vertex = vertices[index];
+            mapping.to_texel(vertex.xz()).extend(vertex.y)
+        });
+
+        let area = (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y);
+
+        if area.abs() <= f32::EPSILON {
+            continue;
+        }
+
+        let min = a.min(b).min(c).truncate().floor().max(Vec2::ZERO);
+        let max = a.max(b).max(c).truncate().ceil();
+        let max = max.min(Vec2::new(width as f32, height as f32));
+
+        for (x, y) in iproduct!(min.x as u32..max.x as u32, min.y as u32..max.y as u32) {
+            let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+
+            // barycentric coordinates of the texel center
+            let u = ((b.x - p.x) * (c.y - p.y) - (c.x - p.x) * (b.y - p.y)) / area;
+            let v = ((c.x - p.x) * (a.y - p.y) - (a.x - p.x) * (c.y - p.y)) / area;
+            let w = 1.0 - u - v;
+
+            if u >= 0.0 && v >= 0.0 && w >= 0.0 {
+                raster.insert(x, y, u * a.z + v * b.z + w * c.z, aggregation);
+            }
+        }
+    }
+
+    raster
+}
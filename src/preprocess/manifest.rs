@@ -0,0 +1,187 @@
+use crate::data_structures::NodeId;
+use bevy::prelude::default;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+};
+
+const CONTENT_MANIFEST_NAME: &str = "manifest.ron";
+
+/// The hash of a source file and the nodes that were produced from it.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ManifestEntry {
+    hash: u64,
+    nodes: Vec<NodeId>,
+}
+
+/// Records the content of all sources of a preprocessed attachment.
+///
+/// It is stored next to the produced nodes and is used to only rebuild the nodes of
+/// sources that have changed since the last run.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct ContentManifest {
+    /// The parameters used for preprocessing. If these change, everything has to be rebuilt.
+    settings: String,
+    /// Maps the name of each source to its hash and produced nodes.
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl ContentManifest {
+    pub(crate) fn new(settings: String) -> Self {
+        Self {
+            settings,
+            entries: default(),
+        }
+    }
+
+    /// Loads the manifest of the directory, if it exists and was created with the same settings.
+    pub(crate) fn load(directory: &str, settings: &str) -> Option<Self> {
+        let manifest = fs::read_to_string(format!("{directory}/{CONTENT_MANIFEST_NAME}")).ok()?;
+        let manifest: Self = ron::from_str(&manifest).ok()?;
+
+        (manifest.settings == settings).then_some(manifest)
+    }
+
+    pub(crate) fn save(&self, directory: &str) {
+        let manifest = ron::ser::to_string_pretty(self, default()).unwrap();
+
+        fs::write(format!("{directory}/{CONTENT_MANIFEST_NAME}"), manifest)
+            .expect("Could not save manifest.");
+    }
+
+    pub(crate) fn insert(&mut self, name: String, hash: u64, nodes: Vec<NodeId>) {
+        self.entries.insert(name, ManifestEntry { hash, nodes });
+    }
+
+    /// Returns all nodes affected by sources, that were added, changed or removed
+    /// compared to the previous manifest.
+    pub(crate) fn dirty_nodes(&self, previous: &Self) -> HashSet<NodeId> {
+        let mut dirty_nodes = HashSet::new();
+
+        for (name, entry) in &self.entries {
+            match previous.entries.get(name) {
+                Some(previous_entry) if previous_entry == entry => {}
+                Some(previous_entry) => {
+                    dirty_nodes.extend(&previous_entry.nodes);
+                    dirty_nodes.extend(&entry.nodes);
+                }
+                None => dirty_nodes.extend(&entry.nodes),
+            }
+        }
+
+        for (name, previous_entry) in &previous.entries {
+            if !self.entries.contains_key(name) {
+                dirty_nodes.extend(&previous_entry.nodes);
+            }
+        }
+
+        dirty_nodes
+    }
+}
+
+/// Hashes the content of a file with FNV-1a, which is stable across runs and platforms.
+/// The file is streamed through the hasher, so that large sources are never loaded at once.
+pub(crate) fn hash_file(file_path: &str) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut hash = 0xcbf29ce484222325;
+
+    loop {
+        let buffer = reader.fill_buf()?;
+
+        if buffer.is_empty() {
+            return Ok(hash);
+        }
+
+        hash = hash_bytes(hash, buffer);
+
+        let length = buffer.len();
+        reader.consume(length);
+    }
+}
+
+fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hashes the source file, which is about to be preprocessed.
+pub(crate) fn hash_source(file_path: &str) -> u64 {
+    hash_file(file_path)
+        .unwrap_or_else(|error| panic!("Could not read the source file {file_path}: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::calc_node_id;
+
+    fn manifest(entries: &[(&str, u64, &[NodeId])]) -> ContentManifest {
+        let mut manifest = ContentManifest::new("settings".into());
+
+        for &(name, hash, nodes) in entries {
+            manifest.insert(name.into(), hash, nodes.to_vec());
+        }
+
+        manifest
+    }
+
+    #[test]
+    fn unchanged_sources_are_clean() {
+        let nodes = [calc_node_id(0, 0, 0)];
+        let previous = manifest(&[("a", 1, &nodes)]);
+        let current = manifest(&[("a", 1, &nodes)]);
+
+        assert!(current.dirty_nodes(&previous).is_empty());
+    }
+
+    #[test]
+    fn changed_added_and_removed_sources_are_dirty() {
+        let (a, b, c, d) = (
+            calc_node_id(0, 0, 0),
+            calc_node_id(0, 1, 0),
+            calc_node_id(0, 0, 1),
+            calc_node_id(0, 1, 1),
+        );
+
+        let previous = manifest(&[
+            ("changed", 1, &[a]),
+            ("removed", 1, &[b]),
+            ("same", 1, &[d]),
+        ]);
+        let current = manifest(&[("changed", 2, &[a]), ("added", 1, &[c]), ("same", 1, &[d])]);
+
+        let dirty_nodes = current.dirty_nodes(&previous);
+
+        assert_eq!(dirty_nodes, HashSet::from([a, b, c]));
+    }
+
+    #[test]
+    fn moved_sources_dirty_old_and_new_nodes() {
+        let (a, b) = (calc_node_id(0, 0, 0), calc_node_id(0, 1, 0));
+        let previous = manifest(&[("a", 1, &[a])]);
+        let current = manifest(&[("a", 1, &[b])]);
+
+        assert_eq!(current.dirty_nodes(&previous), HashSet::from([a, b]));
+    }
+
+    #[test]
+    fn streamed_hash_matches_whole_content() {
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("hash_file_{}.bin", std::process::id()));
+        fs::write(&path, &content).unwrap();
+
+        let hash = hash_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(hash, hash_bytes(0xcbf29ce484222325, &content));
+        assert_ne!(hash, hash_bytes(0xcbf29ce484222325, &content[1..]));
+    }
+
+    #[test]
+    fn missing_files_return_an_error() {
+        assert!(hash_file("does/not/exist.png").is_err());
+    }
+}
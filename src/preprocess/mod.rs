@@ -1,23 +1,62 @@
 pub mod density;
-
-use crate::data_structures::calc_node_id;
+pub mod derive;
+pub mod down_sample;
+pub mod erosion;
+pub mod export;
+pub mod import;
+mod manifest;
+pub mod nodata;
+pub(crate) mod noise;
+pub mod normal;
+pub mod splat;
+pub mod stitch;
+mod streaming;
+pub mod synthesis;
+pub mod vector;
+
+use crate::{
+    data_structures::{calc_node_id, NodeCoordinate, NodeId},
+    preprocess::{
+        down_sample::{down_sample_overlay, DownSampleFilter},
+        manifest::{hash_source, ContentManifest},
+        nodata::{
+            inpaint_nodes, load_node_with_nodata, overlay_node_with_nodata, NoData, NoDataMarker,
+        },
+        stitch::stitch_node,
+        streaming::{split_rows, PngRows},
+    },
+};
+use bevy::prelude::default;
 use image::{
-    imageops::{self, FilterType},
-    io::Reader,
-    DynamicImage, GenericImage, GenericImageView, ImageBuffer, Luma, RgbImage, RgbaImage,
+    imageops, io::Reader, DynamicImage, GrayAlphaImage, ImageBuffer, Luma, LumaA, RgbImage,
+    RgbaImage,
 };
 use itertools::iproduct;
-use std::{fs, ops::Deref};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
 
 #[allow(missing_docs)]
 pub mod prelude {
     #[doc(hidden)]
-    pub use crate::preprocess::{density::preprocess_density, preprocess_tiles, ImageFormat};
-}
-
-#[inline]
-fn div_floor(x: u32, n: u32) -> u32 {
-    x / n
+    pub use crate::preprocess::{
+        density::{preprocess_density, DensityMetric},
+        derive::{preprocess_derived, HeightWindow},
+        down_sample::DownSampleFilter,
+        erosion::{preprocess_erosion, ErosionSettings, HydraulicErosion, ThermalErosion},
+        export::export_nodes,
+        import::{
+            rasterize_mesh, rasterize_points, read_obj, read_xyz, Aggregation, HeightRaster,
+            RasterMapping,
+        },
+        nodata::{NoData, NoDataMarker},
+        normal::{preprocess_normal, NormalEncoding},
+        preprocess_tiles,
+        splat::{preprocess_splat, SplatLayout, SplatNoise, SplatRange, SplatRule},
+        stitch::validate_borders,
+        synthesis::{generate_tiles, NoiseGraph},
+        vector::{preprocess_carve, preprocess_mask, HeightModifier, VectorFeature, VectorShape},
+        ImageFormat,
+    };
 }
 
 #[inline]
@@ -25,11 +64,15 @@ fn div_ceil(x: u32, n: u32) -> u32 {
     (x + (n - 1)) / n
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ImageFormat {
     RGB,
     RGBA,
     LUMA16,
+    /// Two 8 bit channels, stored as luma alpha images.
+    RG8,
+    /// Two 16 bit channels, stored as luma alpha images.
+    RG16,
 }
 
 fn load_node(
@@ -42,14 +85,37 @@ fn load_node(
         output
     } else {
         let size = texture_size + 2 * border_size;
-        match format {
-            ImageFormat::RGB => DynamicImage::from(RgbImage::new(size, size)),
-            ImageFormat::RGBA => DynamicImage::from(RgbaImage::new(size, size)),
-            ImageFormat::LUMA16 => DynamicImage::from(<ImageBuffer<Luma<u16>, _>>::new(size, size)),
-        }
+        new_image(size, size, format)
     }
 }
 
+/// Creates an empty image of the format.
+fn new_image(width: u32, height: u32, format: ImageFormat) -> DynamicImage {
+    match format {
+        ImageFormat::RGB => DynamicImage::from(RgbImage::new(width, height)),
+        ImageFormat::RGBA => DynamicImage::from(RgbaImage::new(width, height)),
+        ImageFormat::LUMA16 => DynamicImage::from(<ImageBuffer<Luma<u16>, _>>::new(width, height)),
+        ImageFormat::RG8 => DynamicImage::from(GrayAlphaImage::new(width, height)),
+        ImageFormat::RG16 => DynamicImage::from(<ImageBuffer<LumaA<u16>, _>>::new(width, height)),
+    }
+}
+
+/// Lists the ids of all nodes stored inside the directory.
+pub(crate) fn existing_nodes(directory: &str) -> Vec<NodeId> {
+    fs::read_dir(directory)
+        .expect("Could not find the node directory.")
+        .filter_map(|path| {
+            let path = path.unwrap().path();
+
+            if path.extension()? != "png" {
+                return None;
+            }
+
+            path.file_stem()?.to_str()?.parse::<NodeId>().ok()
+        })
+        .collect()
+}
+
 fn overlay_node(
     bottom: &mut DynamicImage,
     top: &DynamicImage,
@@ -73,131 +139,73 @@ fn overlay_node(
             x,
             y,
         ),
+        ImageFormat::RG8 => imageops::overlay(
+            bottom.as_mut_luma_alpha8().unwrap(),
+            top.as_luma_alpha8().unwrap(),
+            x,
+            y,
+        ),
+        ImageFormat::RG16 => imageops::overlay(
+            bottom.as_mut_luma_alpha16().unwrap(),
+            top.as_luma_alpha16().unwrap(),
+            x,
+            y,
+        ),
     };
 }
 
-fn down_sample_overlay(
-    node: &mut DynamicImage,
-    child_node: &DynamicImage,
-    child_x: u32,
-    child_y: u32,
+/// Returns the ids of all nodes, that are overlapped by the source tile.
+fn overlapping_nodes(
+    offset: (u32, u32),
+    lod: u32,
+    tile_size: u32,
     texture_size: u32,
     border_size: u32,
-    format: ImageFormat,
-) {
-    let child_size = texture_size >> 1;
+) -> Vec<NodeId> {
+    // first and last chunk coordinate
+    let first = (offset.0 / texture_size, offset.1 / texture_size);
+    let last = (
+        div_ceil(offset.0 + tile_size + 2 * border_size, texture_size),
+        div_ceil(offset.1 + tile_size + 2 * border_size, texture_size),
+    );
 
-    let x = child_x * child_size + border_size;
-    let y = child_y * child_size + border_size;
+    iproduct!(first.0..last.0, first.1..last.1)
+        .map(|(x, y)| calc_node_id(lod, x, y))
+        .collect()
+}
 
-    match format {
-        ImageFormat::RGB => {
-            let child_node = child_node.as_rgb8().unwrap();
-            // crop the border away
-            let child_node = child_node.view(border_size, border_size, texture_size, texture_size);
-            // down sample to half quarter the resolution
-            let child_node = imageops::resize(
-                child_node.deref(),
-                child_size,
-                child_size,
-                FilterType::Triangle,
-            );
-            node.as_mut_rgb8()
-                .unwrap()
-                .copy_from(&child_node, x, y)
-                .unwrap();
-        }
-        ImageFormat::RGBA => {
-            let child_node = child_node.as_rgba8().unwrap();
-            // crop the border away
-            let child_node = child_node.view(border_size, border_size, texture_size, texture_size);
-            // down sample to half quarter the resolution
-            let child_node = imageops::resize(
-                child_node.deref(),
-                child_size,
-                child_size,
-                FilterType::Triangle,
-            );
-            node.as_mut_rgba8()
-                .unwrap()
-                .copy_from(&child_node, x, y)
-                .unwrap();
-        }
-        ImageFormat::LUMA16 => {
-            let child_node = child_node.as_luma16().unwrap();
-            // crop the border away
-            let child_node = child_node.view(border_size, border_size, texture_size, texture_size);
-            // down sample to half quarter the resolution
-            let child_node = imageops::resize(
-                child_node.deref(),
-                child_size,
-                child_size,
-                FilterType::Triangle,
-            );
-            node.as_mut_luma16()
-                .unwrap()
-                .copy_from(&child_node, x, y)
-                .unwrap();
-        }
-    }
+/// Returns the id of the parent node.
+#[inline]
+fn parent_node(node_id: NodeId) -> NodeId {
+    let coordinate = NodeCoordinate::from(node_id);
+    calc_node_id(coordinate.lod + 1, coordinate.x >> 1, coordinate.y >> 1)
 }
 
-pub fn stitch(
-    destination: &mut DynamicImage,
-    source: &DynamicImage,
-    texture_size: u32,
-    border_size: u32,
-    format: ImageFormat,
-    direction: (i32, i32),
-) {
-    let size = texture_size + 2 * border_size;
-    let offset = texture_size + border_size;
-
-    // positions to stitch
-    let iter = match direction {
-        (-1, 0) => iproduct!(0..border_size, 0..size)
-            .map(|(b, i)| (b, i, texture_size + b, i))
-            .collect::<Vec<_>>(),
-        (1, 0) => iproduct!(0..border_size, 0..size)
-            .map(|(b, i)| (offset + b, i, border_size + b, i))
-            .collect::<Vec<_>>(),
-        (0, -1) => iproduct!(0..border_size, 0..size)
-            .map(|(b, i)| (i, b, i, texture_size + b))
-            .collect::<Vec<_>>(),
-        (0, 1) => iproduct!(0..border_size, 0..size)
-            .map(|(b, i)| (i, offset + b, i, border_size + b))
-            .collect::<Vec<_>>(),
-        _ => Vec::new(),
-    };
+/// Returns the ids of the node and all of its existing neighbours.
+fn node_with_neighbours(directory: &str, node_id: NodeId) -> Vec<NodeId> {
+    let coordinate = NodeCoordinate::from(node_id);
 
-    match format {
-        ImageFormat::RGB => {
-            let destination = destination.as_mut_rgb8().unwrap();
-            let source = source.as_rgb8().unwrap();
+    iproduct!(-1..=1, -1..=1)
+        .filter_map(|(dx, dy)| {
+            let x = coordinate.x as i32 + dx;
+            let y = coordinate.y as i32 + dy;
 
-            for (x1, y1, x2, y2) in iter {
-                destination.put_pixel(x1, y1, *source.get_pixel(x2, y2));
+            if x < 0 || y < 0 {
+                return None;
             }
-        }
-        ImageFormat::RGBA => {
-            let destination = destination.as_mut_rgba8().unwrap();
-            let source = source.as_rgba8().unwrap();
 
-            for (x1, y1, x2, y2) in iter {
-                destination.put_pixel(x1, y1, *source.get_pixel(x2, y2));
-            }
-        }
-        ImageFormat::LUMA16 => {
-            let destination = destination.as_mut_luma16().unwrap();
-            let source = source.as_luma16().unwrap();
+            let adjacent_id = calc_node_id(coordinate.lod, x as u32, y as u32);
 
-            for (x1, y1, x2, y2) in iter {
-                destination.put_pixel(x1, y1, *source.get_pixel(x2, y2));
-            }
-        }
-    }
+            (adjacent_id == node_id
+                || Path::new(&format!("{directory}/{adjacent_id}.png")).exists())
+            .then_some(adjacent_id)
+        })
+        .collect()
 }
 
+/// Splits the source tile into all overlapping nodes.
+///
+/// See [`preprocess_tiles`] for the memory requirements of the different source files.
 pub fn split_tile(
     input_file_path: &str,
     output_directory: &str,
@@ -208,72 +216,116 @@ pub fn split_tile(
     border_size: u32,
     format: ImageFormat,
 ) {
+    split_tile_filtered(
+        input_file_path,
+        output_directory,
+        offset,
+        lod,
+        tile_size,
+        texture_size,
+        border_size,
+        format,
+        None,
+        |_| true,
+    );
+}
+
+/// Splits the source tile into all overlapping nodes, that pass the filter.
+/// Source texels without data (according to the `nodata` marker) are skipped.
+///
+/// Non interlaced png files are streamed row by row with bounded memory,
+/// all other files are decoded at once.
+fn split_tile_filtered(
+    input_file_path: &str,
+    output_directory: &str,
+    offset: (u32, u32),
+    lod: u32,
+    tile_size: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    nodata: Option<NoDataMarker>,
+    filter: impl Fn(NodeId) -> bool,
+) {
+    if let Some(rows) = PngRows::open(input_file_path, format) {
+        split_rows(
+            rows,
+            output_directory,
+            offset,
+            lod,
+            tile_size,
+            texture_size,
+            border_size,
+            format,
+            nodata,
+            filter,
+        );
+        return;
+    }
+
     let mut reader = Reader::open(input_file_path).unwrap();
     reader.no_limits();
     let tile = reader.decode().unwrap();
 
-    // first and last chunk coordinate
-    let first = (offset.0 / texture_size, offset.1 / texture_size);
-    let last = (
-        div_ceil(offset.0 + tile_size + 2 * border_size, texture_size),
-        div_ceil(offset.1 + tile_size + 2 * border_size, texture_size),
-    );
-
-    for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
-        let node_id = calc_node_id(lod, x, y);
+    for node_id in overlapping_nodes(offset, lod, tile_size, texture_size, border_size)
+        .into_iter()
+        .filter(|&node_id| filter(node_id))
+    {
+        let NodeCoordinate { x, y, .. } = node_id.into();
         let file_path = format!("{output_directory}/{node_id}.png");
 
-        let mut node = load_node(&file_path, texture_size, border_size, format);
+        let mut node = load_node_with_nodata(&file_path, texture_size, border_size, format, nodata);
 
         let dx = (offset.0 + border_size) as i64 - (x * texture_size) as i64;
         let dy = (offset.1 + border_size) as i64 - (y * texture_size) as i64;
 
-        overlay_node(&mut node, &tile, dx, dy, format);
+        overlay_node_with_nodata(&mut node, &tile, dx, dy, format, nodata);
 
         node.save(&file_path).expect("Could not save file.");
     }
 }
 
-pub fn down_sample_nodes(
+/// Down samples the four children of the node into it.
+pub(crate) fn down_sample_node(
     directory: &str,
-    first: (u32, u32),
-    last: (u32, u32),
-    lod: u32,
+    node_id: NodeId,
     texture_size: u32,
     border_size: u32,
     format: ImageFormat,
+    filter: &DownSampleFilter,
 ) {
-    for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
-        let node_id = calc_node_id(lod, x, y);
-        let file_path = format!("{directory}/{node_id}.png");
+    let NodeCoordinate { lod, x, y } = node_id.into();
+    let file_path = format!("{directory}/{node_id}.png");
+    let nodata = filter.nodata_marker();
 
-        let mut node = load_node(&file_path, texture_size, border_size, format);
+    let mut node = load_node_with_nodata(&file_path, texture_size, border_size, format, nodata);
 
-        let child_origin = (x << 1, y << 1);
-        let child_lod = lod - 1;
+    let child_origin = (x << 1, y << 1);
+    let child_lod = lod - 1;
 
-        for (cx, cy) in iproduct!(0..2, 0..2) {
-            let child_id = calc_node_id(child_lod, child_origin.0 + cx, child_origin.1 + cy);
-            let child_path = format!("{directory}/{child_id}.png");
+    for (cx, cy) in iproduct!(0..2, 0..2) {
+        let child_id = calc_node_id(child_lod, child_origin.0 + cx, child_origin.1 + cy);
+        let child_path = format!("{directory}/{child_id}.png");
 
-            let child_node = load_node(&child_path, texture_size, border_size, format);
+        let child_node =
+            load_node_with_nodata(&child_path, texture_size, border_size, format, nodata);
 
-            down_sample_overlay(
-                &mut node,
-                &child_node,
-                cx,
-                cy,
-                texture_size,
-                border_size,
-                format,
-            );
-        }
-
-        node.save(file_path).expect("Could not save file.");
+        down_sample_overlay(
+            &mut node,
+            &child_node,
+            cx,
+            cy,
+            texture_size,
+            border_size,
+            format,
+            filter,
+        );
     }
+
+    node.save(file_path).expect("Could not save file.");
 }
 
-pub fn stitch_nodes(
+pub fn down_sample_nodes(
     directory: &str,
     first: (u32, u32),
     last: (u32, u32),
@@ -281,154 +333,307 @@ pub fn stitch_nodes(
     texture_size: u32,
     border_size: u32,
     format: ImageFormat,
+    filter: &DownSampleFilter,
 ) {
     for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
         let node_id = calc_node_id(lod, x, y);
-        let file_path = format!("{directory}/{node_id}.png");
+        down_sample_node(
+            directory,
+            node_id,
+            texture_size,
+            border_size,
+            format,
+            filter,
+        );
+    }
+}
 
-        let mut node = load_node(&file_path, texture_size, border_size, format);
+/// Stitches the dirty nodes and their neighbours, whose borders depend on the dirty nodes as well.
+fn stitch_dirty_nodes(
+    directory: &str,
+    dirty_nodes: &HashSet<NodeId>,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) {
+    if border_size == 0 {
+        return;
+    }
 
-        // Todo: should include corners as well
-        for direction in [(0, 1), (0, -1), (1, 0), (-1, 0)] {
-            let x = x as i32 + direction.0;
-            let y = y as i32 + direction.1;
+    let stitch_nodes: HashSet<NodeId> = dirty_nodes
+        .iter()
+        .flat_map(|&node_id| node_with_neighbours(directory, node_id))
+        .collect();
 
-            if x < 0 || y < 0 {
-                continue;
-            };
+    for node_id in stitch_nodes {
+        stitch_node(directory, node_id, texture_size, border_size, format);
+    }
+}
 
-            let adjacent_id = calc_node_id(lod, x as u32, y as u32);
-            let adjacent_path = format!("{directory}/{adjacent_id}.png");
+/// Stitches the dirty nodes of the base lod and rebuilds all of their ancestors,
+/// by down sampling and stitching them again.
+pub(crate) fn rebuild_ancestors(
+    directory: &str,
+    mut dirty_nodes: HashSet<NodeId>,
+    lod_count: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    filter: &DownSampleFilter,
+) {
+    let base_lod = match dirty_nodes.iter().next() {
+        Some(&node_id) => NodeCoordinate::from(node_id).lod,
+        None => return,
+    };
+
+    stitch_dirty_nodes(directory, &dirty_nodes, texture_size, border_size, format);
 
-            let adjacent_node = load_node(&adjacent_path, texture_size, border_size, format);
+    for _ in base_lod + 1..lod_count {
+        dirty_nodes = dirty_nodes.into_iter().map(parent_node).collect();
 
-            stitch(
-                &mut node,
-                &adjacent_node,
+        for &node_id in &dirty_nodes {
+            down_sample_node(
+                directory,
+                node_id,
                 texture_size,
                 border_size,
                 format,
-                direction,
+                filter,
             );
         }
 
-        node.save(file_path).expect("Could not save file.");
+        stitch_dirty_nodes(directory, &dirty_nodes, texture_size, border_size, format);
     }
 }
 
-pub fn preprocess_tiles(
-    input_path: &str,
-    output_directory: &str,
-    base_lod: u32,
-    lod_count: u32,
+/// A source file of an attachment, together with its position inside the terrain.
+struct SourceTile {
+    name: String,
+    path: String,
     offset: (u32, u32),
-    tile_size: u32,
-    texture_size: u32,
-    border_size: u32,
-    format: ImageFormat,
-) {
-    let _ = fs::remove_dir_all(output_directory);
-    fs::create_dir_all(output_directory).unwrap();
+}
 
-    let (offset, size) = if fs::metadata(input_path)
+/// Lists all source tiles of the input path, which is either a single file or a directory
+/// of files following the `name_x_y` naming scheme.
+fn source_tiles(input_path: &str, offset: (u32, u32), tile_size: u32) -> Vec<SourceTile> {
+    if fs::metadata(input_path)
         .expect("Could not find the input path.")
         .is_dir()
     {
-        let mut min_pos = (u32::MAX, u32::MAX);
-        let mut max_pos = (u32::MIN, u32::MIN);
-
-        for file_path in fs::read_dir(input_path)
+        fs::read_dir(input_path)
             .unwrap()
             .map(|path| path.unwrap().path())
-        {
-            let file_name = file_path
-                .with_extension("")
+            .map(|file_path| {
+                let file_name = file_path
+                    .with_extension("")
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+
+                let mut parts = file_name.split('_');
+                parts.next();
+
+                let x = parts.next().unwrap().parse::<u32>().unwrap();
+                let y = parts.next().unwrap().parse::<u32>().unwrap();
+
+                SourceTile {
+                    name: file_path.file_name().unwrap().to_str().unwrap().to_string(),
+                    path: file_path.to_str().unwrap().to_string(),
+                    offset: (x * tile_size + offset.0, y * tile_size + offset.1),
+                }
+            })
+            .collect()
+    } else {
+        vec![SourceTile {
+            name: Path::new(input_path)
                 .file_name()
                 .unwrap()
                 .to_str()
                 .unwrap()
-                .to_string();
+                .to_string(),
+            path: input_path.to_string(),
+            offset,
+        }]
+    }
+}
 
-            let mut parts = file_name.split('_');
-            parts.next();
+/// Returns the size of the source tiles of the input path, which is either a single file or
+/// a directory of equally sized, square files.
+pub(crate) fn source_tile_size(input_path: &str) -> u32 {
+    let file_path = if fs::metadata(input_path)
+        .expect("Could not find the input path.")
+        .is_dir()
+    {
+        fs::read_dir(input_path)
+            .unwrap()
+            .next()
+            .expect("The input directory is empty.")
+            .unwrap()
+            .path()
+    } else {
+        Path::new(input_path).to_path_buf()
+    };
 
-            let x = parts.next().unwrap().parse::<u32>().unwrap();
-            let y = parts.next().unwrap().parse::<u32>().unwrap();
+    let (width, height) = image::image_dimensions(file_path).expect("Could not read source file.");
+    assert_eq!(width, height, "Source tiles have to be square.");
 
-            min_pos = (min_pos.0.min(x), min_pos.1.min(y));
-            max_pos = (max_pos.0.max(x), max_pos.1.max(y));
+    width
+}
 
-            split_tile(
-                file_path.to_str().unwrap(),
-                output_directory,
-                (x * tile_size + offset.0, y * tile_size + offset.1),
+/// Splits the source tiles into nodes and builds all of their lods.
+/// The lods are down sampled with the `filter` configured for this attachment.
+///
+/// If `nodata` is present, source texels without data are skipped while splitting,
+/// optionally inpainted and ignored while down sampling, instead of being treated as zero.
+///
+/// A content manifest is stored alongside the nodes. When run again with the same settings,
+/// only the nodes of changed source tiles, their ancestors and the borders of their neighbours
+/// are rebuilt.
+///
+/// # Memory
+/// Non interlaced png sources are streamed row by row, so that only the source rows covered by
+/// a single row of nodes are kept in memory.
+/// All other sources (interlaced pngs and other file formats, e.g. TIFF) are decoded at once
+/// and thus have to fit into memory. Convert large sources into non interlaced pngs beforehand.
+/// The pixels of all sources have to match the `format`.
+///
+/// Returns the first and last (exclusive) node coordinate of lod 0 covered by the sources.
+pub fn preprocess_tiles(
+    input_path: &str,
+    output_directory: &str,
+    base_lod: u32,
+    lod_count: u32,
+    offset: (u32, u32),
+    tile_size: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    filter: &DownSampleFilter,
+    nodata: Option<NoData>,
+) -> ((u32, u32), (u32, u32)) {
+    let settings = format!(
+        "tiles {base_lod} {lod_count} {offset:?} {tile_size} {texture_size} {border_size} {format:?} {filter:?} {nodata:?}"
+    );
+
+    let marker = nodata.map(|nodata| nodata.marker);
+
+    if let Some(marker) = marker {
+        marker.validate(format);
+    }
+
+    let filter = &match marker {
+        Some(marker) => filter.clone().ignore_nodata(marker),
+        None => filter.clone(),
+    };
+
+    let previous = ContentManifest::load(output_directory, &settings).unwrap_or_else(|| {
+        let _ = fs::remove_dir_all(output_directory);
+        fs::create_dir_all(output_directory).unwrap();
+        default()
+    });
+
+    let sources: Vec<_> = source_tiles(input_path, offset, tile_size)
+        .into_iter()
+        .map(|source| {
+            let nodes = overlapping_nodes(
+                source.offset,
                 base_lod,
                 tile_size,
                 texture_size,
                 border_size,
-                format,
             );
-        }
 
-        let offset = (
-            offset.0 + min_pos.0 * tile_size,
-            offset.1 + min_pos.1 * tile_size,
-        );
-        let size = (
-            (max_pos.0 - min_pos.0 + 1) * tile_size,
-            (max_pos.1 - min_pos.1 + 1) * tile_size,
-        );
+            (source, nodes)
+        })
+        .collect();
 
-        (offset, size)
-    } else {
-        split_tile(
-            input_path,
-            output_directory,
-            offset,
-            base_lod,
-            tile_size,
-            texture_size,
-            border_size,
-            format,
+    let mut manifest = ContentManifest::new(settings);
+
+    for (source, nodes) in &sources {
+        manifest.insert(
+            source.name.clone(),
+            hash_source(&source.path),
+            nodes.clone(),
         );
+    }
 
-        (offset, (tile_size, tile_size))
-    };
+    let extents = sources
+        .iter()
+        .flat_map(|(_, nodes)| nodes)
+        .map(|&node_id| NodeCoordinate::from(node_id))
+        .fold(
+            ((u32::MAX, u32::MAX), (0, 0)),
+            |(first, last), coordinate| {
+                (
+                    (
+                        first.0.min(coordinate.x << base_lod),
+                        first.1.min(coordinate.y << base_lod),
+                    ),
+                    (
+                        last.0.max((coordinate.x + 1) << base_lod),
+                        last.1.max((coordinate.y + 1) << base_lod),
+                    ),
+                )
+            },
+        );
 
-    let mut first = (
-        div_floor(offset.0, texture_size),
-        div_floor(offset.1, texture_size),
-    );
-    let mut last = (
-        div_ceil(offset.0 + size.0 + 2 * border_size, texture_size),
-        div_ceil(offset.1 + size.1 + 2 * border_size, texture_size),
-    );
+    let dirty_nodes = manifest.dirty_nodes(&previous);
 
-    dbg!(&first);
-    dbg!(&last);
+    if dirty_nodes.is_empty() {
+        return extents;
+    }
 
-    for lod in 1..lod_count {
-        first = (div_floor(first.0, 2), div_floor(first.1, 2));
-        last = (div_ceil(last.0, 2), div_ceil(last.1, 2));
+    for node_id in &dirty_nodes {
+        let _ = fs::remove_file(format!("{output_directory}/{node_id}.png"));
+    }
 
-        down_sample_nodes(
-            output_directory,
-            first,
-            last,
-            lod,
-            texture_size,
-            border_size,
-            format,
-        );
+    // rebuild the dirty nodes from all sources overlapping them
+    for (source, nodes) in sources {
+        if nodes.iter().any(|node_id| dirty_nodes.contains(node_id)) {
+            split_tile_filtered(
+                &source.path,
+                output_directory,
+                source.offset,
+                base_lod,
+                tile_size,
+                texture_size,
+                border_size,
+                format,
+                marker,
+                |node_id| dirty_nodes.contains(&node_id),
+            );
+        }
+    }
 
-        stitch_nodes(
+    if let Some(NoData {
+        marker,
+        inpaint: Some(smoothing_iterations),
+    }) = nodata
+    {
+        inpaint_nodes(
             output_directory,
-            first,
-            last,
-            lod,
+            &dirty_nodes,
             texture_size,
             border_size,
             format,
+            marker,
+            smoothing_iterations,
         );
     }
+
+    rebuild_ancestors(
+        output_directory,
+        dirty_nodes,
+        lod_count,
+        texture_size,
+        border_size,
+        format,
+        filter,
+    );
+
+    manifest.save(output_directory);
+
+    extents
 }
@@ -0,0 +1,618 @@
+use crate::{
+    data_structures::{calc_node_id, NodeCoordinate, NodeId},
+    preprocess::{load_node, new_image, overlay_node, ImageFormat},
+};
+use image::{DynamicImage, ImageBuffer, Pixel};
+use itertools::iproduct;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
+
+type NodeBuffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// Marks the texels of a source tile, that contain no data.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NoDataMarker {
+    /// Texels whose channels all equal this normalized value (e.g. 0.0) contain no data.
+    Value(f32),
+    /// Texels with an alpha of zero contain no data.
+    /// Only valid for formats with an alpha channel ([`ImageFormat::RGBA`], [`ImageFormat::RG8`]
+    /// and [`ImageFormat::RG16`], whose second channel is stored as alpha).
+    /// Other formats are rejected.
+    Alpha,
+}
+
+impl NoDataMarker {
+    /// Checks whether texels of the format can be marked by this marker.
+    pub fn supports(self, format: ImageFormat) -> bool {
+        match self {
+            Self::Value(_) => true,
+            Self::Alpha => !matches!(format, ImageFormat::RGB | ImageFormat::LUMA16),
+        }
+    }
+
+    /// Asserts that texels of the format can be marked by this marker.
+    pub(crate) fn validate(self, format: ImageFormat) {
+        assert!(
+            self.supports(format),
+            "The nodata marker {self:?} requires an alpha channel, which {format:?} lacks."
+        );
+    }
+
+    /// Checks whether the normalized texel contains no data.
+    pub(crate) fn matches(self, texel: &[f32; 4], channel_count: usize, max_value: f32) -> bool {
+        match self {
+            Self::Value(value) => texel[..channel_count]
+                .iter()
+                .all(|&channel| ((channel - value) * max_value).abs() < 0.5),
+            Self::Alpha => texel[channel_count - 1] == 0.0,
+        }
+    }
+
+    /// Returns the normalized texel used to mark texels without data.
+    pub(crate) fn texel(self) -> [f32; 4] {
+        match self {
+            Self::Value(value) => [value; 4],
+            Self::Alpha => [0.0; 4],
+        }
+    }
+}
+
+/// Configures how texels without data are treated while preprocessing.
+///
+/// Texels without data never overwrite the data of other source tiles and nodes are
+/// initialized without data instead of zero. The down sampling ignores texels without data.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoData {
+    pub marker: NoDataMarker,
+    /// If present, the voids of the lod 0 nodes are filled by diffusion before the lods are
+    /// built, followed by this count of smoothing iterations.
+    /// The data of the neighbouring nodes is taken into account, to avoid seams between them.
+    /// Nodes without any data are left untouched.
+    pub inpaint: Option<u32>,
+}
+
+#[inline]
+fn normalize<P: Pixel>(pixel: &P, max_value: f32) -> [f32; 4]
+where
+    P::Subpixel: Into<f32>,
+{
+    let mut texel = [0.0; 4];
+
+    for (value, &channel) in texel.iter_mut().zip(pixel.channels()) {
+        *value = channel.into() / max_value;
+    }
+
+    texel
+}
+
+#[inline]
+fn denormalize<P: Pixel>(
+    texel: &[f32; 4],
+    max_value: f32,
+    from_f32: impl Fn(f32) -> P::Subpixel,
+) -> P {
+    let channels: Vec<P::Subpixel> = texel[..P::CHANNEL_COUNT as usize]
+        .iter()
+        .map(|&value| from_f32((value.clamp(0.0, 1.0) * max_value).round()))
+        .collect();
+
+    *P::from_slice(&channels)
+}
+
+/// Fills all values that are `None` by repeatedly averaging their known neighbours,
+/// followed by `smoothing_iterations` relaxation steps over the filled values.
+/// If no value is known, nothing is filled.
+pub(crate) fn inpaint(
+    values: &mut [Option<[f32; 4]>],
+    width: u32,
+    height: u32,
+    smoothing_iterations: u32,
+) {
+    let holes: Vec<usize> = (0..values.len())
+        .filter(|&index| values[index].is_none())
+        .collect();
+
+    if holes.len() == values.len() {
+        return;
+    }
+
+    let neighbour_average = |values: &[Option<[f32; 4]>], index: usize| {
+        let (x, y) = ((index as u32 % width) as i32, (index as u32 / width) as i32);
+
+        let (sum, count) = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .map(|&(dx, dy)| (x + dx, y + dy))
+            .filter(|&(x, y)| x >= 0 && y >= 0 && x < width as i32 && y < height as i32)
+            .filter_map(|(x, y)| values[(y as u32 * width + x as u32) as usize])
+            .fold(([0.0; 4], 0), |(mut sum, count), value| {
+                for (sum, value) in sum.iter_mut().zip(value) {
+                    *sum += value;
+                }
+                (sum, count + 1)
+            });
+
+        (count > 0).then_some(sum.map(|sum| sum / count as f32))
+    };
+
+    // grow the known region into the holes
+    while values.iter().any(Option::is_none) {
+        let previous = values.to_vec();
+
+        for &index in &holes {
+            if previous[index].is_none() {
+                values[index] = neighbour_average(&previous, index);
+            }
+        }
+    }
+
+    // relax the filled values towards a smooth surface
+    for _ in 0..smoothing_iterations {
+        let previous = values.to_vec();
+
+        for &index in &holes {
+            values[index] = neighbour_average(&previous, index);
+        }
+    }
+}
+
+fn fill_buffer<P: Pixel>(
+    node: &mut NodeBuffer<P>,
+    marker: NoDataMarker,
+    max_value: f32,
+    from_f32: impl Fn(f32) -> P::Subpixel,
+) {
+    let texel = denormalize(&marker.texel(), max_value, from_f32);
+
+    for pixel in node.pixels_mut() {
+        *pixel = texel;
+    }
+}
+
+fn overlay_buffer<P: Pixel>(
+    bottom: &mut NodeBuffer<P>,
+    top: &NodeBuffer<P>,
+    x: i64,
+    y: i64,
+    marker: NoDataMarker,
+    max_value: f32,
+) where
+    P::Subpixel: Into<f32>,
+{
+    let channel_count = P::CHANNEL_COUNT as usize;
+
+    for (tx, ty) in iproduct!(0..top.width(), 0..top.height()) {
+        let (bx, by) = (x + tx as i64, y + ty as i64);
+
+        if bx < 0 || by < 0 || bx >= bottom.width() as i64 || by >= bottom.height() as i64 {
+            continue;
+        }
+
+        let pixel = top.get_pixel(tx, ty);
+
+        if !marker.matches(&normalize(pixel, max_value), channel_count, max_value) {
+            bottom.put_pixel(bx as u32, by as u32, *pixel);
+        }
+    }
+}
+
+/// Inpaints the node, while taking the data of its neighbours into account.
+///
+/// The node (including its border) and the interior of its `neighbours` (with their offset
+/// in nodes) are stitched into a window of three by three nodes, which is inpainted as a whole.
+/// Thus voids crossing the edge of the node are filled consistently on both sides.
+fn inpaint_buffer<P: Pixel>(
+    node: &mut NodeBuffer<P>,
+    neighbours: &[((u32, u32), &NodeBuffer<P>)],
+    texture_size: u32,
+    border_size: u32,
+    marker: NoDataMarker,
+    smoothing_iterations: u32,
+    max_value: f32,
+    from_f32: impl Fn(f32) -> P::Subpixel,
+) where
+    P::Subpixel: Into<f32>,
+{
+    let channel_count = P::CHANNEL_COUNT as usize;
+    let size = 3 * texture_size;
+    let node_offset = texture_size - border_size;
+
+    let mut values: Vec<Option<[f32; 4]>> = vec![None; (size * size) as usize];
+
+    let mut insert = |buffer: &NodeBuffer<P>, source: u32, target: (u32, u32), extent: u32| {
+        for (x, y) in iproduct!(0..extent, 0..extent) {
+            let texel = normalize(buffer.get_pixel(source + x, source + y), max_value);
+
+            if !marker.matches(&texel, channel_count, max_value) {
+                values[((target.1 + y) * size + target.0 + x) as usize] = Some(texel);
+            }
+        }
+    };
+
+    for &((x, y), neighbour) in neighbours {
+        insert(
+            neighbour,
+            border_size,
+            (x * texture_size, y * texture_size),
+            texture_size,
+        );
+    }
+
+    insert(
+        node,
+        0,
+        (node_offset, node_offset),
+        texture_size + 2 * border_size,
+    );
+
+    inpaint(&mut values, size, size, smoothing_iterations);
+
+    for (x, y, pixel) in node.enumerate_pixels_mut() {
+        let index = ((node_offset + y) * size + node_offset + x) as usize;
+
+        if let Some(texel) = values[index] {
+            *pixel = denormalize(&texel, max_value, &from_f32);
+        }
+    }
+}
+
+/// Loads the node, or creates a new one without any data, if it does not exist yet.
+pub(crate) fn load_node_with_nodata(
+    file_path: &str,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    marker: Option<NoDataMarker>,
+) -> DynamicImage {
+    let marker = match marker {
+        Some(marker) if !Path::new(file_path).exists() => marker,
+        _ => return load_node(file_path, texture_size, border_size, format),
+    };
+
+    let size = texture_size + 2 * border_size;
+    let mut node = new_image(size, size, format);
+
+    match format {
+        ImageFormat::RGB => fill_buffer(
+            node.as_mut_rgb8().unwrap(),
+            marker,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RGBA => fill_buffer(
+            node.as_mut_rgba8().unwrap(),
+            marker,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::LUMA16 => fill_buffer(
+            node.as_mut_luma16().unwrap(),
+            marker,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+        ImageFormat::RG8 => fill_buffer(
+            node.as_mut_luma_alpha8().unwrap(),
+            marker,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RG16 => fill_buffer(
+            node.as_mut_luma_alpha16().unwrap(),
+            marker,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+    }
+
+    node
+}
+
+/// Overlays the top image onto the bottom one, skipping all texels of the top image without data.
+pub(crate) fn overlay_node_with_nodata(
+    bottom: &mut DynamicImage,
+    top: &DynamicImage,
+    x: i64,
+    y: i64,
+    format: ImageFormat,
+    marker: Option<NoDataMarker>,
+) {
+    let marker = match marker {
+        Some(marker) => marker,
+        None => return overlay_node(bottom, top, x, y, format),
+    };
+
+    match format {
+        ImageFormat::RGB => overlay_buffer(
+            bottom.as_mut_rgb8().unwrap(),
+            top.as_rgb8().unwrap(),
+            x,
+            y,
+            marker,
+            u8::MAX as f32,
+        ),
+        ImageFormat::RGBA => overlay_buffer(
+            bottom.as_mut_rgba8().unwrap(),
+            top.as_rgba8().unwrap(),
+            x,
+            y,
+            marker,
+            u8::MAX as f32,
+        ),
+        ImageFormat::LUMA16 => overlay_buffer(
+            bottom.as_mut_luma16().unwrap(),
+            top.as_luma16().unwrap(),
+            x,
+            y,
+            marker,
+            u16::MAX as f32,
+        ),
+        ImageFormat::RG8 => overlay_buffer(
+            bottom.as_mut_luma_alpha8().unwrap(),
+            top.as_luma_alpha8().unwrap(),
+            x,
+            y,
+            marker,
+            u8::MAX as f32,
+        ),
+        ImageFormat::RG16 => overlay_buffer(
+            bottom.as_mut_luma_alpha16().unwrap(),
+            top.as_luma_alpha16().unwrap(),
+            x,
+            y,
+            marker,
+            u16::MAX as f32,
+        ),
+    }
+}
+
+fn neighbour_buffers<P: Pixel>(
+    neighbours: &[((u32, u32), DynamicImage)],
+    as_buffer: fn(&DynamicImage) -> Option<&NodeBuffer<P>>,
+) -> Vec<((u32, u32), &NodeBuffer<P>)> {
+    neighbours
+        .iter()
+        .map(|(offset, neighbour)| (*offset, as_buffer(neighbour).unwrap()))
+        .collect()
+}
+
+fn inpaint_path(directory: &str, node_id: NodeId) -> String {
+    format!("{directory}/{node_id}_inpainted.png")
+}
+
+/// Fills the voids of the node by diffusion from the surrounding texels, including the data of
+/// its neighbours, and stores the result next to the node.
+fn inpaint_node(
+    directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    marker: NoDataMarker,
+    smoothing_iterations: u32,
+) {
+    let file_path = format!("{directory}/{node_id}.png");
+
+    if !Path::new(&file_path).exists() {
+        return;
+    }
+
+    let mut node = load_node(&file_path, texture_size, border_size, format);
+    let NodeCoordinate { lod, x, y } = node_id.into();
+
+    let neighbours: Vec<_> = iproduct!(0..3, 0..3)
+        .filter(|&offset| offset != (1, 1))
+        .filter_map(|(dx, dy)| {
+            let (x, y) = ((x + dx).checked_sub(1)?, (y + dy).checked_sub(1)?);
+            let neighbour_path = format!("{directory}/{}.png", calc_node_id(lod, x, y));
+
+            Path::new(&neighbour_path).exists().then(|| {
+                let neighbour = load_node(&neighbour_path, texture_size, border_size, format);
+                ((dx, dy), neighbour)
+            })
+        })
+        .collect();
+
+    match format {
+        ImageFormat::RGB => inpaint_buffer(
+            node.as_mut_rgb8().unwrap(),
+            &neighbour_buffers(&neighbours, DynamicImage::as_rgb8),
+            texture_size,
+            border_size,
+            marker,
+            smoothing_iterations,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RGBA => inpaint_buffer(
+            node.as_mut_rgba8().unwrap(),
+            &neighbour_buffers(&neighbours, DynamicImage::as_rgba8),
+            texture_size,
+            border_size,
+            marker,
+            smoothing_iterations,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::LUMA16 => inpaint_buffer(
+            node.as_mut_luma16().unwrap(),
+            &neighbour_buffers(&neighbours, DynamicImage::as_luma16),
+            texture_size,
+            border_size,
+            marker,
+            smoothing_iterations,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+        ImageFormat::RG8 => inpaint_buffer(
+            node.as_mut_luma_alpha8().unwrap(),
+            &neighbour_buffers(&neighbours, DynamicImage::as_luma_alpha8),
+            texture_size,
+            border_size,
+            marker,
+            smoothing_iterations,
+            u8::MAX as f32,
+            |value| value as u8,
+        ),
+        ImageFormat::RG16 => inpaint_buffer(
+            node.as_mut_luma_alpha16().unwrap(),
+            &neighbour_buffers(&neighbours, DynamicImage::as_luma_alpha16),
+            texture_size,
+            border_size,
+            marker,
+            smoothing_iterations,
+            u16::MAX as f32,
+            |value| value as u16,
+        ),
+    }
+
+    node.save(inpaint_path(directory, node_id))
+        .expect("Could not save file.");
+}
+
+/// Fills the voids of the nodes by diffusion from the surrounding texels.
+///
+/// Each node is inpainted together with the data of its neighbours, to avoid seams between them.
+/// All nodes are inpainted from the data before inpainting, so the result does not depend on
+/// the order of the nodes. Voids larger than a node may still show slight discontinuities,
+/// because the data further away is not taken into account.
+pub(crate) fn inpaint_nodes(
+    directory: &str,
+    node_ids: &HashSet<NodeId>,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    marker: NoDataMarker,
+    smoothing_iterations: u32,
+) {
+    for &node_id in node_ids {
+        inpaint_node(
+            directory,
+            node_id,
+            texture_size,
+            border_size,
+            format,
+            marker,
+            smoothing_iterations,
+        );
+    }
+
+    for &node_id in node_ids {
+        let inpainted_path = inpaint_path(directory, node_id);
+
+        if Path::new(&inpainted_path).exists() {
+            fs::rename(inpainted_path, format!("{directory}/{node_id}.png"))
+                .expect("Could not rename file.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    type HeightBuffer = NodeBuffer<Luma<u16>>;
+
+    const TEXTURE_SIZE: u32 = 4;
+    const BORDER_SIZE: u32 = 1;
+
+    /// A terrain of two by one nodes, with a void crossing the edge between them.
+    fn terrain_value(x: i32, y: i32) -> u16 {
+        if x < 0 || y < 0 || x >= 8 || y >= 4 || (3..5).contains(&x) {
+            0
+        } else {
+            1000 + 100 * x as u16 + 10 * y as u16
+        }
+    }
+
+    fn terrain_node(node_x: u32) -> HeightBuffer {
+        let size = TEXTURE_SIZE + 2 * BORDER_SIZE;
+        let origin = (node_x * TEXTURE_SIZE) as i32 - BORDER_SIZE as i32;
+
+        HeightBuffer::from_fn(size, size, |x, y| {
+            Luma([terrain_value(
+                origin + x as i32,
+                y as i32 - BORDER_SIZE as i32,
+            )])
+        })
+    }
+
+    fn inpaint_terrain_node(node: &mut HeightBuffer, neighbours: &[((u32, u32), &HeightBuffer)]) {
+        inpaint_buffer(
+            node,
+            neighbours,
+            TEXTURE_SIZE,
+            BORDER_SIZE,
+            NoDataMarker::Value(0.0),
+            0,
+            u16::MAX as f32,
+            |value| value as u16,
+        );
+    }
+
+    #[test]
+    fn inpaint_fills_all_holes() {
+        let mut values = vec![Some([1.0; 4]), None, None, Some([0.0; 4])];
+        inpaint(&mut values, 4, 1, 2);
+
+        assert!(values.iter().all(Option::is_some));
+        assert_eq!(values[0], Some([1.0; 4]));
+        assert_eq!(values[3], Some([0.0; 4]));
+
+        let filled = values[1].unwrap()[0];
+        assert!(0.0 < filled && filled < 1.0);
+    }
+
+    #[test]
+    fn inpaint_leaves_empty_values_untouched() {
+        let mut values = vec![None; 9];
+        inpaint(&mut values, 3, 3, 2);
+
+        assert!(values.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn neighbours_fill_empty_nodes() {
+        let neighbour = terrain_node(0);
+        let mut node = HeightBuffer::new(6, 6);
+
+        inpaint_terrain_node(&mut node, &[]);
+        assert!(node.pixels().all(|pixel| pixel.0[0] == 0));
+
+        inpaint_terrain_node(&mut node, &[((0, 1), &neighbour)]);
+        assert!(node.pixels().all(|pixel| pixel.0[0] != 0));
+    }
+
+    #[test]
+    fn voids_across_nodes_are_seamless() {
+        let (mut left, mut right) = (terrain_node(0), terrain_node(1));
+        let (left_source, right_source) = (left.clone(), right.clone());
+
+        inpaint_terrain_node(&mut left, &[((2, 1), &right_source)]);
+        inpaint_terrain_node(&mut right, &[((0, 1), &left_source)]);
+
+        assert!(left.pixels().all(|pixel| pixel.0[0] != 0));
+        assert!(right.pixels().all(|pixel| pixel.0[0] != 0));
+
+        for y in 0..TEXTURE_SIZE + 2 * BORDER_SIZE {
+            // the border of each node matches the interior of the other one
+            assert_eq!(left.get_pixel(5, y), right.get_pixel(1, y));
+            assert_eq!(left.get_pixel(4, y), right.get_pixel(0, y));
+        }
+    }
+
+    #[test]
+    fn alpha_markers_require_an_alpha_channel() {
+        assert!(NoDataMarker::Alpha.supports(ImageFormat::RGBA));
+        assert!(NoDataMarker::Alpha.supports(ImageFormat::RG16));
+        assert!(!NoDataMarker::Alpha.supports(ImageFormat::RGB));
+        assert!(!NoDataMarker::Alpha.supports(ImageFormat::LUMA16));
+        assert!(NoDataMarker::Value(0.0).supports(ImageFormat::LUMA16));
+    }
+
+    #[test]
+    #[should_panic]
+    fn alpha_markers_are_rejected_for_luma() {
+        NoDataMarker::Alpha.validate(ImageFormat::LUMA16);
+    }
+}
@@ -0,0 +1,46 @@
+use bevy::prelude::*;
+
+/// Hashes the integer position into a pseudo random value in the range [0, 1].
+#[inline]
+pub(crate) fn hash(x: i32, y: i32, seed: u32) -> f32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ seed.wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0x5bd1_e995);
+    hash ^= hash >> 15;
+
+    hash as f32 / u32::MAX as f32
+}
+
+/// Smoothly interpolated value noise in the range [0, 1].
+pub(crate) fn value_noise(position: Vec2, seed: u32) -> f32 {
+    let cell = position.floor();
+    let t = position - cell;
+    let t = t * t * (3.0 - 2.0 * t);
+    let (x, y) = (cell.x as i32, cell.y as i32);
+
+    let top = hash(x, y, seed) + (hash(x + 1, y, seed) - hash(x, y, seed)) * t.x;
+    let bottom = hash(x, y + 1, seed) + (hash(x + 1, y + 1, seed) - hash(x, y + 1, seed)) * t.x;
+
+    top + (bottom - top) * t.y
+}
+
+/// Gradient (Perlin) noise in the range [-1, 1].
+pub(crate) fn gradient_noise(position: Vec2, seed: u32) -> f32 {
+    let cell = position.floor();
+    let t = position - cell;
+    let (x, y) = (cell.x as i32, cell.y as i32);
+
+    let corner = |dx: i32, dy: i32| {
+        let angle = hash(x + dx, y + dy, seed) * std::f32::consts::TAU;
+        Vec2::new(angle.cos(), angle.sin()).dot(t - Vec2::new(dx as f32, dy as f32))
+    };
+
+    let f = t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+
+    let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * f.x;
+    let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * f.x;
+
+    ((top + (bottom - top) * f.y) * std::f32::consts::SQRT_2).clamp(-1.0, 1.0)
+}
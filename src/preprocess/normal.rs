@@ -0,0 +1,143 @@
+use crate::preprocess::{derive::preprocess_derived, down_sample::DownSampleFilter, ImageFormat};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The encoding of the two channels of a normal attachment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NormalEncoding {
+    /// Stores the x and z components of the normal.
+    /// The y component is reconstructed, which is only valid for upward facing normals.
+    XZ,
+    /// Stores the normal projected onto an octahedron, which is mapped onto the unit square.
+    Octahedral,
+}
+
+impl NormalEncoding {
+    /// Encodes the normal into two values in the range [0, 1].
+    pub fn encode(self, normal: Vec3) -> Vec2 {
+        let encoded = match self {
+            NormalEncoding::XZ => normal.xz(),
+            NormalEncoding::Octahedral => {
+                let normal = normal / (normal.x.abs() + normal.y.abs() + normal.z.abs());
+
+                if normal.y >= 0.0 {
+                    normal.xz()
+                } else {
+                    (1.0 - normal.zx().abs()) * normal.xz().signum()
+                }
+            }
+        };
+
+        encoded * 0.5 + 0.5
+    }
+
+    /// Decodes the normal from two values in the range [0, 1].
+    pub fn decode(self, encoded: Vec2) -> Vec3 {
+        let encoded = encoded * 2.0 - 1.0;
+
+        match self {
+            NormalEncoding::XZ => Vec3::new(
+                encoded.x,
+                (1.0 - encoded.length_squared()).max(0.0).sqrt(),
+                encoded.y,
+            ),
+            NormalEncoding::Octahedral => {
+                let mut normal = Vec3::new(
+                    encoded.x,
+                    1.0 - encoded.x.abs() - encoded.y.abs(),
+                    encoded.y,
+                );
+
+                let t = (-normal.y).max(0.0);
+                normal.x += if normal.x >= 0.0 { -t } else { t };
+                normal.z += if normal.z >= 0.0 { -t } else { t };
+
+                normal.normalize()
+            }
+        }
+    }
+
+    /// Returns the filter used to down sample normals of this encoding.
+    /// The four child normals are decoded, averaged, renormalized and encoded again.
+    pub fn down_sample_filter(self) -> DownSampleFilter {
+        DownSampleFilter::Custom(
+            format!("normal {self:?}"),
+            Arc::new(move |samples| {
+                let normal = samples
+                    .iter()
+                    .map(|sample| self.decode(Vec2::new(sample[0], sample[1])))
+                    .sum::<Vec3>()
+                    .normalize_or_zero();
+
+                let encoded = self.encode(normal);
+
+                [encoded.x, encoded.y, 0.0, 0.0]
+            }),
+        )
+    }
+}
+
+/// Calculates the normal of the height node at the texel position using central differences.
+pub(crate) fn height_normal(
+    height_node: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    x: u32,
+    y: u32,
+    height: f32,
+) -> Vec3 {
+    let sample = |x, y| height_node.get_pixel(x, y).0[0] as f32 / u16::MAX as f32;
+
+    let left = sample(x - 1, y);
+    let up = sample(x, y - 1);
+    let right = sample(x + 1, y);
+    let down = sample(x, y + 1);
+
+    Vec3::new(left - right, 2.0 / height, up - down).normalize()
+}
+
+/// Bakes the normal attachment from the height attachment and builds all of its lods.
+///
+/// The normals are stored in a two channel `format` ([`ImageFormat::RG8`] or
+/// [`ImageFormat::RG16`]) using the `encoding`, which has to match the one configured in the
+/// [`TerrainPipelineConfig`](crate::render::TerrainPipelineConfig).
+/// The height attachment requires a border of at least one texel.
+/// The normals are baked for all existing lod 0 height nodes, see
+/// [`preprocess_derived`](super::derive::preprocess_derived).
+pub fn preprocess_normal(
+    height_directory: &str,
+    normal_directory: &str,
+    lod_count: u32,
+    texture_size: u32,
+    height_border_size: u32,
+    border_size: u32,
+    height: f32,
+    format: ImageFormat,
+    encoding: NormalEncoding,
+) {
+    assert!(
+        height_border_size > 0,
+        "The height attachment requires a border to bake normals."
+    );
+    assert!(
+        matches!(format, ImageFormat::RG8 | ImageFormat::RG16),
+        "Normals can only be stored in two channel formats."
+    );
+
+    preprocess_derived(
+        height_directory,
+        normal_directory,
+        lod_count,
+        texture_size,
+        height_border_size,
+        border_size,
+        height,
+        format,
+        &encoding.down_sample_filter(),
+        &format!("normal {encoding:?}"),
+        |window| {
+            let encoded = encoding.encode(window.normal());
+            [encoded.x, encoded.y, 0.0, 0.0]
+        },
+    );
+}
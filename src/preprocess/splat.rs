@@ -0,0 +1,228 @@
+use crate::preprocess::{
+    density::DensityMetric,
+    derive::{preprocess_derived, HeightWindow},
+    down_sample::DownSampleFilter,
+    noise::value_noise,
+    ImageFormat,
+};
+use bevy::prelude::*;
+
+/// A range of values with smooth transitions at both ends.
+///
+/// Values inside `min..max` have full influence, which falls off to zero over `falloff`
+/// outside of the range.
+#[derive(Clone, Copy, Debug)]
+pub struct SplatRange {
+    pub min: f32,
+    pub max: f32,
+    pub falloff: f32,
+}
+
+impl SplatRange {
+    pub fn new(min: f32, max: f32, falloff: f32) -> Self {
+        Self { min, max, falloff }
+    }
+
+    fn factor(&self, value: f32) -> f32 {
+        let smoothstep = |edge0: f32, edge1: f32, x: f32| {
+            if edge0 == edge1 {
+                return if x >= edge1 { 1.0 } else { 0.0 };
+            }
+
+            let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        };
+
+        smoothstep(self.min - self.falloff, self.min, value)
+            * (1.0 - smoothstep(self.max, self.max + self.falloff, value))
+    }
+}
+
+/// Modulates the weight of a rule with value noise, to break up the transitions between
+/// materials.
+#[derive(Clone, Copy, Debug)]
+pub struct SplatNoise {
+    /// The size of the noise features in texels of lod 0.
+    pub scale: f32,
+    /// The strength of the modulation, where zero disables the noise.
+    pub amplitude: f32,
+    pub seed: u32,
+}
+
+/// Assigns a material to all texels matching its conditions.
+///
+/// Conditions that are `None` are always met.
+#[derive(Clone, Copy, Debug)]
+pub struct SplatRule {
+    /// The index of the material.
+    pub material: u8,
+    /// The weight of the material, if all conditions are fully met.
+    pub weight: f32,
+    /// The height range in world units.
+    pub height: Option<SplatRange>,
+    /// The slope range, where zero is flat and one is vertical.
+    /// See [`DensityMetric::Slope`].
+    pub slope: Option<SplatRange>,
+    /// The range of the discrete Laplacian of the height in world units.
+    /// Positive values are concave (e.g. valleys), negative ones convex (e.g. ridges).
+    pub curvature: Option<SplatRange>,
+    pub noise: Option<SplatNoise>,
+}
+
+impl SplatRule {
+    /// Creates a rule that covers the entire terrain with the material.
+    pub fn new(material: u8) -> Self {
+        Self {
+            material,
+            weight: 1.0,
+            height: None,
+            slope: None,
+            curvature: None,
+            noise: None,
+        }
+    }
+
+    fn weight(&self, window: &HeightWindow, position: Vec2) -> f32 {
+        let mut weight = self.weight;
+
+        if let Some(range) = self.height {
+            weight *= range.factor(window.get(0, 0));
+        }
+        if let Some(range) = self.slope {
+            weight *= range.factor(DensityMetric::Slope.density(window));
+        }
+        if let Some(range) = self.curvature {
+            weight *= range.factor(window.laplacian());
+        }
+        if let Some(noise) = self.noise {
+            let value = value_noise(position / noise.scale, noise.seed);
+            weight *= (1.0 + noise.amplitude * (2.0 * value - 1.0)).max(0.0);
+        }
+
+        weight
+    }
+}
+
+/// The layout the material weights are stored in.
+/// Both layouts are stored as [`ImageFormat::RGBA`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplatLayout {
+    /// Stores the normalized weights of up to four materials in the four channels.
+    Weights,
+    /// Stores the index of the strongest material in the red channel, the index of the
+    /// second strongest in the green channel and the blend factor towards the second one
+    /// in the blue channel. Supports up to 256 materials.
+    IndexBlend,
+}
+
+impl SplatLayout {
+    /// Returns the filter used to down sample weights of this layout.
+    /// Material indices must never be interpolated.
+    pub fn down_sample_filter(self) -> DownSampleFilter {
+        match self {
+            Self::Weights => DownSampleFilter::Average,
+            Self::IndexBlend => DownSampleFilter::Mode,
+        }
+    }
+
+    /// Encodes the weights of all materials into the normalized channel values of a texel.
+    fn encode(self, weights: &[f32]) -> [f32; 4] {
+        let total: f32 = weights.iter().sum();
+
+        // texels not covered by any rule fall back to the first material
+        if total <= 0.0 {
+            return match self {
+                Self::Weights => [1.0, 0.0, 0.0, 0.0],
+                Self::IndexBlend => [0.0, 0.0, 0.0, 1.0],
+            };
+        }
+
+        match self {
+            Self::Weights => {
+                let mut texel = [0.0; 4];
+
+                for (value, weight) in texel.iter_mut().zip(weights) {
+                    *value = weight / total;
+                }
+
+                texel
+            }
+            Self::IndexBlend => {
+                let mut materials: Vec<_> = weights.iter().enumerate().collect();
+                materials.sort_by(|a, b| b.1.total_cmp(a.1));
+
+                let (first, &first_weight) = materials[0];
+                let (second, &second_weight) = materials.get(1).copied().unwrap_or((first, &0.0));
+                let blend = second_weight / (first_weight + second_weight);
+
+                [
+                    first as f32 / u8::MAX as f32,
+                    second as f32 / u8::MAX as f32,
+                    blend,
+                    1.0,
+                ]
+            }
+        }
+    }
+}
+
+/// Generates the material weights of the terrain from the height attachment using the
+/// `rules` and builds all of their lods.
+///
+/// The weights of all rules of the same material are accumulated and normalized per texel.
+/// The nodes are stored as [`ImageFormat::RGBA`] in the configured `layout`.
+/// The height attachment requires a border of at least one texel.
+/// The weights are generated for all existing lod 0 height nodes, see
+/// [`preprocess_derived`](super::derive::preprocess_derived).
+pub fn preprocess_splat(
+    height_directory: &str,
+    splat_directory: &str,
+    lod_count: u32,
+    texture_size: u32,
+    height_border_size: u32,
+    border_size: u32,
+    height: f32,
+    rules: &[SplatRule],
+    layout: SplatLayout,
+) {
+    assert!(
+        height_border_size > 0,
+        "The height attachment requires a border to generate material weights."
+    );
+
+    let material_count = rules
+        .iter()
+        .map(|rule| rule.material as usize + 1)
+        .max()
+        .unwrap_or(1);
+
+    if layout == SplatLayout::Weights {
+        assert!(
+            material_count <= 4,
+            "The weights layout supports at most four materials."
+        );
+    }
+
+    preprocess_derived(
+        height_directory,
+        splat_directory,
+        lod_count,
+        texture_size,
+        height_border_size,
+        border_size,
+        height,
+        ImageFormat::RGBA,
+        &layout.down_sample_filter(),
+        &format!("splat {rules:?} {layout:?}"),
+        |window| {
+            let position = window.global_position().as_vec2();
+            let mut weights = vec![0.0; material_count];
+
+            for rule in rules {
+                weights[rule.material as usize] += rule.weight(window, position);
+            }
+
+            layout.encode(&weights)
+        },
+    );
+}
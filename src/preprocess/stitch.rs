@@ -0,0 +1,373 @@
+use crate::{
+    data_structures::{calc_node_id, NodeCoordinate, NodeId},
+    preprocess::{existing_nodes, load_node, ImageFormat},
+};
+use image::{DynamicImage, ImageBuffer, Pixel};
+use itertools::iproduct;
+use std::{collections::HashMap, path::Path};
+
+/// The directions of all eight adjacent nodes.
+const DIRECTIONS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+type NodeBuffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// A border texel of a node, whose value does not match the value of its adjacent node.
+#[derive(Clone, Debug)]
+pub struct BorderMismatch {
+    /// The id of the node with the inconsistent border.
+    pub node_id: NodeId,
+    /// The direction of the adjacent node the border is shared with.
+    pub direction: (i32, i32),
+    /// The count of mismatching texels in this border section.
+    pub texel_count: u32,
+    /// The largest difference of a single channel in this border section.
+    pub max_difference: f64,
+}
+
+/// Returns the direction of the border section the texel lies in, or (0, 0) for interior texels.
+#[inline]
+fn border_direction(x: u32, y: u32, texture_size: u32, border_size: u32) -> (i32, i32) {
+    let direction = |v: u32| {
+        if v < border_size {
+            -1
+        } else if v >= border_size + texture_size {
+            1
+        } else {
+            0
+        }
+    };
+
+    (direction(x), direction(y))
+}
+
+/// Iterates over the positions of all border texels of a node.
+fn border_texels(texture_size: u32, border_size: u32) -> impl Iterator<Item = (u32, u32)> {
+    let size = texture_size + 2 * border_size;
+
+    iproduct!(0..size, 0..size)
+        .filter(move |&(x, y)| border_direction(x, y, texture_size, border_size) != (0, 0))
+}
+
+/// Loads all existing adjacent nodes of the node, keyed by their direction.
+fn load_adjacent_nodes(
+    directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) -> HashMap<(i32, i32), DynamicImage> {
+    let NodeCoordinate { lod, x, y } = node_id.into();
+
+    DIRECTIONS
+        .iter()
+        .filter_map(|&direction| {
+            let x = x as i32 + direction.0;
+            let y = y as i32 + direction.1;
+
+            if x < 0 || y < 0 {
+                return None;
+            }
+
+            let adjacent_id = calc_node_id(lod, x as u32, y as u32);
+            let adjacent_path = format!("{directory}/{adjacent_id}.png");
+
+            Path::new(&adjacent_path).exists().then(|| {
+                let adjacent_node = load_node(&adjacent_path, texture_size, border_size, format);
+                (direction, adjacent_node)
+            })
+        })
+        .collect()
+}
+
+/// Determines the value a border texel should have.
+///
+/// Border texels are copied from the interior of the adjacent node.
+/// If that node does not exist, the closest interior texel of an existing edge neighbour
+/// or the node itself is replicated instead.
+fn expected_texel<P: Pixel>(
+    node: &NodeBuffer<P>,
+    adjacent_nodes: &HashMap<(i32, i32), &NodeBuffer<P>>,
+    x: u32,
+    y: u32,
+    texture_size: u32,
+    border_size: u32,
+) -> P {
+    let (dx, dy) = border_direction(x, y, texture_size, border_size);
+
+    let clamp = |v: u32| v.clamp(border_size, border_size + texture_size - 1);
+    let shift = |v: u32, d: i32| (v as i32 - d * texture_size as i32) as u32;
+
+    if let Some(adjacent_node) = adjacent_nodes.get(&(dx, dy)) {
+        return *adjacent_node.get_pixel(shift(x, dx), shift(y, dy));
+    }
+
+    if dx != 0 && dy != 0 {
+        if let Some(adjacent_node) = adjacent_nodes.get(&(dx, 0)) {
+            return *adjacent_node.get_pixel(shift(x, dx), clamp(y));
+        }
+        if let Some(adjacent_node) = adjacent_nodes.get(&(0, dy)) {
+            return *adjacent_node.get_pixel(clamp(x), shift(y, dy));
+        }
+    }
+
+    *node.get_pixel(clamp(x), clamp(y))
+}
+
+fn stitch_buffer<P: Pixel>(
+    node: &mut NodeBuffer<P>,
+    adjacent_nodes: &HashMap<(i32, i32), &NodeBuffer<P>>,
+    texture_size: u32,
+    border_size: u32,
+) {
+    for (x, y) in border_texels(texture_size, border_size) {
+        let texel = expected_texel(node, adjacent_nodes, x, y, texture_size, border_size);
+        node.put_pixel(x, y, texel);
+    }
+}
+
+fn validate_buffer<P: Pixel>(
+    node_id: NodeId,
+    node: &NodeBuffer<P>,
+    adjacent_nodes: &HashMap<(i32, i32), &NodeBuffer<P>>,
+    texture_size: u32,
+    border_size: u32,
+) -> Vec<BorderMismatch>
+where
+    P::Subpixel: Into<f64>,
+{
+    let mut mismatches: HashMap<(i32, i32), BorderMismatch> = HashMap::new();
+
+    for (x, y) in border_texels(texture_size, border_size) {
+        let expected = expected_texel(node, adjacent_nodes, x, y, texture_size, border_size);
+        let actual = node.get_pixel(x, y);
+
+        let difference = expected
+            .channels()
+            .iter()
+            .zip(actual.channels())
+            .map(|(&a, &b)| (a.into() - b.into()).abs())
+            .fold(0.0, f64::max);
+
+        if difference > 0.0 {
+            let direction = border_direction(x, y, texture_size, border_size);
+            let mismatch = mismatches.entry(direction).or_insert(BorderMismatch {
+                node_id,
+                direction,
+                texel_count: 0,
+                max_difference: 0.0,
+            });
+
+            mismatch.texel_count += 1;
+            mismatch.max_difference = mismatch.max_difference.max(difference);
+        }
+    }
+
+    mismatches.into_values().collect()
+}
+
+/// Copies the borders (including the corners) of the node from its eight adjacent nodes.
+pub(crate) fn stitch_node(
+    directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) {
+    if border_size == 0 {
+        return;
+    }
+
+    let file_path = format!("{directory}/{node_id}.png");
+
+    let mut node = load_node(&file_path, texture_size, border_size, format);
+    let adjacent_nodes = load_adjacent_nodes(directory, node_id, texture_size, border_size, format);
+
+    match format {
+        ImageFormat::RGB => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_rgb8().unwrap()))
+                .collect();
+            stitch_buffer(
+                node.as_mut_rgb8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            );
+        }
+        ImageFormat::RGBA => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_rgba8().unwrap()))
+                .collect();
+            stitch_buffer(
+                node.as_mut_rgba8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            );
+        }
+        ImageFormat::LUMA16 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma16().unwrap()))
+                .collect();
+            stitch_buffer(
+                node.as_mut_luma16().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            );
+        }
+        ImageFormat::RG8 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma_alpha8().unwrap()))
+                .collect();
+            stitch_buffer(
+                node.as_mut_luma_alpha8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            );
+        }
+        ImageFormat::RG16 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma_alpha16().unwrap()))
+                .collect();
+            stitch_buffer(
+                node.as_mut_luma_alpha16().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            );
+        }
+    }
+
+    node.save(file_path).expect("Could not save file.");
+}
+
+pub fn stitch_nodes(
+    directory: &str,
+    first: (u32, u32),
+    last: (u32, u32),
+    lod: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) {
+    for (x, y) in iproduct!(first.0..last.0, first.1..last.1) {
+        let node_id = calc_node_id(lod, x, y);
+        stitch_node(directory, node_id, texture_size, border_size, format);
+    }
+}
+
+/// Checks the border of the node against its adjacent nodes.
+pub fn validate_node(
+    directory: &str,
+    node_id: NodeId,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) -> Vec<BorderMismatch> {
+    if border_size == 0 {
+        return Vec::new();
+    }
+
+    let file_path = format!("{directory}/{node_id}.png");
+
+    let node = load_node(&file_path, texture_size, border_size, format);
+    let adjacent_nodes = load_adjacent_nodes(directory, node_id, texture_size, border_size, format);
+
+    match format {
+        ImageFormat::RGB => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_rgb8().unwrap()))
+                .collect();
+            validate_buffer(
+                node_id,
+                node.as_rgb8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            )
+        }
+        ImageFormat::RGBA => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_rgba8().unwrap()))
+                .collect();
+            validate_buffer(
+                node_id,
+                node.as_rgba8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            )
+        }
+        ImageFormat::LUMA16 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma16().unwrap()))
+                .collect();
+            validate_buffer(
+                node_id,
+                node.as_luma16().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            )
+        }
+        ImageFormat::RG8 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma_alpha8().unwrap()))
+                .collect();
+            validate_buffer(
+                node_id,
+                node.as_luma_alpha8().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            )
+        }
+        ImageFormat::RG16 => {
+            let adjacent_nodes: HashMap<_, _> = adjacent_nodes
+                .iter()
+                .map(|(&direction, node)| (direction, node.as_luma_alpha16().unwrap()))
+                .collect();
+            validate_buffer(
+                node_id,
+                node.as_luma_alpha16().unwrap(),
+                &adjacent_nodes,
+                texture_size,
+                border_size,
+            )
+        }
+    }
+}
+
+/// Checks the borders of all nodes inside the directory against their adjacent nodes
+/// and reports every border section that does not match.
+pub fn validate_borders(
+    directory: &str,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+) -> Vec<BorderMismatch> {
+    existing_nodes(directory)
+        .into_iter()
+        .flat_map(|node_id| validate_node(directory, node_id, texture_size, border_size, format))
+        .collect()
+}
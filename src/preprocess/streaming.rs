@@ -0,0 +1,180 @@
+use crate::{
+    data_structures::{NodeCoordinate, NodeId},
+    preprocess::{
+        nodata::{load_node_with_nodata, overlay_node_with_nodata, NoDataMarker},
+        overlapping_nodes, ImageFormat,
+    },
+};
+use image::{DynamicImage, GrayAlphaImage, ImageBuffer, Luma, LumaA, RgbImage, RgbaImage};
+use itertools::Itertools;
+use png::{BitDepth, ColorType, Decoder, Reader, Transformations};
+use std::{collections::VecDeque, fs::File, io::BufReader};
+
+/// Reads a png source tile row by row, without decoding the entire image at once.
+pub(crate) struct PngRows {
+    reader: Reader<BufReader<File>>,
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+    /// The index of the next row to be read.
+    next_row: u32,
+}
+
+impl PngRows {
+    /// Opens the file for streaming, if it is a non interlaced png, whose pixels match the
+    /// format exactly. Otherwise the file has to be decoded at once, see
+    /// [`preprocess_tiles`](super::preprocess_tiles).
+    pub(crate) fn open(file_path: &str, format: ImageFormat) -> Option<Self> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(file_path).ok()?));
+        decoder.set_transformations(Transformations::EXPAND);
+        decoder.set_limits(png::Limits { bytes: usize::MAX });
+
+        let reader = decoder.read_info().ok()?;
+        let (width, height, interlaced) = {
+            let info = reader.info();
+            (info.width, info.height, info.interlaced)
+        };
+
+        if interlaced {
+            return None;
+        }
+
+        let supported = matches!(
+            (format, reader.output_color_type()),
+            (ImageFormat::RGB, (ColorType::Rgb, BitDepth::Eight))
+                | (ImageFormat::RGBA, (ColorType::Rgba, BitDepth::Eight))
+                | (
+                    ImageFormat::LUMA16,
+                    (ColorType::Grayscale, BitDepth::Sixteen)
+                )
+                | (
+                    ImageFormat::RG8,
+                    (ColorType::GrayscaleAlpha, BitDepth::Eight)
+                )
+                | (
+                    ImageFormat::RG16,
+                    (ColorType::GrayscaleAlpha, BitDepth::Sixteen)
+                )
+        );
+
+        supported.then(|| Self {
+            reader,
+            width,
+            height,
+            format,
+            next_row: 0,
+        })
+    }
+
+    fn read_row(&mut self) -> Vec<u8> {
+        let row = self
+            .reader
+            .next_row()
+            .expect("Could not read source file.")
+            .expect("The source file ended unexpectedly.");
+
+        self.next_row += 1;
+        row.data().to_vec()
+    }
+
+    /// Assembles the buffered rows into an image of the format.
+    fn strip(&self, rows: &VecDeque<Vec<u8>>) -> DynamicImage {
+        let width = self.width;
+        let height = rows.len() as u32;
+        let bytes: Vec<u8> = rows.iter().flatten().copied().collect();
+
+        // png stores 16 bit samples in big endian order
+        let words = || -> Vec<u16> {
+            bytes
+                .chunks_exact(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                .collect()
+        };
+
+        match self.format {
+            ImageFormat::RGB => RgbImage::from_raw(width, height, bytes).map(DynamicImage::from),
+            ImageFormat::RGBA => RgbaImage::from_raw(width, height, bytes).map(DynamicImage::from),
+            ImageFormat::LUMA16 => <ImageBuffer<Luma<u16>, _>>::from_raw(width, height, words())
+                .map(DynamicImage::from),
+            ImageFormat::RG8 => {
+                GrayAlphaImage::from_raw(width, height, bytes).map(DynamicImage::from)
+            }
+            ImageFormat::RG16 => <ImageBuffer<LumaA<u16>, _>>::from_raw(width, height, words())
+                .map(DynamicImage::from),
+        }
+        .unwrap()
+    }
+}
+
+/// Splits the source tile into all overlapping nodes, that pass the filter, one row of nodes
+/// at a time.
+///
+/// Only the source rows covered by the current row of nodes (including their borders)
+/// are kept in memory and every node is loaded and saved exactly once.
+pub(crate) fn split_rows(
+    mut rows: PngRows,
+    output_directory: &str,
+    offset: (u32, u32),
+    lod: u32,
+    tile_size: u32,
+    texture_size: u32,
+    border_size: u32,
+    format: ImageFormat,
+    nodata: Option<NoDataMarker>,
+    filter: impl Fn(NodeId) -> bool,
+) {
+    let node_rows = overlapping_nodes(offset, lod, tile_size, texture_size, border_size)
+        .into_iter()
+        .filter(|&node_id| filter(node_id))
+        .map(|node_id| (NodeCoordinate::from(node_id), node_id))
+        .sorted_by_key(|(coordinate, _)| coordinate.y)
+        .group_by(|(coordinate, _)| coordinate.y);
+
+    let mut buffer = VecDeque::new();
+    // the index of the first buffered row inside the source tile
+    let mut buffer_start = 0;
+
+    for (y, nodes) in &node_rows {
+        // the source rows covered by this row of nodes including their borders
+        let first_row = (y * texture_size) as i64 - (border_size + offset.1) as i64;
+        let last_row = first_row + (texture_size + 2 * border_size) as i64;
+
+        let first_row = first_row.clamp(0, rows.height as i64) as u32;
+        let last_row = last_row.clamp(0, rows.height as i64) as u32;
+
+        while buffer_start < first_row && !buffer.is_empty() {
+            buffer.pop_front();
+            buffer_start += 1;
+        }
+
+        while rows.next_row < last_row {
+            let row = rows.read_row();
+
+            if rows.next_row <= first_row {
+                buffer_start = rows.next_row;
+            } else {
+                buffer.push_back(row);
+            }
+        }
+
+        if buffer.is_empty() {
+            continue;
+        }
+
+        let strip = rows.strip(&buffer);
+
+        for (NodeCoordinate { x, .. }, node_id) in nodes {
+            let file_path = format!("{output_directory}/{node_id}.png");
+
+            let mut node =
+                load_node_with_nodata(&file_path, texture_size, border_size, format, nodata);
+
+            let dx = (offset.0 + border_size) as i64 - (x * texture_size) as i64;
+            let dy = (offset.1 + buffer_start + border_size) as i64 - (y * texture_size) as i64;
+
+            overlay_node_with_nodata(&mut node, &strip, dx, dy, format, nodata);
+
+            node.save(&file_path).expect("Could not save file.");
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crate::preprocess::noise::gradient_noise;
+use bevy::prelude::*;
+use image::{DynamicImage, ImageBuffer, Luma};
+use itertools::iproduct;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// A composable graph of noise functions, that produces a height in the range [0, 1]
+/// for every position.
+///
+/// Positions are measured in texels of the generated source tiles.
+/// The graph is fully deterministic, all randomness is derived from the seeds of its nodes.
+/// It can be stored and loaded with serde, to share terrain prototypes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum NoiseGraph {
+    Constant(f32),
+    /// Fractal Brownian motion, which sums octaves of gradient noise.
+    Fbm {
+        /// The frequency of the first octave in features per texel.
+        frequency: f32,
+        octaves: u32,
+        /// The frequency multiplier between octaves.
+        lacunarity: f32,
+        /// The amplitude multiplier between octaves.
+        gain: f32,
+        seed: u32,
+    },
+    /// Ridged multifractal noise, which produces sharp mountain ridges.
+    Ridged {
+        /// The frequency of the first octave in features per texel.
+        frequency: f32,
+        octaves: u32,
+        /// The frequency multiplier between octaves.
+        lacunarity: f32,
+        /// The amplitude multiplier between octaves.
+        gain: f32,
+        seed: u32,
+    },
+    /// Offsets the position of the `source` by the `warp` graph, scaled by `strength` texels.
+    DomainWarp {
+        source: Box<NoiseGraph>,
+        warp: Box<NoiseGraph>,
+        strength: f32,
+    },
+    /// Quantizes the `source` into `steps` plateaus, with smooth slopes in between.
+    /// A `smoothness` of zero produces hard steps, one produces gentle ones.
+    /// Less than one step is treated as a single step.
+    Terrace {
+        source: Box<NoiseGraph>,
+        steps: u32,
+        smoothness: f32,
+    },
+    /// Falls off from one inside the `radius` around the `center` to zero over `falloff`,
+    /// e.g. to shape islands.
+    RadialMask {
+        center: (f32, f32),
+        radius: f32,
+        falloff: f32,
+    },
+    /// Blends from `a` to `b` by the value of the `mask`.
+    Blend {
+        a: Box<NoiseGraph>,
+        b: Box<NoiseGraph>,
+        mask: Box<NoiseGraph>,
+    },
+    Add(Box<NoiseGraph>, Box<NoiseGraph>),
+    Multiply(Box<NoiseGraph>, Box<NoiseGraph>),
+    /// Computes `source * scale + bias`.
+    ScaleBias {
+        source: Box<NoiseGraph>,
+        scale: f32,
+        bias: f32,
+    },
+}
+
+impl NoiseGraph {
+    /// Samples the graph at the position. The result is not clamped.
+    pub fn sample(&self, position: Vec2) -> f32 {
+        match self {
+            Self::Constant(value) => *value,
+            Self::Fbm {
+                frequency,
+                octaves,
+                lacunarity,
+                gain,
+                seed,
+            } => {
+                let mut value = 0.0;
+                let mut amplitude = 0.5;
+                let mut frequency = *frequency;
+
+                for octave in 0..*octaves {
+                    value +=
+                        amplitude * gradient_noise(position * frequency, seed.wrapping_add(octave));
+                    amplitude *= gain;
+                    frequency *= lacunarity;
+                }
+
+                value * 0.5 + 0.5
+            }
+            Self::Ridged {
+                frequency,
+                octaves,
+                lacunarity,
+                gain,
+                seed,
+            } => {
+                let mut value = 0.0;
+                let mut amplitude = 0.5;
+                let mut weight = 1.0;
+                let mut frequency = *frequency;
+
+                for octave in 0..*octaves {
+                    let ridge =
+                        1.0 - gradient_noise(position * frequency, seed.wrapping_add(octave)).abs();
+                    let ridge = ridge * ridge * weight;
+
+                    // detail is concentrated on the ridges
+                    weight = (ridge * 2.0).clamp(0.0, 1.0);
+                    value += amplitude * ridge;
+                    amplitude *= gain;
+                    frequency *= lacunarity;
+                }
+
+                value
+            }
+            Self::DomainWarp {
+                source,
+                warp,
+                strength,
+            } => {
+                // decorrelate both axes by sampling the warp at distant positions
+                let offset = Vec2::new(
+                    warp.sample(position),
+                    warp.sample(position + Vec2::new(5273.1, 1731.7)),
+                ) * 2.0
+                    - 1.0;
+
+                source.sample(position + offset * *strength)
+            }
+            Self::Terrace {
+                source,
+                steps,
+                smoothness,
+            } => {
+                let steps = (*steps).max(1) as f32;
+                let value = source.sample(position) * steps;
+                let step = value.floor();
+                let t = value - step;
+
+                // shift the slope between two plateaus towards its end
+                let sharpness = 1.0 - smoothness.clamp(0.0, 1.0);
+                let t = ((t - sharpness) / (1.0 - sharpness).max(f32::EPSILON)).max(0.0);
+                let t = t * t * (3.0 - 2.0 * t);
+
+                (step + t) / steps
+            }
+            Self::RadialMask {
+                center,
+                radius,
+                falloff,
+            } => {
+                let distance = position.distance(Vec2::new(center.0, center.1));
+                (1.0 - (distance - radius) / falloff.max(f32::EPSILON)).clamp(0.0, 1.0)
+            }
+            Self::Blend { a, b, mask } => {
+                let mask = mask.sample(position).clamp(0.0, 1.0);
+                a.sample(position) * (1.0 - mask) + b.sample(position) * mask
+            }
+            Self::Add(a, b) => a.sample(position) + b.sample(position),
+            Self::Multiply(a, b) => a.sample(position) * b.sample(position),
+            Self::ScaleBias {
+                source,
+                scale,
+                bias,
+            } => source.sample(position) * scale + bias,
+        }
+    }
+}
+
+/// Generates the source height tiles of the `graph` and stores them as `{name}_{x}_{y}.png`
+/// inside the output directory, which can be preprocessed with
+/// [`preprocess_tiles`](super::preprocess_tiles). The name must not contain underscores.
+///
+/// The heights are clamped to the range [0, 1] and stored as 16 bit grayscale images.
+pub fn generate_tiles(
+    output_directory: &str,
+    name: &str,
+    graph: &NoiseGraph,
+    tile_count: (u32, u32),
+    tile_size: u32,
+) {
+    fs::create_dir_all(output_directory).unwrap();
+
+    for (x, y) in iproduct!(0..tile_count.0, 0..tile_count.1) {
+        let tile = <ImageBuffer<Luma<u16>, _>>::from_fn(tile_size, tile_size, |u, v| {
+            let position = Vec2::new((x * tile_size + u) as f32, (y * tile_size + v) as f32);
+            let value = graph.sample(position).clamp(0.0, 1.0);
+
+            Luma([(value * u16::MAX as f32).round() as u16])
+        });
+
+        DynamicImage::from(tile)
+            .save(format!("{output_directory}/{name}_{x}_{y}.png"))
+            .expect("Could not save file.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terrace(value: f32, steps: u32, smoothness: f32) -> f32 {
+        NoiseGraph::Terrace {
+            source: Box::new(NoiseGraph::Constant(value)),
+            steps,
+            smoothness,
+        }
+        .sample(Vec2::ZERO)
+    }
+
+    #[test]
+    fn hard_terraces_form_plateaus() {
+        assert_eq!(terrace(0.5, 4, 0.0), 0.5);
+        assert_eq!(terrace(0.6, 4, 0.0), 0.5);
+        assert_eq!(terrace(0.74, 4, 0.0), 0.5);
+        assert_eq!(terrace(0.76, 4, 0.0), 0.75);
+    }
+
+    #[test]
+    fn smooth_terraces_are_monotonic() {
+        let mut previous = 0.0;
+
+        for i in 0..=100 {
+            let value = terrace(i as f32 / 100.0, 5, 0.5);
+
+            assert!(value >= previous);
+            previous = value;
+        }
+
+        assert!((terrace(0.6, 4, 1.0) - (2.0 + 0.352) / 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zero_steps_are_treated_as_one() {
+        for value in [0.0, 0.3, 1.0] {
+            let height = terrace(value, 0, 0.5);
+
+            assert!(height.is_finite());
+            assert_eq!(height, terrace(value, 1, 0.5));
+        }
+    }
+
+    #[test]
+    fn combinators() {
+        let constant = |value| Box::new(NoiseGraph::Constant(value));
+
+        let blend = NoiseGraph::Blend {
+            a: constant(0.2),
+            b: constant(0.6),
+            mask: constant(0.25),
+        };
+        assert!((blend.sample(Vec2::ZERO) - 0.3).abs() < 1e-6);
+
+        let scale_bias = NoiseGraph::ScaleBias {
+            source: constant(0.5),
+            scale: 2.0,
+            bias: -0.25,
+        };
+        assert_eq!(scale_bias.sample(Vec2::ZERO), 0.75);
+
+        let mask = NoiseGraph::RadialMask {
+            center: (0.0, 0.0),
+            radius: 10.0,
+            falloff: 10.0,
+        };
+        assert_eq!(mask.sample(Vec2::new(5.0, 0.0)), 1.0);
+        assert_eq!(mask.sample(Vec2::new(15.0, 0.0)), 0.5);
+        assert_eq!(mask.sample(Vec2::new(25.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn noise_is_deterministic_and_seeded() {
+        let fbm = |seed| NoiseGraph::Fbm {
+            frequency: 0.01,
+            octaves: 5,
+            lacunarity: 2.0,
+            gain: 0.5,
+            seed,
+        };
+
+        let positions = (0..64).map(|i| Vec2::new(i as f32 * 13.7, i as f32 * 7.3));
+
+        for position in positions {
+            assert_eq!(fbm(1).sample(position), fbm(1).sample(position));
+            assert!((0.0..=1.0).contains(&fbm(1).sample(position)));
+        }
+
+        assert!((0..64).any(|i| {
+            let position = Vec2::new(i as f32 * 13.7, i as f32 * 7.3);
+            fbm(1).sample(position) != fbm(2).sample(position)
+        }));
+    }
+}
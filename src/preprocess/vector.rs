@@ -0,0 +1,307 @@
+use crate::preprocess::{
+    derive::{preprocess_derived, HeightWindow},
+    down_sample::DownSampleFilter,
+    ImageFormat,
+};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// The geometry of a vector feature in texels of lod 0.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VectorShape {
+    /// An open line (e.g. a road or river) of the given width.
+    Polyline { points: Vec<(f32, f32)>, width: f32 },
+    /// A filled, closed polygon (e.g. a lake or forest area) using the even-odd rule.
+    Polygon { points: Vec<(f32, f32)> },
+}
+
+/// Modifies the height covered by a vector feature.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum HeightModifier {
+    /// Lowers the height by `depth` world units.
+    Carve { depth: f32 },
+    /// Sets the height to `height` world units.
+    Flatten { height: f32 },
+}
+
+/// A vector feature authored by level designers, like a road, river, lake or forest area.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VectorFeature {
+    pub shape: VectorShape,
+    /// The width in texels over which the coverage fades out at the edges of the shape.
+    /// A falloff of zero still anti-aliases the edges over a single texel.
+    pub falloff: f32,
+    /// Applied to the height by [`preprocess_carve`], if present.
+    pub modifier: Option<HeightModifier>,
+}
+
+/// Returns the distance from the position to the segment from `a` to `b`.
+fn segment_distance(position: Vec2, a: Vec2, b: Vec2) -> f32 {
+    let segment = b - a;
+    let t =
+        ((position - a).dot(segment) / segment.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+
+    position.distance(a + segment * t)
+}
+
+impl VectorShape {
+    /// Returns the minimum and maximum corner of the bounding box of the shape.
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        let (points, extent) = match self {
+            Self::Polyline { points, width } => (points, width / 2.0),
+            Self::Polygon { points } => (points, 0.0),
+        };
+
+        let (min, max) = points.iter().fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), &point| (min.min(point.into()), max.max(point.into())),
+        );
+
+        (min - extent, max + extent)
+    }
+
+    /// Returns the signed distance from the position to the edge of the shape,
+    /// which is negative inside of it.
+    pub fn signed_distance(&self, position: Vec2) -> f32 {
+        match self {
+            Self::Polyline { points, width } => {
+                let distance = points
+                    .windows(2)
+                    .map(|segment| segment_distance(position, segment[0].into(), segment[1].into()))
+                    .fold(f32::MAX, f32::min);
+
+                distance - width / 2.0
+            }
+            Self::Polygon { points } => {
+                let mut inside = false;
+                let mut distance = f32::MAX;
+
+                for (i, &a) in points.iter().enumerate() {
+                    let a = Vec2::from(a);
+                    let b = Vec2::from(points[(i + 1) % points.len()]);
+
+                    distance = distance.min(segment_distance(position, a, b));
+
+                    // count the crossings of a ray in positive x direction
+                    if (a.y > position.y) != (b.y > position.y)
+                        && position.x < a.x + (position.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+
+                if inside {
+                    -distance
+                } else {
+                    distance
+                }
+            }
+        }
+    }
+}
+
+impl VectorFeature {
+    /// Returns the anti-aliased coverage of the feature at the position in the range [0, 1].
+    pub fn coverage(&self, position: Vec2) -> f32 {
+        let distance = self.shape.signed_distance(position);
+
+        1.0 - ((distance + 0.5) / (1.0 + self.falloff)).clamp(0.0, 1.0)
+    }
+
+    /// Returns the minimum and maximum corner of the region with a coverage above zero.
+    fn coverage_bounds(&self) -> (Vec2, Vec2) {
+        let (min, max) = self.shape.bounds();
+        let margin = 0.5 + self.falloff;
+
+        (min - margin, max + margin)
+    }
+}
+
+/// Culls the features against the node, which is currently derived.
+/// All texels of a node are derived consecutively, thus the features are culled once per node.
+struct NodeFeatures<'a> {
+    features: &'a [VectorFeature],
+    bounds: Vec<(Vec2, Vec2)>,
+    /// The bounds of the current node and the indices of the features overlapping it.
+    current: RefCell<((Vec2, Vec2), Vec<usize>)>,
+}
+
+impl<'a> NodeFeatures<'a> {
+    fn new(features: &'a [VectorFeature]) -> Self {
+        Self {
+            features,
+            bounds: features
+                .iter()
+                .map(VectorFeature::coverage_bounds)
+                .collect(),
+            current: RefCell::new(((Vec2::NAN, Vec2::NAN), Vec::new())),
+        }
+    }
+
+    /// Folds all features, whose bounds intersect the node of the window, in order.
+    fn fold<T>(
+        &self,
+        window: &HeightWindow,
+        init: T,
+        f: impl FnMut(T, &'a VectorFeature) -> T,
+    ) -> T {
+        let node = window.node_bounds();
+        let mut current = self.current.borrow_mut();
+
+        if current.0 != node {
+            let overlapping = self
+                .bounds
+                .iter()
+                .enumerate()
+                .filter(|(_, (min, max))| min.cmplt(node.1).all() && max.cmpgt(node.0).all())
+                .map(|(index, _)| index)
+                .collect();
+
+            *current = (node, overlapping);
+        }
+
+        current
+            .1
+            .iter()
+            .map(|&index| &self.features[index])
+            .fold(init, f)
+    }
+}
+
+/// The position of the center of the texel.
+#[inline]
+fn texel_center(position: UVec2) -> Vec2 {
+    position.as_vec2() + 0.5
+}
+
+/// Rasterizes the `features` into an anti-aliased mask attachment and builds all of its lods.
+///
+/// The mask stores the largest coverage of all features as [`ImageFormat::LUMA16`].
+/// Features are only evaluated for the nodes overlapped by their bounding box.
+/// It is rasterized for all existing lod 0 height nodes, see
+/// [`preprocess_derived`](super::derive::preprocess_derived).
+pub fn preprocess_mask(
+    height_directory: &str,
+    mask_directory: &str,
+    lod_count: u32,
+    texture_size: u32,
+    height_border_size: u32,
+    border_size: u32,
+    features: &[VectorFeature],
+    filter: &DownSampleFilter,
+) {
+    let node_features = NodeFeatures::new(features);
+
+    preprocess_derived(
+        height_directory,
+        mask_directory,
+        lod_count,
+        texture_size,
+        height_border_size,
+        border_size,
+        1.0,
+        ImageFormat::LUMA16,
+        filter,
+        &format!("mask {features:?}"),
+        |window| {
+            let position = texel_center(window.global_position());
+
+            let coverage = node_features.fold(window, 0.0, |coverage: f32, feature| {
+                coverage.max(feature.coverage(position))
+            });
+
+            [coverage, 0.0, 0.0, 0.0]
+        },
+    );
+}
+
+/// Applies the height modifiers of the `features` to the height attachment and stores the
+/// result as a new height attachment, including all of its lods.
+///
+/// The modifiers are applied in order and blended with the height by the coverage of
+/// their feature, so that the falloff produces smooth embankments.
+pub fn preprocess_carve(
+    height_directory: &str,
+    carved_directory: &str,
+    lod_count: u32,
+    texture_size: u32,
+    border_size: u32,
+    height: f32,
+    features: &[VectorFeature],
+    filter: &DownSampleFilter,
+) {
+    let node_features = NodeFeatures::new(features);
+
+    preprocess_derived(
+        height_directory,
+        carved_directory,
+        lod_count,
+        texture_size,
+        border_size,
+        border_size,
+        height,
+        ImageFormat::LUMA16,
+        filter,
+        &format!("carve {features:?}"),
+        |window| {
+            let position = texel_center(window.global_position());
+
+            let value = node_features.fold(window, window.get(0, 0), |value, feature| {
+                let modifier = match feature.modifier {
+                    Some(modifier) => modifier,
+                    None => return value,
+                };
+
+                let target = match modifier {
+                    HeightModifier::Carve { depth } => value - depth,
+                    HeightModifier::Flatten { height } => height,
+                };
+
+                value + (target - value) * feature.coverage(position)
+            });
+
+            [value / height, 0.0, 0.0, 0.0]
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::iproduct;
+
+    #[test]
+    fn features_are_uncovered_outside_of_their_bounds() {
+        let features = [
+            VectorFeature {
+                shape: VectorShape::Polyline {
+                    points: vec![(10.0, 10.0), (40.0, 20.0), (30.0, 50.0)],
+                    width: 6.0,
+                },
+                falloff: 4.0,
+                modifier: None,
+            },
+            VectorFeature {
+                shape: VectorShape::Polygon {
+                    points: vec![(60.0, 60.0), (90.0, 65.0), (70.0, 90.0)],
+                },
+                falloff: 0.0,
+                modifier: None,
+            },
+        ];
+
+        for feature in &features {
+            let (min, max) = feature.coverage_bounds();
+
+            for (x, y) in iproduct!(0..100, 0..100) {
+                let position = texel_center(UVec2::new(x, y));
+                let inside = position.cmpge(min).all() && position.cmple(max).all();
+
+                if !inside {
+                    assert_eq!(feature.coverage(position), 0.0, "{position}");
+                }
+            }
+        }
+    }
+}
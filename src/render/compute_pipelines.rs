@@ -1,4 +1,4 @@
-use crate::render::shaders::{PREPARE_INDIRECT_SHADER, TESSELATION_SHADER};
+use crate::render::shaders::{DEPTH_PYRAMID_SHADER, PREPARE_INDIRECT_SHADER, TESSELATION_SHADER};
 use crate::render::terrain_data::terrain_bind_group_layout;
 use crate::render::TerrainPipelineConfig;
 use crate::{
@@ -32,6 +32,8 @@ pub enum TerrainComputePipelineId {
     PrepareTessellation,
     PrepareRefinement,
     PrepareRender,
+    BuildDepthPyramid,
+    DownSampleDepthPyramid,
 }
 
 bitflags::bitflags! {
@@ -40,6 +42,8 @@ pub struct TerrainComputePipelineFlags: u32 {
     const NONE               = 0;
     const DENSITY            = (1 << 0);
     const TEST               = (2 << 0);
+    const OCCLUSION_CULLING  = (1 << 2);
+    const MULTISAMPLED       = (1 << 3);
 }
 }
 
@@ -57,6 +61,23 @@ impl TerrainComputePipelineFlags {
         key
     }
 
+    pub fn from_settings(
+        debug: &DebugTerrain,
+        config: &TerrainPipelineConfig,
+        msaa: &Msaa,
+    ) -> Self {
+        let mut key = TerrainComputePipelineFlags::from_debug(debug);
+
+        if config.occlusion_culling {
+            key |= TerrainComputePipelineFlags::OCCLUSION_CULLING;
+        }
+        if msaa.samples > 1 {
+            key |= TerrainComputePipelineFlags::MULTISAMPLED;
+        }
+
+        key
+    }
+
     pub fn shader_defs(&self) -> Vec<String> {
         let mut shader_defs = Vec::new();
 
@@ -66,6 +87,12 @@ impl TerrainComputePipelineFlags {
         if (self.bits & TerrainComputePipelineFlags::TEST.bits) != 0 {
             shader_defs.push("TEST".to_string());
         }
+        if (self.bits & TerrainComputePipelineFlags::OCCLUSION_CULLING.bits) != 0 {
+            shader_defs.push("OCCLUSION_CULLING".to_string());
+        }
+        if (self.bits & TerrainComputePipelineFlags::MULTISAMPLED.bits) != 0 {
+            shader_defs.push("MULTISAMPLED".to_string());
+        }
 
         shader_defs
     }
@@ -76,8 +103,12 @@ pub struct TerrainComputePipelines {
     pub(crate) tessellation_layout: BindGroupLayout,
     pub(crate) cull_data_layout: BindGroupLayout,
     pub(crate) terrain_layout: BindGroupLayout,
+    pub(crate) depth_pyramid_source_layout: BindGroupLayout,
+    pub(crate) depth_pyramid_multisampled_source_layout: BindGroupLayout,
+    pub(crate) depth_pyramid_layout: BindGroupLayout,
     prepare_indirect_shader: Handle<Shader>,
     tessellation_shader: Handle<Shader>,
+    depth_pyramid_shader: Handle<Shader>,
 }
 
 impl FromWorld for TerrainComputePipelines {
@@ -89,17 +120,27 @@ impl FromWorld for TerrainComputePipelines {
         let tessellation_layout = device.create_bind_group_layout(&TESSELLATION_LAYOUT);
         let cull_data_layout = device.create_bind_group_layout(&CULL_DATA_LAYOUT);
         let terrain_layout = terrain_bind_group_layout(&device, config.attachment_count);
+        let depth_pyramid_source_layout =
+            device.create_bind_group_layout(&DEPTH_PYRAMID_SOURCE_LAYOUT);
+        let depth_pyramid_multisampled_source_layout =
+            device.create_bind_group_layout(&DEPTH_PYRAMID_MULTISAMPLED_SOURCE_LAYOUT);
+        let depth_pyramid_layout = device.create_bind_group_layout(&DEPTH_PYRAMID_LAYOUT);
 
         let prepare_indirect_shader = PREPARE_INDIRECT_SHADER.typed();
         let tessellation_shader = TESSELATION_SHADER.typed();
+        let depth_pyramid_shader = DEPTH_PYRAMID_SHADER.typed();
 
         TerrainComputePipelines {
             prepare_indirect_layout,
             tessellation_layout,
             cull_data_layout,
             terrain_layout,
+            depth_pyramid_source_layout,
+            depth_pyramid_multisampled_source_layout,
+            depth_pyramid_layout,
             prepare_indirect_shader,
             tessellation_shader,
+            depth_pyramid_shader,
         }
     }
 }
@@ -172,6 +213,20 @@ impl SpecializedComputePipeline for TerrainComputePipelines {
                 shader = self.prepare_indirect_shader.clone();
                 entry_point = "prepare_render".into();
             }
+            TerrainComputePipelineId::BuildDepthPyramid => {
+                if key.1.contains(TerrainComputePipelineFlags::MULTISAMPLED) {
+                    layout = Some(vec![self.depth_pyramid_multisampled_source_layout.clone()]);
+                } else {
+                    layout = Some(vec![self.depth_pyramid_source_layout.clone()]);
+                }
+                shader = self.depth_pyramid_shader.clone();
+                entry_point = "build_depth_pyramid".into();
+            }
+            TerrainComputePipelineId::DownSampleDepthPyramid => {
+                layout = Some(vec![self.depth_pyramid_layout.clone()]);
+                shader = self.depth_pyramid_shader.clone();
+                entry_point = "down_sample_depth_pyramid".into();
+            }
         }
 
         ComputePipelineDescriptor {
@@ -192,6 +247,8 @@ pub struct TerrainComputeNode {
         SResMut<SpecializedComputePipelines<TerrainComputePipelines>>,
         SRes<TerrainComputePipelines>,
         SRes<DebugTerrain>,
+        SRes<TerrainPipelineConfig>,
+        SRes<Msaa>,
     )>,
     pipelines: [CachedComputePipelineId; TerrainComputePipelineId::COUNT],
 }
@@ -251,9 +308,10 @@ impl render_graph::Node for TerrainComputeNode {
         self.terrain_query.update_archetypes(world);
         self.view_query.update_archetypes(world);
 
-        let (mut pipeline_cache, mut pipelines, pipeline, debug) = self.system_state.get_mut(world);
+        let (mut pipeline_cache, mut pipelines, pipeline, debug, config, msaa) =
+            self.system_state.get_mut(world);
 
-        let flags = TerrainComputePipelineFlags::from_debug(&debug);
+        let flags = TerrainComputePipelineFlags::from_settings(&debug, &config, &msaa);
         for id in TerrainComputePipelineId::iter() {
             self.pipelines[id as usize] =
                 pipelines.specialize(&mut pipeline_cache, &pipeline, (id, flags));
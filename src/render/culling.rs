@@ -1,7 +1,12 @@
-use crate::{terrain::Terrain, TerrainComputePipelines, TerrainView, TerrainViewComponents};
+use crate::{
+    data_structures::gpu_node_atlas::GpuNodeAtlas,
+    render::depth_pyramid::DepthPyramid,
+    terrain::{Terrain, TerrainComponents},
+    TerrainComputePipelines, TerrainView, TerrainViewComponents,
+};
 use bevy::{
-    math::Vec3Swizzles,
-    pbr::MeshUniform,
+    math::Vec4Swizzles,
+    pbr::{LightEntity, MeshUniform},
     prelude::*,
     render::{render_resource::*, renderer::RenderDevice, view::ExtractedView},
 };
@@ -9,10 +14,11 @@ use bevy::{
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, ShaderType)]
 pub struct CullingData {
-    pub(crate) world_position: Vec4,
     pub(crate) view_proj: Mat4,
     pub(crate) model: Mat4,
     pub(crate) planes: [Vec4; 6],
+    /// The view projection matrix of the depth stored in the depth pyramid.
+    pub(crate) previous_view_proj: Mat4,
 }
 
 #[derive(Component)]
@@ -20,6 +26,8 @@ pub struct CullingBindGroup {
     pub(crate) value: BindGroup,
 }
 
+/// Returns the normalized world space planes of the view frustum (left, right, bottom, top,
+/// near and far), which point inwards.
 pub fn planes(
     view_projection: &Mat4,
     view_translation: &Vec3,
@@ -35,6 +43,7 @@ pub fn planes(
         } else {
             row3 - row
         };
+        *plane /= plane.xyz().length();
     }
     let far_center = *view_translation - far * *view_backward;
     planes[5] = view_backward.extend(-view_backward.dot(far_center));
@@ -42,59 +51,121 @@ pub fn planes(
     planes
 }
 
+impl CullingBindGroup {
+    fn new(
+        device: &RenderDevice,
+        compute_pipelines: &TerrainComputePipelines,
+        gpu_node_atlas: &GpuNodeAtlas,
+        depth_pyramid: &DepthPyramid,
+        culling_data: &CullingData,
+    ) -> Self {
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(culling_data).unwrap();
+
+        let buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: None,
+            contents: &buffer.into_inner(),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        let value = device.create_bind_group(&BindGroupDescriptor {
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&depth_pyramid.view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: gpu_node_atlas.height_bounds_buffer.as_entire_binding(),
+                },
+            ],
+            label: None,
+            layout: &compute_pipelines.cull_data_layout,
+        });
+
+        Self { value }
+    }
+}
+
+/// Returns the view projection matrix and the frustum planes of the view.
+fn view_frustum(extracted_view: &ExtractedView) -> (Mat4, [Vec4; 6]) {
+    let view_proj = extracted_view.projection * extracted_view.transform.compute_matrix().inverse();
+
+    // the planes are tested against the tile bounds transformed by the model matrix
+    let planes = planes(
+        &view_proj,
+        &extracted_view.transform.translation(),
+        &extracted_view.transform.back(),
+        10000.0,
+    );
+
+    (view_proj, planes)
+}
+
 pub(crate) fn queue_terrain_culling_bind_group(
     device: Res<RenderDevice>,
     compute_pipelines: Res<TerrainComputePipelines>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    depth_pyramids: Res<TerrainViewComponents<DepthPyramid>>,
     mut culling_bind_groups: ResMut<TerrainViewComponents<CullingBindGroup>>,
     terrain_query: Query<(Entity, &MeshUniform), With<Terrain>>,
     view_query: Query<(Entity, &ExtractedView), With<TerrainView>>,
+    light_query: Query<Entity, (With<TerrainView>, Without<ExtractedView>)>,
+    shadow_view_query: Query<(&ExtractedView, &LightEntity)>,
 ) {
-    for (view, extracted_view) in view_query.iter() {
-        let view_proj =
-            extracted_view.projection * extracted_view.transform.compute_matrix().inverse();
+    let mut views: Vec<_> = view_query
+        .iter()
+        .map(|(view, extracted_view)| (view, view_frustum(extracted_view)))
+        .collect();
 
-        let _planes = planes(
-            &extracted_view.projection,
-            &extracted_view.transform.translation(),
-            &extracted_view.transform.back(),
-            10000.0,
-        );
+    // shadow casting lights render their shadow views from a single tessellation
+    for light in light_query.iter() {
+        let directional_view = shadow_view_query
+            .iter()
+            .find(|(_, light_entity)| match light_entity {
+                LightEntity::Directional { light_entity } => *light_entity == light,
+                LightEntity::Point { .. } => false,
+            })
+            .map(|(extracted_view, _)| extracted_view);
 
-        // dbg!(&planes);
+        // only directional lights are supported, the terrain is not queued into the shadow
+        // phases of other lights
+        let frustum = match directional_view {
+            Some(extracted_view) => view_frustum(extracted_view),
+            None => (Mat4::IDENTITY, [Vec4::ZERO; 6]),
+        };
 
-        let planes = [default(); 6];
+        views.push((light, frustum));
+    }
 
+    for (view, (view_proj, planes)) in views {
         for (terrain, mesh_uniform) in terrain_query.iter() {
+            let gpu_node_atlas = gpu_node_atlases.get(&terrain).unwrap();
+            let depth_pyramid = match depth_pyramids.get(&(terrain, view)) {
+                Some(depth_pyramid) => depth_pyramid,
+                None => continue,
+            };
+
             let culling_data = CullingData {
-                world_position: extracted_view.transform.translation().xyzx(),
                 view_proj,
                 model: mesh_uniform.transform,
                 planes,
+                previous_view_proj: depth_pyramid.previous_view_proj,
             };
 
-            let mut buffer = encase::UniformBuffer::new(Vec::new());
-            buffer.write(&culling_data).unwrap();
-
-            let buffer = device.create_buffer_with_data(&BufferInitDescriptor {
-                label: None,
-                contents: &buffer.into_inner(),
-                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-            });
-
-            let cull_bind_group = device.create_bind_group(&BindGroupDescriptor {
-                entries: &[BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                }],
-                label: None,
-                layout: &compute_pipelines.cull_data_layout,
-            });
-
             culling_bind_groups.insert(
                 (terrain, view),
-                CullingBindGroup {
-                    value: cull_bind_group,
-                },
+                CullingBindGroup::new(
+                    &device,
+                    &compute_pipelines,
+                    gpu_node_atlas,
+                    depth_pyramid,
+                    &culling_data,
+                ),
             );
         }
     }
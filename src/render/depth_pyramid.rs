@@ -0,0 +1,391 @@
+use crate::{
+    render::{
+        compute_pipelines::{
+            TerrainComputePipelineFlags, TerrainComputePipelineId, TerrainComputePipelines,
+        },
+        TerrainPipelineConfig,
+    },
+    terrain::Terrain,
+    DebugTerrain, TerrainView, TerrainViewComponents,
+};
+use bevy::{
+    core_pipeline::core_3d::Opaque3d,
+    ecs::system::{
+        lifetimeless::{SRes, SResMut},
+        SystemState,
+    },
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{self},
+        render_phase::RenderPhase,
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        texture::TextureCache,
+        view::{ExtractedView, ViewDepthTexture},
+    },
+};
+use std::{mem, num::NonZeroU32};
+
+/// The hierarchical depth buffer of a view, used for the occlusion culling of the terrain tiles.
+///
+/// Each texel stores the farthest depth of all texels of the level below it.
+/// The first level is the largest power of two fitting into the view and is built from
+/// the depth of the view, after all cameras have been rendered.
+/// Thus the tiles are culled against the depth of the previous frame.
+pub struct DepthPyramid {
+    /// The view of all levels of the pyramid.
+    pub(crate) view: TextureView,
+    /// The size of the first level.
+    size: UVec2,
+    /// The size of the view, the pyramid has been created for.
+    view_size: UVec2,
+    /// The view projection matrix of the depth stored in the pyramid.
+    pub(crate) previous_view_proj: Mat4,
+    /// The view projection matrix of the depth, the pyramid is built from this frame.
+    view_proj: Mat4,
+    /// The views of the individual levels.
+    level_views: Vec<TextureView>,
+    /// The bind groups to down sample each level into the next one.
+    level_bind_groups: Vec<BindGroup>,
+    /// The bind group to build the first level from the depth of this frame.
+    source_bind_group: Option<BindGroup>,
+}
+
+impl DepthPyramid {
+    fn new(
+        device: &RenderDevice,
+        compute_pipelines: &TerrainComputePipelines,
+        view_size: UVec2,
+        view_proj: Mat4,
+    ) -> Self {
+        // the largest power of two fitting into the view
+        let size = UVec2::new(
+            1 << (31 - view_size.x.max(1).leading_zeros()),
+            1 << (31 - view_size.y.max(1).leading_zeros()),
+        );
+        let level_count = 32 - size.max_element().leading_zeros();
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("depth_pyramid"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+        });
+
+        let view = texture.create_view(&TextureViewDescriptor::default());
+
+        let level_views: Vec<TextureView> = (0..level_count)
+            .map(|level| {
+                texture.create_view(&TextureViewDescriptor {
+                    base_mip_level: level,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..default()
+                })
+            })
+            .collect();
+
+        let level_bind_groups = level_views
+            .windows(2)
+            .map(|levels| {
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: "depth_pyramid_bind_group".into(),
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&levels[1]),
+                        },
+                        BindGroupEntry {
+                            binding: 2,
+                            resource: BindingResource::TextureView(&levels[0]),
+                        },
+                    ],
+                    layout: &compute_pipelines.depth_pyramid_layout,
+                })
+            })
+            .collect();
+
+        Self {
+            view,
+            size,
+            view_size,
+            previous_view_proj: view_proj,
+            view_proj,
+            level_views,
+            level_bind_groups,
+            source_bind_group: None,
+        }
+    }
+
+    /// Creates a pyramid, which never occludes anything, for views without a depth texture
+    /// (e.g. shadow casting lights). Its single texel is zero initialized to the farthest depth.
+    fn empty(device: &RenderDevice, compute_pipelines: &TerrainComputePipelines) -> Self {
+        Self::new(device, compute_pipelines, UVec2::ONE, Mat4::IDENTITY)
+    }
+
+    /// Prepares the pyramid to be built from the depth of this frame.
+    fn update(
+        &mut self,
+        device: &RenderDevice,
+        source_layout: &BindGroupLayout,
+        depth: &TextureView,
+        view_proj: Mat4,
+    ) {
+        self.previous_view_proj = mem::replace(&mut self.view_proj, view_proj);
+
+        self.source_bind_group = Some(device.create_bind_group(&BindGroupDescriptor {
+            label: "depth_pyramid_source_bind_group".into(),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(depth),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&self.level_views[0]),
+                },
+            ],
+            layout: source_layout,
+        }));
+    }
+
+    fn build<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        build_pipeline: &'a ComputePipeline,
+        down_sample_pipeline: &'a ComputePipeline,
+    ) {
+        let source_bind_group = match &self.source_bind_group {
+            Some(source_bind_group) => source_bind_group,
+            None => return,
+        };
+
+        pass.set_pipeline(build_pipeline);
+        pass.set_bind_group(0, source_bind_group, &[]);
+        pass.dispatch_workgroups((self.size.x + 7) / 8, (self.size.y + 7) / 8, 1);
+
+        pass.set_pipeline(down_sample_pipeline);
+
+        for (level, bind_group) in self.level_bind_groups.iter().enumerate() {
+            let level = level as u32 + 1;
+            let width = (self.size.x >> level).max(1);
+            let height = (self.size.y >> level).max(1);
+
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups((width + 7) / 8, (height + 7) / 8, 1);
+        }
+    }
+}
+
+/// Replaces the depth textures of the terrain views with ones, that can be sampled as well,
+/// when occlusion culling is enabled.
+/// The depth textures prepared by bevy can only be used as render attachments, thus the depth
+/// pyramid could not be built from them.
+pub(crate) fn prepare_terrain_depth_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    device: Res<RenderDevice>,
+    msaa: Res<Msaa>,
+    config: Res<TerrainPipelineConfig>,
+    view_query: Query<(Entity, &ExtractedCamera), (With<TerrainView>, With<RenderPhase<Opaque3d>>)>,
+) {
+    if !config.occlusion_culling {
+        return;
+    }
+
+    for (view, camera) in view_query.iter() {
+        let size = match camera.physical_target_size {
+            Some(size) => size,
+            None => continue,
+        };
+
+        let depth = texture_cache.get(
+            &device,
+            TextureDescriptor {
+                label: Some("terrain_view_depth_texture"),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: msaa.samples,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Depth32Float,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            },
+        );
+
+        commands.entity(view).insert(ViewDepthTexture {
+            texture: depth.texture,
+            view: depth.default_view,
+        });
+    }
+}
+
+/// Creates the depth pyramids of all terrain views and prepares them to be built from
+/// the depth of this frame. Shadow casting lights receive an empty pyramid.
+///
+/// Without occlusion culling all views receive an empty pyramid, which is never built.
+pub(crate) fn queue_depth_pyramids(
+    device: Res<RenderDevice>,
+    msaa: Res<Msaa>,
+    config: Res<TerrainPipelineConfig>,
+    compute_pipelines: Res<TerrainComputePipelines>,
+    mut depth_pyramids: ResMut<TerrainViewComponents<DepthPyramid>>,
+    terrain_query: Query<Entity, With<Terrain>>,
+    view_query: Query<(Entity, &ExtractedView, &ViewDepthTexture), With<TerrainView>>,
+    light_query: Query<Entity, (With<TerrainView>, Without<ViewDepthTexture>)>,
+) {
+    if !config.occlusion_culling {
+        for view in view_query
+            .iter()
+            .map(|(view, ..)| view)
+            .chain(light_query.iter())
+        {
+            for terrain in terrain_query.iter() {
+                depth_pyramids
+                    .entry((terrain, view))
+                    .or_insert_with(|| DepthPyramid::empty(&device, &compute_pipelines));
+            }
+        }
+
+        return;
+    }
+
+    let source_layout = if msaa.samples > 1 {
+        &compute_pipelines.depth_pyramid_multisampled_source_layout
+    } else {
+        &compute_pipelines.depth_pyramid_source_layout
+    };
+
+    for (view, extracted_view, depth) in view_query.iter() {
+        let view_size = UVec2::new(extracted_view.width, extracted_view.height);
+        let view_proj =
+            extracted_view.projection * extracted_view.transform.compute_matrix().inverse();
+
+        for terrain in terrain_query.iter() {
+            // recreate the pyramid, when the view has been resized
+            if depth_pyramids
+                .get(&(terrain, view))
+                .map_or(true, |depth_pyramid| depth_pyramid.view_size != view_size)
+            {
+                depth_pyramids.insert(
+                    (terrain, view),
+                    DepthPyramid::new(&device, &compute_pipelines, view_size, view_proj),
+                );
+            }
+
+            let depth_pyramid = depth_pyramids.get_mut(&(terrain, view)).unwrap();
+            depth_pyramid.update(&device, source_layout, &depth.view, view_proj);
+        }
+    }
+
+    for light in light_query.iter() {
+        for terrain in terrain_query.iter() {
+            depth_pyramids
+                .entry((terrain, light))
+                .or_insert_with(|| DepthPyramid::empty(&device, &compute_pipelines));
+        }
+    }
+}
+
+/// Builds the depth pyramids of all terrain views, after all cameras have been rendered.
+pub struct TerrainDepthPyramidNode {
+    terrain_query: QueryState<Entity, With<Terrain>>,
+    view_query: QueryState<Entity, With<TerrainView>>,
+    system_state: SystemState<(
+        SResMut<PipelineCache>,
+        SResMut<SpecializedComputePipelines<TerrainComputePipelines>>,
+        SRes<TerrainComputePipelines>,
+        SRes<DebugTerrain>,
+        SRes<TerrainPipelineConfig>,
+        SRes<Msaa>,
+    )>,
+    build_pipeline: CachedComputePipelineId,
+    down_sample_pipeline: CachedComputePipelineId,
+}
+
+impl FromWorld for TerrainDepthPyramidNode {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            terrain_query: world.query_filtered(),
+            view_query: world.query_filtered(),
+            system_state: SystemState::new(world),
+            build_pipeline: CachedComputePipelineId::INVALID,
+            down_sample_pipeline: CachedComputePipelineId::INVALID,
+        }
+    }
+}
+
+impl render_graph::Node for TerrainDepthPyramidNode {
+    fn update(&mut self, world: &mut World) {
+        self.terrain_query.update_archetypes(world);
+        self.view_query.update_archetypes(world);
+
+        let (mut pipeline_cache, mut pipelines, pipeline, debug, config, msaa) =
+            self.system_state.get_mut(world);
+
+        if !config.occlusion_culling {
+            return;
+        }
+
+        let flags = TerrainComputePipelineFlags::from_settings(&debug, &config, &msaa);
+
+        self.build_pipeline = pipelines.specialize(
+            &mut pipeline_cache,
+            &pipeline,
+            (TerrainComputePipelineId::BuildDepthPyramid, flags),
+        );
+        self.down_sample_pipeline = pipelines.specialize(
+            &mut pipeline_cache,
+            &pipeline,
+            (TerrainComputePipelineId::DownSampleDepthPyramid, flags),
+        );
+    }
+
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        if !world.resource::<TerrainPipelineConfig>().occlusion_culling {
+            return Ok(());
+        }
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let depth_pyramids = world.resource::<TerrainViewComponents<DepthPyramid>>();
+
+        let (build_pipeline, down_sample_pipeline) = match (
+            pipeline_cache.get_compute_pipeline(self.build_pipeline),
+            pipeline_cache.get_compute_pipeline(self.down_sample_pipeline),
+        ) {
+            (Some(build_pipeline), Some(down_sample_pipeline)) => {
+                (build_pipeline, down_sample_pipeline)
+            }
+            _ => return Ok(()), // some pipelines are not loaded yet
+        };
+
+        let pass = &mut context
+            .command_encoder
+            .begin_compute_pass(&ComputePassDescriptor::default());
+
+        for terrain in self.terrain_query.iter_manual(world) {
+            for view in self.view_query.iter_manual(world) {
+                if let Some(depth_pyramid) = depth_pyramids.get(&(terrain, view)) {
+                    depth_pyramid.build(pass, build_pipeline, down_sample_pipeline);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
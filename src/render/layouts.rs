@@ -2,7 +2,7 @@ use crate::{
     render::culling::CullingData, terrain::TerrainConfigUniform,
     terrain_view::TerrainViewConfigUniform,
 };
-use bevy::render::render_resource::*;
+use bevy::{pbr::MeshUniform, render::render_resource::*};
 use std::mem;
 
 pub(crate) const TERRAIN_CONFIG_SIZE: BufferAddress =
@@ -11,6 +11,8 @@ pub(crate) const TERRAIN_VIEW_CONFIG_SIZE: BufferAddress =
     mem::size_of::<TerrainViewConfigUniform>() as BufferAddress;
 pub(crate) const CULL_DATA_BUFFER_SIZE: BufferAddress =
     mem::size_of::<CullingData>() as BufferAddress;
+pub(crate) const MESH_UNIFORM_SIZE: BufferAddress = mem::size_of::<MeshUniform>() as BufferAddress;
+pub(crate) const HEIGHT_BOUNDS_SIZE: BufferAddress = 2 * 4;
 pub(crate) const TILE_SIZE: BufferAddress = 6 * 4;
 pub(crate) const INDIRECT_BUFFER_SIZE: BufferAddress = 5 * 4;
 pub(crate) const PARAMETER_BUFFER_SIZE: BufferAddress = 6 * 4;
@@ -46,6 +48,98 @@ pub(crate) const CULL_DATA_LAYOUT: BindGroupLayoutDescriptor = BindGroupLayoutDe
             },
             count: None,
         },
+        // depth pyramid
+        BindGroupLayoutEntry {
+            binding: 1,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
+        // height bounds
+        BindGroupLayoutEntry {
+            binding: 2,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(HEIGHT_BOUNDS_SIZE),
+            },
+            count: None,
+        },
+    ],
+};
+
+const DEPTH_PYRAMID_LEVEL_ENTRY: BindGroupLayoutEntry = BindGroupLayoutEntry {
+    binding: 1,
+    visibility: ShaderStages::COMPUTE,
+    ty: BindingType::StorageTexture {
+        access: StorageTextureAccess::WriteOnly,
+        format: TextureFormat::R32Float,
+        view_dimension: TextureViewDimension::D2,
+    },
+    count: None,
+};
+
+pub(crate) const DEPTH_PYRAMID_SOURCE_LAYOUT: BindGroupLayoutDescriptor =
+    BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            // view depth
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Depth,
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            // first level
+            DEPTH_PYRAMID_LEVEL_ENTRY,
+        ],
+    };
+
+pub(crate) const DEPTH_PYRAMID_MULTISAMPLED_SOURCE_LAYOUT: BindGroupLayoutDescriptor =
+    BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            // view depth
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Depth,
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: true,
+                },
+                count: None,
+            },
+            // first level
+            DEPTH_PYRAMID_LEVEL_ENTRY,
+        ],
+    };
+
+pub(crate) const DEPTH_PYRAMID_LAYOUT: BindGroupLayoutDescriptor = BindGroupLayoutDescriptor {
+    label: None,
+    entries: &[
+        // level
+        DEPTH_PYRAMID_LEVEL_ENTRY,
+        // previous level
+        BindGroupLayoutEntry {
+            binding: 2,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        },
     ],
 };
 
@@ -146,5 +240,16 @@ pub(crate) const TERRAIN_VIEW_LAYOUT: BindGroupLayoutDescriptor = BindGroupLayou
             },
             count: None,
         },
+        // mesh
+        BindGroupLayoutEntry {
+            binding: 3,
+            visibility: ShaderStages::VERTEX_FRAGMENT,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(MESH_UNIFORM_SIZE),
+            },
+            count: None,
+        },
     ],
 };
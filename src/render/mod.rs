@@ -1,30 +1,46 @@
 use crate::{
+    data_structures::AttachmentIndex,
+    preprocess::normal::NormalEncoding,
     render::{
         terrain_data::SetTerrainBindGroup,
+        terrain_material::SetTerrainMaterialBindGroup,
         terrain_view_data::{DrawTerrainCommand, SetTerrainViewBindGroup},
     },
     terrain::Terrain,
+    terrain_manifest::{TerrainLoadError, TerrainManifest},
 };
 use bevy::{
-    pbr::{MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{MeshUniform, SetMeshViewBindGroup, SetShadowViewBindGroup},
     prelude::*,
     render::{render_phase::SetItemPipeline, Extract},
 };
 
 pub(crate) mod compute_pipelines;
 pub(crate) mod culling;
+pub(crate) mod depth_pyramid;
 pub(crate) mod layouts;
 pub(crate) mod render_pipeline;
 pub(crate) mod shaders;
+pub(crate) mod splat_material;
 pub(crate) mod terrain_data;
+pub(crate) mod terrain_material;
 pub(crate) mod terrain_view_data;
 
 /// Configures the default terrain pipeline.
 pub struct TerrainPipelineConfig {
-    /// The path of the terrain shader.
+    /// The path of the terrain shader, which is used by materials without their own shaders.
     pub shader: String,
     /// The number of terrain attachments.
     pub attachment_count: usize,
+    /// The index and the encoding of the baked normal attachment, if the terrain has one.
+    /// Otherwise the normals are calculated from the height attachment.
+    /// Use [`TerrainPipelineConfig::from_manifest`] to read it from the preprocessed terrain.
+    pub normal_attachment: Option<(AttachmentIndex, NormalEncoding)>,
+    /// The index of the albedo attachment, if the terrain has one.
+    pub albedo_attachment: Option<AttachmentIndex>,
+    /// Whether tiles hidden behind the depth of the previous frame are culled.
+    /// The first attachment has to be the height.
+    pub occlusion_culling: bool,
 }
 
 impl Default for TerrainPipelineConfig {
@@ -32,21 +48,63 @@ impl Default for TerrainPipelineConfig {
         Self {
             shader: "shaders/terrain.wgsl".into(),
             attachment_count: 2,
+            normal_attachment: None,
+            albedo_attachment: None,
+            occlusion_culling: false,
         }
     }
 }
 
+impl TerrainPipelineConfig {
+    /// Creates the config of a terrain from the manifest written during preprocessing,
+    /// so that it matches the `attachments` loaded by
+    /// [`TerrainConfig::load`](crate::terrain::TerrainConfig::load).
+    /// The albedo attachment is the one named `"albedo"`.
+    pub fn from_manifest(
+        manifest: &TerrainManifest,
+        attachments: &[&str],
+    ) -> Result<Self, TerrainLoadError> {
+        let attachments = manifest.select_attachments(attachments)?;
+
+        Ok(Self {
+            attachment_count: attachments.len(),
+            normal_attachment: attachments
+                .iter()
+                .enumerate()
+                .find_map(|(index, attachment)| Some((index, attachment.normal_encoding?))),
+            albedo_attachment: attachments
+                .iter()
+                .position(|attachment| attachment.name == "albedo"),
+            ..default()
+        })
+    }
+}
+
 /// The draw function of the terrain. It sets the pipeline and the bind groups and then issues the
 /// draw call.
-pub(crate) type DrawTerrain = (
+pub(crate) type DrawTerrain<M> = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetTerrainViewBindGroup<1>,
     SetTerrainBindGroup<2>,
-    SetMeshBindGroup<3>,
+    SetTerrainMaterialBindGroup<M, 3>,
     DrawTerrainCommand,
 );
 
+/// The draw function of the terrain into the shadow maps of the lights.
+pub(crate) type DrawTerrainShadow<M> = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetTerrainViewBindGroup<1>,
+    SetTerrainBindGroup<2>,
+    SetTerrainMaterialBindGroup<M, 3>,
+    DrawTerrainCommand,
+);
+
+/// The flag of the [`MeshUniform`], that lets the terrain receive shadows
+/// (`MESH_FLAGS_SHADOW_RECEIVER_BIT` in `bevy_pbr::mesh_types`).
+const MESH_FLAGS_SHADOW_RECEIVER_BIT: u32 = 1 << 0;
+
 /// Extracts the [`MeshUniform`] data of all terrains.
 pub(crate) fn extract_terrain(
     mut commands: Commands,
@@ -56,7 +114,7 @@ pub(crate) fn extract_terrain(
         let transform = transform.compute_matrix();
 
         commands.get_or_spawn(entity).insert(MeshUniform {
-            flags: 0,
+            flags: MESH_FLAGS_SHADOW_RECEIVER_BIT,
             transform,
             inverse_transpose_model: transform.inverse().transpose(),
         });
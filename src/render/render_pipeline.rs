@@ -1,13 +1,24 @@
+use crate::preprocess::normal::NormalEncoding;
 use crate::render::terrain_data::terrain_bind_group_layout;
 use crate::render::TerrainPipelineConfig;
-use crate::{render::layouts::TERRAIN_VIEW_LAYOUT, DebugTerrain, DrawTerrain, Terrain};
+use crate::{
+    render::{
+        layouts::TERRAIN_VIEW_LAYOUT,
+        terrain_material::{
+            RenderTerrainMaterials, TerrainMaterial, TerrainMaterialKey, TerrainMaterialPipeline,
+        },
+        DrawTerrain, DrawTerrainShadow,
+    },
+    DebugTerrain, Terrain, TerrainView,
+};
 use bevy::core_pipeline::core_3d::Opaque3d;
 use bevy::render::render_phase::{DrawFunctions, RenderPhase};
 use bevy::{
-    pbr::MeshPipeline,
+    pbr::{LightEntity, MeshPipeline, Shadow, ShadowPipeline},
     prelude::*,
     render::{render_resource::*, renderer::RenderDevice, texture::BevyDefault},
 };
+use std::hash::Hash;
 
 bitflags::bitflags! {
 #[repr(transparent)]
@@ -23,6 +34,9 @@ pub struct TerrainPipelineKey: u32 {
     const BRIGHT             = (1 << 7);
     const LIGHTING           = (1 << 8);
     const TEST               = (1 << 9);
+    const NORMAL_ATTACHMENT  = (1 << 10);
+    const NORMAL_OCTAHEDRAL  = (1 << 11);
+    const SHADOW             = (1 << 12);
     const MSAA_RESERVED_BITS = TerrainPipelineKey::MSAA_MASK_BITS << TerrainPipelineKey::MSAA_SHIFT_BITS;
 }
 }
@@ -77,6 +91,25 @@ impl TerrainPipelineKey {
         key
     }
 
+    pub fn from_config(config: &TerrainPipelineConfig) -> Self {
+        match config.normal_attachment {
+            None => TerrainPipelineKey::NONE,
+            Some((_, NormalEncoding::XZ)) => TerrainPipelineKey::NORMAL_ATTACHMENT,
+            Some((_, NormalEncoding::Octahedral)) => {
+                TerrainPipelineKey::NORMAL_ATTACHMENT | TerrainPipelineKey::NORMAL_OCTAHEDRAL
+            }
+        }
+    }
+
+    /// Returns the key of the depth only pipeline, which renders the terrain into shadow maps.
+    /// Only the debug settings, that change the geometry, are kept.
+    pub fn shadow(debug: &DebugTerrain) -> Self {
+        TerrainPipelineKey::SHADOW
+            | TerrainPipelineKey::from_msaa_samples(1)
+            | (TerrainPipelineKey::from_debug(debug)
+                & (TerrainPipelineKey::CIRCULAR_LOD | TerrainPipelineKey::MESH_MORPH))
+    }
+
     pub fn msaa_samples(&self) -> u32 {
         ((self.bits >> Self::MSAA_SHIFT_BITS) & Self::MSAA_MASK_BITS) + 1
     }
@@ -122,17 +155,33 @@ impl TerrainPipelineKey {
             shader_defs.push("TEST".to_string());
         }
 
+        if (self.bits & TerrainPipelineKey::NORMAL_ATTACHMENT.bits) != 0 {
+            shader_defs.push("NORMAL_ATTACHMENT".to_string());
+        }
+        if (self.bits & TerrainPipelineKey::NORMAL_OCTAHEDRAL.bits) != 0 {
+            shader_defs.push("NORMAL_OCTAHEDRAL".to_string());
+        }
+
+        if (self.bits & TerrainPipelineKey::SHADOW.bits) != 0 {
+            shader_defs.push("SHADOW".to_string());
+        }
+
         shader_defs
     }
 }
 
 /// The pipeline used to render the terrain entities.
+/// It is extended by the [`TerrainMaterialPipeline`] of each material with its bind group.
+#[derive(Clone)]
 pub struct TerrainRenderPipeline {
     pub(crate) view_layout: BindGroupLayout,
-    pub(crate) mesh_layout: BindGroupLayout,
+    pub(crate) shadow_view_layout: BindGroupLayout,
     pub(crate) terrain_layout: BindGroupLayout,
     pub(crate) terrain_view_layout: BindGroupLayout,
     pub(crate) shader: Handle<Shader>,
+    /// The shader defs selecting the bindings of the albedo and normal attachments,
+    /// whose indices depend on the attachments loaded by the terrain.
+    pub(crate) attachment_defs: Vec<String>,
 }
 
 impl FromWorld for TerrainRenderPipeline {
@@ -140,20 +189,31 @@ impl FromWorld for TerrainRenderPipeline {
         let device = world.resource::<RenderDevice>();
         let asset_server = world.resource::<AssetServer>();
         let mesh_pipeline = world.resource::<MeshPipeline>();
+        let shadow_pipeline = world.resource::<ShadowPipeline>();
         let config = world.resource::<TerrainPipelineConfig>();
 
         let view_layout = mesh_pipeline.view_layout.clone();
-        let mesh_layout = mesh_pipeline.mesh_layout.clone();
+        let shadow_view_layout = shadow_pipeline.view_layout.clone();
         let terrain_layout = terrain_bind_group_layout(&device, config.attachment_count);
         let terrain_view_layout = device.create_bind_group_layout(&TERRAIN_VIEW_LAYOUT);
         let shader = asset_server.load(&config.shader);
 
+        let mut attachment_defs = Vec::new();
+        if let Some(index) = config.albedo_attachment {
+            attachment_defs.push("ALBEDO_ATTACHMENT".to_string());
+            attachment_defs.push(format!("ALBEDO_ATTACHMENT_{index}"));
+        }
+        if let Some((index, _)) = config.normal_attachment {
+            attachment_defs.push(format!("NORMAL_ATTACHMENT_{index}"));
+        }
+
         Self {
             view_layout,
-            mesh_layout,
+            shadow_view_layout,
             terrain_layout,
             terrain_view_layout,
             shader,
+            attachment_defs,
         }
     }
 }
@@ -162,45 +222,63 @@ impl SpecializedRenderPipeline for TerrainRenderPipeline {
     type Key = TerrainPipelineKey;
 
     fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
-        let shader_defs = key.shader_defs();
+        let mut shader_defs = key.shader_defs();
+        shader_defs.extend(self.attachment_defs.iter().cloned());
+        let shadow = (key.bits & TerrainPipelineKey::SHADOW.bits) != 0;
+
+        // shadow maps only store the depth, thus the fragment shader is skipped
+        let (view_layout, cull_mode, fragment, depth_compare) = if shadow {
+            (
+                self.shadow_view_layout.clone(),
+                None,
+                None,
+                CompareFunction::GreaterEqual,
+            )
+        } else {
+            (
+                self.view_layout.clone(),
+                Some(Face::Back),
+                Some(FragmentState {
+                    shader: self.shader.clone(),
+                    shader_defs: shader_defs.clone(),
+                    entry_point: "fragment".into(),
+                    targets: vec![Some(ColorTargetState {
+                        format: TextureFormat::bevy_default(),
+                        blend: Some(BlendState::REPLACE),
+                        write_mask: ColorWrites::ALL,
+                    })],
+                }),
+                CompareFunction::Greater,
+            )
+        };
 
         RenderPipelineDescriptor {
             label: None,
             layout: Some(vec![
-                self.view_layout.clone(),
+                view_layout,
                 self.terrain_view_layout.clone(),
                 self.terrain_layout.clone(), // Todo: do this properly for multiple maps
-                self.mesh_layout.clone(),
             ]),
             vertex: VertexState {
                 shader: self.shader.clone(),
                 entry_point: "vertex".into(),
-                shader_defs: shader_defs.clone(),
+                shader_defs,
                 buffers: Vec::new(),
             },
             primitive: PrimitiveState {
                 front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
+                cull_mode,
                 unclipped_depth: false,
                 polygon_mode: key.polygon_mode(),
                 conservative: false,
                 topology: PrimitiveTopology::TriangleStrip,
                 strip_index_format: None,
             },
-            fragment: Some(FragmentState {
-                shader: self.shader.clone(),
-                shader_defs,
-                entry_point: "fragment".into(),
-                targets: vec![Some(ColorTargetState {
-                    format: TextureFormat::bevy_default(),
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
+            fragment,
             depth_stencil: Some(DepthStencilState {
                 format: TextureFormat::Depth32Float,
                 depth_write_enabled: true,
-                depth_compare: CompareFunction::Greater,
+                depth_compare,
                 stencil: StencilState {
                     front: StencilFaceState::IGNORE,
                     back: StencilFaceState::IGNORE,
@@ -222,25 +300,42 @@ impl SpecializedRenderPipeline for TerrainRenderPipeline {
     }
 }
 
-/// Queses all terrain entities for rendering via the terrain pipeline.
-pub(crate) fn queue_terrain(
-    terrain_pipeline: Res<TerrainRenderPipeline>,
+/// Queues all terrain entities with the material for rendering via its pipeline.
+pub(crate) fn queue_terrain<M: TerrainMaterial>(
+    material_pipeline: Res<TerrainMaterialPipeline<M>>,
     draw_functions: Res<DrawFunctions<Opaque3d>>,
     msaa: Res<Msaa>,
     debug: Res<DebugTerrain>,
-    mut pipelines: ResMut<SpecializedRenderPipelines<TerrainRenderPipeline>>,
+    config: Res<TerrainPipelineConfig>,
+    render_materials: Res<RenderTerrainMaterials<M>>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TerrainMaterialPipeline<M>>>,
     mut pipeline_cache: ResMut<PipelineCache>,
     mut view_query: Query<&mut RenderPhase<Opaque3d>>,
-    terrain_query: Query<Entity, With<Terrain>>,
-) {
-    let draw_function = draw_functions.read().get_id::<DrawTerrain>().unwrap();
+    terrain_query: Query<(Entity, &Handle<M>), With<Terrain>>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = draw_functions.read().get_id::<DrawTerrain<M>>().unwrap();
+
+    let key = TerrainPipelineKey::from_msaa_samples(msaa.samples)
+        | TerrainPipelineKey::from_debug(&debug)
+        | TerrainPipelineKey::from_config(&config);
 
     for mut opaque_phase in view_query.iter_mut() {
-        for entity in terrain_query.iter() {
-            let key = TerrainPipelineKey::from_msaa_samples(msaa.samples)
-                | TerrainPipelineKey::from_debug(&debug);
+        for (entity, material) in terrain_query.iter() {
+            let material = match render_materials.get(material) {
+                Some(material) => material,
+                None => continue,
+            };
 
-            let pipeline = pipelines.specialize(&mut pipeline_cache, &terrain_pipeline, key);
+            let pipeline = pipelines.specialize(
+                &mut pipeline_cache,
+                &material_pipeline,
+                TerrainMaterialKey {
+                    terrain_key: key,
+                    bind_group_data: material.key.clone(),
+                },
+            );
 
             opaque_phase.add(Opaque3d {
                 entity,
@@ -251,3 +346,62 @@ pub(crate) fn queue_terrain(
         }
     }
 }
+
+/// Queues all terrain entities with the material into the shadow phases of the shadow casting
+/// directional lights, which are terrain views.
+/// Point lights are not supported, the terrain does not cast shadows into their cube maps.
+pub(crate) fn queue_terrain_shadows<M: TerrainMaterial>(
+    material_pipeline: Res<TerrainMaterialPipeline<M>>,
+    draw_functions: Res<DrawFunctions<Shadow>>,
+    debug: Res<DebugTerrain>,
+    render_materials: Res<RenderTerrainMaterials<M>>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TerrainMaterialPipeline<M>>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    mut view_query: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    light_query: Query<Entity, With<TerrainView>>,
+    terrain_query: Query<(Entity, &Handle<M>), With<Terrain>>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = draw_functions
+        .read()
+        .get_id::<DrawTerrainShadow<M>>()
+        .unwrap();
+
+    let key = TerrainPipelineKey::shadow(&debug);
+
+    for (light_entity, mut shadow_phase) in view_query.iter_mut() {
+        let light = match light_entity {
+            LightEntity::Directional { light_entity } => *light_entity,
+            LightEntity::Point { .. } => continue,
+        };
+
+        // only lights with their own tessellation can render the terrain
+        if light_query.get(light).is_err() {
+            continue;
+        }
+
+        for (entity, material) in terrain_query.iter() {
+            let material = match render_materials.get(material) {
+                Some(material) => material,
+                None => continue,
+            };
+
+            let pipeline = pipelines.specialize(
+                &mut pipeline_cache,
+                &material_pipeline,
+                TerrainMaterialKey {
+                    terrain_key: key,
+                    bind_group_data: material.key.clone(),
+                },
+            );
+
+            shadow_phase.add(Shadow {
+                entity,
+                pipeline,
+                draw_function,
+                distance: 0.0,
+            });
+        }
+    }
+}
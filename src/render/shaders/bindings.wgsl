@@ -0,0 +1,23 @@
+#define_import_path bevy_terrain::bindings
+
+// Declares the terrain view and the terrain bindings of the terrain pipeline.
+// Requires the imports of bevy_pbr::mesh_types, bevy_terrain::config and bevy_terrain::tile.
+// All attachments except the height (the first one) are declared by the shader itself.
+
+// terrain view bindings
+@group(1) @binding(0)
+var<uniform> view_config: TerrainViewConfig;
+@group(1) @binding(1)
+var quadtree: texture_2d_array<u32>;
+@group(1) @binding(2)
+var<storage> tiles: TileList;
+@group(1) @binding(3)
+var<uniform> mesh: Mesh;
+
+// terrain bindings
+@group(2) @binding(0)
+var<uniform> config: TerrainConfig;
+@group(2) @binding(1)
+var filter_sampler: sampler;
+@group(2) @binding(2)
+var height_atlas: texture_2d_array<f32>;
@@ -1,5 +1,16 @@
 #define_import_path bevy_terrain::config
 
+struct TerrainConfig {
+    lod_count: u32,
+    height: f32,
+    chunk_size: u32,
+    terrain_size: u32,
+    // the scale and offset of the atlas coordinates of the attachments, which skip their borders,
+    // packed into four attachments per vector
+    attachment_scales: array<vec4<f32>, 2>,
+    attachment_offsets: array<vec4<f32>, 2>,
+}
+
 struct TerrainViewConfig {
     height_under_viewer: f32,
 
@@ -13,5 +24,8 @@ struct TerrainViewConfig {
     morph_blend: f32,
     vertex_blend: f32,
     fragment_blend: f32,
+
+    density_thresholds: vec4<f32>,
+    view_position: vec4<f32>,
 }
 
@@ -54,7 +54,7 @@ fn show_lod(lod: u32, world_position: vec3<f32>) -> vec4<f32> {
     var color = lod_color(lod);
 
     for (var i = 0u; i < config.lod_count; i = i + 1u) {
-        let viewer_distance = distance(view.world_position.xyz, world_position);
+        let viewer_distance = distance(view_config.view_position.xyz, world_position);
         let circle = f32(1u << i) * view_config.view_distance;
 
         if (viewer_distance < circle && circle - f32(2 << i) < viewer_distance) {
@@ -63,7 +63,7 @@ fn show_lod(lod: u32, world_position: vec3<f32>) -> vec4<f32> {
 
 #ifndef CIRCULAR_LOD
         let node_size = node_size(i);
-        let grid_position = floor(view.world_position.xz / node_size + 0.5 - f32(view_config.node_count >> 1u)) * node_size;
+        let grid_position = floor(view_config.view_position.xz / node_size + 0.5 - f32(view_config.node_count >> 1u)) * node_size;
         let grid_size = node_size * f32(view_config.node_count);
         let thickness = f32(4u << i);
 
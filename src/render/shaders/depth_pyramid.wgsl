@@ -0,0 +1,78 @@
+// Builds the depth pyramid used for the occlusion culling of the terrain tiles.
+// Because of the reversed z, the farthest depth is the smallest one.
+
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var view_depth: texture_depth_multisampled_2d;
+#endif
+#ifndef MULTISAMPLED
+@group(0) @binding(0)
+var view_depth: texture_depth_2d;
+#endif
+@group(0) @binding(1)
+var level: texture_storage_2d<r32float, write>;
+@group(0) @binding(2)
+var previous_level: texture_2d<f32>;
+
+fn load_view_depth(coords: vec2<i32>) -> f32 {
+#ifdef MULTISAMPLED
+    var depth = 1.0;
+
+    for (var i = 0; i < textureNumSamples(view_depth); i = i + 1) {
+        depth = min(depth, textureLoad(view_depth, coords, i));
+    }
+
+    return depth;
+#endif
+
+#ifndef MULTISAMPLED
+    return textureLoad(view_depth, coords, 0);
+#endif
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn build_depth_pyramid(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let coords = vec2<i32>(invocation_id.xy);
+    let size = textureDimensions(level);
+
+    if (coords.x >= size.x || coords.y >= size.y) {
+        return;
+    }
+
+    // the first level is smaller than the view, thus each texel covers up to 3x3 texels
+    let view_size = textureDimensions(view_depth);
+    let scale = vec2<f32>(view_size) / vec2<f32>(size);
+    let start = vec2<i32>(floor(vec2<f32>(coords) * scale));
+    let end = min(vec2<i32>(ceil(vec2<f32>(coords + vec2<i32>(1)) * scale)), view_size);
+
+    var depth = 1.0;
+
+    for (var y = start.y; y < end.y; y = y + 1) {
+        for (var x = start.x; x < end.x; x = x + 1) {
+            depth = min(depth, load_view_depth(vec2<i32>(x, y)));
+        }
+    }
+
+    textureStore(level, coords, vec4<f32>(depth));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn down_sample_depth_pyramid(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let coords = vec2<i32>(invocation_id.xy);
+    let size = textureDimensions(level);
+
+    if (coords.x >= size.x || coords.y >= size.y) {
+        return;
+    }
+
+    let previous_size = textureDimensions(previous_level);
+
+    var depth = 1.0;
+
+    for (var i = 0; i < 4; i = i + 1) {
+        let previous_coords = min(coords * 2 + vec2<i32>(i & 1, i >> 1u), previous_size - 1);
+        depth = min(depth, textureLoad(previous_level, previous_coords, 0).x);
+    }
+
+    textureStore(level, coords, vec4<f32>(depth));
+}
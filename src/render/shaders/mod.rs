@@ -12,11 +12,17 @@ const TERRAIN_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 234313897973543254);
 const DEBUG_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 513467378691355413);
+const BINDINGS_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 807261934516283579);
 
+pub(crate) const SPLAT_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 318274695013427791);
 pub(crate) const PREPARE_INDIRECT_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 242384313596767307);
 pub(crate) const TESSELATION_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 938732132468373352);
+pub(crate) const DEPTH_PYRAMID_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 471530865249187624);
 
 pub(crate) fn add_shader(app: &mut App) {
     let mut assets = app.world.resource_mut::<Assets<_>>();
@@ -36,6 +42,11 @@ pub(crate) fn add_shader(app: &mut App) {
         Shader::from_wgsl(include_str!("terrain.wgsl")),
     );
     assets.set_untracked(DEBUG_SHADER, Shader::from_wgsl(include_str!("debug.wgsl")));
+    assets.set_untracked(
+        BINDINGS_SHADER,
+        Shader::from_wgsl(include_str!("bindings.wgsl")),
+    );
+    assets.set_untracked(SPLAT_SHADER, Shader::from_wgsl(include_str!("splat.wgsl")));
     assets.set_untracked(
         PREPARE_INDIRECT_SHADER,
         Shader::from_wgsl(include_str!("prepare_indirect.wgsl")),
@@ -44,4 +55,8 @@ pub(crate) fn add_shader(app: &mut App) {
         TESSELATION_SHADER,
         Shader::from_wgsl(include_str!("tessellation.wgsl")),
     );
+    assets.set_untracked(
+        DEPTH_PYRAMID_SHADER,
+        Shader::from_wgsl(include_str!("depth_pyramid.wgsl")),
+    );
 }
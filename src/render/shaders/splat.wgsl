@@ -0,0 +1,271 @@
+// The shader of the splat material.
+// Blends tiled material layers by the weights of a splat map attachment.
+// The layers are blended by their heights and projected triplanar on steep slopes.
+
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_types
+#import bevy_terrain::config
+#import bevy_terrain::tile
+#import bevy_terrain::bindings
+
+struct SplatSettings {
+    layer_count: u32,
+    weight_attachment: u32,
+    index_blend: u32,
+    tile_size: f32,
+    height_blend: f32,
+    triplanar_start: f32,
+    triplanar_end: f32,
+}
+
+// the weight attachment of the terrain bindings (binding 2 + attachment index)
+#ifdef WEIGHT_ATTACHMENT_1
+@group(2) @binding(3)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_2
+@group(2) @binding(4)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_3
+@group(2) @binding(5)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_4
+@group(2) @binding(6)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_5
+@group(2) @binding(7)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_6
+@group(2) @binding(8)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+#ifdef WEIGHT_ATTACHMENT_7
+@group(2) @binding(9)
+var weight_atlas: texture_2d_array<f32>;
+#endif
+
+// material bindings
+@group(3) @binding(0)
+var<uniform> splat: SplatSettings;
+@group(3) @binding(1)
+var albedo_layers: texture_2d_array<f32>;
+@group(3) @binding(2)
+var layer_sampler: sampler;
+@group(3) @binding(3)
+var normal_layers: texture_2d_array<f32>;
+@group(3) @binding(4)
+var roughness_layers: texture_2d_array<f32>;
+
+#import bevy_pbr::pbr_types
+#import bevy_pbr::utils
+#import bevy_pbr::clustered_forward
+#import bevy_pbr::lighting
+#import bevy_pbr::shadows
+#import bevy_pbr::pbr_functions
+
+#import bevy_terrain::atlas
+#import bevy_terrain::terrain
+#import bevy_terrain::debug
+
+// The (up to four) layers covering a position and their weights.
+struct Splat {
+    layers: vec4<i32>,
+    weights: vec4<f32>,
+}
+
+// The blended layers. The normal is in tangent space for a single projection
+// and in world space after all projections have been combined.
+struct Surface {
+    albedo: vec3<f32>,
+    normal: vec3<f32>,
+    roughness: f32,
+}
+
+// The texture coordinates of the layers in world space and their screen space derivatives.
+// The derivatives are computed upfront, because the layers are sampled in non-uniform control flow.
+struct LayerCoords {
+    position: vec3<f32>,
+    dx: vec3<f32>,
+    dy: vec3<f32>,
+}
+
+fn sample_splat(atlas_index: i32, atlas_coords: vec2<f32>) -> Splat {
+    let coords = attachment_coords(splat.weight_attachment, atlas_coords);
+
+    if (splat.index_blend == 1u) {
+        // material indices must never be interpolated
+        let size = textureDimensions(weight_atlas);
+        let texel = clamp(vec2<i32>(coords * vec2<f32>(size)), vec2<i32>(0), size - 1);
+        let value = textureLoad(weight_atlas, texel, atlas_index, 0);
+        let layers = vec2<i32>(round(value.xy * 255.0));
+
+        return Splat(vec4<i32>(layers, 0, 0), vec4<f32>(1.0 - value.z, value.z, 0.0, 0.0));
+    }
+
+    let weights = textureSampleLevel(weight_atlas, filter_sampler, coords, atlas_index, 0.0);
+
+    return Splat(vec4<i32>(0, 1, 2, 3), weights);
+}
+
+// Samples the layers of the splat and blends them by their weights and heights.
+fn blend_layers(s: Splat, uv: vec2<f32>, dx: vec2<f32>, dy: vec2<f32>) -> Surface {
+    var albedo = array<vec3<f32>, 4>(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
+    var normal = array<vec3<f32>, 4>(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
+    var roughness = vec4<f32>(0.0);
+    var heights = vec4<f32>(0.0);
+
+    for (var i = 0; i < 4; i = i + 1) {
+        if (s.weights[i] > 0.0) {
+            let layer = clamp(s.layers[i], 0, i32(splat.layer_count) - 1);
+            let color = textureSampleGrad(albedo_layers, layer_sampler, uv, layer, dx, dy);
+
+            albedo[i] = color.rgb;
+            heights[i] = color.a;
+            normal[i] = textureSampleGrad(normal_layers, layer_sampler, uv, layer, dx, dy).xyz * 2.0 - 1.0;
+            roughness[i] = textureSampleGrad(roughness_layers, layer_sampler, uv, layer, dx, dy).x;
+        }
+    }
+
+    var weights = s.weights;
+
+    if (splat.height_blend > 0.0) {
+        // only the highest layers within the blend range remain visible
+        let h = heights + s.weights;
+        let cutoff = max(max(h.x, h.y), max(h.z, h.w)) - splat.height_blend;
+        weights = select(vec4<f32>(0.0), max(h - cutoff, vec4<f32>(0.0)), s.weights > vec4<f32>(0.0));
+    }
+
+    weights = weights / max(dot(weights, vec4<f32>(1.0)), 0.0001);
+
+    var surface = Surface(vec3<f32>(0.0), vec3<f32>(0.0), 0.0);
+
+    for (var i = 0; i < 4; i = i + 1) {
+        surface.albedo = surface.albedo + albedo[i] * weights[i];
+        surface.normal = surface.normal + normal[i] * weights[i];
+        surface.roughness = surface.roughness + roughness[i] * weights[i];
+    }
+
+    return surface;
+}
+
+// Projects the layers top down and additionally along the x and z axis on steep slopes.
+// The tangent space normals of the projections are reoriented with whiteout blending.
+fn splat_surface(s: Splat, coords: LayerCoords, normal: vec3<f32>) -> Surface {
+    let slope = 1.0 - normal.y;
+    let triplanar = smoothstep(splat.triplanar_start, splat.triplanar_end, slope);
+
+    var weights = pow(abs(normal), vec3<f32>(4.0));
+    weights = mix(vec3<f32>(0.0, 1.0, 0.0), weights / (weights.x + weights.y + weights.z), triplanar);
+
+    // flip the projections on the back sides, to keep the textures from being mirrored
+    let axis_sign = select(vec3<f32>(-1.0), vec3<f32>(1.0), normal >= vec3<f32>(0.0));
+    let p = coords.position;
+
+    var surface = Surface(vec3<f32>(0.0), vec3<f32>(0.0), 0.0);
+
+    if (weights.x > 0.0) {
+        let flip = vec2<f32>(axis_sign.x, 1.0);
+        let x = blend_layers(s, p.zy * flip, coords.dx.zy * flip, coords.dy.zy * flip);
+        let tangent = x.normal.xy * flip;
+
+        surface.albedo = surface.albedo + x.albedo * weights.x;
+        surface.normal = surface.normal + vec3<f32>(normal.x, tangent.y + normal.y, tangent.x + normal.z) * weights.x;
+        surface.roughness = surface.roughness + x.roughness * weights.x;
+    }
+
+    if (weights.y > 0.0) {
+        let flip = vec2<f32>(axis_sign.y, 1.0);
+        let y = blend_layers(s, p.xz * flip, coords.dx.xz * flip, coords.dy.xz * flip);
+        let tangent = y.normal.xy * flip;
+
+        surface.albedo = surface.albedo + y.albedo * weights.y;
+        surface.normal = surface.normal + vec3<f32>(tangent.x + normal.x, normal.y, tangent.y + normal.z) * weights.y;
+        surface.roughness = surface.roughness + y.roughness * weights.y;
+    }
+
+    if (weights.z > 0.0) {
+        let flip = vec2<f32>(-axis_sign.z, 1.0);
+        let z = blend_layers(s, p.xy * flip, coords.dx.xy * flip, coords.dy.xy * flip);
+        let tangent = z.normal.xy * flip;
+
+        surface.albedo = surface.albedo + z.albedo * weights.z;
+        surface.normal = surface.normal + vec3<f32>(tangent.x + normal.x, tangent.y + normal.y, normal.z) * weights.z;
+        surface.roughness = surface.roughness + z.roughness * weights.z;
+    }
+
+    surface.normal = normalize(surface.normal);
+
+    return surface;
+}
+
+fn lookup_surface(lookup: AtlasLookup, coords: LayerCoords) -> Surface {
+    // the height is always the first attachment, which is bound as the height atlas
+    let height_coords = attachment_coords(0u, lookup.atlas_coords);
+    let normal = calculate_normal(height_coords, lookup.atlas_index, lookup.lod);
+    let s = sample_splat(lookup.atlas_index, lookup.atlas_coords);
+
+    return splat_surface(s, coords, normal);
+}
+
+@vertex
+fn vertex(vertex: VertexInput) -> VertexOutput {
+    return terrain_vertex(vertex);
+}
+
+@fragment
+fn fragment(fragment: FragmentInput) -> FragmentOutput {
+    let position = fragment.world_position.xyz / splat.tile_size;
+    let coords = LayerCoords(position, dpdx(position), dpdy(position));
+
+    let blend = calculate_blend(fragment.world_position.xyz, view_config.fragment_blend);
+
+    let lookup = atlas_lookup(blend.log_distance, fragment.local_position);
+    var surface = lookup_surface(lookup, coords);
+
+    if (blend.ratio < 1.0) {
+        let lookup2 = atlas_lookup(blend.log_distance + 1.0, fragment.local_position);
+        let surface2 = lookup_surface(lookup2, coords);
+
+        surface.albedo = mix(surface2.albedo, surface.albedo, blend.ratio);
+        surface.normal = normalize(mix(surface2.normal, surface.normal, blend.ratio));
+        surface.roughness = mix(surface2.roughness, surface.roughness, blend.ratio);
+    }
+
+    var color = vec4<f32>(surface.albedo, 1.0);
+
+    #ifdef SHOW_LOD
+        color = mix(color, show_lod(lookup.lod, fragment.world_position.xyz), 0.4);
+    #endif
+
+    #ifdef LIGHTING
+        var pbr_input: PbrInput = pbr_input_new();
+        pbr_input.material.base_color = color;
+        pbr_input.material.perceptual_roughness = surface.roughness;
+        pbr_input.material.reflectance = 0.1;
+        pbr_input.frag_coord = fragment.frag_coord;
+        pbr_input.world_position = fragment.world_position;
+        pbr_input.world_normal = surface.normal;
+        pbr_input.is_orthographic = view.projection[3].w == 1.0;
+        pbr_input.N = surface.normal;
+        pbr_input.V = calculate_view(fragment.world_position, pbr_input.is_orthographic);
+
+        color = pbr(pbr_input);
+    #endif
+    #ifndef LIGHTING
+        let ambient = 0.1;
+        let direction = normalize(vec3<f32>(0.0, 1.0, 1.0));
+        let diffuse = max(dot(direction, surface.normal), 0.0);
+
+        color = color * (ambient + diffuse);
+    #endif
+
+    #ifdef SHOW_TILES
+        color = mix(fragment.color, color, 0.8);
+    #endif
+
+    return FragmentOutput(color);
+}
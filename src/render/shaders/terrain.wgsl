@@ -30,7 +30,7 @@ struct Blend {
 }
 
 fn calculate_blend(world_position: vec3<f32>, blend_range: f32) -> Blend {
-    let viewer_distance = distance(world_position, view.world_position.xyz);
+    let viewer_distance = distance(world_position, view_config.view_position.xyz);
     let log_distance = log2(2.0 * viewer_distance / view_config.view_distance);
     let ratio = (1.0 - log_distance % 1.0) / blend_range;
 
@@ -39,7 +39,7 @@ fn calculate_blend(world_position: vec3<f32>, blend_range: f32) -> Blend {
 
 fn calculate_morph(local_position: vec2<f32>, tile: Tile) -> f32 {
     let world_position = vec3<f32>(local_position.x, view_config.height_under_viewer, local_position.y);
-    let viewer_distance = distance(world_position, view.world_position.xyz);
+    let viewer_distance = distance(world_position, view_config.view_position.xyz);
     let morph_distance = f32(tile.size) * view_config.view_distance;
 
     return clamp(1.0 - (1.0 - viewer_distance / morph_distance) / view_config.morph_blend, 0.0, 1.0);
@@ -112,14 +112,46 @@ fn calculate_position(vertex_index: u32, tile: Tile, vertices_per_row: u32, true
     return local_position;
 }
 
+// Returns the coordinates of the attachment inside its atlas, which skip its border.
+fn attachment_coords(attachment: u32, atlas_coords: vec2<f32>) -> vec2<f32> {
+    let scale = config.attachment_scales[attachment >> 2u][attachment & 3u];
+    let offset = config.attachment_offsets[attachment >> 2u][attachment & 3u];
+
+    return atlas_coords * scale + offset;
+}
+
 fn calculate_normal(uv: vec2<f32>, atlas_index: i32, lod: u32) -> vec3<f32> {
     let left  = textureSampleLevel(height_atlas, filter_sampler, uv, atlas_index, 0.0, vec2<i32>(-1,  0)).x;
     let up    = textureSampleLevel(height_atlas, filter_sampler, uv, atlas_index, 0.0, vec2<i32>( 0, -1)).x;
     let right = textureSampleLevel(height_atlas, filter_sampler, uv, atlas_index, 0.0, vec2<i32>( 1,  0)).x;
     let down  = textureSampleLevel(height_atlas, filter_sampler, uv, atlas_index, 0.0, vec2<i32>( 0,  1)).x;
 
-    return normalize(vec3<f32>(right - left, f32(2u << lod) / config.height, down - up));
+    return normalize(vec3<f32>(left - right, f32(2u << lod) / config.height, up - down));
+}
+
+#ifdef NORMAL_ATTACHMENT
+fn decode_normal(encoded: vec2<f32>) -> vec3<f32> {
+    let f = encoded * 2.0 - 1.0;
+
+#ifdef NORMAL_OCTAHEDRAL
+    var normal = vec3<f32>(f.x, 1.0 - abs(f.x) - abs(f.y), f.y);
+    let t = max(-normal.y, 0.0);
+    normal.x = normal.x + select(t, -t, normal.x >= 0.0);
+    normal.z = normal.z + select(t, -t, normal.z >= 0.0);
+
+    return normalize(normal);
+#endif
+
+#ifndef NORMAL_OCTAHEDRAL
+    return vec3<f32>(f.x, sqrt(max(1.0 - dot(f, f), 0.0)), f.y);
+#endif
+}
+
+// Reads the baked normal instead of calculating it from the height attachment.
+fn sample_normal(uv: vec2<f32>, atlas_index: i32) -> vec3<f32> {
+    return decode_normal(textureSample(normal_atlas, filter_sampler, uv, atlas_index).xy);
 }
+#endif
 
 fn vertex_output(local_position: vec2<f32>, height: f32) -> VertexOutput {
     let world_position = mesh.model * vec4<f32>(local_position.x, height, local_position.y, 1.0);
@@ -132,3 +164,49 @@ fn vertex_output(local_position: vec2<f32>, height: f32) -> VertexOutput {
 
     return output;
 }
+
+fn vertex_height(atlas_index: i32, atlas_coords: vec2<f32>) -> f32 {
+    let height_coords = attachment_coords(0u, atlas_coords);
+    return config.height * textureSampleLevel(height_atlas, filter_sampler, height_coords, atlas_index, 0.0).x;
+}
+
+// Computes the position of the vertex inside its tile and displaces it by the blended height.
+// Requires the import of bevy_terrain::debug, if SHOW_TILES is defined.
+fn terrain_vertex(vertex: VertexInput) -> VertexOutput {
+    var tile_lod = 0u;
+    for (; tile_lod < 4u; tile_lod = tile_lod + 1u) {
+        if (vertex.index < tiles.counts[tile_lod].y) {
+            break;
+        }
+    }
+
+    let tile_size = calc_tile_count(tile_lod);
+    let vertices_per_row = (tile_size + 2u) << 1u;
+    let vertices_per_tile = vertices_per_row * tile_size;
+
+    let tile_index  = (vertex.index - tiles.counts[tile_lod].x) / vertices_per_tile + tile_lod * 100000u;
+    let vertex_index = (vertex.index - tiles.counts[tile_lod].x) % vertices_per_tile;
+
+    let tile = tiles.data[tile_index];
+    let local_position = calculate_position(vertex_index, tile, vertices_per_row, tile_size);
+
+    let world_position = vec3<f32>(local_position.x, view_config.height_under_viewer, local_position.y);
+    let blend = calculate_blend(world_position, view_config.vertex_blend);
+
+    let lookup = atlas_lookup(blend.log_distance, local_position);
+    var height = vertex_height(lookup.atlas_index, lookup.atlas_coords);
+
+    if (blend.ratio < 1.0) {
+        let lookup2 = atlas_lookup(blend.log_distance + 1.0, local_position);
+        var height2 = vertex_height(lookup2.atlas_index, lookup2.atlas_coords);
+        height = mix(height2, height, blend.ratio);
+    }
+
+    var output = vertex_output(local_position, height);
+
+#ifdef SHOW_TILES
+    output.color = show_tiles(tile, local_position, tile_lod);
+#endif
+
+    return output;
+}
@@ -4,26 +4,11 @@
 
 // Todo: increase workgroup size
 
-struct TerrainConfig {
-    lod_count: u32,
-    height: f32,
-    chunk_size: u32,
-    terrain_size: u32,
-    height_scale: f32,
-    density_scale: f32,
-    _empty: u32,
-    _empty: u32,
-    height_offset: f32,
-    density_offset: f32,
-    _empty: u32,
-    _empty: u32,
-}
-
 struct CullData {
-    world_position: vec4<f32>,
     view_proj: mat4x4<f32>,
     model: mat4x4<f32>,
     planes: array<vec4<f32>, 6>,
+    previous_view_proj: mat4x4<f32>,
 }
 
 @group(0) @binding(0)
@@ -39,6 +24,10 @@ var<storage, read_write> parameters: Parameters;
 
 @group(1) @binding(0)
 var<uniform> view: CullData;
+@group(1) @binding(1)
+var depth_pyramid: texture_2d<f32>;
+@group(1) @binding(2)
+var<storage> height_bounds: array<vec2<f32>>;
 
  // terrain bindings
 @group(2) @binding(0)
@@ -103,42 +92,114 @@ fn final_index(lod: u32) -> i32 {
     // return atomicAdd(&parameters.final_indices[lod], 1) + i32(lod) * 1000000;
 }
 
-fn frustum_cull(position: vec2<f32>, size: f32) -> bool {
-    let aabb_min = vec3<f32>(position.x, 0.0, position.y);
-    let aabb_max = vec3<f32>(position.x + size, 1000.0, position.y + size);
-
-    var corners = array<vec4<f32>, 8>(
-        vec4<f32>(aabb_min.x, aabb_min.y, aabb_min.z, 1.0),
-        vec4<f32>(aabb_min.x, aabb_min.y, aabb_max.z, 1.0),
-        vec4<f32>(aabb_min.x, aabb_max.y, aabb_min.z, 1.0),
-        vec4<f32>(aabb_min.x, aabb_max.y, aabb_max.z, 1.0),
-        vec4<f32>(aabb_max.x, aabb_min.y, aabb_min.z, 1.0),
-        vec4<f32>(aabb_max.x, aabb_min.y, aabb_max.z, 1.0),
-        vec4<f32>(aabb_max.x, aabb_max.y, aabb_min.z, 1.0),
-        vec4<f32>(aabb_max.x, aabb_max.y, aabb_max.z, 1.0)
+// Returns the minimum and maximum height of the tile, using the height bounds of the node
+// covering it, which include the bounds of its loaded descendants.
+fn tile_height_bounds(position: vec2<f32>, size: f32) -> vec2<f32> {
+    let lookup = atlas_lookup(log2(size), position + 0.5 * size);
+
+    // the coarsest nodes may be smaller than the tile
+    if (node_size(lookup.lod) < size) {
+        return vec2<f32>(0.0, config.height);
+    }
+
+    return height_bounds[lookup.atlas_index] * config.height;
+}
+
+// Returns the corners of the bounding box of the tile in world space.
+fn tile_corners(position: vec2<f32>, size: f32) -> array<vec4<f32>, 8> {
+    let bounds = tile_height_bounds(position, size);
+    let aabb_min = vec3<f32>(position.x, bounds.x, position.y);
+    let aabb_max = vec3<f32>(position.x + size, bounds.y, position.y + size);
+
+    return array<vec4<f32>, 8>(
+        view.model * vec4<f32>(aabb_min.x, aabb_min.y, aabb_min.z, 1.0),
+        view.model * vec4<f32>(aabb_min.x, aabb_min.y, aabb_max.z, 1.0),
+        view.model * vec4<f32>(aabb_min.x, aabb_max.y, aabb_min.z, 1.0),
+        view.model * vec4<f32>(aabb_min.x, aabb_max.y, aabb_max.z, 1.0),
+        view.model * vec4<f32>(aabb_max.x, aabb_min.y, aabb_min.z, 1.0),
+        view.model * vec4<f32>(aabb_max.x, aabb_min.y, aabb_max.z, 1.0),
+        view.model * vec4<f32>(aabb_max.x, aabb_max.y, aabb_min.z, 1.0),
+        view.model * vec4<f32>(aabb_max.x, aabb_max.y, aabb_max.z, 1.0)
     );
+}
+
+// Checks whether the tile lies completely outside of the view frustum.
+fn frustum_cull(bounds_corners: array<vec4<f32>, 8>) -> bool {
+    var corners = bounds_corners;
 
+    // the far plane is ignored, so that the terrain stays visible up to the horizon
     for (var i = 0; i < 5; i = i + 1) {
         let plane = view.planes[i];
 
-        var in = 0u;
+        var outside = true;
 
         for (var j = 0; j < 8; j = j + 1) {
-            let corner = corners[j];
-
-            if (dot(plane, corner) < 0.0) {
-                in = in + 1u;
+            if (dot(plane, corners[j]) >= 0.0) {
+                outside = false;
+                break;
             }
+        }
 
-            if (in == 0u) {
-                return true;
-            }
+        if (outside) {
+            return true;
         }
     }
 
     return false;
 }
 
+// Checks whether the tile is hidden behind the depth of the previous frame.
+fn occlusion_cull(bounds_corners: array<vec4<f32>, 8>) -> bool {
+    var corners = bounds_corners;
+
+    var uv_min = vec2<f32>(1.0);
+    var uv_max = vec2<f32>(0.0);
+    var nearest_depth = 0.0;
+
+    for (var i = 0; i < 8; i = i + 1) {
+        let clip_position = view.previous_view_proj * corners[i];
+
+        // the tile reaches behind the previous view
+        if (clip_position.w <= 0.0) {
+            return false;
+        }
+
+        let ndc_position = clip_position.xyz / clip_position.w;
+        let uv = ndc_position.xy * vec2<f32>(0.5, -0.5) + 0.5;
+
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        // the depth is reversed, thus the nearest depth is the largest one
+        nearest_depth = max(nearest_depth, ndc_position.z);
+    }
+
+    // the tile lies outside of the previous view
+    if (any(uv_max < vec2<f32>(0.0)) || any(uv_min > vec2<f32>(1.0))) {
+        return false;
+    }
+
+    uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+    uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+
+    // select the level, where the tile covers at most 2x2 texels
+    let extent = (uv_max - uv_min) * vec2<f32>(textureDimensions(depth_pyramid, 0));
+    let level = clamp(i32(ceil(log2(max(max(extent.x, extent.y), 1.0)))), 0, textureNumLevels(depth_pyramid) - 1);
+    let level_size = textureDimensions(depth_pyramid, level);
+
+    let start = min(vec2<i32>(uv_min * vec2<f32>(level_size)), level_size - 1);
+    let end = min(vec2<i32>(uv_max * vec2<f32>(level_size)), level_size - 1);
+
+    var farthest_depth = 1.0;
+
+    for (var y = start.y; y <= end.y; y = y + 1) {
+        for (var x = start.x; x <= end.x; x = x + 1) {
+            farthest_depth = min(farthest_depth, textureLoad(depth_pyramid, vec2<i32>(x, y), level).x);
+        }
+    }
+
+    return nearest_depth < farthest_depth;
+}
+
 fn divide(coords: vec2<u32>, size: u32) -> bool {
     var divide = false;
 
@@ -148,7 +209,7 @@ fn divide(coords: vec2<u32>, size: u32) -> bool {
 
         let local_position = vec2<f32>(x, y) * view_config.tile_scale * f32(size);
         let world_position = vec3<f32>(local_position.x, view_config.height_under_viewer, local_position.y);
-        let distance = length(view.world_position.xyz - world_position) * 0.99; // consider adding a small error mitigation
+        let distance = length(view_config.view_position.xyz - world_position) * 0.99; // consider adding a small error mitigation
 
         divide = divide || (distance < f32(size >> 1u) * view_config.view_distance);
     }
@@ -161,11 +222,18 @@ fn tile_lod(coords: vec2<u32>, size: u32) -> u32 {
     let log_distance = log2(view_config.tile_scale * f32(size));
 
     let lookup = atlas_lookup(log_distance, local_position);
-    let slope = textureSampleLevel(density_atlas, filter_sampler, lookup.atlas_coords, lookup.atlas_index, 0.0).x;
+    let density = textureSampleLevel(density_atlas, filter_sampler, lookup.atlas_coords, lookup.atlas_index, 0.0).x;
 
-    let slope = min(slope * 10.0, 0.999);
 #ifdef DENSITY
-    return u32(slope * 4.0);
+    // map the density to one of the four tile resolutions
+    var lod = 0u;
+    for (var i = 0u; i < 3u; i = i + 1u) {
+        if (density > view_config.density_thresholds[i]) {
+            lod = lod + 1u;
+        }
+    }
+
+    return lod;
     // return u32(simplexNoise2(local_position / 1600.0) * 4.0);
 #endif
 
@@ -241,9 +309,19 @@ fn refine_tiles(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 continue;
             }
 
-            // if (frustum_cull(local_position, config.tile_scale * f32(config.tile_size * size))) {
-            //     continue;
-            // }
+            let corners = tile_corners(local_position, view_config.tile_scale * f32(size));
+
+            // cull tiles outside of the view frustum
+            if (frustum_cull(corners)) {
+                continue;
+            }
+
+#ifdef OCCLUSION_CULLING
+            // cull tiles hidden behind the depth of the previous frame
+            if (occlusion_cull(corners)) {
+                continue;
+            }
+#endif
 
             temporary_tiles.data[child_index()] = Tile(vec2<u32>(x, y), size, 0u, 0u, 0u);
         }
@@ -0,0 +1,162 @@
+//! A built-in terrain material, which layers tiled material textures according to a splat map.
+//!
+//! # Explanation
+//! The weights of the layers are read from an attachment of the terrain, e.g. one generated by
+//! [`preprocess_splat`](crate::preprocess::splat::preprocess_splat), which is streamed through
+//! the node atlas like all other attachments.
+//! The albedo, normal and roughness textures of all layers are stored in texture arrays
+//! and repeated across the terrain in world space, so they keep their resolution up close.
+//!
+//! Overlapping layers are blended by the height stored in the alpha channel of their albedo,
+//! so that e.g. grass fills the gaps between stones first.
+//! Steep slopes are projected along all three axes (triplanar), to keep the textures from
+//! stretching. The slope is taken from the normal calculated from the height attachment.
+//!
+//! The weight attachment is sampled from the terrain bindings, whose binding is selected
+//! by a shader def derived from the [`SplatMaterialKey`].
+
+use crate::{
+    editing::attachment_index,
+    preprocess::splat::SplatLayout,
+    render::{
+        shaders::SPLAT_SHADER,
+        terrain_material::{TerrainMaterial, TerrainMaterialKey},
+    },
+    terrain::{TerrainConfig, MAX_ATTACHMENT_COUNT},
+};
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::render_resource::{AsBindGroup, RenderPipelineDescriptor, ShaderRef, ShaderType},
+};
+
+/// The parameters of the [`SplatMaterial`], which are passed to its shader.
+#[derive(Clone, ShaderType)]
+pub struct SplatSettings {
+    /// The number of layers in the texture arrays.
+    pub layer_count: u32,
+    /// The index of the weight attachment.
+    pub weight_attachment: u32,
+    /// Whether the weights are stored in the [`SplatLayout::IndexBlend`] layout.
+    pub index_blend: u32,
+    /// The size in world units, after which the layer textures repeat.
+    pub tile_size: f32,
+    /// The height range, over which overlapping layers are blended.
+    /// Zero disables the height based blending, the layers are blended by their weights only.
+    pub height_blend: f32,
+    /// The slope (zero is flat and one is vertical), at which the triplanar projection starts.
+    pub triplanar_start: f32,
+    /// The slope, at which the triplanar projection is fully blended in.
+    pub triplanar_end: f32,
+}
+
+/// Shades the terrain with tiled material layers, which are blended by the weights of a splat map.
+///
+/// The textures of the layers are stored in texture arrays with one layer per material.
+/// The alpha channel of the albedo stores the height used for the height based blending and
+/// the normals are stored in tangent space.
+/// All textures have to be array textures (e.g. created with
+/// [`Image::reinterpret_stacked_2d_as_array`]) and their sampler of the albedo,
+/// which is used for all layer textures, should repeat.
+///
+/// Baked normals of the [`TerrainPipelineConfig`](super::TerrainPipelineConfig) are ignored,
+/// the normal is always calculated from the height, so that it follows edits of the height.
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "4c5e1e3a-9f0b-4d7e-8a6a-2f1f3b7d9c21"]
+#[bind_group_data(SplatMaterialKey)]
+pub struct SplatMaterial {
+    #[uniform(0)]
+    pub settings: SplatSettings,
+    #[texture(1, dimension = "2d_array")]
+    #[sampler(2)]
+    pub albedo_layers: Handle<Image>,
+    #[texture(3, dimension = "2d_array")]
+    pub normal_layers: Handle<Image>,
+    #[texture(4, dimension = "2d_array")]
+    pub roughness_layers: Handle<Image>,
+}
+
+/// The bind group data of the [`SplatMaterial`], which selects the binding of its weight
+/// attachment.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SplatMaterialKey {
+    weight_attachment: u32,
+}
+
+impl From<&SplatMaterial> for SplatMaterialKey {
+    fn from(material: &SplatMaterial) -> Self {
+        Self {
+            weight_attachment: material.settings.weight_attachment,
+        }
+    }
+}
+
+impl SplatMaterial {
+    /// Creates a material, which blends the layers by the weights of the attachment
+    /// `weight_name`, stored in the `layout`.
+    /// The weight attachment can not be the first attachment, which is bound as the height.
+    /// The default settings repeat the textures every eight units, blend the layers over
+    /// a height range of 0.2 and project slopes between 0.3 and 0.5 triplanar.
+    pub fn new(
+        config: &TerrainConfig,
+        weight_name: &str,
+        layout: SplatLayout,
+        layer_count: u32,
+        albedo_layers: Handle<Image>,
+        normal_layers: Handle<Image>,
+        roughness_layers: Handle<Image>,
+    ) -> Self {
+        let weight_attachment = attachment_index(config, weight_name);
+
+        assert!(
+            (1..MAX_ATTACHMENT_COUNT).contains(&weight_attachment),
+            "The weight attachment {weight_name} has the index {weight_attachment}, \
+             but only the attachments 1 to {} can be sampled by the splat material.",
+            MAX_ATTACHMENT_COUNT - 1
+        );
+
+        Self {
+            settings: SplatSettings {
+                layer_count,
+                weight_attachment: weight_attachment as u32,
+                index_blend: (layout == SplatLayout::IndexBlend) as u32,
+                tile_size: 8.0,
+                height_blend: 0.2,
+                triplanar_start: 0.3,
+                triplanar_end: 0.5,
+            },
+            albedo_layers,
+            normal_layers,
+            roughness_layers,
+        }
+    }
+}
+
+impl TerrainMaterial for SplatMaterial {
+    fn vertex_shader() -> ShaderRef {
+        SPLAT_SHADER.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        SPLAT_SHADER.typed().into()
+    }
+
+    fn specialize(descriptor: &mut RenderPipelineDescriptor, key: TerrainMaterialKey<Self>) {
+        let weight_def = format!(
+            "WEIGHT_ATTACHMENT_{}",
+            key.bind_group_data.weight_attachment
+        );
+
+        // the normal is always calculated from the height, thus the normal attachment is unused
+        let specialize_defs = |shader_defs: &mut Vec<String>| {
+            shader_defs.retain(|def| def != "NORMAL_ATTACHMENT" && def != "NORMAL_OCTAHEDRAL");
+            shader_defs.push(weight_def.clone());
+        };
+
+        specialize_defs(&mut descriptor.vertex.shader_defs);
+
+        if let Some(fragment) = &mut descriptor.fragment {
+            specialize_defs(&mut fragment.shader_defs);
+        }
+    }
+}
@@ -1,16 +1,17 @@
 use crate::{
-    render::layouts::TERRAIN_CONFIG_SIZE,
+    render::layouts::{MESH_UNIFORM_SIZE, TERRAIN_CONFIG_SIZE},
     terrain::{Terrain, TerrainComponents},
     TerrainConfig,
 };
 use bevy::{
     ecs::system::{lifetimeless::SRes, SystemParamItem},
+    pbr::MeshUniform,
     prelude::*,
     render::{
         render_asset::RenderAssets,
         render_phase::{EntityRenderCommand, RenderCommandResult, TrackedRenderPass},
         render_resource::*,
-        renderer::RenderDevice,
+        renderer::{RenderDevice, RenderQueue},
         Extract,
     },
 };
@@ -57,6 +58,9 @@ pub fn terrain_bind_group_layout(
 
 pub struct TerrainData {
     pub(crate) terrain_bind_group: BindGroup,
+    /// The buffer of the [`MeshUniform`], which is bound to the terrain view bind group,
+    /// because the last bind group is reserved for the material.
+    pub(crate) mesh_buffer: Buffer,
 }
 
 impl TerrainData {
@@ -117,7 +121,17 @@ impl TerrainData {
             layout: &layout,
         });
 
-        Self { terrain_bind_group }
+        let mesh_buffer = device.create_buffer(&BufferDescriptor {
+            label: "mesh_buffer".into(),
+            size: MESH_UNIFORM_SIZE,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            terrain_bind_group,
+            mesh_buffer,
+        }
     }
 }
 
@@ -132,6 +146,21 @@ pub(crate) fn initialize_terrain_data(
     }
 }
 
+/// Writes the [`MeshUniform`] of all terrains into their mesh buffer.
+pub(crate) fn queue_terrain_mesh_uniform(
+    queue: Res<RenderQueue>,
+    terrain_data: Res<TerrainComponents<TerrainData>>,
+    terrain_query: Query<(Entity, &MeshUniform), With<Terrain>>,
+) {
+    for (terrain, mesh_uniform) in terrain_query.iter() {
+        let data = terrain_data.get(&terrain).unwrap();
+
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(mesh_uniform).unwrap();
+        queue.write_buffer(&data.mesh_buffer, 0, &buffer.into_inner());
+    }
+}
+
 pub struct SetTerrainBindGroup<const I: usize>;
 
 impl<const I: usize> EntityRenderCommand for SetTerrainBindGroup<I> {
@@ -0,0 +1,333 @@
+//! Custom shading of the terrain with materials, modelled after the [`Material`] of bevy.
+//!
+//! # Explanation
+//! Each terrain entity is rendered with the material of its `Handle<M>`.
+//! The bind group of the material is bound to group 3 of the terrain pipeline.
+//! The groups 0 to 2 contain the view, the terrain view and the terrain bindings.
+//! Shaders declare them by importing `bevy_terrain::bindings`, after
+//! `bevy_pbr::mesh_view_bindings`, `bevy_pbr::mesh_types`, `bevy_terrain::config`
+//! and `bevy_terrain::tile`.
+//! The terrain bindings only declare the height attachment (binding 2),
+//! all further attachments are declared by the shader itself (binding 2 + attachment index).
+//!
+//! Afterwards `bevy_terrain::atlas` provides the atlas lookup and `bevy_terrain::terrain`
+//! the vertex logic of the terrain (`terrain_vertex`) and the normal calculation.
+//! The vertex and fragment entry points of material shaders have to be called
+//! `vertex` and `fragment`.
+//!
+//! The pipeline of each material is specialized by its bind group data
+//! (see [`AsBindGroup::Data`]), e.g. to select the bindings of its attachments via shader defs.
+//!
+//! [`Material`]: bevy::pbr::Material
+
+use crate::render::{
+    render_pipeline::{
+        queue_terrain, queue_terrain_shadows, TerrainPipelineKey, TerrainRenderPipeline,
+    },
+    DrawTerrain, DrawTerrainShadow,
+};
+use bevy::{
+    core_pipeline::core_3d::Opaque3d,
+    ecs::system::{
+        lifetimeless::{Read, SQuery, SRes},
+        SystemParamItem,
+    },
+    pbr::Shadow,
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, EntityRenderCommand, RenderCommandResult, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::RenderDevice,
+        texture::FallbackImage,
+        Extract, RenderApp, RenderStage,
+    },
+    utils::{HashMap, HashSet},
+};
+use std::{hash::Hash, marker::PhantomData, mem};
+
+/// Materials shade the terrain entities, which carry a `Handle<M>`, with their own shaders
+/// and an additional bind group of uniforms and textures.
+/// Each material type has to be registered with a [`TerrainMaterialPlugin`].
+pub trait TerrainMaterial: AsBindGroup + Send + Sync + Clone + TypeUuid + Sized + 'static {
+    /// Returns the vertex shader of the material.
+    /// Defaults to the shader of the [`TerrainPipelineConfig`](super::TerrainPipelineConfig).
+    fn vertex_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Returns the fragment shader of the material.
+    /// Defaults to the shader of the [`TerrainPipelineConfig`](super::TerrainPipelineConfig).
+    fn fragment_shader() -> ShaderRef {
+        ShaderRef::Default
+    }
+
+    /// Customizes the pipeline descriptor of the material, e.g. to add shader defs.
+    /// Shadow pipelines are specialized with the [`TerrainPipelineKey::SHADOW`] key
+    /// and have no fragment state.
+    #[allow(unused_variables)]
+    #[inline]
+    fn specialize(descriptor: &mut RenderPipelineDescriptor, key: TerrainMaterialKey<Self>) {}
+}
+
+/// The key of the pipeline of a material, which consists of the [`TerrainPipelineKey`]
+/// and the bind group data of the material.
+pub struct TerrainMaterialKey<M: TerrainMaterial> {
+    pub terrain_key: TerrainPipelineKey,
+    pub bind_group_data: M::Data,
+}
+
+impl<M: TerrainMaterial> Clone for TerrainMaterialKey<M>
+where
+    M::Data: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            terrain_key: self.terrain_key,
+            bind_group_data: self.bind_group_data.clone(),
+        }
+    }
+}
+
+impl<M: TerrainMaterial> PartialEq for TerrainMaterialKey<M>
+where
+    M::Data: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.terrain_key == other.terrain_key && self.bind_group_data == other.bind_group_data
+    }
+}
+
+impl<M: TerrainMaterial> Eq for TerrainMaterialKey<M> where M::Data: Eq {}
+
+impl<M: TerrainMaterial> Hash for TerrainMaterialKey<M>
+where
+    M::Data: Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.terrain_key.hash(state);
+        self.bind_group_data.hash(state);
+    }
+}
+
+/// The material of terrains without a custom material.
+/// It shades them with the shader of the [`TerrainPipelineConfig`](super::TerrainPipelineConfig).
+#[derive(AsBindGroup, TypeUuid, Clone, Default)]
+#[uuid = "96076189-73e3-4e28-bc40-db839957d11b"]
+pub struct DefaultTerrainMaterial {}
+
+impl TerrainMaterial for DefaultTerrainMaterial {}
+
+/// Adds the rendering of all terrains with the material `M`.
+/// Has to be added after the [`TerrainPlugin`](crate::TerrainPlugin).
+pub struct TerrainMaterialPlugin<M: TerrainMaterial>(PhantomData<M>);
+
+impl<M: TerrainMaterial> Default for TerrainMaterialPlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M: TerrainMaterial> Plugin for TerrainMaterialPlugin<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.add_asset::<M>()
+            .add_plugin(ExtractComponentPlugin::<Handle<M>>::default());
+
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawTerrain<M>>()
+            .add_render_command::<Shadow, DrawTerrainShadow<M>>()
+            .init_resource::<TerrainMaterialPipeline<M>>()
+            .init_resource::<SpecializedRenderPipelines<TerrainMaterialPipeline<M>>>()
+            .init_resource::<ExtractedTerrainMaterials<M>>()
+            .init_resource::<RenderTerrainMaterials<M>>()
+            .add_system_to_stage(RenderStage::Extract, extract_terrain_materials::<M>)
+            .add_system_to_stage(RenderStage::Prepare, prepare_terrain_materials::<M>)
+            .add_system_to_stage(RenderStage::Queue, queue_terrain::<M>)
+            .add_system_to_stage(RenderStage::Queue, queue_terrain_shadows::<M>);
+    }
+}
+
+/// The [`TerrainRenderPipeline`] extended by the bind group and the shaders of the material.
+pub struct TerrainMaterialPipeline<M: TerrainMaterial> {
+    pub terrain_pipeline: TerrainRenderPipeline,
+    pub material_layout: BindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub fragment_shader: Option<Handle<Shader>>,
+    marker: PhantomData<M>,
+}
+
+impl<M: TerrainMaterial> FromWorld for TerrainMaterialPipeline<M> {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let asset_server = world.resource::<AssetServer>();
+
+        let load_shader = |shader| match shader {
+            ShaderRef::Default => None,
+            ShaderRef::Handle(handle) => Some(handle),
+            ShaderRef::Path(path) => Some(asset_server.load(path)),
+        };
+
+        Self {
+            terrain_pipeline: world.resource::<TerrainRenderPipeline>().clone(),
+            material_layout: M::bind_group_layout(device),
+            vertex_shader: load_shader(M::vertex_shader()),
+            fragment_shader: load_shader(M::fragment_shader()),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: TerrainMaterial> SpecializedRenderPipeline for TerrainMaterialPipeline<M>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = TerrainMaterialKey<M>;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut descriptor = self.terrain_pipeline.specialize(key.terrain_key);
+
+        if let Some(vertex_shader) = &self.vertex_shader {
+            descriptor.vertex.shader = vertex_shader.clone();
+        }
+
+        if let (Some(fragment_shader), Some(fragment)) =
+            (&self.fragment_shader, &mut descriptor.fragment)
+        {
+            fragment.shader = fragment_shader.clone();
+        }
+
+        descriptor
+            .layout
+            .as_mut()
+            .unwrap()
+            .push(self.material_layout.clone());
+
+        M::specialize(&mut descriptor, key);
+
+        descriptor
+    }
+}
+
+/// The materials, that have been added or changed this frame.
+pub struct ExtractedTerrainMaterials<M: TerrainMaterial> {
+    extracted: Vec<(Handle<M>, M)>,
+    removed: Vec<Handle<M>>,
+}
+
+impl<M: TerrainMaterial> Default for ExtractedTerrainMaterials<M> {
+    fn default() -> Self {
+        Self {
+            extracted: default(),
+            removed: default(),
+        }
+    }
+}
+
+/// The bind group of a material, that is ready to be rendered.
+pub struct PreparedTerrainMaterial<M: TerrainMaterial> {
+    pub bindings: Vec<OwnedBindingResource>,
+    pub bind_group: BindGroup,
+    /// The bind group data, which specializes the pipeline of the material.
+    pub key: M::Data,
+}
+
+/// Resource that stores the prepared materials of the type `M`.
+pub type RenderTerrainMaterials<M> = HashMap<Handle<M>, PreparedTerrainMaterial<M>>;
+
+/// Extracts all materials, that have been added or changed.
+fn extract_terrain_materials<M: TerrainMaterial>(
+    mut commands: Commands,
+    mut events: Extract<EventReader<AssetEvent<M>>>,
+    assets: Extract<Res<Assets<M>>>,
+) {
+    let mut changed = HashSet::default();
+    let mut removed = Vec::new();
+
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                changed.insert(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                changed.remove(handle);
+                removed.push(handle.clone_weak());
+            }
+        }
+    }
+
+    let extracted = changed
+        .into_iter()
+        .filter_map(|handle| {
+            let material = assets.get(&handle)?.clone();
+            Some((handle, material))
+        })
+        .collect();
+
+    commands.insert_resource(ExtractedTerrainMaterials { extracted, removed });
+}
+
+/// Creates the bind groups of the extracted materials.
+/// Materials, whose images are not loaded yet, are retried in the next frame.
+fn prepare_terrain_materials<M: TerrainMaterial>(
+    mut prepare_next_frame: Local<Vec<(Handle<M>, M)>>,
+    device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    pipeline: Res<TerrainMaterialPipeline<M>>,
+    mut extracted_materials: ResMut<ExtractedTerrainMaterials<M>>,
+    mut render_materials: ResMut<RenderTerrainMaterials<M>>,
+) {
+    for handle in mem::take(&mut extracted_materials.removed) {
+        render_materials.remove(&handle);
+    }
+
+    let queued = mem::take(&mut *prepare_next_frame);
+
+    for (handle, material) in queued
+        .into_iter()
+        .chain(mem::take(&mut extracted_materials.extracted))
+    {
+        match material.as_bind_group(&pipeline.material_layout, &device, &images, &fallback_image) {
+            Ok(prepared) => {
+                render_materials.insert(
+                    handle,
+                    PreparedTerrainMaterial {
+                        bindings: prepared.bindings,
+                        bind_group: prepared.bind_group,
+                        key: prepared.data,
+                    },
+                );
+            }
+            Err(AsBindGroupError::RetryNextUpdate) => {
+                prepare_next_frame.push((handle, material));
+            }
+        }
+    }
+}
+
+pub struct SetTerrainMaterialBindGroup<M: TerrainMaterial, const I: usize>(PhantomData<M>);
+
+impl<M: TerrainMaterial, const I: usize> EntityRenderCommand for SetTerrainMaterialBindGroup<M, I> {
+    type Param = (SRes<RenderTerrainMaterials<M>>, SQuery<Read<Handle<M>>>);
+
+    #[inline]
+    fn render<'w>(
+        _view: Entity,
+        terrain: Entity,
+        (render_materials, material_query): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let material = material_query.get(terrain).unwrap();
+        let material = render_materials.into_inner().get(material).unwrap();
+
+        pass.set_bind_group(I, &material.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
@@ -4,14 +4,19 @@ use crate::{
         INDIRECT_BUFFER_SIZE, PARAMETER_BUFFER_SIZE, TERRAIN_VIEW_CONFIG_SIZE, TERRAIN_VIEW_LAYOUT,
         TILE_SIZE,
     },
-    terrain::Terrain,
+    render::terrain_data::TerrainData,
+    terrain::{Terrain, TerrainComponents},
     terrain_view::{TerrainView, TerrainViewConfig},
     TerrainViewComponents,
 };
 use bevy::render::render_asset::RenderAssets;
 use bevy::render::Extract;
 use bevy::{
-    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    ecs::system::{
+        lifetimeless::{Read, SQuery, SRes},
+        SystemParamItem,
+    },
+    pbr::LightEntity,
     prelude::*,
     render::{
         render_phase::{EntityRenderCommand, RenderCommandResult, TrackedRenderPass},
@@ -33,6 +38,7 @@ impl TerrainViewData {
     fn new(
         device: &RenderDevice,
         images: &RenderAssets<Image>,
+        terrain_data: &TerrainData,
         view_config: &TerrainViewConfig,
     ) -> Self {
         let indirect_buffer = Self::create_indirect_buffer(device);
@@ -92,6 +98,10 @@ impl TerrainViewData {
                     binding: 2,
                     resource: final_tile_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: terrain_data.mesh_buffer.as_entire_binding(),
+                },
             ],
             layout: &device.create_bind_group_layout(&TERRAIN_VIEW_LAYOUT),
         });
@@ -157,35 +167,53 @@ impl TerrainViewData {
 pub(crate) fn initialize_terrain_view_data(
     device: Res<RenderDevice>,
     images: Res<RenderAssets<Image>>,
+    terrain_data: Res<TerrainComponents<TerrainData>>,
     mut terrain_view_data: ResMut<TerrainViewComponents<TerrainViewData>>,
     view_configs: Extract<Res<TerrainViewComponents<TerrainViewConfig>>>,
     view_query: Extract<Query<Entity, With<TerrainView>>>,
     terrain_query: Extract<Query<Entity, Added<Terrain>>>,
 ) {
     for terrain in terrain_query.iter() {
+        let terrain_data = terrain_data.get(&terrain).unwrap();
+
         for view in view_query.iter() {
             let view_config = view_configs.get(&(terrain, view)).unwrap();
 
             terrain_view_data.insert(
                 (terrain, view),
-                TerrainViewData::new(&device, &images, view_config),
+                TerrainViewData::new(&device, &images, terrain_data, view_config),
             );
         }
     }
 }
 
+/// Returns the terrain view of the rendered view.
+/// Shadow views are spawned each frame for their light, which is the terrain view.
+#[inline]
+fn terrain_view(view: Entity, light_query: &Query<&LightEntity>) -> Entity {
+    match light_query.get(view) {
+        Ok(LightEntity::Directional { light_entity })
+        | Ok(LightEntity::Point { light_entity, .. }) => *light_entity,
+        Err(_) => view,
+    }
+}
+
 pub struct SetTerrainViewBindGroup<const I: usize>;
 
 impl<const I: usize> EntityRenderCommand for SetTerrainViewBindGroup<I> {
-    type Param = SRes<TerrainViewComponents<TerrainViewData>>;
+    type Param = (
+        SRes<TerrainViewComponents<TerrainViewData>>,
+        SQuery<Read<LightEntity>>,
+    );
 
     #[inline]
     fn render<'w>(
         view: Entity,
         terrain: Entity,
-        terrain_view_data: SystemParamItem<'w, '_, Self::Param>,
+        (terrain_view_data, light_query): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        let view = terrain_view(view, &light_query);
         let data = terrain_view_data
             .into_inner()
             .get(&(terrain, view))
@@ -199,15 +227,19 @@ impl<const I: usize> EntityRenderCommand for SetTerrainViewBindGroup<I> {
 pub(crate) struct DrawTerrainCommand;
 
 impl EntityRenderCommand for DrawTerrainCommand {
-    type Param = SRes<TerrainViewComponents<TerrainViewData>>;
+    type Param = (
+        SRes<TerrainViewComponents<TerrainViewData>>,
+        SQuery<Read<LightEntity>>,
+    );
 
     #[inline]
     fn render<'w>(
         view: Entity,
         terrain: Entity,
-        terrain_view_data: SystemParamItem<'w, '_, Self::Param>,
+        (terrain_view_data, light_query): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        let view = terrain_view(view, &light_query);
         let data = terrain_view_data
             .into_inner()
             .get(&(terrain, view))
@@ -1,6 +1,7 @@
 use crate::{
     attachment_loader::AttachmentFromDiskLoader,
     data_structures::{AtlasAttachment, AttachmentIndex},
+    terrain_manifest::{TerrainLoadError, TerrainManifest},
 };
 use bevy::{
     ecs::{query::QueryItem, system::lifetimeless::Read},
@@ -13,6 +14,10 @@ use std::str::FromStr;
 
 pub type TerrainComponents<C> = HashMap<Entity, C>;
 
+/// The maximum number of attachments of a terrain, whose atlas coordinates are stored
+/// in the [`TerrainConfigUniform`].
+pub const MAX_ATTACHMENT_COUNT: usize = 8;
+
 #[derive(Clone, Copy, Component)]
 pub struct Terrain;
 
@@ -32,8 +37,8 @@ pub(crate) struct TerrainConfigUniform {
     height: f32,
     chunk_size: u32,
     terrain_size: u32,
-    attachment_scales: Vec4,
-    attachment_offsets: Vec4,
+    attachment_scales: [Vec4; MAX_ATTACHMENT_COUNT / 4],
+    attachment_offsets: [Vec4; MAX_ATTACHMENT_COUNT / 4],
 }
 
 #[derive(Clone, Component)]
@@ -45,6 +50,10 @@ pub struct TerrainConfig {
     pub node_atlas_size: u32,
     pub path: String,
     pub attachments: Vec<AtlasAttachment>,
+    /// The index of the attachment storing the height, which is the first one by default.
+    /// The terrain shaders bind the first attachment as the height atlas, thus the height
+    /// has to remain the first attachment, when the terrain is rendered.
+    pub height_attachment: AttachmentIndex,
 }
 
 impl TerrainConfig {
@@ -64,16 +73,72 @@ impl TerrainConfig {
             terrain_size,
             path,
             attachments: default(),
+            height_attachment: 0,
         }
     }
 
+    /// Creates the config and the loader of the `attachments` from the manifest written
+    /// during preprocessing. The height attachment of the manifest is always loaded as the
+    /// first attachment, followed by the `attachments` in the given order.
+    ///
+    /// The `path` of the terrain is relative to the asset folder (e.g. `"terrain/"`).
+    /// Fails if the manifest has no attachment of one of the names or if there are more than
+    /// [`MAX_ATTACHMENT_COUNT`] attachments.
+    pub fn load(
+        path: &str,
+        node_atlas_size: u32,
+        attachments: &[&str],
+    ) -> Result<(Self, AttachmentFromDiskLoader), TerrainLoadError> {
+        let manifest = TerrainManifest::load(&format!("assets/{path}"));
+
+        Self::from_manifest(&manifest, path, node_atlas_size, attachments)
+    }
+
+    /// Creates the config and the loader of the `attachments` from an already loaded manifest.
+    /// See [`TerrainConfig::load`] for details.
+    pub fn from_manifest(
+        manifest: &TerrainManifest,
+        path: &str,
+        node_atlas_size: u32,
+        attachments: &[&str],
+    ) -> Result<(Self, AttachmentFromDiskLoader), TerrainLoadError> {
+        let attachments = manifest.select_attachments(attachments)?;
+
+        let mut config = Self::new(
+            manifest.terrain_size,
+            manifest.chunk_size,
+            manifest.lod_count,
+            manifest.height,
+            node_atlas_size,
+            path.to_string(),
+        );
+        let mut from_disk_loader = AttachmentFromDiskLoader::default();
+
+        for attachment in attachments {
+            config.add_attachment_from_disk(
+                &mut from_disk_loader,
+                &attachment.name,
+                attachment.texture_format(),
+                attachment.texture_size,
+                attachment.border_size,
+            );
+        }
+
+        Ok((config, from_disk_loader))
+    }
+
     pub fn add_attachment(
         &mut self,
-        name: &'static str,
+        name: &str,
         format: TextureFormat,
         texture_size: u32,
         border_size: u32,
     ) -> AttachmentIndex {
+        assert!(
+            self.attachments.len() < MAX_ATTACHMENT_COUNT,
+            "A terrain can not have more than {MAX_ATTACHMENT_COUNT} attachments."
+        );
+
         // Todo: fix this awful hack
         let atlas_handle = HandleUntyped::weak_from_u64(
             Uuid::from_str("6ea26da6-6cf8-4ea2-9986-1d7bf6c17d6f").unwrap(),
@@ -82,7 +147,7 @@ impl TerrainConfig {
         .typed();
 
         self.attachments.push(AtlasAttachment {
-            name,
+            name: name.to_string(),
             handle: atlas_handle,
             texture_size,
             border_size,
@@ -95,7 +160,7 @@ impl TerrainConfig {
     pub fn add_attachment_from_disk(
         &mut self,
         from_disk_loader: &mut AttachmentFromDiskLoader,
-        name: &'static str,
+        name: &str,
         format: TextureFormat,
         texture_size: u32,
         border_size: u32,
@@ -110,14 +175,13 @@ impl TerrainConfig {
     }
 
     pub(crate) fn shader_data(&self) -> TerrainConfigUniform {
-        // Todo: figure out a better way to store data for more than four attachments
-        let mut scales = [1.0; 4];
-        let mut offsets = [0.0; 4];
+        let mut scales = [Vec4::ONE; MAX_ATTACHMENT_COUNT / 4];
+        let mut offsets = [Vec4::ZERO; MAX_ATTACHMENT_COUNT / 4];
 
         for (i, attachment) in self.attachments.iter().enumerate() {
-            scales[i] = attachment.texture_size as f32
+            scales[i / 4][i % 4] = attachment.texture_size as f32
                 / (attachment.texture_size + 2 * attachment.border_size) as f32;
-            offsets[i] = attachment.border_size as f32
+            offsets[i / 4][i % 4] = attachment.border_size as f32
                 / (attachment.texture_size + 2 * attachment.border_size) as f32;
         }
 
@@ -126,8 +190,94 @@ impl TerrainConfig {
             height: self.height,
             chunk_size: self.chunk_size,
             terrain_size: self.terrain_size,
-            attachment_scales: Vec4::from_array(scales),
-            attachment_offsets: Vec4::from_array(offsets),
+            attachment_scales: scales,
+            attachment_offsets: offsets,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_structures::calc_node_id,
+        preprocess::{down_sample::DownSampleFilter, erosion::ErosionSettings, ImageFormat},
+        terrain_manifest::AttachmentManifest,
+    };
+    use image::{ImageBuffer, Luma};
+    use std::{fs, process};
+
+    #[test]
+    fn eroded_terrains_load_the_eroded_height_and_masks() {
+        let directory = format!(
+            "{}/eroded_terrain_{}/",
+            std::env::temp_dir().display(),
+            process::id()
+        );
+        let mut manifest = TerrainManifest::new(&directory, 4, 4, 1, 100.0);
+
+        // a single node with a texture size of 4 and a border of 2
+        let height_directory = manifest.attachment_directory("height");
+        fs::create_dir_all(&height_directory).unwrap();
+        ImageBuffer::from_fn(8, 8, |x, y| Luma([(x * y * 1000) as u16]))
+            .save(format!("{height_directory}/{}.png", calc_node_id(0, 0, 0)))
+            .unwrap();
+        manifest.attachments.push(AttachmentManifest {
+            name: "height".to_string(),
+            format: ImageFormat::LUMA16,
+            srgb: false,
+            texture_size: 4,
+            border_size: 2,
+            first: (0, 0),
+            last: (1, 1),
+            normal_encoding: None,
+        });
+
+        let settings = ErosionSettings {
+            overlap: 2,
+            ..default()
+        };
+        manifest.preprocess_erosion(
+            "eroded",
+            "height",
+            Some("flow"),
+            Some("deposition"),
+            &settings,
+            &DownSampleFilter::Average,
+        );
+
+        let (config, from_disk_loader) =
+            TerrainConfig::from_manifest(&manifest, "terrain/", 16, &["flow", "deposition"])
+                .unwrap();
+
+        let names: Vec<&str> = config
+            .attachments
+            .iter()
+            .map(|attachment| attachment.name.as_str())
+            .collect();
+        assert_eq!(names, ["eroded", "flow", "deposition"]);
+        assert_eq!(from_disk_loader.attachments[&0].path, "terrain/data/eroded");
+
+        for index in 1..3 {
+            assert_eq!(config.attachments[index].format, TextureFormat::R16Unorm);
+            assert_eq!(config.attachments[index].border_size, 0);
+            assert_eq!(
+                from_disk_loader.attachments[&index].format,
+                TextureFormat::R16Unorm
+            );
+        }
+
+        // the height skips its border, while the masks cover the whole texture
+        let shader_data = config.shader_data();
+        assert_eq!(
+            shader_data.attachment_scales[0],
+            Vec4::new(0.5, 1.0, 1.0, 1.0)
+        );
+        assert_eq!(
+            shader_data.attachment_offsets[0],
+            Vec4::new(0.25, 0.0, 0.0, 0.0)
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,652 @@
+use crate::{
+    preprocess::{
+        density::{preprocess_density, DensityMetric},
+        derive::{preprocess_derived, HeightWindow},
+        down_sample::DownSampleFilter,
+        erosion::{preprocess_erosion, ErosionSettings},
+        nodata::NoData,
+        normal::{preprocess_normal, NormalEncoding},
+        preprocess_tiles, source_tile_size,
+        splat::{preprocess_splat, SplatLayout, SplatRule},
+        vector::{preprocess_carve, preprocess_mask, VectorFeature},
+        ImageFormat,
+    },
+    terrain::MAX_ATTACHMENT_COUNT,
+};
+use bevy::{prelude::default, render::render_resource::TextureFormat};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, fs, iter};
+
+const TERRAIN_MANIFEST_NAME: &str = "terrain.ron";
+
+fn default_height_attachment() -> String {
+    "height".to_string()
+}
+
+/// The reasons, why the attachments of a terrain can not be loaded from its manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TerrainLoadError {
+    /// The manifest has no attachment with this name.
+    UnknownAttachment(String),
+    /// The number of attachments exceeds [`MAX_ATTACHMENT_COUNT`].
+    TooManyAttachments(usize),
+}
+
+impl fmt::Display for TerrainLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownAttachment(name) => {
+                write!(f, "The terrain manifest has no attachment {name:?}.")
+            }
+            Self::TooManyAttachments(count) => write!(
+                f,
+                "The {count} attachments exceed the {MAX_ATTACHMENT_COUNT} attachments \
+                 supported by the terrain shaders."
+            ),
+        }
+    }
+}
+
+impl Error for TerrainLoadError {}
+
+/// Describes a preprocessed attachment of the terrain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttachmentManifest {
+    /// The name of the attachment, which is also the name of its node directory.
+    pub name: String,
+    /// The format the nodes are stored in.
+    pub format: ImageFormat,
+    /// Whether the color channels are sRGB encoded.
+    pub srgb: bool,
+    /// The none overlapping texture size in pixels.
+    pub texture_size: u32,
+    /// The overlapping border size around the texture.
+    pub border_size: u32,
+    /// The first node coordinate of lod 0 containing data.
+    pub first: (u32, u32),
+    /// The last (exclusive) node coordinate of lod 0 containing data.
+    pub last: (u32, u32),
+    /// The encoding of the normals, if this is a baked normal attachment.
+    #[serde(default)]
+    pub normal_encoding: Option<NormalEncoding>,
+}
+
+impl AttachmentManifest {
+    /// Returns the texture format of the attachment inside the node atlas.
+    pub fn texture_format(&self) -> TextureFormat {
+        match (self.format, self.srgb) {
+            (ImageFormat::RGB | ImageFormat::RGBA, false) => TextureFormat::Rgba8Unorm,
+            (ImageFormat::RGB | ImageFormat::RGBA, true) => TextureFormat::Rgba8UnormSrgb,
+            (ImageFormat::LUMA16, _) => TextureFormat::R16Unorm,
+            (ImageFormat::RG8, _) => TextureFormat::Rg8Unorm,
+            (ImageFormat::RG16, _) => TextureFormat::Rg16Unorm,
+        }
+    }
+}
+
+/// Describes a preprocessed terrain and all of its attachments.
+///
+/// It is written by the preprocessing methods into `terrain.ron` inside the terrain directory
+/// and read by [`TerrainConfig::load`](crate::terrain::TerrainConfig::load), so that the
+/// runtime configuration always matches the preprocessed data.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TerrainManifest {
+    pub terrain_size: u32,
+    pub chunk_size: u32,
+    pub lod_count: u32,
+    pub height: f32,
+    /// The attachments in the order they are bound to the terrain.
+    pub attachments: Vec<AttachmentManifest>,
+    /// The name of the attachment rendered as the height of the terrain.
+    /// Preprocessing steps, that modify the height (e.g. erosion), select their result.
+    #[serde(default = "default_height_attachment")]
+    pub height_attachment: String,
+    /// The terrain directory on disk.
+    #[serde(skip)]
+    directory: String,
+}
+
+impl TerrainManifest {
+    /// Creates a new manifest for a terrain stored in the `directory` without any attachments.
+    pub fn new(
+        directory: &str,
+        terrain_size: u32,
+        chunk_size: u32,
+        lod_count: u32,
+        height: f32,
+    ) -> Self {
+        Self {
+            terrain_size,
+            chunk_size,
+            lod_count,
+            height,
+            attachments: default(),
+            height_attachment: default_height_attachment(),
+            directory: directory.to_string(),
+        }
+    }
+
+    /// Loads the manifest of the terrain stored in the `directory`.
+    pub fn load(directory: &str) -> Self {
+        let manifest = fs::read_to_string(format!("{directory}{TERRAIN_MANIFEST_NAME}"))
+            .expect("Could not find the terrain manifest. Preprocess the terrain first.");
+        let mut manifest: Self = ron::from_str(&manifest).expect("Invalid terrain manifest.");
+        manifest.directory = directory.to_string();
+
+        manifest
+    }
+
+    pub fn save(&self) {
+        let manifest = ron::ser::to_string_pretty(self, default()).unwrap();
+
+        fs::write(
+            format!("{}{TERRAIN_MANIFEST_NAME}", self.directory),
+            manifest,
+        )
+        .expect("Could not save terrain manifest.");
+    }
+
+    pub fn attachment(&self, name: &str) -> Option<&AttachmentManifest> {
+        self.attachments
+            .iter()
+            .find(|attachment| attachment.name == name)
+    }
+
+    /// Returns the height attachment followed by the attachments with the `names` in the order
+    /// they are bound to the terrain. Repeated names are only selected once.
+    pub fn select_attachments(
+        &self,
+        names: &[&str],
+    ) -> Result<Vec<&AttachmentManifest>, TerrainLoadError> {
+        let mut attachments: Vec<&AttachmentManifest> = Vec::new();
+
+        for name in iter::once(self.height_attachment.as_str()).chain(names.iter().copied()) {
+            if attachments.iter().any(|attachment| attachment.name == name) {
+                continue;
+            }
+
+            let attachment = self
+                .attachment(name)
+                .ok_or_else(|| TerrainLoadError::UnknownAttachment(name.to_string()))?;
+            attachments.push(attachment);
+        }
+
+        if attachments.len() > MAX_ATTACHMENT_COUNT {
+            return Err(TerrainLoadError::TooManyAttachments(attachments.len()));
+        }
+
+        Ok(attachments)
+    }
+
+    /// Returns the encoding of the baked normal attachment, if the terrain has one.
+    pub fn normal_encoding(&self) -> Option<NormalEncoding> {
+        self.attachments
+            .iter()
+            .find_map(|attachment| attachment.normal_encoding)
+    }
+
+    /// Returns the first and last (exclusive) node coordinate of lod 0 containing data
+    /// of any attachment.
+    pub fn extents(&self) -> ((u32, u32), (u32, u32)) {
+        self.attachments.iter().fold(
+            ((u32::MAX, u32::MAX), (0, 0)),
+            |(first, last), attachment| {
+                (
+                    (
+                        first.0.min(attachment.first.0),
+                        first.1.min(attachment.first.1),
+                    ),
+                    (last.0.max(attachment.last.0), last.1.max(attachment.last.1)),
+                )
+            },
+        )
+    }
+
+    /// Returns the directory the nodes of the attachment are stored in.
+    pub fn attachment_directory(&self, name: &str) -> String {
+        format!("{}data/{name}", self.directory)
+    }
+
+    /// Adds the attachment or replaces the one with the same name and saves the manifest.
+    fn insert_attachment(&mut self, attachment: AttachmentManifest) {
+        if let Some(existing) = self
+            .attachments
+            .iter_mut()
+            .find(|existing| existing.name == attachment.name)
+        {
+            *existing = attachment;
+        } else {
+            self.attachments.push(attachment);
+        }
+
+        self.save();
+    }
+
+    fn height_attachment(&self, height_name: &str) -> AttachmentManifest {
+        self.attachment(height_name)
+            .expect("The height attachment has to be preprocessed first.")
+            .clone()
+    }
+
+    /// Preprocesses the source tiles of the input path into the attachment `name`.
+    /// See [`preprocess_tiles`] for details.
+    pub fn preprocess_tiles(
+        &mut self,
+        input_path: &str,
+        name: &str,
+        format: ImageFormat,
+        srgb: bool,
+        texture_size: u32,
+        border_size: u32,
+        filter: &DownSampleFilter,
+        nodata: Option<NoData>,
+    ) {
+        let (first, last) = preprocess_tiles(
+            input_path,
+            &self.attachment_directory(name),
+            0,
+            self.lod_count,
+            (0, 0),
+            source_tile_size(input_path),
+            texture_size,
+            border_size,
+            format,
+            filter,
+            nodata,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            format,
+            srgb,
+            texture_size,
+            border_size,
+            first,
+            last,
+            normal_encoding: None,
+        });
+    }
+
+    /// Computes the density attachment `name` from the height attachment `height_name`.
+    /// See [`preprocess_density`] for details.
+    pub fn preprocess_density(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        metric: &DensityMetric,
+        filter: &DownSampleFilter,
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_density(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            self.height,
+            metric,
+            filter,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            format: ImageFormat::LUMA16,
+            srgb: false,
+            border_size: 0,
+            ..height
+        });
+    }
+
+    /// Bakes the normal attachment `name` from the height attachment `height_name`.
+    /// See [`preprocess_normal`] for details.
+    pub fn preprocess_normal(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        border_size: u32,
+        format: ImageFormat,
+        encoding: NormalEncoding,
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_normal(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            border_size,
+            self.height,
+            format,
+            encoding,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            format,
+            srgb: false,
+            border_size,
+            normal_encoding: Some(encoding),
+            ..height
+        });
+    }
+
+    /// Generates the material weight attachment `name` from the height attachment
+    /// `height_name`. See [`preprocess_splat`] for details.
+    pub fn preprocess_splat(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        border_size: u32,
+        rules: &[SplatRule],
+        layout: SplatLayout,
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_splat(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            border_size,
+            self.height,
+            rules,
+            layout,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            format: ImageFormat::RGBA,
+            srgb: false,
+            border_size,
+            ..height
+        });
+    }
+
+    /// Erodes the height attachment `height_name` into the new height attachment `name`,
+    /// which is then rendered as the height of the terrain.
+    /// The optional flow and deposition masks are added as attachments as well.
+    /// See [`preprocess_erosion`] for details.
+    pub fn preprocess_erosion(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        flow_name: Option<&str>,
+        deposition_name: Option<&str>,
+        settings: &ErosionSettings,
+        filter: &DownSampleFilter,
+    ) {
+        let height = self.height_attachment(height_name);
+        let flow_directory = flow_name.map(|name| self.attachment_directory(name));
+        let deposition_directory = deposition_name.map(|name| self.attachment_directory(name));
+
+        preprocess_erosion(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            flow_directory.as_deref(),
+            deposition_directory.as_deref(),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            self.height,
+            settings,
+            filter,
+        );
+
+        self.height_attachment = name.to_string();
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            ..height.clone()
+        });
+
+        for mask_name in [flow_name, deposition_name].into_iter().flatten() {
+            self.insert_attachment(AttachmentManifest {
+                name: mask_name.to_string(),
+                format: ImageFormat::LUMA16,
+                srgb: false,
+                border_size: 0,
+                normal_encoding: None,
+                ..height.clone()
+            });
+        }
+    }
+
+    /// Derives the attachment `name` from the height attachment `height_name` by evaluating
+    /// `texel` for every texel. See [`preprocess_derived`] for details.
+    pub fn preprocess_derived(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        border_size: u32,
+        format: ImageFormat,
+        srgb: bool,
+        filter: &DownSampleFilter,
+        settings: &str,
+        texel: impl Fn(&HeightWindow) -> [f32; 4],
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_derived(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            border_size,
+            self.height,
+            format,
+            filter,
+            settings,
+            texel,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            format,
+            srgb,
+            border_size,
+            ..height
+        });
+    }
+
+    /// Rasterizes the `features` into the mask attachment `name`.
+    /// See [`preprocess_mask`] for details.
+    pub fn preprocess_mask(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        border_size: u32,
+        features: &[VectorFeature],
+        filter: &DownSampleFilter,
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_mask(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            border_size,
+            features,
+            filter,
+        );
+
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            border_size,
+            ..height
+        });
+    }
+
+    /// Applies the height modifiers of the `features` to the height attachment `height_name`
+    /// and stores the result as the height attachment `name`, which is then rendered as the
+    /// height of the terrain.
+    /// See [`preprocess_carve`] for details.
+    pub fn preprocess_carve(
+        &mut self,
+        name: &str,
+        height_name: &str,
+        features: &[VectorFeature],
+        filter: &DownSampleFilter,
+    ) {
+        let height = self.height_attachment(height_name);
+
+        preprocess_carve(
+            &self.attachment_directory(height_name),
+            &self.attachment_directory(name),
+            self.lod_count,
+            height.texture_size,
+            height.border_size,
+            self.height,
+            features,
+            filter,
+        );
+
+        self.height_attachment = name.to_string();
+        self.insert_attachment(AttachmentManifest {
+            name: name.to_string(),
+            ..height
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::calc_node_id;
+    use image::{ImageBuffer, Luma};
+    use std::process;
+
+    fn attachment(name: &str, normal_encoding: Option<NormalEncoding>) -> AttachmentManifest {
+        AttachmentManifest {
+            name: name.to_string(),
+            format: ImageFormat::RG8,
+            srgb: false,
+            texture_size: 128,
+            border_size: 1,
+            first: (0, 0),
+            last: (1, 1),
+            normal_encoding,
+        }
+    }
+
+    #[test]
+    fn normal_encoding_is_read_from_the_attachments() {
+        let mut manifest = TerrainManifest::new("", 128, 128, 1, 1.0);
+        assert_eq!(manifest.normal_encoding(), None);
+
+        manifest.attachments.push(attachment("height", None));
+        manifest
+            .attachments
+            .push(attachment("normal", Some(NormalEncoding::Octahedral)));
+
+        let manifest: TerrainManifest =
+            ron::from_str(&ron::ser::to_string(&manifest).unwrap()).unwrap();
+
+        assert_eq!(manifest.normal_encoding(), Some(NormalEncoding::Octahedral));
+    }
+
+    #[test]
+    fn attachments_are_selected_by_name() {
+        let mut manifest = TerrainManifest::new("", 128, 128, 1, 1.0);
+        manifest.attachments = ["height", "albedo", "normal"]
+            .map(|name| attachment(name, None))
+            .to_vec();
+
+        let names = |attachments: Vec<&AttachmentManifest>| {
+            attachments
+                .iter()
+                .map(|attachment| attachment.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(
+                manifest
+                    .select_attachments(&["height", "normal", "height"])
+                    .unwrap()
+            ),
+            ["height", "normal"]
+        );
+        assert_eq!(
+            manifest
+                .select_attachments(&["height", "splat"])
+                .unwrap_err(),
+            TerrainLoadError::UnknownAttachment("splat".to_string())
+        );
+    }
+
+    #[test]
+    fn too_many_attachments_are_rejected() {
+        let names: Vec<String> = (0..=MAX_ATTACHMENT_COUNT)
+            .map(|i| format!("attachment_{i}"))
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        let mut manifest = TerrainManifest::new("", 128, 128, 1, 1.0);
+        manifest.attachments = names.iter().map(|name| attachment(name, None)).collect();
+        manifest.height_attachment = names[0].to_string();
+
+        assert!(manifest
+            .select_attachments(&names[..MAX_ATTACHMENT_COUNT])
+            .is_ok());
+        assert_eq!(
+            manifest.select_attachments(&names).unwrap_err(),
+            TerrainLoadError::TooManyAttachments(MAX_ATTACHMENT_COUNT + 1)
+        );
+    }
+
+    #[test]
+    fn manifests_without_normal_encoding_still_load() {
+        let attachment: AttachmentManifest = ron::from_str(
+            r#"(name: "height", format: LUMA16, srgb: false, texture_size: 128, border_size: 1,
+            first: (0, 0), last: (1, 1))"#,
+        )
+        .unwrap();
+
+        assert_eq!(attachment.normal_encoding, None);
+    }
+
+    #[test]
+    fn manifests_without_height_attachment_render_the_height() {
+        let manifest: TerrainManifest = ron::from_str(
+            "(terrain_size: 128, chunk_size: 128, lod_count: 1, height: 1.0, attachments: [])",
+        )
+        .unwrap();
+
+        assert_eq!(manifest.height_attachment, "height");
+    }
+
+    #[test]
+    fn carved_heights_are_rendered() {
+        let directory = format!(
+            "{}/carved_terrain_{}/",
+            std::env::temp_dir().display(),
+            process::id()
+        );
+        let mut manifest = TerrainManifest::new(&directory, 4, 4, 1, 100.0);
+
+        let height_directory = manifest.attachment_directory("height");
+        fs::create_dir_all(&height_directory).unwrap();
+        ImageBuffer::from_pixel(6, 6, Luma([1000u16]))
+            .save(format!("{height_directory}/{}.png", calc_node_id(0, 0, 0)))
+            .unwrap();
+        manifest.attachments.push(AttachmentManifest {
+            format: ImageFormat::LUMA16,
+            texture_size: 4,
+            ..attachment("height", None)
+        });
+
+        manifest.preprocess_carve("carved", "height", &[], &DownSampleFilter::Average);
+
+        let attachments = manifest.select_attachments(&["height"]).unwrap();
+        assert_eq!(manifest.height_attachment, "carved");
+        assert_eq!(attachments[0].name, "carved");
+        assert_eq!(attachments[0].border_size, 1);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -12,6 +12,13 @@ use std::str::FromStr;
 /// Resource that stores components that are associated to a terrain entity and a view entity.
 pub type TerrainViewComponents<C> = HashMap<(Entity, Entity), C>;
 
+/// Marks a view, which tessellates and renders the terrain.
+///
+/// Besides cameras, shadow casting directional lights can be terrain views as well.
+/// They receive their own tessellation, which is rendered into their shadow maps.
+/// Directional lights use their translation only to select the level of detail,
+/// so it should follow the camera.
+/// Point lights are not supported, the terrain does not cast shadows into their shadow maps.
 #[derive(Clone, Copy, Component)]
 pub struct TerrainView;
 
@@ -39,6 +46,9 @@ pub(crate) struct TerrainViewConfigUniform {
     morph_blend: f32,
     vertex_blend: f32,
     fragment_blend: f32,
+
+    density_thresholds: Vec4,
+    view_position: Vec4,
 }
 
 #[derive(Clone, Component)]
@@ -57,6 +67,11 @@ pub struct TerrainViewConfig {
     pub morph_blend: f32,
     pub vertex_blend: f32,
     pub fragment_blend: f32,
+    /// The densities above which tiles are tessellated with the next higher resolution.
+    /// Tiles with a density below the first threshold use the lowest of the four resolutions.
+    pub density_thresholds: [f32; 3],
+    /// The position of the view, which the level of detail is selected for.
+    pub(crate) view_position: Vec3,
 }
 
 impl TerrainViewConfig {
@@ -70,12 +85,7 @@ impl TerrainViewConfig {
         vertex_blend: f32,
         fragment_blend: f32,
     ) -> Self {
-        // Todo: fix this awful hack
-        let quadtree_handle = HandleUntyped::weak_from_u64(
-            Uuid::from_str("6ea26da6-6cf8-4ea2-9986-1d7bf6c17d6f").unwrap(),
-            fastrand::u64(..),
-        )
-        .typed();
+        let quadtree_handle = Self::quadtree_handle();
 
         let tile_count = 1000000;
 
@@ -96,9 +106,32 @@ impl TerrainViewConfig {
             morph_blend,
             vertex_blend,
             fragment_blend,
+            density_thresholds: [0.025, 0.05, 0.075],
+            view_position: Vec3::ZERO,
+        }
+    }
+
+    /// Derives the config of a shadow casting light from the config of a camera.
+    /// The light uses the same load and view distances, but tessellates the terrain with
+    /// larger tiles, because shadows tolerate a coarser geometry.
+    /// Its quadtree is separate and selects the nodes around the translation of the light.
+    pub fn shadow_config(&self) -> Self {
+        Self {
+            quadtree_handle: Self::quadtree_handle(),
+            tile_scale: 2.0 * self.tile_scale,
+            ..self.clone()
         }
     }
 
+    fn quadtree_handle() -> Handle<Image> {
+        // Todo: fix this awful hack
+        HandleUntyped::weak_from_u64(
+            Uuid::from_str("6ea26da6-6cf8-4ea2-9986-1d7bf6c17d6f").unwrap(),
+            fastrand::u64(..),
+        )
+        .typed()
+    }
+
     pub(crate) fn change_tile_scale(&mut self, new: f32) {
         self.tile_scale = new;
         // self.refinement_count = (self.terrain_size as f32 / self.tile_scale).log2().ceil() as u32;
@@ -115,6 +148,23 @@ impl TerrainViewConfig {
             morph_blend: self.morph_blend,
             vertex_blend: self.vertex_blend,
             fragment_blend: self.fragment_blend,
+            density_thresholds: Vec3::from_array(self.density_thresholds).extend(1.0),
+            view_position: self.view_position.extend(1.0),
+        }
+    }
+}
+
+/// Updates the position of all terrain views, which their level of detail is selected for.
+pub(crate) fn update_view_position(
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    view_query: Query<(Entity, &GlobalTransform), With<TerrainView>>,
+    terrain_query: Query<Entity, With<Terrain>>,
+) {
+    for terrain in terrain_query.iter() {
+        for (view, view_transform) in view_query.iter() {
+            if let Some(view_config) = view_configs.get_mut(&(terrain, view)) {
+                view_config.view_position = view_transform.translation();
+            }
         }
     }
 }